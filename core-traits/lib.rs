@@ -855,6 +855,40 @@ where
     }
 }
 
+/// A row of the results of a query with a return map -- `:keys`, `:strs`, or `:syms` -- which
+/// associates each name with the row's value for the corresponding `:find` element, in order.
+/// The names are keywords, strings, or symbols, according to the kind of return map.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyedRow(pub IndexMap<edn::Value, Binding>);
+
+impl Deref for KeyedRow {
+    type Target = IndexMap<edn::Value, Binding>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl KeyedRow {
+    /// The value for the key named `name`, whatever its kind: `"name"` finds `:name`, `"name"`,
+    /// and `name`.
+    pub fn by_name(&self, name: &str) -> Option<&Binding> {
+        self.0.iter().find(|&(key, _)| {
+            match key {
+                &edn::Value::Keyword(ref k) => k.to_string()[1..] == *name,
+                &edn::Value::Text(ref s) => s == name,
+                key => key.to_string() == name,
+            }
+        }).map(|(_, v)| v)
+    }
+}
+
+impl From<IndexMap<edn::Value, Binding>> for KeyedRow {
+    fn from(src: IndexMap<edn::Value, Binding>) -> Self {
+        KeyedRow(src)
+    }
+}
+
 impl Binding {
     /// Returns true if the provided type is `Some` and matches this value's type, or if the
     /// provided type is `None`.
@@ -0,0 +1,125 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! User-defined aggregates.
+
+use std::collections::{
+    BTreeMap,
+};
+
+use std::fmt;
+
+use std::sync::{
+    Arc,
+};
+
+use core_traits::{
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+/// An aggregate implemented in Rust, which can be used in `:find` once it has been registered
+/// with a store: `[:find ?team (histogram ?age) :where …]`.
+///
+/// Like the built-in aggregates, it is applied to the values of a single variable within each
+/// group. The values are folded into a `State`, starting from `init`, with `step` called once
+/// per value, and `finish` producing the result.
+pub trait CustomAggregate: Send + Sync + 'static {
+    type State;
+
+    /// The type of the result when aggregating values that might have any of `input_types`, or
+    /// `None` if the aggregate can't be applied to such values. `finish` must produce values
+    /// of this type.
+    fn result_type(&self, input_types: ValueTypeSet) -> Option<ValueType>;
+
+    fn init(&self) -> Self::State;
+    fn step(&self, state: &mut Self::State, value: TypedValue);
+
+    /// Produce the result of the aggregate, or `None` if there is none, in which case the group
+    /// is omitted from the results. `finish` isn't called for groups with no values.
+    fn finish(&self, state: Self::State) -> Option<TypedValue>;
+}
+
+/// What a query needs to know about a registered aggregate.
+trait AggregateResultType: Send + Sync {
+    fn result_type(&self, input_types: ValueTypeSet) -> Option<ValueType>;
+}
+
+impl<A> AggregateResultType for A where A: CustomAggregate {
+    fn result_type(&self, input_types: ValueTypeSet) -> Option<ValueType> {
+        CustomAggregate::result_type(self, input_types)
+    }
+}
+
+/// The custom aggregates known to a store, by name. Each must also be registered with each
+/// SQLite connection that runs queries using it, under its `sql_name`.
+#[derive(Clone, Default)]
+pub struct AggregateRegistry {
+    aggregates: BTreeMap<String, Arc<dyn AggregateResultType>>,
+}
+
+impl fmt::Debug for AggregateRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.aggregates.keys()).finish()
+    }
+}
+
+impl AggregateRegistry {
+    /// Register `aggregate` under `name`, replacing any aggregate already registered under it.
+    /// Built-in aggregates, like `count`, take precedence over those registered here.
+    pub fn register<A>(&mut self, name: &str, aggregate: Arc<A>) where A: CustomAggregate {
+        self.aggregates.insert(name.to_string(), aggregate);
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.aggregates.contains_key(name)
+    }
+
+    /// Return `None` if no aggregate is registered under `name`, and otherwise the type of its
+    /// result when applied to values that might have any of `input_types`.
+    pub fn result_type(&self, name: &str, input_types: ValueTypeSet) -> Option<Option<ValueType>> {
+        self.aggregates.get(name).map(|a| a.result_type(input_types))
+    }
+
+    /// The name of the SQL aggregate function for the aggregate registered under `name`. It takes
+    /// two arguments: a value, and its type tag.
+    pub fn sql_name(name: &str) -> String {
+        escaped_sql_name("mentat_custom_", name)
+    }
+}
+
+/// Prefix `name` to make a SQL identifier. Symbols can contain characters that SQL identifiers
+/// can't, so those are escaped.
+pub(crate) fn escaped_sql_name(prefix: &str, name: &str) -> String {
+    let mut out = prefix.to_string();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("_{:02x}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sql_name() {
+        assert_eq!(AggregateRegistry::sql_name("histogram"), "mentat_custom_histogram");
+
+        // Escaping keeps distinct names distinct.
+        assert_eq!(AggregateRegistry::sql_name("weighted-avg"), "mentat_custom_weighted_2davg");
+        assert_eq!(AggregateRegistry::sql_name("weighted_avg"), "mentat_custom_weighted_5favg");
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! User-defined predicates and functions.
+
+use std::collections::{
+    BTreeMap,
+};
+
+use std::fmt;
+
+use std::sync::{
+    Arc,
+};
+
+use core_traits::{
+    TypedValue,
+    ValueType,
+};
+
+use aggregates::escaped_sql_name;
+
+/// The Rust implementation of a `CustomFunction`.
+type FunctionImpl = Arc<dyn Fn(&[TypedValue]) -> Option<TypedValue> + Send + Sync>;
+
+/// A function implemented in Rust, which can be used in a query once it has been registered with
+/// a store. One that returns a boolean is a predicate, `[(my/valid-email? ?s)]`; any function
+/// can bind its result, `[(my/normalize ?s) ?n]`.
+///
+/// Each argument has a declared type, and arguments of other types don't match. The function
+/// returns a value of its declared return type, or `None` if it has no result, in which case
+/// neither does the clause.
+#[derive(Clone)]
+pub struct CustomFunction {
+    argument_types: Vec<ValueType>,
+    return_type: ValueType,
+    function: FunctionImpl,
+}
+
+impl fmt::Debug for CustomFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomFunction({:?} -> {:?})", self.argument_types, self.return_type)
+    }
+}
+
+impl CustomFunction {
+    pub fn new<F>(argument_types: Vec<ValueType>, return_type: ValueType, function: F) -> CustomFunction
+    where F: Fn(&[TypedValue]) -> Option<TypedValue> + Send + Sync + 'static {
+        CustomFunction {
+            argument_types: argument_types,
+            return_type: return_type,
+            function: Arc::new(function),
+        }
+    }
+
+    pub fn predicate<F>(argument_types: Vec<ValueType>, predicate: F) -> CustomFunction
+    where F: Fn(&[TypedValue]) -> bool + Send + Sync + 'static {
+        CustomFunction::new(argument_types, ValueType::Boolean, move |args| {
+            Some(TypedValue::Boolean(predicate(args)))
+        })
+    }
+
+    pub fn argument_types(&self) -> &[ValueType] {
+        &self.argument_types
+    }
+
+    pub fn return_type(&self) -> ValueType {
+        self.return_type
+    }
+
+    pub fn is_predicate(&self) -> bool {
+        self.return_type == ValueType::Boolean
+    }
+
+    /// Apply the function to `args`, which have the declared argument types.
+    pub fn call(&self, args: &[TypedValue]) -> Option<TypedValue> {
+        (self.function)(args)
+    }
+}
+
+/// The custom functions known to a store, by name. Each must also be registered with each
+/// SQLite connection that runs queries using it, under its `sql_name`.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionRegistry {
+    functions: BTreeMap<String, CustomFunction>,
+}
+
+impl FunctionRegistry {
+    /// Register `function` under `name`, replacing any function already registered under it.
+    /// Built-in functions, like `ground`, take precedence over those registered here.
+    pub fn register(&mut self, name: &str, function: CustomFunction) {
+        self.functions.insert(name.to_string(), function);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CustomFunction> {
+        self.functions.get(name)
+    }
+
+    /// The name of the SQL function for the function registered under `name`. It takes the
+    /// function's arguments, without their type tags.
+    pub fn sql_name(name: &str) -> String {
+        escaped_sql_name("mentat_fn_", name)
+    }
+}
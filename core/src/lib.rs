@@ -25,7 +25,9 @@ use core_traits::{
     ValueType,
 };
 
+mod aggregates;
 mod cache;
+mod functions;
 
 use std::collections::{
     BTreeMap,
@@ -52,6 +54,16 @@ pub use edn::parse::{
     parse_query,
 };
 
+pub use aggregates::{
+    AggregateRegistry,
+    CustomAggregate,
+};
+
+pub use functions::{
+    CustomFunction,
+    FunctionRegistry,
+};
+
 pub use cache::{
     CachedAttributes,
     UpdateableCache,
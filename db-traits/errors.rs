@@ -144,6 +144,10 @@ pub enum DbErrorKind {
     #[error("bad SQL (value_type_tag, value) pair: ({0:?}, {1:?})")]
     BadSQLValuePair(rusqlite::types::Value, i32),
 
+    /// A custom function returned a value that isn't of its declared return type.
+    #[error("custom function {0} returned a value that is not of type {1:?}")]
+    BadCustomFunctionResult(String, ValueType),
+
     // /// The SQLite store user_version isn't recognized.  This could be an old version of Mentat
     // /// trying to open a newer version SQLite store; or it could be a corrupt file; or ...
     // #[error("bad SQL store user_version: {0}")]
@@ -1105,6 +1105,40 @@ impl AttributeCaches {
         cache.populate_cache_for_entities_and_attributes(schema, sqlite, attrs, entities)?;
         Ok(cache)
     }
+
+    /// Fetch the entities that refer to `values` through the requested ref attributes and put
+    /// them in a new cache, in which those attributes are cached in reverse.
+    /// The caller is responsible for ensuring that `values` is unique.
+    pub fn make_reverse_cache_for_values_and_attributes<'s, 'c>(schema: &'s Schema,
+                                                                sqlite: &'c rusqlite::Connection,
+                                                                attrs: &BTreeSet<Entid>,
+                                                                values: &Vec<Entid>) -> Result<AttributeCaches> {
+        let mut cache = AttributeCaches::default();
+        if attrs.is_empty() || values.is_empty() {
+            return Ok(cache);
+        }
+
+        // Refs are the only values in the VAET index.
+        let mut qb = SQLiteQueryBuilder::new();
+        qb.push_sql("SELECT a, e, v, value_type_tag FROM datoms WHERE index_vaet IS NOT 0 AND v IN (");
+        interpose!(item, values,
+                   { qb.push_sql(&item.to_string()) },
+                   { qb.push_sql(", ") });
+        qb.push_sql(") AND a IN (");
+        interpose!(item, attrs,
+                   { qb.push_sql(&item.to_string()) },
+                   { qb.push_sql(", ") });
+        qb.push_sql(") ORDER BY a ASC, e ASC");
+
+        cache.reverse_cached_attributes.extend(attrs.iter());
+
+        let SQLQuery { sql, args } = qb.finish();
+        assert!(args.is_empty());
+        let mut stmt = sqlite.prepare(sql.as_str())?;
+        let replacing = false;
+        cache.repopulate_from_aevt(schema, &mut stmt, vec![], replacing)?;
+        Ok(cache)
+    }
 }
 
 
@@ -1335,6 +1369,12 @@ pub struct InProgressSQLiteAttributeCache {
     pub overlay: AttributeCaches,
     unregistered_forward: BTreeSet<Entid>,
     unregistered_reverse: BTreeSet<Entid>,
+
+    /// Whether attributes have been registered or unregistered.
+    changed_registrations: bool,
+
+    /// Whether a transaction has asserted or retracted values of cached attributes.
+    changed_values: bool,
 }
 
 impl InProgressSQLiteAttributeCache {
@@ -1345,9 +1385,21 @@ impl InProgressSQLiteAttributeCache {
             overlay: overlay,
             unregistered_forward: Default::default(),
             unregistered_reverse: Default::default(),
+            changed_registrations: false,
+            changed_values: false,
         }
     }
 
+    /// Whether attributes have been registered or unregistered since this cache was made.
+    pub fn has_changed_registrations(&self) -> bool {
+        self.changed_registrations
+    }
+
+    /// Whether the values of cached attributes have changed since this cache was made.
+    pub fn has_changed_values(&self) -> bool {
+        self.changed_values
+    }
+
     pub fn register_forward<U>(&mut self, schema: &Schema, sqlite: &rusqlite::Connection, attribute: U) -> Result<()>
     where U: Into<Entid> {
         let a = attribute.into();
@@ -1359,6 +1411,7 @@ impl InProgressSQLiteAttributeCache {
             return Ok(());
         }
 
+        self.changed_registrations = true;
         self.unregistered_forward.remove(&a);
         self.overlay.forward_cached_attributes.insert(a);
         self.overlay.repopulate(schema, sqlite, a)
@@ -1375,6 +1428,7 @@ impl InProgressSQLiteAttributeCache {
             return Ok(());
         }
 
+        self.changed_registrations = true;
         self.unregistered_reverse.remove(&a);
         self.overlay.reverse_cached_attributes.insert(a);
         self.overlay.repopulate(schema, sqlite, a)
@@ -1395,6 +1449,7 @@ impl InProgressSQLiteAttributeCache {
             return Ok(());
         }
 
+        self.changed_registrations = true;
         self.unregistered_forward.remove(&a);
         self.unregistered_reverse.remove(&a);
         if !reverse_done {
@@ -1411,12 +1466,14 @@ impl InProgressSQLiteAttributeCache {
     pub fn unregister<U>(&mut self, attribute: U)
     where U: Into<Entid> {
         let a = attribute.into();
+        self.changed_registrations = true;
         self.overlay.unregister_attribute(a);
         self.unregistered_forward.insert(a);
         self.unregistered_reverse.insert(a);
     }
 
     pub fn unregister_all(&mut self) {
+        self.changed_registrations = true;
         self.overlay.unregister_all_attributes();
         self.unregistered_forward.extend(self.inner.forward_cached_attributes.iter().cloned());
         self.unregistered_reverse.extend(self.inner.reverse_cached_attributes.iter().cloned());
@@ -1640,6 +1697,13 @@ impl<'a> TransactWatcher for InProgressCacheTransactWatcher<'a> {
         // try to break this out as a helper function.
         let collected_retractions = mem::replace(&mut self.collected_retractions, Default::default());
         let collected_assertions = mem::replace(&mut self.collected_assertions, Default::default());
+        let is_cached = |evs: &Either<(), Vec<(Entid, TypedValue)>>| match evs {
+            &Either::Left(_) => false,
+            &Either::Right(_) => true,
+        };
+        if collected_retractions.values().any(&is_cached) || collected_assertions.values().any(&is_cached) {
+            self.cache.changed_values = true;
+        }
         let mut intermediate_expansion =
             once(collected_retractions)
                 .chain(once(collected_assertions))
@@ -29,6 +29,7 @@ use {repeat_values, to_namespaced_keyword};
 use edn::{DateTime, Utc, Uuid, Value};
 
 use entids;
+use functions;
 
 use core_traits::{attribute, Attribute, AttributeBitFlags, Entid, TypedValue, ValueType};
 
@@ -101,6 +102,8 @@ fn make_connection(
         initial_pragmas
     ))?;
 
+    functions::add_query_functions(&conn)?;
+
     Ok(conn)
 }
 
@@ -560,7 +563,11 @@ pub fn read_partition_map(conn: &rusqlite::Connection) -> Result<PartitionMap> {
 
 /// Read the ident map materialized view from the given SQL store.
 pub(crate) fn read_ident_map(conn: &rusqlite::Connection) -> Result<IdentMap> {
-    let v = read_materialized_view(conn, "idents")?;
+    read_ident_map_from(conn, "idents")
+}
+
+fn read_ident_map_from(conn: &rusqlite::Connection, table: &str) -> Result<IdentMap> {
+    let v = read_materialized_view(conn, table)?;
     v.into_iter().map(|(e, a, typed_value)| {
         if a != entids::DB_IDENT {
             bail!(DbErrorKind::NotYetImplemented(format!("bad idents materialized view: expected :db/ident but got {}", a)));
@@ -575,12 +582,23 @@ pub(crate) fn read_ident_map(conn: &rusqlite::Connection) -> Result<IdentMap> {
 
 /// Read the schema materialized view from the given SQL store.
 pub(crate) fn read_attribute_map(conn: &rusqlite::Connection) -> Result<AttributeMap> {
-    let entid_triples = read_materialized_view(conn, "schema")?;
+    read_attribute_map_from(conn, "schema")
+}
+
+fn read_attribute_map_from(conn: &rusqlite::Connection, table: &str) -> Result<AttributeMap> {
+    let entid_triples = read_materialized_view(conn, table)?;
     let mut attribute_map = AttributeMap::default();
     metadata::update_attribute_map_from_entid_triples(&mut attribute_map, entid_triples, vec![])?;
     Ok(attribute_map)
 }
 
+/// Read the schema of the Mentat store attached to the given SQL store as `database`.
+pub fn read_attached_schema(conn: &rusqlite::Connection, database: &str) -> Result<Schema> {
+    let ident_map = read_ident_map_from(conn, format!("{}.idents", database).as_str())?;
+    let attribute_map = read_attribute_map_from(conn, format!("{}.schema", database).as_str())?;
+    Ok(Schema::from_ident_map_and_attribute_map(ident_map, attribute_map)?)
+}
+
 /// Read the materialized views from the given SQL store and return a Mentat `DB` for querying and
 /// applying transactions.
 pub(crate) fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
@@ -0,0 +1,526 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! SQL functions that queries can use beyond SQLite's built-ins. These are registered on every
+//! connection by `new_connection`.
+
+use std::error::Error as StdError;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use regex::Regex;
+use rusqlite;
+use rusqlite::functions::{Aggregate, Context, FunctionFlags};
+use rusqlite::types::{ToSql, ToSqlOutput, Value, ValueRef};
+
+use core_traits::TypedValue;
+
+use mentat_core::{
+    AggregateRegistry, CustomAggregate, CustomFunction, FunctionRegistry, SQLValueType,
+};
+
+use db::TypedSQLValue;
+
+use db_traits::errors::{DbErrorKind, Result};
+
+pub(crate) fn add_query_functions(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    // `re_find(pattern, text)` is the first match of the regular expression `pattern` in `text`,
+    // or `NULL` if there is none. Each statement compiles a constant pattern only once.
+    conn.create_scalar_function("re_find", 2, flags, |ctx| {
+        let pattern = ctx.get_or_create_aux(
+            0,
+            |v| -> ::std::result::Result<Regex, Box<dyn StdError + Send + Sync>> {
+                Ok(Regex::new(v.as_str()?)?)
+            },
+        )?;
+        let text = ctx.get::<Option<String>>(1)?;
+        Ok(text.and_then(|text| pattern.find(&text).map(|m| m.as_str().to_string())))
+    })?;
+
+    // `mentat_fulltext_score(matchinfo(fulltext_values, 'pcnalx'))` is the BM25 relevance of a
+    // fulltext match: higher is more relevant.
+    conn.create_scalar_function("mentat_fulltext_score", 1, flags, |ctx| {
+        let info = ctx.get_raw(0).as_blob().map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+        matchinfo_values(info)
+            .and_then(|values| bm25(&values))
+            .ok_or_else(|| rusqlite::Error::UserFunctionError("expected matchinfo(..., 'pcnalx')".into()))
+    })?;
+
+    // `mentat_fulltext_highlight(text, offsets(fulltext_values), start, end)` is `text` with each
+    // matched term wrapped in `start` and `end`.
+    conn.create_scalar_function("mentat_fulltext_highlight", 4, flags, |ctx| {
+        let text = ctx.get::<String>(0)?;
+        let offsets = ctx.get::<String>(1)?;
+        let start = ctx.get::<String>(2)?;
+        let end = ctx.get::<String>(3)?;
+        Ok(highlight(&text, &offsets, &start, &end))
+    })?;
+
+    conn.create_aggregate_function("mentat_median", 1, flags, Median)?;
+    conn.create_aggregate_function("mentat_variance", 1, flags, Variance { stddev: false })?;
+    conn.create_aggregate_function("mentat_stddev", 1, flags, Variance { stddev: true })?;
+    conn.create_aggregate_function("mentat_collect", 2, flags, Collect)?;
+    Ok(())
+}
+
+/// `matchinfo` produces an array of native-endian 32-bit unsigned integers. Returns `None` if
+/// `info` isn't a whole number of them.
+#[allow(clippy::manual_is_multiple_of)]
+fn matchinfo_values(info: &[u8]) -> Option<Vec<u32>> {
+    if info.len() % 4 != 0 {
+        return None;
+    }
+    Some(info.chunks_exact(4)
+             .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+             .collect())
+}
+
+/// Okapi BM25, as computed by FTS5, from the `pcnalx` fields of `matchinfo`: the number of
+/// phrases and columns, the number of rows, the average number of tokens in each column, the
+/// number of tokens in each column of this row, and for each phrase and column, the hits in this
+/// row, the hits in all rows, and the number of rows with hits. Unlike FTS5's `bm25`, which is
+/// negated so that it sorts in ascending order, the score is positive. Returns `None` if `info`
+/// is too short to hold all of these.
+fn bm25(info: &[u32]) -> Option<f64> {
+    const K1: f64 = 1.2;
+    const B: f64 = 0.75;
+
+    if info.len() < 3 {
+        return None;
+    }
+    let (phrases, columns) = (info[0] as usize, info[1] as usize);
+    let expected = phrases.checked_mul(columns)
+                          .and_then(|n| n.checked_mul(3))
+                          .and_then(|n| n.checked_add(3 + 2 * columns));
+    if expected.map_or(true, |expected| info.len() < expected) {
+        return None;
+    }
+    let rows = info[2] as f64;
+    let averages = &info[3..3 + columns];
+    let lengths = &info[3 + columns..3 + 2 * columns];
+    let hits = &info[3 + 2 * columns..];
+
+    let mut score = 0.0;
+    for phrase in 0..phrases {
+        for column in 0..columns {
+            let offset = 3 * (phrase * columns + column);
+            let (frequency, rows_with_hits) = (hits[offset] as f64, hits[offset + 2] as f64);
+            if frequency == 0.0 {
+                continue;
+            }
+
+            // Adding one keeps the weight of terms in more than half of the rows positive.
+            let idf = (1.0 + (rows - rows_with_hits + 0.5) / (rows_with_hits + 0.5)).ln();
+            let length = lengths[column] as f64 / (averages[column] as f64).max(1.0);
+            score += idf * (frequency * (K1 + 1.0)) / (frequency + K1 * (1.0 - B + B * length));
+        }
+    }
+    Some(score)
+}
+
+/// Wrap the matches described by `offsets` — which lists the column, term, byte offset, and size
+/// of each — in `start` and `end`. Matches in other columns than the text are ignored.
+fn highlight(text: &str, offsets: &str, start: &str, end: &str) -> String {
+    let numbers: Vec<usize> = offsets.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+    let mut matches: Vec<(usize, usize)> = numbers.chunks(4)
+        .filter(|m| m.len() == 4 && m[0] == 0)
+        .map(|m| (m[2], m[2] + m[3]))
+        .filter(|&(from, to)| to <= text.len() && text.is_char_boundary(from) && text.is_char_boundary(to))
+        .collect();
+    matches.sort();
+
+    let mut out = String::with_capacity(text.len());
+    let mut position = 0;
+    for (from, to) in matches {
+        // A token can match more than one term; highlight it once.
+        if from < position {
+            continue;
+        }
+        out.push_str(&text[position..from]);
+        out.push_str(start);
+        out.push_str(&text[from..to]);
+        out.push_str(end);
+        position = to;
+    }
+    out.push_str(&text[position..]);
+    out
+}
+
+/// The median of numeric values, averaging the middle two if there are an even number of them.
+struct Median;
+
+impl Aggregate<Vec<f64>, Option<f64>> for Median {
+    fn init(&self, _: &mut Context) -> rusqlite::Result<Vec<f64>> {
+        Ok(vec![])
+    }
+
+    fn step(&self, ctx: &mut Context, values: &mut Vec<f64>) -> rusqlite::Result<()> {
+        if let Some(v) = ctx.get::<Option<f64>>(0)? {
+            values.push(v);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context, values: Option<Vec<f64>>) -> rusqlite::Result<Option<f64>> {
+        let mut values = values.unwrap_or_default();
+        if values.is_empty() {
+            return Ok(None);
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let middle = values.len() / 2;
+        if values.len() % 2 == 0 {
+            Ok(Some((values[middle - 1] + values[middle]) / 2.0))
+        } else {
+            Ok(Some(values[middle]))
+        }
+    }
+}
+
+/// The population variance or standard deviation of numeric values, computed in a single pass
+/// with Welford's algorithm. The accumulator is the count, the mean, and the sum of squared
+/// differences from the mean.
+struct Variance {
+    stddev: bool,
+}
+
+impl Aggregate<(u64, f64, f64), Option<f64>> for Variance {
+    fn init(&self, _: &mut Context) -> rusqlite::Result<(u64, f64, f64)> {
+        Ok((0, 0.0, 0.0))
+    }
+
+    fn step(&self, ctx: &mut Context, acc: &mut (u64, f64, f64)) -> rusqlite::Result<()> {
+        if let Some(v) = ctx.get::<Option<f64>>(0)? {
+            let &mut (ref mut count, ref mut mean, ref mut m2) = acc;
+            *count += 1;
+            let delta = v - *mean;
+            *mean += delta / (*count as f64);
+            *m2 += delta * (v - *mean);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context, acc: Option<(u64, f64, f64)>) -> rusqlite::Result<Option<f64>> {
+        match acc {
+            Some((count, _, m2)) if count > 0 => {
+                let variance = m2 / (count as f64);
+                Ok(Some(if self.stddev { variance.sqrt() } else { variance }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// Each value collected by `mentat_collect` is encoded as its type tag, the kind of SQL value that
+// represents it, and that SQL value.
+const COLLECTED_INTEGER: u8 = 1;
+const COLLECTED_REAL: u8 = 2;
+const COLLECTED_TEXT: u8 = 3;
+const COLLECTED_BLOB: u8 = 4;
+
+/// `mentat_collect(value, value_type_tag)` gathers every value in a group into a single blob,
+/// so that aggregates that produce collections -- `distinct`, `sample`, and so on -- can be
+/// finished in Rust. Use `decode_collected_values` to turn the blob back into values.
+struct Collect;
+
+impl Aggregate<Vec<u8>, Vec<u8>> for Collect {
+    fn init(&self, _: &mut Context) -> rusqlite::Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn step(&self, ctx: &mut Context, out: &mut Vec<u8>) -> rusqlite::Result<()> {
+        let tag = ctx.get::<i32>(1)?;
+        let (kind, bytes) = match ctx.get_raw(0) {
+            ValueRef::Null => return Ok(()),
+            ValueRef::Integer(i) => (COLLECTED_INTEGER, i.to_le_bytes().to_vec()),
+            ValueRef::Real(f) => (COLLECTED_REAL, f.to_bits().to_le_bytes().to_vec()),
+            ValueRef::Text(t) => (COLLECTED_TEXT, t.to_vec()),
+            ValueRef::Blob(b) => (COLLECTED_BLOB, b.to_vec()),
+        };
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.push(kind);
+        if kind == COLLECTED_TEXT || kind == COLLECTED_BLOB {
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        }
+        out.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context, out: Option<Vec<u8>>) -> rusqlite::Result<Vec<u8>> {
+        Ok(out.unwrap_or_default())
+    }
+}
+
+/// Decode the values gathered by the `mentat_collect` aggregate, in the order they were seen.
+pub fn decode_collected_values(mut bytes: &[u8]) -> Result<Vec<TypedValue>> {
+    fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if bytes.len() < n {
+            bail!(DbErrorKind::BadSQLValuePair(Value::Blob(bytes.to_vec()), -1));
+        }
+        let (head, tail) = bytes.split_at(n);
+        *bytes = tail;
+        Ok(head)
+    }
+
+    fn take_8(bytes: &mut &[u8]) -> Result<[u8; 8]> {
+        let mut out = [0u8; 8];
+        out.copy_from_slice(take(bytes, 8)?);
+        Ok(out)
+    }
+
+    let mut values = vec![];
+    while !bytes.is_empty() {
+        let mut tag = [0u8; 4];
+        tag.copy_from_slice(take(&mut bytes, 4)?);
+        let tag = i32::from_le_bytes(tag);
+        let kind = take(&mut bytes, 1)?[0];
+        let value = match kind {
+            COLLECTED_INTEGER => Value::Integer(i64::from_le_bytes(take_8(&mut bytes)?)),
+            COLLECTED_REAL => Value::Real(f64::from_bits(u64::from_le_bytes(take_8(&mut bytes)?))),
+            COLLECTED_TEXT | COLLECTED_BLOB => {
+                let mut len = [0u8; 4];
+                len.copy_from_slice(take(&mut bytes, 4)?);
+                let data = take(&mut bytes, u32::from_le_bytes(len) as usize)?.to_vec();
+                if kind == COLLECTED_TEXT {
+                    match String::from_utf8(data) {
+                        Ok(s) => Value::Text(s),
+                        Err(e) => bail!(DbErrorKind::BadSQLValuePair(Value::Blob(e.into_bytes()), tag)),
+                    }
+                } else {
+                    Value::Blob(data)
+                }
+            }
+            _ => bail!(DbErrorKind::BadSQLValuePair(Value::Blob(bytes.to_vec()), tag)),
+        };
+        values.push(TypedValue::from_sql_value_pair(value, tag)?);
+    }
+    Ok(values)
+}
+
+/// Register `aggregate` with `conn` under the SQL name for `name`, so that queries run on `conn`
+/// can use it. See `AggregateRegistry`.
+pub fn register_custom_aggregate<A>(
+    conn: &rusqlite::Connection,
+    name: &str,
+    aggregate: Arc<A>,
+) -> Result<()>
+where
+    A: CustomAggregate,
+{
+    conn.create_aggregate_function(
+        AggregateRegistry::sql_name(name).as_str(),
+        2,
+        FunctionFlags::SQLITE_UTF8,
+        SQLiteAggregate(aggregate),
+    )?;
+    Ok(())
+}
+
+/// Adapts a `CustomAggregate` to SQLite. Its arguments are a value and that value's type tag.
+///
+/// A panic in the aggregate is caught by rusqlite, and so the aggregate's state is never observed
+/// after one.
+struct SQLiteAggregate<A>(Arc<A>);
+
+/// The result of a custom aggregate or function, as SQL. The type tag isn't needed: it's known
+/// when the query is translated.
+struct CustomResult(TypedValue);
+
+impl ToSql for CustomResult {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.0.to_sql_value_pair().0)
+    }
+}
+
+impl<A> Aggregate<AssertUnwindSafe<A::State>, Option<CustomResult>> for SQLiteAggregate<A>
+where
+    A: CustomAggregate,
+{
+    fn init(&self, _: &mut Context) -> rusqlite::Result<AssertUnwindSafe<A::State>> {
+        Ok(AssertUnwindSafe(self.0.init()))
+    }
+
+    fn step(&self, ctx: &mut Context, state: &mut AssertUnwindSafe<A::State>) -> rusqlite::Result<()> {
+        let value = ctx.get::<Value>(0)?;
+        if value == Value::Null {
+            return Ok(());
+        }
+        let tag = ctx.get::<i32>(1)?;
+        let value = TypedValue::from_sql_value_pair(value, tag)
+            .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+        self.0.step(&mut state.0, value);
+        Ok(())
+    }
+
+    fn finalize(
+        &self,
+        _: &mut Context,
+        state: Option<AssertUnwindSafe<A::State>>,
+    ) -> rusqlite::Result<Option<CustomResult>> {
+        Ok(state.and_then(|state| self.0.finish(state.0)).map(CustomResult))
+    }
+}
+
+/// Register `function` with `conn` under the SQL name for `name`, so that queries run on `conn`
+/// can use it. See `FunctionRegistry`.
+pub fn register_custom_function(
+    conn: &rusqlite::Connection,
+    name: &str,
+    function: CustomFunction,
+) -> Result<()> {
+    let argument_types = function.argument_types().to_vec();
+    let return_type = function.return_type();
+    let name = name.to_string();
+    conn.create_scalar_function(
+        FunctionRegistry::sql_name(&name).as_str(),
+        argument_types.len() as i32,
+        FunctionFlags::SQLITE_UTF8,
+        move |ctx| {
+            // The arguments have their declared types, so we know their type tags.
+            let mut args = Vec::with_capacity(argument_types.len());
+            for (i, value_type) in argument_types.iter().enumerate() {
+                let value = ctx.get::<Value>(i)?;
+                if value == Value::Null {
+                    return Ok(None);
+                }
+                let value = TypedValue::from_sql_value_pair(value, value_type.value_type_tag())
+                    .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+                args.push(value);
+            }
+            match function.call(&args) {
+                Some(result) => {
+                    if !result.matches_type(return_type) {
+                        let e = DbErrorKind::BadCustomFunctionResult(name.clone(), return_type);
+                        return Err(rusqlite::Error::UserFunctionError(Box::new(e)));
+                    }
+                    Ok(Some(CustomResult(result)))
+                }
+                None => Ok(None),
+            }
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use db::new_connection;
+
+    #[test]
+    fn test_aggregate_functions() {
+        let conn = new_connection("").expect("connection");
+        conn.execute_batch("CREATE TABLE t (v); INSERT INTO t VALUES (1), (2), (4), (9);")
+            .expect("table");
+
+        let (median, variance, stddev): (f64, f64, f64) = conn
+            .query_row(
+                "SELECT mentat_median(v), mentat_variance(v), mentat_stddev(v) FROM t",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .expect("aggregated");
+        assert_eq!(median, 3.0);
+        assert!((variance - 9.5).abs() < 1e-9);
+        assert!((stddev - 9.5f64.sqrt()).abs() < 1e-9);
+
+        // No rows: no result.
+        let median: Option<f64> = conn
+            .query_row("SELECT mentat_median(v) FROM t WHERE v > 10", [], |row| row.get(0))
+            .expect("aggregated");
+        assert_eq!(median, None);
+    }
+
+    #[test]
+    fn test_fulltext_functions() {
+        let conn = new_connection("").expect("connection");
+        conn.execute_batch(r#"CREATE VIRTUAL TABLE f USING FTS4 (text NOT NULL, searchid INT);
+                              INSERT INTO f (text) VALUES ('the quick brown fox'), ('fox fox fox'),
+                                                          ('a brown dog'), ('the end');"#)
+            .expect("table");
+
+        let mut stmt = conn
+            .prepare("SELECT text, mentat_fulltext_score(matchinfo(f, 'pcnalx')), \
+                             mentat_fulltext_highlight(text, offsets(f), '[', ']') \
+                      FROM f WHERE text MATCH 'fox OR dog' ORDER BY 2 DESC")
+            .expect("prepared");
+        let rows: Vec<(String, f64, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .expect("queried")
+            .collect::<rusqlite::Result<_>>()
+            .expect("rows");
+
+        // A rarer term weighs more than a common one, and more hits in a shorter text rank higher.
+        let texts: Vec<&str> = rows.iter().map(|r| r.0.as_str()).collect();
+        assert_eq!(texts, vec!["a brown dog", "fox fox fox", "the quick brown fox"]);
+        assert!(rows.iter().all(|r| r.1 > 0.0));
+        assert_eq!(rows[1].2, "[fox] [fox] [fox]");
+        assert_eq!(rows[2].2, "the quick brown [fox]");
+
+        // Anything else is an error, not a crash.
+        let score: rusqlite::Result<f64> =
+            conn.query_row("SELECT mentat_fulltext_score(X'0100000001000000')", [], |row| row.get(0));
+        assert!(score.is_err());
+
+        // Including a blob that isn't a whole number of integers.
+        let score: rusqlite::Result<f64> =
+            conn.query_row("SELECT mentat_fulltext_score(X'010000000100')", [], |row| row.get(0));
+        assert!(score.is_err());
+    }
+
+    #[test]
+    fn test_fulltext_score_common_terms() {
+        let conn = new_connection("").expect("connection");
+        conn.execute_batch(r#"CREATE VIRTUAL TABLE f USING FTS4 (text NOT NULL, searchid INT);
+                              INSERT INTO f (text) VALUES ('fox'), ('fox fox');"#)
+            .expect("table");
+
+        let mut stmt = conn
+            .prepare("SELECT text, mentat_fulltext_score(matchinfo(f, 'pcnalx')) \
+                      FROM f WHERE text MATCH 'fox' ORDER BY 2 DESC")
+            .expect("prepared");
+        let rows: Vec<(String, f64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("queried")
+            .collect::<rusqlite::Result<_>>()
+            .expect("rows");
+
+        // A term in every row still has some weight, so more hits rank higher.
+        let texts: Vec<&str> = rows.iter().map(|r| r.0.as_str()).collect();
+        assert_eq!(texts, vec!["fox fox", "fox"]);
+        assert!(rows[1].1 > 0.1);
+        assert!(rows[0].1 > rows[1].1);
+    }
+
+    #[test]
+    fn test_collect() {
+        let conn = new_connection("").expect("connection");
+        conn.execute_batch(
+            "CREATE TABLE t (v, tag);
+             INSERT INTO t VALUES (1, 5), (2.5, 5), ('foo', 10), (X'00112233445566778899AABBCCDDEEFF', 11);",
+        )
+        .expect("table");
+
+        let collected: Vec<u8> = conn
+            .query_row("SELECT mentat_collect(v, tag) FROM t", [], |row| row.get(0))
+            .expect("aggregated");
+        let values = decode_collected_values(&collected).expect("decoded");
+        assert_eq!(values.len(), 4);
+        assert_eq!(values[0], TypedValue::Long(1));
+        assert_eq!(values[1], TypedValue::Double(2.5.into()));
+        assert_eq!(values[2], TypedValue::typed_string("foo"));
+        assert!(values[3].matches_type(::core_traits::ValueType::Uuid));
+
+        // Truncated input is an error, not a panic.
+        assert!(decode_collected_values(&collected[..collected.len() - 1]).is_err());
+    }
+}
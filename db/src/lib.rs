@@ -17,6 +17,7 @@ extern crate itertools;
 #[macro_use] extern crate serde_derive;
 
 extern crate petgraph;
+extern crate regex;
 extern crate rusqlite;
 extern crate tabwriter;
 extern crate time;
@@ -43,6 +44,7 @@ pub mod cache;
 pub mod db;
 mod bootstrap;
 pub mod entids;
+mod functions;
 pub mod internal_types;    // pub because we need them for building entities programmatically.
 mod metadata;
 mod schema;
@@ -81,6 +83,13 @@ pub use entids::{
 pub use db::{
     TypedSQLValue,
     new_connection,
+    read_attached_schema,
+};
+
+pub use functions::{
+    decode_collected_values,
+    register_custom_aggregate,
+    register_custom_function,
 };
 
 #[cfg(feature = "sqlcipher")]
@@ -47,6 +47,7 @@ use ::{
     OrderedFloat,
     Uuid,
     Utc,
+    Value,
 };
 
 use ::value_rc::{
@@ -62,7 +63,7 @@ pub use ::{
 pub type SrcVarName = String;          // Do not include the required syntactic '$'.
 
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct Variable(pub Rc<PlainSymbol>);
+pub struct Variable(pub ValueRc<PlainSymbol>);
 
 impl Variable {
     pub fn as_str(&self) -> &str {
@@ -81,7 +82,7 @@ impl Variable {
     pub fn from_valid_name(name: &str) -> Variable {
         let s = PlainSymbol::plain(name);
         assert!(s.is_var_symbol());
-        Variable(Rc::new(s))
+        Variable(ValueRc::new(s))
     }
 }
 
@@ -105,7 +106,7 @@ impl FromValue<Variable> for Variable {
 impl Variable {
     pub fn from_rc(sym: Rc<PlainSymbol>) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(sym.clone()))
+            Some(Variable(ValueRc::from_rc(sym)))
         } else {
             None
         }
@@ -114,7 +115,7 @@ impl Variable {
     /// TODO: intern strings. #398.
     pub fn from_symbol(sym: &PlainSymbol) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(Rc::new(sym.clone())))
+            Some(Variable(ValueRc::new(sym.clone())))
         } else {
             None
         }
@@ -165,9 +166,18 @@ pub enum Direction {
     Descending,
 }
 
-/// An abstract declaration of ordering: direction and variable.
+/// Whether values that might be missing, like those bound by `get-else`, sort before or after
+/// the rest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NullOrdering {
+    First,
+    Last,
+}
+
+/// An abstract declaration of ordering: direction, what to order by -- a variable, or an aggregate
+/// in the find spec -- and where to put missing values.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Order(pub Direction, pub Variable);   // Future: Element instead of Variable?
+pub struct Order(pub Direction, pub Element, pub Option<NullOrdering>);
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SrcVar {
@@ -232,6 +242,9 @@ pub enum FnArg {
     // The collection values representable in EDN.  There's no advantage to destructuring up front,
     // since consumers will need to handle arbitrarily nested EDN themselves anyway.
     Vector(Vec<FnArg>),
+    Set(Vec<FnArg>),
+    // `_`, for functions that allow an argument to match anything, like `fulltext`'s attribute.
+    Placeholder,
 }
 
 impl FromValue<FnArg> for FnArg {
@@ -244,6 +257,8 @@ impl FromValue<FnArg> for FnArg {
                 SrcVar::from_symbol(x).map(FnArg::SrcVar),
             PlainSymbol(ref x) if x.is_var_symbol() =>
                 Variable::from_symbol(x).map(FnArg::Variable),
+            PlainSymbol(ref x) if x.0 == "_" =>
+                Some(FnArg::Placeholder),
             PlainSymbol(_) => None,
             Keyword(ref x) =>
                 Some(FnArg::IdentOrKeyword(x.clone())),
@@ -286,6 +301,11 @@ impl std::fmt::Display for FnArg {
             &FnArg::IdentOrKeyword(ref kw) => write!(f, "{}", kw),
             &FnArg::Constant(ref constant) => write!(f, "{:?}", constant),
             &FnArg::Vector(ref vec) => write!(f, "{:?}", vec),
+            &FnArg::Set(ref set) => {
+                let members: Vec<String> = set.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "#{{{}}}", members.join(" "))
+            },
+            &FnArg::Placeholder => write!(f, "_"),
         }
     }
 }
@@ -470,23 +490,57 @@ impl PatternValuePlace {
     }
 }
 
-// Not yet used.
-// pub enum PullDefaultValue {
-//     EntidOrInteger(i64),
-//     IdentOrKeyword(Rc<Keyword>),
-//     Constant(NonIntegerConstant),
-// }
+/// The value given for an attribute by `(default :attr value)` when an entity has none.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullDefaultValue {
+    EntidOrInteger(i64),
+    IdentOrKeyword(ValueRc<Keyword>),
+    Constant(NonIntegerConstant),
+}
+
+impl FromValue<PullDefaultValue> for PullDefaultValue {
+    fn from_value(v: &::ValueAndSpan) -> Option<PullDefaultValue> {
+        use ::SpannedValue::*;
+        match v.inner {
+            Integer(x) =>
+                Some(PullDefaultValue::EntidOrInteger(x)),
+            Keyword(ref x) =>
+                Some(PullDefaultValue::IdentOrKeyword(ValueRc::new(x.clone()))),
+            Instant(x) =>
+                Some(PullDefaultValue::Constant(NonIntegerConstant::Instant(x))),
+            Uuid(x) =>
+                Some(PullDefaultValue::Constant(NonIntegerConstant::Uuid(x))),
+            Boolean(x) =>
+                Some(PullDefaultValue::Constant(NonIntegerConstant::Boolean(x))),
+            Float(x) =>
+                Some(PullDefaultValue::Constant(NonIntegerConstant::Float(x))),
+            Text(ref x) =>
+                Some(PullDefaultValue::Constant(x.clone().into())),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PullDefaultValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            &PullDefaultValue::EntidOrInteger(i) => write!(f, "{}", i),
+            &PullDefaultValue::IdentOrKeyword(ref k) => write!(f, "{}", k),
+            &PullDefaultValue::Constant(ref c) => write!(f, "{:?}", c),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PullConcreteAttribute {
-    Ident(Rc<Keyword>),
+    Ident(ValueRc<Keyword>),
     Entid(i64),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct NamedPullAttribute {
     pub attribute: PullConcreteAttribute,
-    pub alias: Option<Rc<Keyword>>,
+    pub alias: Option<ValueRc<Keyword>>,
 }
 
 impl From<PullConcreteAttribute> for NamedPullAttribute {
@@ -498,13 +552,40 @@ impl From<PullConcreteAttribute> for NamedPullAttribute {
     }
 }
 
+impl From<i64> for PullAttributeSpec {
+    fn from(entid: i64) -> Self {
+        PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(entid).into())
+    }
+}
+
+/// What the entities referred to by an attribute in a map spec are pulled with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullMapValue {
+    /// A pattern of their own, like `[:person/name]`.
+    Patterns(Vec<PullAttributeSpec>),
+
+    /// The enclosing pattern again, to at most this depth: `{:node/child 3}`. `...` has no limit
+    /// (`None`). Either way, an entity already pulled further up isn't pulled again.
+    Recursion(Option<u64>),
+}
+
+/// One entry of a map spec, like `:person/friend [:person/name]`: the entities that a ref
+/// attribute refers to are themselves pulled. A key like `(limit :person/friend 10)` pulls at
+/// most that many.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PullMapEntry {
+    pub attribute: NamedPullAttribute,
+    pub limit: Option<u64>,
+    pub value: PullMapValue,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PullAttributeSpec {
     Wildcard,
     Attribute(NamedPullAttribute),
-    // PullMapSpec(Vec<…>),
-    // LimitedAttribute(NamedPullAttribute, u64),  // Limit nil => Attribute instead.
-    // DefaultedAttribute(NamedPullAttribute, PullDefaultValue),
+    PullMapSpec(Vec<PullMapEntry>),
+    LimitedAttribute(NamedPullAttribute, u64),  // Limit nil => Attribute instead.
+    DefaultedAttribute(NamedPullAttribute, PullDefaultValue),
 }
 
 impl std::fmt::Display for PullConcreteAttribute {
@@ -540,6 +621,36 @@ impl std::fmt::Display for PullAttributeSpec {
             &PullAttributeSpec::Attribute(ref attr) => {
                 write!(f, "{}", attr)
             },
+            &PullAttributeSpec::PullMapSpec(ref entries) => {
+                write!(f, "{{")?;
+                for (i, entry) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    match entry.limit {
+                        Some(limit) => write!(f, "(limit {} {}) ", entry.attribute, limit)?,
+                        None => write!(f, "{} ", entry.attribute)?,
+                    }
+                    match entry.value {
+                        PullMapValue::Patterns(ref patterns) => {
+                            write!(f, "[ ")?;
+                            for p in patterns.iter() {
+                                write!(f, "{} ", p)?;
+                            }
+                            write!(f, "]")?;
+                        },
+                        PullMapValue::Recursion(Some(depth)) => write!(f, "{}", depth)?,
+                        PullMapValue::Recursion(None) => write!(f, "...")?,
+                    }
+                }
+                write!(f, "}}")
+            },
+            &PullAttributeSpec::LimitedAttribute(ref attr, limit) => {
+                write!(f, "(limit {} {})", attr, limit)
+            },
+            &PullAttributeSpec::DefaultedAttribute(ref attr, ref default) => {
+                write!(f, "(default {} {})", attr, default)
+            },
         }
     }
 }
@@ -623,6 +734,15 @@ pub enum Limit {
     Variable(Variable),
 }
 
+/// The number of results to skip, from `:offset`. Like `Limit`, this can be fixed in the query
+/// or supplied as an input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Offset {
+    None,
+    Fixed(u64),
+    Variable(Variable),
+}
+
 /// A definition of the first part of a find query: the
 /// `[:find ?foo ?bar…]` bit.
 ///
@@ -722,6 +842,70 @@ impl FindSpec {
             &FindRel(ref v)    => Box::new(v.iter()),
         }
     }
+
+    /// The order in which to page through results: `order`, then each variable in the find spec
+    /// that it doesn't order by, ascending. Results are distinct, so each has its own place in
+    /// this order.
+    pub fn keyset_order(&self, order: Option<&Vec<Order>>) -> Vec<Order> {
+        let mut keyset: Vec<Order> = order.cloned().unwrap_or_default();
+        for e in self.columns() {
+            let var = match e {
+                &Element::Variable(ref var) | &Element::Corresponding(ref var) => var,
+                _ => continue,
+            };
+            let ordered = keyset.iter().any(|o| match o.1 {
+                Element::Variable(ref v) => v == var,
+                _ => false,
+            });
+            if !ordered {
+                keyset.push(Order(Direction::Ascending, Element::Variable(var.clone()), None));
+            }
+        }
+        keyset
+    }
+}
+
+/// The kind of key in a return map: `:keys` makes keywords, `:strs` strings, and `:syms` symbols.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ReturnMapKind {
+    Keys,
+    Strs,
+    Syms,
+}
+
+/// A return map, like `:keys name age`, which names the elements of a relation's find spec. Each
+/// row of the results is then a map from those names to the row's values, rather than a tuple.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReturnMap {
+    pub kind: ReturnMapKind,
+    pub names: Vec<String>,
+}
+
+impl ReturnMap {
+    /// The name in a return map for the provided EDN value: a symbol other than a variable, like
+    /// `name` or `person/name`.
+    pub fn name_from_value(v: &::ValueAndSpan) -> Option<String> {
+        match v.inner {
+            ::SpannedValue::PlainSymbol(ref s) if !s.is_var_symbol() && !s.is_src_symbol() => Some(s.to_string()),
+            ::SpannedValue::NamespacedSymbol(ref s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The key for each element of the find spec, in order.
+    pub fn keys(&self) -> Vec<Value> {
+        self.names.iter().map(|name| {
+            let (namespace, name) = match name.find('/') {
+                Some(i) if i > 0 && i < name.len() - 1 => (Some(&name[..i]), &name[i + 1..]),
+                _ => (None, name.as_str()),
+            };
+            match self.kind {
+                ReturnMapKind::Keys => Value::from_keyword(namespace, name),
+                ReturnMapKind::Strs => Value::Text(namespace.map_or_else(|| name.to_string(), |ns| format!("{}/{}", ns, name))),
+                ReturnMapKind::Syms => Value::from_symbol(namespace, name),
+            }
+        }).collect()
+    }
 }
 
 // Datomic accepts variable or placeholder.  DataScript accepts recursive bindings.  Mentat sticks
@@ -964,6 +1148,28 @@ pub struct TypeAnnotation {
     pub variable: Variable,
 }
 
+/// An invocation of a named rule inside a `:where` clause, like `(ancestor ?x ?y)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuleExpr {
+    pub name: PlainSymbol,
+    pub args: Vec<FnArg>,
+}
+
+/// A single rule definition, like
+///
+/// ```edn
+/// [(ancestor ?c ?p) [?c :person/parent ?p]]
+/// ```
+///
+/// Several definitions can share a name: a rule invocation matches if any of them do. A rule
+/// whose body invokes itself, directly or indirectly, is recursive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub name: PlainSymbol,
+    pub vars: Vec<Variable>,
+    pub clauses: Vec<WhereClause>,
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum WhereClause {
@@ -971,7 +1177,7 @@ pub enum WhereClause {
     OrJoin(OrJoin),
     Pred(Predicate),
     WhereFn(WhereFn),
-    RuleExpr,
+    RuleExpr(RuleExpr),
     Pattern(Pattern),
     TypeAnnotation(TypeAnnotation),
 }
@@ -983,19 +1189,34 @@ pub struct ParsedQuery {
     pub default_source: SrcVar,
     pub with: Vec<Variable>,
     pub in_vars: Vec<Variable>,
+    pub in_bindings: Vec<Binding>,
     pub in_sources: BTreeSet<SrcVar>,
+    pub in_rules: bool,
     pub limit: Limit,
+    pub offset: Offset,
     pub where_clauses: Vec<WhereClause>,
     pub order: Option<Vec<Order>>,
+    pub return_map: Option<ReturnMap>,
+}
+
+/// One of the inputs named in an `:in` clause: a source like `$`, the rule set `%`, a variable,
+/// or a collection or relation binding like `[?x ...]` or `[[?x ?y]]`.
+pub(crate) enum InElement {
+    Source(SrcVar),
+    Rules,
+    Variable(Variable),
+    Binding(Binding),
 }
 
 pub(crate) enum QueryPart {
     FindSpec(FindSpec),
     WithVars(Vec<Variable>),
-    InVars(Vec<Variable>),
+    In(Vec<InElement>),
     Limit(Limit),
+    Offset(Offset),
     WhereClauses(Vec<WhereClause>),
     Order(Vec<Order>),
+    ReturnMap(ReturnMap),
 }
 
 /// A `ParsedQuery` represents a parsed but potentially invalid query to the query algebrizer.
@@ -1009,9 +1230,14 @@ impl ParsedQuery {
         let mut find_spec: Option<FindSpec> = None;
         let mut with: Option<Vec<Variable>> = None;
         let mut in_vars: Option<Vec<Variable>> = None;
+        let mut in_bindings: Vec<Binding> = vec![];
+        let mut in_sources: BTreeSet<SrcVar> = BTreeSet::default();
+        let mut in_rules = false;
         let mut limit: Option<Limit> = None;
+        let mut offset: Option<Offset> = None;
         let mut where_clauses: Option<Vec<WhereClause>> = None;
         let mut order: Option<Vec<Order>> = None;
+        let mut return_map: Option<ReturnMap> = None;
 
         for part in parts.into_iter() {
             match part {
@@ -1027,11 +1253,34 @@ impl ParsedQuery {
                     }
                     with = Some(x)
                 },
-                QueryPart::InVars(x) => {
+                QueryPart::In(x) => {
                     if in_vars.is_some() {
                         return Err("find query has repeated :in");
                     }
-                    in_vars = Some(x)
+                    let mut vars = vec![];
+                    for element in x.into_iter() {
+                        match element {
+                            InElement::Source(src) => {
+                                if !in_sources.insert(src) {
+                                    return Err("find query has repeated source in :in");
+                                }
+                            },
+                            InElement::Rules => {
+                                if in_rules {
+                                    return Err("find query has repeated % in :in");
+                                }
+                                in_rules = true;
+                            },
+                            InElement::Variable(var) => vars.push(var),
+                            InElement::Binding(binding) => {
+                                if !binding.is_valid() {
+                                    return Err("find query has invalid binding in :in");
+                                }
+                                in_bindings.push(binding);
+                            },
+                        }
+                    }
+                    in_vars = Some(vars)
                 },
                 QueryPart::Limit(x) => {
                     if limit.is_some() {
@@ -1039,6 +1288,12 @@ impl ParsedQuery {
                     }
                     limit = Some(x)
                 },
+                QueryPart::Offset(x) => {
+                    if offset.is_some() {
+                        return Err("find query has repeated :offset");
+                    }
+                    offset = Some(x)
+                },
                 QueryPart::WhereClauses(x) => {
                     if where_clauses.is_some() {
                         return Err("find query has repeated :where");
@@ -1051,18 +1306,44 @@ impl ParsedQuery {
                     }
                     order = Some(x)
                 },
+                QueryPart::ReturnMap(x) => {
+                    if return_map.is_some() {
+                        return Err("find query has repeated :keys, :strs, or :syms");
+                    }
+                    return_map = Some(x)
+                },
+            }
+        }
+
+        let find_spec = find_spec.ok_or("expected :find")?;
+        if let Some(ref return_map) = return_map {
+            match find_spec {
+                FindSpec::FindRel(ref elements) => {
+                    if elements.len() != return_map.names.len() {
+                        return Err("find query has a different number of return keys and :find elements");
+                    }
+                },
+                _ => return Err("find query with :keys, :strs, or :syms must find a relation"),
+            }
+            let names: BTreeSet<&String> = return_map.names.iter().collect();
+            if names.len() != return_map.names.len() {
+                return Err("find query has repeated return keys");
             }
         }
 
         Ok(ParsedQuery {
-            find_spec: find_spec.ok_or("expected :find")?,
+            find_spec,
             default_source: SrcVar::DefaultSrc,
             with: with.unwrap_or(vec![]),
             in_vars: in_vars.unwrap_or(vec![]),
-            in_sources: BTreeSet::default(),
+            in_bindings,
+            in_sources,
+            in_rules,
             limit: limit.unwrap_or(Limit::None),
+            offset: offset.unwrap_or(Offset::None),
             where_clauses: where_clauses.ok_or("expected :where")?,
             order,
+            return_map,
         })
     }
 }
@@ -1116,7 +1397,7 @@ impl ContainsVariables for WhereClause {
             &NotJoin(ref n)        => n.accumulate_mentioned_variables(acc),
             &WhereFn(ref f)        => f.accumulate_mentioned_variables(acc),
             &TypeAnnotation(ref a) => a.accumulate_mentioned_variables(acc),
-            &RuleExpr              => (),
+            &RuleExpr(ref r)       => r.accumulate_mentioned_variables(acc),
         }
     }
 }
@@ -1180,6 +1461,16 @@ impl ContainsVariables for Predicate {
     }
 }
 
+impl ContainsVariables for RuleExpr {
+    fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
+        for arg in &self.args {
+            if let &FnArg::Variable(ref v) = arg {
+                acc_ref(acc, v)
+            }
+        }
+    }
+}
+
 impl ContainsVariables for TypeAnnotation {
     fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
         acc_ref(acc, &self.variable);
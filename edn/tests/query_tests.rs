@@ -13,15 +13,21 @@ extern crate edn;
 use edn::{
     Keyword,
     PlainSymbol,
+    Value,
+    ValueRc,
 };
 
 use edn::query::{
+    Aggregate,
+    Binding,
     Direction,
     Element,
     FindSpec,
     FnArg,
     Limit,
     NonIntegerConstant,
+    NullOrdering,
+    Offset,
     Order,
     OrJoin,
     OrWhereClause,
@@ -29,13 +35,27 @@ use edn::query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    Pull,
+    PullAttributeSpec,
+    PullConcreteAttribute,
+    PullDefaultValue,
+    PullMapEntry,
+    PullMapValue,
+    QueryFunction,
+    ReturnMap,
+    ReturnMapKind,
+    Rule,
+    RuleExpr,
     UnifyVars,
     Variable,
+    VariableOrPlaceholder,
     WhereClause,
 };
 
 use edn::parse::{
+    parse_pull_pattern,
     parse_query,
+    parse_rules,
 };
 
 ///! N.B., parsing a query can be done without reference to a DB.
@@ -221,20 +241,37 @@ fn can_parse_order_by() {
     // Defaults to ascending.
     let default = "[:find ?x :where [?x :foo/baz ?y] :order ?y]";
     assert_eq!(parse_query(default).unwrap().order,
-               Some(vec![Order(Direction::Ascending, Variable::from_valid_name("?y"))]));
+               Some(vec![Order(Direction::Ascending, Element::Variable(Variable::from_valid_name("?y")), None)]));
 
     let ascending = "[:find ?x :where [?x :foo/baz ?y] :order (asc ?y)]";
     assert_eq!(parse_query(ascending).unwrap().order,
-               Some(vec![Order(Direction::Ascending, Variable::from_valid_name("?y"))]));
+               Some(vec![Order(Direction::Ascending, Element::Variable(Variable::from_valid_name("?y")), None)]));
 
     let descending = "[:find ?x :where [?x :foo/baz ?y] :order (desc ?y)]";
     assert_eq!(parse_query(descending).unwrap().order,
-               Some(vec![Order(Direction::Descending, Variable::from_valid_name("?y"))]));
+               Some(vec![Order(Direction::Descending, Element::Variable(Variable::from_valid_name("?y")), None)]));
 
     let mixed = "[:find ?x :where [?x :foo/baz ?y] :order (desc ?y) (asc ?x)]";
     assert_eq!(parse_query(mixed).unwrap().order,
-               Some(vec![Order(Direction::Descending, Variable::from_valid_name("?y")),
-                         Order(Direction::Ascending, Variable::from_valid_name("?x"))]));
+               Some(vec![Order(Direction::Descending, Element::Variable(Variable::from_valid_name("?y")), None),
+                         Order(Direction::Ascending, Element::Variable(Variable::from_valid_name("?x")), None)]));
+
+    let aggregate = "[:find ?x (count ?y) :where [?x :foo/baz ?y] :order (desc (count ?y))]";
+    assert_eq!(parse_query(aggregate).unwrap().order,
+               Some(vec![Order(Direction::Descending,
+                               Element::Aggregate(Aggregate {
+                                   func: QueryFunction::from_symbol(&PlainSymbol::plain("count")).unwrap(),
+                                   args: vec![FnArg::Variable(Variable::from_valid_name("?y"))],
+                               }),
+                               None)]));
+
+    let nulls = "[:find ?x :where [?x :foo/baz ?y] :order (asc ?y :nulls-last) (desc ?x :nulls-first)]";
+    assert_eq!(parse_query(nulls).unwrap().order,
+               Some(vec![Order(Direction::Ascending, Element::Variable(Variable::from_valid_name("?y")), Some(NullOrdering::Last)),
+                         Order(Direction::Descending, Element::Variable(Variable::from_valid_name("?x")), Some(NullOrdering::First))]));
+
+    let bare_nulls_invalid = "[:find ?x :where [?x :foo/baz ?y] :order ?y :nulls-last]";
+    assert!(parse_query(bare_nulls_invalid).is_err());
 }
 
 #[test]
@@ -266,6 +303,35 @@ fn can_parse_limit() {
                Limit::Variable(Variable::from_valid_name("?limit")));
 }
 
+#[test]
+fn can_parse_offset() {
+    let invalid = "[:find ?x :where [?x :foo/baz ?y] :offset]";
+    assert!(parse_query(invalid).is_err());
+
+    let negative_invalid = "[:find ?x :where [?x :foo/baz ?y] :offset -1]";
+    assert!(parse_query(negative_invalid).is_err());
+
+    let repeated_invalid = "[:find ?x :where [?x :foo/baz ?y] :offset 1 :offset 2]";
+    assert!(parse_query(repeated_invalid).is_err());
+
+    let none = "[:find ?x :where [?x :foo/baz ?y]]";
+    assert_eq!(parse_query(none).unwrap().offset,
+               Offset::None);
+
+    let zero = "[:find ?x :where [?x :foo/baz ?y] :offset 0]";
+    assert_eq!(parse_query(zero).unwrap().offset,
+               Offset::Fixed(0));
+
+    let with_limit = "[:find ?x :where [?x :foo/baz ?y] :limit 10 :offset 20]";
+    let parsed = parse_query(with_limit).unwrap();
+    assert_eq!(parsed.limit, Limit::Fixed(10));
+    assert_eq!(parsed.offset, Offset::Fixed(20));
+
+    let variable_with_in = "[:find ?x :in ?offset :where [?x :foo/baz ?y] :offset ?offset]";
+    assert_eq!(parse_query(variable_with_in).unwrap().offset,
+               Offset::Variable(Variable::from_valid_name("?offset")));
+}
+
 #[test]
 fn can_parse_uuid() {
     let expected = edn::Uuid::parse_str("4cb3f828-752d-497a-90c9-b1fd516d5644").expect("valid uuid");
@@ -298,3 +364,210 @@ fn can_parse_exotic_whitespace() {
                                 PatternNonValuePlace::Placeholder)
                        .expect("valid pattern")));
 }
+
+#[test]
+fn can_parse_in_bindings() {
+    let s = "[:find ?x :in $ ?a [?b ...] [[?c _]] :where [?x :foo/bar ?b]]";
+    let p = parse_query(s).expect("to be able to parse find");
+    assert_eq!(p.in_vars, vec![Variable::from_valid_name("?a")]);
+    assert_eq!(p.in_bindings,
+               vec![
+                   Binding::BindColl(Variable::from_valid_name("?b")),
+                   Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?c")),
+                                         VariableOrPlaceholder::Placeholder]),
+               ]);
+
+    // A relation binding must bind something, and can't bind a variable twice.
+    assert!(parse_query("[:find ?x :in [[_ _]] :where [?x :foo/bar ?y]]").is_err());
+    assert!(parse_query("[:find ?x :in [[?y ?y]] :where [?x :foo/bar ?y]]").is_err());
+}
+
+#[test]
+fn can_parse_function_names() {
+    let s = r#"[:find ?c :where [(+ ?a 1) ?b] [(- ?b) ?c] [(/ ?c 2) ?d] [(-millis ?t 5) ?e] [(str/includes? ?s "x") ?f]]"#;
+    let p = parse_query(s).expect("to be able to parse find");
+    let operators: Vec<String> = p.where_clauses.into_iter().map(|clause| match clause {
+        WhereClause::WhereFn(f) => f.operator.0,
+        _ => panic!("expected a where-fn"),
+    }).collect();
+    assert_eq!(operators, vec!["+", "-", "/", "-millis", "str/includes?"]);
+}
+
+#[test]
+fn can_parse_rules() {
+    let s = "[:find ?x :in $ % :where (ancestor ?x :foo/bar)]";
+    let p = parse_query(s).expect("to be able to parse find");
+    assert!(p.in_rules);
+    assert_eq!(p.where_clauses,
+               vec![
+                   WhereClause::RuleExpr(RuleExpr {
+                       name: PlainSymbol::plain("ancestor"),
+                       args: vec![
+                           FnArg::Variable(Variable::from_valid_name("?x")),
+                           FnArg::IdentOrKeyword(Keyword::namespaced("foo", "bar")),
+                       ],
+                   }),
+               ]);
+
+    // `%` may only appear once.
+    assert!(parse_query("[:find ?x :in % % :where (ancestor ?x ?y)]").is_err());
+
+    let rules = parse_rules("[[(parent ?c ?p) [?c :person/parent ?p]]]").expect("to be able to parse rules");
+    assert_eq!(rules,
+               vec![
+                   Rule {
+                       name: PlainSymbol::plain("parent"),
+                       vars: vec![Variable::from_valid_name("?c"), Variable::from_valid_name("?p")],
+                       clauses: vec![
+                           WhereClause::Pattern(Pattern {
+                               source: None,
+                               entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?c")),
+                               attribute: ident("person", "parent"),
+                               value: PatternValuePlace::Variable(Variable::from_valid_name("?p")),
+                               tx: PatternNonValuePlace::Placeholder,
+                           }),
+                       ],
+                   },
+               ]);
+
+    // Rule heads must name distinct variables, and rules must have a body.
+    assert!(parse_rules("[[(parent ?c ?c) [?c :person/parent ?c]]]").is_err());
+    assert!(parse_rules("[[(parent ?c ?p)]]").is_err());
+}
+
+#[test]
+fn can_parse_return_maps() {
+    let p = parse_query("[:find ?x ?name :keys id person/name :where [?x :foo/name ?name]]").expect("to be able to parse :keys");
+    assert_eq!(p.return_map,
+               Some(ReturnMap {
+                   kind: ReturnMapKind::Keys,
+                   names: vec!["id".to_string(), "person/name".to_string()],
+               }));
+    assert_eq!(p.return_map.unwrap().keys(),
+               vec![Value::Keyword(Keyword::plain("id")), Value::Keyword(Keyword::namespaced("person", "name"))]);
+
+    // The clause can come after `:where`.
+    let p = parse_query("[:find ?x :where [?x :foo/name _] :strs id]").expect("to be able to parse :strs");
+    assert_eq!(p.return_map.unwrap().keys(), vec![Value::Text("id".to_string())]);
+
+    let p = parse_query("[:find ?x :syms x/id :where [?x :foo/name _]]").expect("to be able to parse :syms");
+    assert_eq!(p.return_map.unwrap().keys(), vec![Value::from_symbol("x", "id")]);
+
+    assert!(parse_query("[:find ?x :where [?x :foo/name _]]").unwrap().return_map.is_none());
+
+    // Only relations can be returned as maps, one key for each element.
+    assert!(parse_query("[:find ?x . :keys x :where [?x :foo/name _]]").is_err());
+    assert!(parse_query("[:find [?x ...] :keys x :where [?x :foo/name _]]").is_err());
+    assert!(parse_query("[:find ?x ?y :keys x :where [?x :foo/name ?y]]").is_err());
+    assert!(parse_query("[:find ?x ?y :keys x x :where [?x :foo/name ?y]]").is_err());
+    assert!(parse_query("[:find ?x :keys x :strs x :where [?x :foo/name _]]").is_err());
+    assert!(parse_query("[:find ?x :keys ?x :where [?x :foo/name _]]").is_err());
+}
+
+#[test]
+fn can_parse_pull_map_specs() {
+    let name = PullAttributeSpec::Attribute(PullConcreteAttribute::Ident(ValueRc::new(Keyword::namespaced("person", "name"))).into());
+    let friends = PullAttributeSpec::PullMapSpec(vec![
+        PullMapEntry {
+            attribute: PullConcreteAttribute::Ident(ValueRc::new(Keyword::namespaced("person", "friend"))).into(),
+            limit: None,
+            value: PullMapValue::Patterns(vec![name.clone()]),
+        },
+    ]);
+
+    let p = parse_query("[:find (pull ?x [:person/name {:person/friend [:person/name]}]) :where [?x :person/name _]]")
+        .expect("to be able to parse pull");
+    assert_eq!(p.find_spec,
+               FindSpec::FindRel(vec![
+                   Element::Pull(Pull {
+                       var: Variable::from_valid_name("?x"),
+                       patterns: vec![name.clone(), friends.clone()],
+                   }),
+               ]));
+
+    assert_eq!(parse_pull_pattern("[:person/name {:person/friend [:person/name]}]").expect("to be able to parse pattern"),
+               vec![name, friends]);
+
+    // A map spec can have more than one entry, and nest.
+    let p = parse_pull_pattern("[{:person/friend [{:person/pet [*]}] :person/pet [*]}]").expect("to be able to parse pattern");
+    match p[0] {
+        PullAttributeSpec::PullMapSpec(ref entries) => {
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].value, PullMapValue::Patterns(vec![PullAttributeSpec::Wildcard]));
+        },
+        ref x => panic!("expected a map spec, got {:?}", x),
+    }
+
+    // Each entry needs a pattern.
+    assert!(parse_pull_pattern("[{:person/friend}]").is_err());
+    assert!(parse_pull_pattern("[{:person/friend []}]").is_err());
+}
+
+#[test]
+fn can_parse_pull_options() {
+    let named = |ns, name| PullConcreteAttribute::Ident(ValueRc::new(Keyword::namespaced(ns, name))).into();
+
+    let p = parse_pull_pattern(r#"[(limit :node/child 10) (limit :node/tag nil) (default :node/name "none")]"#)
+        .expect("to be able to parse pattern");
+    assert_eq!(p,
+               vec![
+                   PullAttributeSpec::LimitedAttribute(named("node", "child"), 10),
+                   PullAttributeSpec::Attribute(named("node", "tag")),
+                   PullAttributeSpec::DefaultedAttribute(named("node", "name"),
+                                                         PullDefaultValue::Constant(NonIntegerConstant::from("none"))),
+               ]);
+
+    let p = parse_pull_pattern("[(default :node/size 0) (default :node/kind :kind/leaf)]").expect("to be able to parse pattern");
+    assert_eq!(p,
+               vec![
+                   PullAttributeSpec::DefaultedAttribute(named("node", "size"), PullDefaultValue::EntidOrInteger(0)),
+                   PullAttributeSpec::DefaultedAttribute(named("node", "kind"),
+                                                         PullDefaultValue::IdentOrKeyword(ValueRc::new(Keyword::namespaced("kind", "leaf")))),
+               ]);
+
+    // Recursion, with and without a depth limit, and limits in map keys.
+    let p = parse_pull_pattern("[:node/name {:node/child ... (limit :node/link 2) 3}]").expect("to be able to parse pattern");
+    assert_eq!(p[1],
+               PullAttributeSpec::PullMapSpec(vec![
+                   PullMapEntry {
+                       attribute: named("node", "child"),
+                       limit: None,
+                       value: PullMapValue::Recursion(None),
+                   },
+                   PullMapEntry {
+                       attribute: named("node", "link"),
+                       limit: Some(2),
+                       value: PullMapValue::Recursion(Some(3)),
+                   },
+               ]));
+
+    assert!(parse_pull_pattern("[(limit :node/child 0)]").is_err());
+    assert!(parse_pull_pattern("[(limit :node/child)]").is_err());
+    assert!(parse_pull_pattern("[(default :node/name)]").is_err());
+    assert!(parse_pull_pattern("[(default :node/name ?x)]").is_err());
+    assert!(parse_pull_pattern("[{:node/child 0}]").is_err());
+}
+
+#[test]
+fn can_parse_reversed_pull_attributes() {
+    let owner = Keyword::namespaced("car", "_owner");
+    assert!(owner.is_backward());
+
+    let p = parse_pull_pattern("[:car/_owner {:car/_owner [:car/model]} :car/_owner :as :cars]").expect("to be able to parse pattern");
+    assert_eq!(p[0], PullAttributeSpec::Attribute(PullConcreteAttribute::Ident(ValueRc::new(owner.clone())).into()));
+    match p[1] {
+        PullAttributeSpec::PullMapSpec(ref entries) => {
+            assert_eq!(entries[0].attribute.attribute, PullConcreteAttribute::Ident(ValueRc::new(owner.clone())));
+        },
+        ref x => panic!("expected a map spec, got {:?}", x),
+    }
+    match p[2] {
+        PullAttributeSpec::Attribute(ref attribute) => {
+            assert_eq!(attribute.alias, Some(ValueRc::new(Keyword::plain("cars"))));
+        },
+        ref x => panic!("expected an attribute, got {:?}", x),
+    }
+
+    // Aliases can't be reversed.
+    assert!(parse_pull_pattern("[:car/owner :as :_cars]").is_err());
+}
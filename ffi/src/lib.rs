@@ -99,6 +99,7 @@ pub use mentat::{
     FindSpec,
     HasSchema,
     InProgress,
+    KeyedRow,
     KnownEntid,
     Queryable,
     QueryBuilder,
@@ -1521,6 +1522,21 @@ pub unsafe extern "C" fn query_builder_execute(query_builder: *mut QueryBuilder,
     translate_result(results, error)
 }
 
+/// Executes a query with a return map -- `:keys`, `:strs`, or `:syms` -- and returns its rows.
+///
+/// # Safety
+///
+/// Callers are responsible for managing the memory for the return value.
+/// A destructor `keyed_row_list_destroy` is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn query_builder_execute_maps(query_builder: *mut QueryBuilder, error: *mut ExternError) -> *mut Vec<KeyedRow> {
+    assert_not_null!(query_builder);
+    let query_builder = &mut *query_builder;
+    let results = query_builder.execute_maps();
+    translate_result(results, error)
+}
+
 fn unwrap_conversion<T>(value: Option<T>, expected_type: ValueType) -> T {
     match value {
         Some(v) => v,
@@ -1673,6 +1689,42 @@ pub unsafe extern "C" fn row_at_index(rows: *mut RelResult<Binding>, index: c_in
     result.row(index as usize).map_or_else(std::ptr::null_mut, |v| Box::into_raw(Box::new(v.to_vec())))
 }
 
+/// Returns the row at the provided `index` of the results of a query with a return map.
+/// If there is no row present at the `index`, a null pointer is returned.
+///
+/// # Safety
+///
+/// Callers are responsible for managing the memory for the return value.
+/// A destructor `keyed_row_destroy` is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn keyed_row_at_index(rows: *mut Vec<KeyedRow>, index: c_int) -> *mut KeyedRow {
+    assert_not_null!(rows);
+    let rows = &*rows;
+    if index < 0 {
+        return std::ptr::null_mut();
+    }
+    rows.get(index as usize).map_or_else(std::ptr::null_mut, |row| Box::into_raw(Box::new(row.clone())))
+}
+
+/// Returns the value for the key named `name` in a row of the results of a query with a return
+/// map, whether the key is a keyword, a string, or a symbol: `"name"` finds `:name`.
+/// If the row has no such key, a null pointer is returned.
+///
+/// # Safety
+///
+/// Callers are responsible for managing the memory for the return value, a
+/// [Binding](mentat::Binding) that belongs to the caller rather than to the row.
+/// The `Binding` destructor `typed_value_destroy` is provided for releasing the memory for
+/// this pointer type; `keyed_row_destroy` only releases the row.
+#[no_mangle]
+pub unsafe extern "C" fn keyed_row_value_for_name(row: *mut KeyedRow, name: *const c_char) -> *mut Binding {
+    assert_not_null!(row);
+    let row = &*row;
+    let name = c_char_to_string(name);
+    row.by_name(name).map_or_else(std::ptr::null_mut, |v| Box::into_raw(Box::new(v.clone())))
+}
+
 /// Consumes the `RelResult<Binding>` and returns an iterator over the values.
 ///
 /// # Safety
@@ -2074,6 +2126,12 @@ define_destructor!(typed_value_list_iter_destroy, BindingIterator);
 /// Destructor for releasing the memory of [RelResult<Binding>](mentat::RelResult).
 define_destructor!(typed_value_result_set_destroy, RelResult<Binding>);
 
+/// Destructor for releasing the memory of [KeyedRow](mentat::KeyedRow).
+define_destructor!(keyed_row_destroy, KeyedRow);
+
+/// Destructor for releasing the memory of [Vec<KeyedRow>][mentat::KeyedRow].
+define_destructor!(keyed_row_list_destroy, Vec<KeyedRow>);
+
 /// Destructor for releasing the memory of [BindingListIterator](::BindingListIterator).
 define_destructor!(typed_value_result_set_iter_destroy, BindingListIterator);
 
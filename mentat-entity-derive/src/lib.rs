@@ -248,6 +248,113 @@ pub fn derive_entity(input: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
+/// Derive macro for the FromBindings trait, which maps a row of query results into a struct.
+///
+/// # Attributes
+///
+/// ## Container attributes (on the struct):
+/// - `#[bindings(by_name)]` - Take each field from the find variable named after it: `?team_name`
+///   or `?team-name` for `team_name`. By default fields are taken in order.
+///
+/// ## Field attributes:
+/// - `#[bindings(var = "?x")]` - Take the field from the find variable `?x`
+///
+/// # Example
+///
+/// ```ignore
+/// #[derive(FromBindings)]
+/// #[bindings(by_name)]
+/// struct Member {
+///     name: String,
+///     #[bindings(var = "?t")]
+///     team: String,
+/// }
+///
+/// let members: Vec<Member> = QueryBuilder::new(&mut store, "[:find ?name ?t :where …]")
+///     .execute_as()?;
+/// ```
+#[proc_macro_derive(FromBindings, attributes(bindings))]
+pub fn derive_from_bindings(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let name = &input.ident;
+    let by_name = has_bindings_flag(&input.attrs, "by_name");
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => panic!("FromBindings can only be derived for structs"),
+    };
+
+    let takes: Vec<proc_macro2::TokenStream> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            let field_name = field
+                .ident
+                .as_ref()
+                .map(|ident| ident.to_string().replace("r#", ""))
+                .unwrap_or_else(|| index.to_string());
+            match (binding_var(&field.attrs), by_name) {
+                (Some(var), _) => quote! { row.take_var(#var, #field_name)? },
+                (None, true) => quote! { row.take_named(#field_name)? },
+                (None, false) => quote! { row.take_at(#index, #field_name)? },
+            }
+        })
+        .collect();
+
+    let construct = match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|field| field.ident.as_ref().unwrap());
+            quote! { #name { #(#idents: #takes),* } }
+        }
+        Fields::Unnamed(_) => quote! { #name(#(#takes),*) },
+        Fields::Unit => quote! { #name },
+    };
+
+    let expanded = quote! {
+        impl mentat_entity::FromBindings for #name {
+            fn from_bindings(columns: &[String], row: Vec<mentat_entity::core_traits::Binding>)
+                -> mentat_entity::public_traits::errors::Result<Self>
+            {
+                let mut row = mentat_entity::BindingRow::new(columns, row);
+                Ok(#construct)
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+fn bindings_metas(attrs: &[Attribute]) -> Vec<NestedMeta> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("bindings"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(meta_list)) => Some(meta_list.nested.into_iter()),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+fn has_bindings_flag(attrs: &[Attribute], flag: &str) -> bool {
+    bindings_metas(attrs).iter().any(|nested| match nested {
+        NestedMeta::Meta(Meta::Path(path)) => path.is_ident(flag),
+        _ => false,
+    })
+}
+
+fn binding_var(attrs: &[Attribute]) -> Option<String> {
+    bindings_metas(attrs).iter().find_map(|nested| match nested {
+        NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+            path,
+            lit: Lit::Str(s),
+            ..
+        })) if path.is_ident("var") => Some(s.value()),
+        _ => None,
+    })
+}
+
 fn extract_namespace(attrs: &[Attribute]) -> String {
     for attr in attrs {
         if attr.path.is_ident("entity") {
@@ -0,0 +1,203 @@
+// Copyright 2024 Mentat Contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Mapping rows of query results into Rust values.
+
+use chrono::{DateTime, Utc};
+
+use uuid::Uuid;
+
+use core_traits::{Binding, KnownEntid, StructuredMap, TypedValue, ValueType};
+
+use mentat_core::Keyword;
+
+use public_traits::errors::{MentatError, Result};
+
+/// A type that can be built from one row of query results, usually with
+/// `#[derive(FromBindings)]`.
+pub trait FromBindings: Sized {
+    /// Build a value from `row`, whose columns are described by `columns`: each is the element of
+    /// the query's `:find`, like `?name` or `(count ?x)`.
+    fn from_bindings(columns: &[String], row: Vec<Binding>) -> Result<Self>;
+}
+
+/// A type that a single result column can be converted into.
+pub trait FromBinding: Sized {
+    /// The bindings this type accepts, for errors.
+    fn expected() -> String;
+
+    /// Convert `binding`, or return `None` if it isn't one that this type accepts.
+    fn from_binding(binding: Binding) -> Option<Self>;
+
+    /// The value to use when the query has no column for this one, if there is one.
+    fn from_missing() -> Option<Self> {
+        None
+    }
+}
+
+fn describe(binding: &Binding) -> String {
+    match binding {
+        Binding::Scalar(value) => value.value_type().to_string(),
+        Binding::Vec(_) => "a vector".to_string(),
+        Binding::Map(_) => "a map".to_string(),
+    }
+}
+
+macro_rules! from_scalar {
+    ($t:ty, $expected:expr, $pattern:pat => $value:expr) => {
+        impl FromBinding for $t {
+            fn expected() -> String {
+                $expected.to_string()
+            }
+
+            fn from_binding(binding: Binding) -> Option<Self> {
+                match binding {
+                    Binding::Scalar($pattern) => Some($value),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// An `Entid` is an `i64`, so this accepts entities as well as longs.
+from_scalar!(i64, format!("{} or {}", ValueType::Long, ValueType::Ref),
+             TypedValue::Long(v) | TypedValue::Ref(v) => v);
+from_scalar!(KnownEntid, ValueType::Ref, TypedValue::Ref(v) => KnownEntid(v));
+from_scalar!(f64, ValueType::Double, TypedValue::Double(v) => v.into_inner());
+from_scalar!(bool, ValueType::Boolean, TypedValue::Boolean(v) => v);
+from_scalar!(String, ValueType::String, TypedValue::String(v) => (*v).clone());
+from_scalar!(Keyword, ValueType::Keyword, TypedValue::Keyword(v) => (*v).clone());
+from_scalar!(DateTime<Utc>, ValueType::Instant, TypedValue::Instant(v) => v);
+from_scalar!(Uuid, ValueType::Uuid, TypedValue::Uuid(v) => v);
+from_scalar!(TypedValue, "a value", v => v);
+
+impl FromBinding for Binding {
+    fn expected() -> String {
+        "anything".to_string()
+    }
+
+    fn from_binding(binding: Binding) -> Option<Self> {
+        Some(binding)
+    }
+}
+
+impl FromBinding for StructuredMap {
+    fn expected() -> String {
+        "a map".to_string()
+    }
+
+    fn from_binding(binding: Binding) -> Option<Self> {
+        match binding {
+            Binding::Map(m) => Some((*m).clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<T> FromBinding for Vec<T>
+where
+    T: FromBinding,
+{
+    fn expected() -> String {
+        format!("a vector of {}", T::expected())
+    }
+
+    fn from_binding(binding: Binding) -> Option<Self> {
+        match binding {
+            Binding::Vec(vs) => vs.iter().cloned().map(T::from_binding).collect(),
+            _ => None,
+        }
+    }
+}
+
+/// An optional field is `None` when the query has no column for it, rather than an error.
+impl<T> FromBinding for Option<T>
+where
+    T: FromBinding,
+{
+    fn expected() -> String {
+        T::expected()
+    }
+
+    fn from_binding(binding: Binding) -> Option<Self> {
+        T::from_binding(binding).map(Some)
+    }
+
+    fn from_missing() -> Option<Self> {
+        Some(None)
+    }
+}
+
+/// A row of query results being taken apart, one column per field.
+pub struct BindingRow<'c> {
+    columns: &'c [String],
+    row: Vec<Option<Binding>>,
+}
+
+impl<'c> BindingRow<'c> {
+    pub fn new(columns: &'c [String], row: Vec<Binding>) -> BindingRow<'c> {
+        BindingRow {
+            columns,
+            row: row.into_iter().map(Some).collect(),
+        }
+    }
+
+    /// Convert the binding in column `index` for `field`.
+    pub fn take_at<T>(&mut self, index: usize, field: &str) -> Result<T>
+    where
+        T: FromBinding,
+    {
+        match self.row.get_mut(index).and_then(|b| b.take()) {
+            Some(binding) => convert(field, binding),
+            None => missing(field.to_string()),
+        }
+    }
+
+    /// Convert the binding in the column for the find variable `var` for `field`.
+    pub fn take_var<T>(&mut self, var: &str, field: &str) -> Result<T>
+    where
+        T: FromBinding,
+    {
+        match self.columns.iter().position(|c| c == var) {
+            Some(index) => self.take_at(index, field),
+            None => missing(format!("{} ({})", field, var)),
+        }
+    }
+
+    /// Convert the binding in the column for the find variable named after `field`: `?team_name`
+    /// or `?team-name` for `team_name`.
+    pub fn take_named<T>(&mut self, field: &str) -> Result<T>
+    where
+        T: FromBinding,
+    {
+        let var = format!("?{}", field);
+        if self.columns.contains(&var) {
+            return self.take_var(&var, field);
+        }
+        self.take_var(&var.replace('_', "-"), field)
+    }
+}
+
+fn missing<T>(field: String) -> Result<T>
+where
+    T: FromBinding,
+{
+    T::from_missing().ok_or(MentatError::MissingBinding(field))
+}
+
+fn convert<T>(field: &str, binding: Binding) -> Result<T>
+where
+    T: FromBinding,
+{
+    let found = describe(&binding);
+    T::from_binding(binding)
+        .ok_or_else(|| MentatError::BindingTypeMismatch(field.to_string(), T::expected(), found))
+}
@@ -48,15 +48,17 @@ extern crate uuid;
 
 #[macro_use]
 pub extern crate mentat_entity_derive;
-pub use mentat_entity_derive::Entity;
+pub use mentat_entity_derive::{Entity, FromBindings};
 
 pub use core_traits;
 pub use mentat_core;
 pub use mentat_transaction;
 pub use public_traits;
 pub use public_traits::errors::MentatError;
+pub use bindings::{BindingRow, FromBinding, FromBindings};
 pub use read::{find_entity_by_unique, read_entity_attributes};
 
+mod bindings;
 mod read;
 use thiserror::Error;
 
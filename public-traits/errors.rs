@@ -82,6 +82,13 @@ pub enum MentatError {
     #[error("schema changed since query was prepared")]
     PreparedQuerySchemaMismatch,
 
+    /// The query was interrupted with an `InterruptHandle`, or ran for longer than its timeout.
+    #[error("query interrupted")]
+    Interrupted,
+
+    #[error("can't page through query: {0}")]
+    InvalidPagination(String),
+
     #[error("provided value of type {0} doesn't match attribute value type {1}")]
     ValueTypeMismatch(ValueType, ValueType),
 
@@ -108,7 +115,7 @@ pub enum MentatError {
     AlgebrizerError(#[from] AlgebrizerError),
 
     #[error(transparent)]
-    ProjectorError(#[from] ProjectorError),
+    ProjectorError(ProjectorError),
 
     #[error(transparent)]
     PullError(#[from] PullError),
@@ -122,6 +129,12 @@ pub enum MentatError {
     #[error("Entity error: {0}")]
     EntityError(String),
 
+    #[error("no result column for {0}")]
+    MissingBinding(String),
+
+    #[error("can't convert the result column for {0}: expected {1}, got {2}")]
+    BindingTypeMismatch(String, String, String),
+
     #[cfg(feature = "syncable")]
     #[error(transparent)]
     TolstoyError(#[from] TolstoyError),
@@ -143,8 +156,20 @@ pub enum MentatError {
     SerializationError(#[from] serde_json::Error),
 }
 
+impl From<ProjectorError> for MentatError {
+    fn from(error: ProjectorError) -> MentatError {
+        match error {
+            ProjectorError::Interrupted => MentatError::Interrupted,
+            error => MentatError::ProjectorError(error),
+        }
+    }
+}
+
 impl From<rusqlite::Error> for MentatError {
     fn from(error: rusqlite::Error) -> MentatError {
+        if error.sqlite_error_code() == Some(rusqlite::ErrorCode::OperationInterrupted) {
+            return MentatError::Interrupted;
+        }
         use std::error::Error;
         let cause = match error.source() {
             Some(e) => e.to_string(),
@@ -26,6 +26,9 @@ pub enum BindingError {
     UnexpectedBinding,
     RepeatedBoundVariable, // TODO: include repeated variable(s).
 
+    /// Expected `?x` but got some other type of binding.
+    ExpectedBindScalar,
+
     /// Expected `[[?x ?y]]` but got some other type of binding.  Mentat is deliberately more strict
     /// than Datomic: we won't try to make sense of non-obvious (and potentially erroneous) bindings.
     ExpectedBindRel,
@@ -70,6 +73,12 @@ pub enum AlgebrizerError {
     #[error("invalid limit {0} of type {1}: expected natural number.")]
     InvalidLimit(String, ValueType),
 
+    #[error("invalid offset {0} of type {1}: expected non-negative integer.")]
+    InvalidOffset(String, ValueType),
+
+    #[error("invalid keyset cursor: {0}")]
+    InvalidKeysetCursor(String),
+
     #[error("mismatched bindings in ground")]
     GroundBindingsMismatch,
 
@@ -82,6 +91,12 @@ pub enum AlgebrizerError {
     #[error(":limit var {0} not present in :in")]
     UnknownLimitVar(PlainSymbol),
 
+    #[error(":offset var {0} not present in :in")]
+    UnknownOffsetVar(PlainSymbol),
+
+    #[error("invalid :order: {0}")]
+    InvalidOrder(String),
+
     #[error("unbound variable {0} in order clause or function call")]
     UnboundVariable(PlainSymbol),
 
@@ -92,6 +107,24 @@ pub enum AlgebrizerError {
     #[error("non-matching variables in 'not' clause")]
     NonMatchingVariablesInNotClause,
 
+    #[error("no rule named {0}")]
+    UnknownRule(PlainSymbol),
+
+    #[error("rule {0} is defined with differing numbers of variables")]
+    InconsistentRuleArity(PlainSymbol),
+
+    #[error("rule {0} doesn't mention its variable {1} in its body")]
+    UnusedRuleVariable(PlainSymbol, PlainSymbol),
+
+    #[error("unsupported recursion in rule {0}: a recursive rule must invoke itself at most once per definition, outside of 'or' and 'not'")]
+    UnsupportedRecursion(PlainSymbol),
+
+    #[error("unknown source ${0}: a source must be named in :in and supplied as a query input")]
+    UnknownSource(String),
+
+    #[error("input rows for {0} have {2} values, but its binding in :in has {1} places")]
+    InputBindingMismatch(PlainSymbol, usize, usize),
+
     #[error("binding error in {0}: {1:?}")]
     InvalidBinding(PlainSymbol, BindingError),
 
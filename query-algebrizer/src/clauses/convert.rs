@@ -88,6 +88,8 @@ impl ValueTypes for FnArg {
 
                 // These don't make sense here. TODO: split FnArg into scalar and non-scalar…
                 &FnArg::Vector(_) |
+                &FnArg::Set(_) |
+                &FnArg::Placeholder |
                 &FnArg::SrcVar(_) => bail!(AlgebrizerError::UnsupportedArgument),
 
                 // These are all straightforward.
@@ -218,6 +220,8 @@ impl ConjoiningClauses {
 
             // These don't make sense here.
             FnArg::Vector(_) |
+            FnArg::Set(_) |
+            FnArg::Placeholder |
             FnArg::SrcVar(_) => bail!(AlgebrizerError::InvalidGroundConstant),
 
             // These are all straightforward.
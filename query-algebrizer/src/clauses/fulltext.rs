@@ -8,29 +8,68 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use core_traits::{TypedValue, ValueType};
+use core_traits::{Entid, TypedValue, ValueType};
 
-use mentat_core::HasSchema;
+use mentat_core::{HasSchema, Schema};
 
 use mentat_core::util::Either;
 
-use edn::query::{Binding, FnArg, NonIntegerConstant, SrcVar, VariableOrPlaceholder, WhereFn};
+use edn::query::{
+    Binding, FnArg, NonIntegerConstant, PlainSymbol, Variable, VariableOrPlaceholder, WhereFn,
+};
 
 use clauses::ConjoiningClauses;
 
+use clauses::sources::known_for_source;
+
 use query_algebrizer_traits::errors::{AlgebrizerError, BindingError, Result};
 
 use types::{
-    Column, ColumnConstraint, DatomsColumn, DatomsTable, EmptyBecause, FulltextColumn,
-    QualifiedAlias, QueryValue, SourceAlias,
+    Column, ColumnAlternation, ColumnConstraint, ColumnConstraintOrAlternation, ComputedFunction,
+    ComputedValue, DatomsColumn, DatomsTable, EmptyBecause, FulltextColumn, QualifiedAlias,
+    QueryValue, SourceAlias, TableAlias,
 };
 
 use Known;
 
+/// The markup that `fulltext` wraps around matched terms in snippets and highlights, and the
+/// ellipsis that marks text omitted from a snippet, unless the query gives its own.
+const DEFAULT_MARKUP: [&str; 3] = ["<b>", "</b>", "…"];
+
+/// The attributes that `fulltext` searches: a fixed set, or whichever attribute a column holds,
+/// which must be one of the given fulltext attributes.
+enum SearchedAttributes {
+    Fixed(Vec<Entid>),
+    Column(QualifiedAlias, Vec<Entid>),
+}
+
+fn is_fulltext(schema: &Schema, attribute: Entid) -> bool {
+    schema.attribute_for_entid(attribute).map_or(false, |a| a.fulltext)
+}
+
+fn fulltext_attributes(schema: &Schema) -> Vec<Entid> {
+    schema.attribute_map
+          .iter()
+          .filter(|&(_, a)| a.fulltext)
+          .map(|(e, _)| *e)
+          .collect()
+}
+
 impl ConjoiningClauses {
-    #[allow(unused_variables)]
+    /// `[(fulltext $ :foo/text "search") [[?entity ?value ?tx ?score ?snippet ?highlight ?attr]]]`
+    /// binds the entities and values of `:foo/text` that match the search, together with the
+    /// transaction, the BM25 relevance of the match (higher is more relevant), a fragment of the
+    /// text around the matched terms, the whole text with the matched terms marked up, and the
+    /// attribute. Trailing bindings can be omitted.
+    ///
+    /// Rather than a single attribute, the search can be over a set of attributes,
+    /// `#{:foo/title :foo/notes}`, every fulltext attribute, `_`, or the attribute bound to a
+    /// variable by an earlier clause.
+    ///
+    /// Matched terms are wrapped in `<b>` and `</b>`, and text omitted from a snippet is marked
+    /// with `…`. An optional fourth argument, like `["<mark>" "</mark>" "..."]`, replaces them.
     pub(crate) fn apply_fulltext(&mut self, known: Known, where_fn: WhereFn) -> Result<()> {
-        if where_fn.args.len() != 3 {
+        if where_fn.args.len() != 3 && where_fn.args.len() != 4 {
             bail!(AlgebrizerError::InvalidNumberOfArguments(
                 where_fn.operator.clone(),
                 where_fn.args.len(),
@@ -54,16 +93,16 @@ impl ConjoiningClauses {
             ));
         }
 
-        // We should have exactly four bindings. Destructure them now.
+        // We should have at most seven bindings. Destructure them now.
         let bindings = match where_fn.binding {
             Binding::BindRel(bindings) => {
                 let bindings_count = bindings.len();
-                if bindings_count < 1 || bindings_count > 4 {
+                if bindings_count < 1 || bindings_count > 7 {
                     bail!(AlgebrizerError::InvalidBinding(
                         where_fn.operator.clone(),
                         BindingError::InvalidNumberOfBindings {
                             number: bindings.len(),
-                            expected: 4,
+                            expected: 7,
                         }
                     ));
                 }
@@ -87,89 +126,115 @@ impl ConjoiningClauses {
         let b_score = bindings
             .next()
             .unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_snippet = bindings
+            .next()
+            .unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_highlight = bindings
+            .next()
+            .unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_attribute = bindings
+            .next()
+            .unwrap_or(VariableOrPlaceholder::Placeholder);
 
         let mut args = where_fn.args.into_iter();
 
-        // TODO: process source variables.
-        match args.next().unwrap() {
-            FnArg::SrcVar(SrcVar::DefaultSrc) => {}
+        let source = match args.next().unwrap() {
+            FnArg::SrcVar(source) => source,
             _ => bail!(AlgebrizerError::InvalidArgument(
                 where_fn.operator.clone(),
                 "source variable",
                 0
             )),
-        }
+        };
 
+        // The attribute is resolved against the schema of the source that we're searching.
+        let sources = self.sources.clone();
+        let known = known_for_source(&sources, known, &source)?;
         let schema = known.schema;
 
-        // TODO: accept placeholder and set of attributes.  Alternately, consider putting the search
-        // term before the attribute arguments and collect the (variadic) attributes into a set.
-        // let a: Entid  = self.resolve_attribute_argument(&where_fn.operator, 1, args.next().unwrap())?;
-        //
-        // TODO: improve the expression of this matching, possibly by using attribute_for_* uniformly.
-        let a = match args.next().unwrap() {
-            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
-            // Must be an entid.
-            FnArg::EntidOrInteger(e) => Some(e),
-            FnArg::Variable(v) => {
-                // If it's already bound, then let's expand the variable.
-                // TODO: allow non-constant attributes.
-                match self.bound_value(&v) {
-                    Some(TypedValue::Ref(entid)) => Some(entid),
-                    Some(tv) => {
-                        bail!(AlgebrizerError::InputTypeDisagreement(
-                            v.name().clone(),
-                            ValueType::Ref,
-                            tv.value_type()
-                        ))
-                    }
-                    None => {
-                        bail!(AlgebrizerError::UnboundVariable((*v.0).clone()))
-                    }
+        // An unknown ident, or an entity that isn't present in the store, is likely enough to be a
+        // coding error that we choose to bail instead of marking the pattern as known-empty.
+        let attributes = match args.next().unwrap() {
+            FnArg::Placeholder => {
+                let fulltext = fulltext_attributes(schema);
+                if fulltext.is_empty() {
+                    self.mark_known_empty(EmptyBecause::NoFulltextAttributes);
+                    return Ok(());
+                }
+                SearchedAttributes::Fixed(fulltext)
+            },
+            FnArg::Set(attributes) => {
+                let mut entids = Vec::with_capacity(attributes.len());
+                for attribute in attributes {
+                    entids.push(self.resolve_fulltext_attribute(schema, &where_fn.operator, attribute)?);
                 }
-            }
-            _ => None,
-        };
 
-        // An unknown ident, or an entity that isn't present in the store, or isn't a fulltext
-        // attribute, is likely enough to be a coding error that we choose to bail instead of
-        // marking the pattern as known-empty.
-        let a = a.ok_or(AlgebrizerError::InvalidArgument(
-            where_fn.operator.clone(),
-            "attribute",
-            1,
-        ))?;
-        let attribute =
-            schema
-                .attribute_for_entid(a)
-                .cloned()
-                .ok_or(AlgebrizerError::InvalidArgument(
-                    where_fn.operator.clone(),
-                    "attribute",
-                    1,
-                ))?;
-
-        if !attribute.fulltext {
-            // We can never get results from a non-fulltext attribute!
-            println!("Can't run fulltext on non-fulltext attribute {}.", a);
-            self.mark_known_empty(EmptyBecause::NonFulltextAttribute(a));
-            return Ok(());
-        }
+                // Only the fulltext attributes can match.
+                let fulltext: Vec<Entid> = entids.iter()
+                                                 .cloned()
+                                                 .filter(|a| is_fulltext(schema, *a))
+                                                 .collect();
+                if fulltext.is_empty() {
+                    self.mark_known_empty(EmptyBecause::NonFulltextAttribute(entids[0]));
+                    return Ok(());
+                }
+                SearchedAttributes::Fixed(fulltext)
+            },
+            FnArg::Variable(ref v) if self.bound_value(v).is_none() &&
+                                      !self.input_variables.contains(v) &&
+                                      self.column_bindings.contains_key(v) => {
+                // The attribute is bound by an earlier clause; it must be a fulltext attribute.
+                self.constrain_var_to_type(v.clone(), ValueType::Ref);
+                if self.is_known_empty() {
+                    return Ok(());
+                }
+                let fulltext = fulltext_attributes(schema);
+                if fulltext.is_empty() {
+                    self.mark_known_empty(EmptyBecause::NoFulltextAttributes);
+                    return Ok(());
+                }
+                let column = self.column_bindings[v][0].clone();
+                SearchedAttributes::Column(column, fulltext)
+            },
+            attribute => {
+                let a = self.resolve_fulltext_attribute(schema, &where_fn.operator, attribute)?;
+                if !is_fulltext(schema, a) {
+                    // We can never get results from a non-fulltext attribute!
+                    self.mark_known_empty(EmptyBecause::NonFulltextAttribute(a));
+                    return Ok(());
+                }
+                SearchedAttributes::Fixed(vec![a])
+            },
+        };
 
         let fulltext_values_alias = self.next_alias_for_table(DatomsTable::FulltextValues);
         let datoms_table_alias = self.next_alias_for_table(DatomsTable::Datoms);
 
         // We do a fulltext lookup by joining the fulltext values table against datoms -- just
         // like applying a pattern, but two tables contribute instead of one.
-        self.from.push(SourceAlias(
-            DatomsTable::FulltextValues,
-            fulltext_values_alias.clone(),
-        ));
-        self.from
-            .push(SourceAlias(DatomsTable::Datoms, datoms_table_alias.clone()));
+        self.push_source_table(
+            &source,
+            SourceAlias(DatomsTable::FulltextValues, fulltext_values_alias.clone()),
+        );
+        self.push_source_table(
+            &source,
+            SourceAlias(DatomsTable::Datoms, datoms_table_alias.clone()),
+        );
 
-        // TODO: constrain the type in the more general cases (e.g., `a` is a var).
-        self.constrain_attribute(datoms_table_alias.clone(), a);
+        let allowed = match attributes {
+            SearchedAttributes::Fixed(allowed) => allowed,
+            SearchedAttributes::Column(column, allowed) => {
+                self.wheres.add_intersection(ColumnConstraint::Equals(
+                    QualifiedAlias(
+                        datoms_table_alias.clone(),
+                        Column::Fixed(DatomsColumn::Attribute),
+                    ),
+                    QueryValue::Column(column),
+                ));
+                allowed
+            },
+        };
+        self.constrain_attribute_to_one_of(datoms_table_alias.clone(), allowed);
 
         // Join the datoms table to the fulltext values table.
         self.wheres.add_intersection(ColumnConstraint::Equals(
@@ -242,6 +307,37 @@ impl ConjoiningClauses {
             Either::Right(qa) => QueryValue::Column(qa),
         };
 
+        let mut markup = DEFAULT_MARKUP.map(|s| QueryValue::TypedValue(TypedValue::typed_string(s)));
+        if let Some(arg) = args.next() {
+            let strings = match arg {
+                FnArg::Vector(strings) => strings,
+                _ => bail!(AlgebrizerError::InvalidArgument(
+                    where_fn.operator.clone(),
+                    "vector of strings",
+                    3
+                )),
+            };
+            if strings.len() < 2 || strings.len() > 3 {
+                bail!(AlgebrizerError::InvalidArgument(
+                    where_fn.operator.clone(),
+                    "vector of strings",
+                    3
+                ));
+            }
+            for (i, s) in strings.into_iter().enumerate() {
+                match s {
+                    FnArg::Constant(NonIntegerConstant::Text(s)) => {
+                        markup[i] = QueryValue::TypedValue(TypedValue::String(s));
+                    },
+                    _ => bail!(AlgebrizerError::InvalidArgument(
+                        where_fn.operator.clone(),
+                        "vector of strings",
+                        3
+                    )),
+                }
+            }
+        }
+
         let constraint = ColumnConstraint::Matches(
             QualifiedAlias(
                 fulltext_values_alias.clone(),
@@ -296,23 +392,123 @@ impl ConjoiningClauses {
             );
         }
 
-        if let VariableOrPlaceholder::Variable(ref var) = b_score {
-            // Scores are doubles.
-            self.constrain_var_to_type(var.clone(), ValueType::Double);
+        if let VariableOrPlaceholder::Variable(ref var) = b_attribute {
+            // Attributes are refs.
+            self.constrain_var_to_type(var.clone(), ValueType::Ref);
+            if self.is_known_empty() {
+                return Ok(());
+            }
 
-            // We do not allow the score to be bound.
-            if self.value_bindings.contains_key(var) || self.input_variables.contains(var) {
-                bail!(AlgebrizerError::InvalidBinding(
-                    var.name(),
-                    BindingError::UnexpectedBinding
-                ));
+            self.bind_column_to_var(
+                schema,
+                datoms_table_alias.clone(),
+                DatomsColumn::Attribute,
+                var.clone(),
+            );
+        }
+
+        let table = QueryValue::Column(QualifiedAlias(
+            fulltext_values_alias.clone(),
+            Column::Fulltext(FulltextColumn::Table),
+        ));
+        let text = QueryValue::Column(QualifiedAlias(
+            fulltext_values_alias.clone(),
+            Column::Fulltext(FulltextColumn::Text),
+        ));
+        let [start, end, ellipsis] = markup;
+
+        let outputs = vec![
+            (b_score, ComputedFunction::FulltextScore, vec![table.clone()]),
+            (b_snippet, ComputedFunction::FulltextSnippet, vec![table.clone(), start.clone(), end.clone(), ellipsis]),
+            (b_highlight, ComputedFunction::FulltextHighlight, vec![table, text, start, end]),
+        ];
+        for (binding, function, args) in outputs {
+            if let VariableOrPlaceholder::Variable(var) = binding {
+                self.bind_fulltext_output(schema, fulltext_values_alias.clone(), var, function, args)?;
+                if self.is_known_empty() {
+                    return Ok(());
+                }
             }
+        }
+
+        Ok(())
+    }
 
-            // We bind the value ourselves. This handily takes care of substituting into existing uses.
-            // TODO: produce real scores using SQLite's matchinfo.
-            self.bind_value(var, TypedValue::Double(0.0.into()));
+    /// Resolve one of the attributes that `fulltext` searches to an entid.
+    fn resolve_fulltext_attribute(&self, schema: &Schema, operator: &PlainSymbol, attribute: FnArg) -> Result<Entid> {
+        // TODO: improve the expression of this matching, possibly by using attribute_for_* uniformly.
+        let a = match attribute {
+            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
+            // Must be an entid.
+            FnArg::EntidOrInteger(e) => Some(e),
+            FnArg::Variable(v) => {
+                // If it's already bound, then let's expand the variable.
+                match self.bound_value(&v) {
+                    Some(TypedValue::Ref(entid)) => Some(entid),
+                    Some(tv) => {
+                        bail!(AlgebrizerError::InputTypeDisagreement(
+                            v.name().clone(),
+                            ValueType::Ref,
+                            tv.value_type()
+                        ))
+                    }
+                    None => {
+                        // Sorry, we haven't implemented late binding.
+                        bail!(AlgebrizerError::UnboundVariable((*v.0).clone()))
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        a.and_then(|a| schema.attribute_for_entid(a).map(|_| a))
+         .ok_or(AlgebrizerError::InvalidArgument(operator.clone(), "attribute", 1).into())
+    }
+
+    /// Constrain the attribute of the datoms table `table` to be one of `attributes`.
+    fn constrain_attribute_to_one_of(&mut self, table: TableAlias, mut attributes: Vec<Entid>) {
+        if attributes.len() == 1 {
+            self.constrain_attribute(table, attributes.pop().unwrap());
+            return;
+        }
+
+        let mut alternation = ColumnAlternation::default();
+        for a in attributes {
+            alternation.add_alternate(vec![ColumnConstraint::Equals(
+                QualifiedAlias(table.clone(), Column::Fixed(DatomsColumn::Attribute)),
+                QueryValue::Entid(a),
+            )].into());
         }
+        self.wheres.add(ColumnConstraintOrAlternation::Alternation(alternation));
+    }
+
+    /// Bind `var` to a value computed from the match in the fulltext values table `table`.
+    fn bind_fulltext_output(&mut self,
+                            schema: &Schema,
+                            table: TableAlias,
+                            var: Variable,
+                            function: ComputedFunction,
+                            args: Vec<QueryValue>) -> Result<()> {
+        let value_type = match function {
+            ComputedFunction::FulltextScore => ValueType::Double,
+            _ => ValueType::String,
+        };
+        self.constrain_var_to_type(var.clone(), value_type);
 
+        // We do not allow these to be bound.
+        if self.value_bindings.contains_key(&var) || self.input_variables.contains(&var) {
+            bail!(AlgebrizerError::InvalidBinding(
+                var.name(),
+                BindingError::UnexpectedBinding
+            ));
+        }
+
+        let column = Column::Computed(Box::new(ComputedValue {
+            function: function,
+            args: args,
+            value_type: value_type,
+        }));
+        self.bind_column_to_var(schema, table, column, var);
         Ok(())
     }
 }
@@ -325,7 +521,7 @@ mod testing {
 
     use mentat_core::Schema;
 
-    use edn::query::{Binding, FnArg, Keyword, PlainSymbol, Variable};
+    use edn::query::{Binding, FnArg, Keyword, PlainSymbol, SrcVar, Variable};
 
     use clauses::{add_attribute, associate_ident};
 
@@ -423,7 +619,7 @@ mod testing {
         );
 
         let bindings = cc.column_bindings;
-        assert_eq!(bindings.len(), 3);
+        assert_eq!(bindings.len(), 4);
 
         assert_eq!(
             bindings
@@ -456,15 +652,25 @@ mod testing {
             )]
         );
 
-        // Score is a value binding.
-        let values = cc.value_bindings;
+        // The score is computed from the match.
         assert_eq!(
-            values
+            bindings
                 .get(&Variable::from_valid_name("?score"))
                 .expect("column binding for ?score")
                 .clone(),
-            TypedValue::Double(0.0.into())
+            vec![QualifiedAlias(
+                "fulltext_values00".to_string(),
+                Column::Computed(Box::new(ComputedValue {
+                    function: ComputedFunction::FulltextScore,
+                    args: vec![QueryValue::Column(QualifiedAlias(
+                        "fulltext_values00".to_string(),
+                        Column::Fulltext(FulltextColumn::Table)
+                    ))],
+                    value_type: ValueType::Double,
+                }))
+            )]
         );
+        assert!(cc.value_bindings.is_empty());
 
         let known_types = cc.known_types;
         assert_eq!(known_types.len(), 4);
@@ -0,0 +1,413 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use core_traits::{
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use edn::query::{
+    Binding,
+    Predicate,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
+    BindingError,
+    Result,
+};
+
+use types::{
+    Column,
+    ColumnConstraint,
+    ComputedFunction,
+    ComputedValue,
+    QualifiedAlias,
+    QueryValue,
+    TableAlias,
+};
+
+use Known;
+
+impl ComputedFunction {
+    /// The least and, if there is one, the greatest number of arguments the function accepts.
+    fn arity(&self) -> (usize, Option<usize>) {
+        use self::ComputedFunction::*;
+        match *self {
+            Add | Subtract | Multiply | Str => (1, None),
+            Divide => (2, None),
+            Quot | Mod | StartsWith | Includes | ReFind | MinusMillis => (2, Some(2)),
+            Subs => (2, Some(3)),
+            UpperCase | LowerCase | DayStart | FulltextScore => (1, Some(1)),
+            FulltextSnippet | FulltextHighlight => (4, Some(4)),
+            Custom { ref argument_types, .. } => (argument_types.len(), Some(argument_types.len())),
+        }
+    }
+
+    /// The types the argument in `position` may have.
+    fn argument_types(&self, position: usize) -> ValueTypeSet {
+        use self::ComputedFunction::*;
+        match (self, position) {
+            (Add, _) | (Subtract, _) | (Multiply, _) | (Divide, _) => ValueTypeSet::of_numeric_types(),
+            (Quot, _) | (Mod, _) => ValueTypeSet::of_longs(),
+
+            // Instants and booleans would be concatenated as integers, so we don't allow them.
+            (Str, _) => {
+                let mut types = ValueTypeSet::of_one(ValueType::String);
+                types.insert(ValueType::Long);
+                types.insert(ValueType::Double);
+                types
+            },
+            (Subs, 0) => ValueTypeSet::of_one(ValueType::String),
+            (Subs, _) => ValueTypeSet::of_longs(),
+            (UpperCase, _) | (LowerCase, _) |
+            (StartsWith, _) | (Includes, _) | (ReFind, _) => ValueTypeSet::of_one(ValueType::String),
+            (DayStart, _) | (MinusMillis, 0) => ValueTypeSet::of_one(ValueType::Instant),
+            (MinusMillis, _) => ValueTypeSet::of_longs(),
+            (FulltextScore, _) | (FulltextSnippet, 0) | (FulltextHighlight, 0) => ValueTypeSet::any(),
+            (FulltextSnippet, _) | (FulltextHighlight, _) => ValueTypeSet::of_one(ValueType::String),
+            (Custom { argument_types, .. }, _) => ValueTypeSet::of_one(argument_types[position]),
+        }
+    }
+
+    /// The type of the value computed from arguments that might have the given types.
+    fn result_type(&self, argument_types: &[ValueTypeSet]) -> ValueType {
+        use self::ComputedFunction::*;
+        match *self {
+            // Arithmetic on longs yields a long. If any argument might be a double, the result
+            // must be a double, because we can't tell which we'll get until the query runs.
+            Add | Subtract | Multiply => {
+                let longs = ValueTypeSet::of_longs();
+                if argument_types.iter().all(|types| types.is_subset(&longs)) {
+                    ValueType::Long
+                } else {
+                    ValueType::Double
+                }
+            },
+            Divide => ValueType::Double,
+            Quot | Mod => ValueType::Long,
+            Str | Subs | UpperCase | LowerCase | ReFind => ValueType::String,
+            FulltextScore => ValueType::Double,
+            FulltextSnippet | FulltextHighlight => ValueType::String,
+            StartsWith | Includes => ValueType::Boolean,
+            DayStart | MinusMillis => ValueType::Instant,
+            Custom { return_type, .. } => return_type,
+        }
+    }
+}
+
+/// Bindings and constraints are qualified by a table alias, but a computed value spans all the
+/// tables its arguments come from. Nominally, it belongs to the first.
+fn nominal_table(args: &[QueryValue]) -> TableAlias {
+    args.iter()
+        .filter_map(|arg| match arg {
+            &QueryValue::Column(ref qa) => Some(qa.0.clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap_or_default()
+}
+
+impl ConjoiningClauses {
+    /// Functions like `[(+ ?a ?b) ?c]` and `[(upper-case ?name) ?upper]` bind their output to a
+    /// SQL expression over their arguments. Each argument must already be bound, and is narrowed
+    /// to the types the function accepts; the output has a single type inferred from those.
+    ///
+    /// Arithmetic follows SQLite rather than Clojure: `/` always produces a double, and dividing
+    /// by zero yields no result rather than an error. `upper-case` and `lower-case` only change
+    /// the case of ASCII characters. `day-start` rounds an instant down to midnight UTC, and
+    /// `(-millis ?instant ?n)` is the instant `?n` milliseconds earlier.
+    pub(crate) fn apply_computed_fn(&mut self, known: Known, function: ComputedFunction, where_fn: WhereFn) -> Result<()> {
+        let (least, greatest) = function.arity();
+        let count = where_fn.args.len();
+        if count < least {
+            bail!(AlgebrizerError::InvalidNumberOfArguments(where_fn.operator.clone(), count, least));
+        }
+        if let Some(greatest) = greatest {
+            if count > greatest {
+                bail!(AlgebrizerError::InvalidNumberOfArguments(where_fn.operator.clone(), count, greatest));
+            }
+        }
+
+        if where_fn.binding.is_empty() {
+            // The binding must introduce at least one bound variable.
+            bail!(AlgebrizerError::InvalidBinding(where_fn.operator.clone(), BindingError::NoBoundVariable));
+        }
+
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => {
+                bail!(AlgebrizerError::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindScalar))
+            },
+        };
+
+        let mut args = Vec::with_capacity(count);
+        let mut types = Vec::with_capacity(count);
+        for (position, arg) in where_fn.args.into_iter().enumerate() {
+            let (value, value_types) = self.resolve_typed_argument(&where_fn.operator, position, arg, function.argument_types(position))?;
+            args.push(value);
+            types.push(value_types);
+        }
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let value_type = function.result_type(&types);
+        self.constrain_var_to_type(var.clone(), value_type);
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let table = nominal_table(&args);
+
+        let nullable = function.is_nullable();
+        let column = Column::Computed(Box::new(ComputedValue {
+            function: function,
+            args: args,
+            value_type: value_type,
+        }));
+
+        if nullable {
+            self.wheres.add_intersection(ColumnConstraint::NotNull(QualifiedAlias(table.clone(), column.clone())));
+        }
+
+        self.bind_column_to_var(known.schema, table, column, var);
+        Ok(())
+    }
+}
+
+impl ConjoiningClauses {
+    /// A custom predicate, like `[(my/valid-email? ?s)]`, is a custom function that returns a
+    /// boolean. We constrain its result to be true.
+    pub(crate) fn apply_custom_predicate(&mut self, function: ComputedFunction, predicate: Predicate) -> Result<()> {
+        let (expected, _) = function.arity();
+        let count = predicate.args.len();
+        if count != expected {
+            bail!(AlgebrizerError::InvalidNumberOfArguments(predicate.operator.clone(), count, expected));
+        }
+
+        let mut args = Vec::with_capacity(count);
+        for (position, arg) in predicate.args.into_iter().enumerate() {
+            let (value, _) = self.resolve_typed_argument(&predicate.operator, position, arg, function.argument_types(position))?;
+            args.push(value);
+        }
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let table = nominal_table(&args);
+        let column = Column::Computed(Box::new(ComputedValue {
+            function: function,
+            args: args,
+            value_type: ValueType::Boolean,
+        }));
+        self.wheres.add_intersection(ColumnConstraint::Equals(QualifiedAlias(table, column),
+                                                              QueryValue::TypedValue(TypedValue::Boolean(true))));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use core_traits::{
+        Attribute,
+    };
+
+    use mentat_core::{
+        CustomFunction,
+        FunctionRegistry,
+        Schema,
+    };
+
+    use edn::query::{
+        Keyword,
+        PlainSymbol,
+        Variable,
+    };
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+    };
+
+    use types::{
+        ColumnConstraintOrAlternation,
+        DatomsColumn,
+    };
+
+    use {
+        algebrize,
+        parse_find_string,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, Keyword::namespaced("foo", "long"), 65);
+        associate_ident(&mut schema, Keyword::namespaced("foo", "double"), 66);
+        associate_ident(&mut schema, Keyword::namespaced("foo", "name"), 67);
+        add_attribute(&mut schema, 65, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 66, Attribute {
+            value_type: ValueType::Double,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 67, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        schema
+    }
+
+    fn alg(schema: &Schema, input: &str) -> ConjoiningClauses {
+        let known = Known::for_schema(schema);
+        algebrize(known, parse_find_string(input).expect("parsed")).expect("algebrized").cc
+    }
+
+    #[test]
+    fn test_arithmetic_result_types() {
+        let schema = prepopulated_schema();
+
+        let cc = alg(&schema, r#"[:find ?y :where [?x :foo/long ?v] [(+ ?v 1) ?y]]"#);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Long));
+
+        let cc = alg(&schema, r#"[:find ?y :where [?x :foo/long ?v] [(* ?v 1.5) ?y]]"#);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Double));
+
+        // A value of an unknown attribute might be a long or a double, so we must compute a double.
+        let cc = alg(&schema, r#"[:find ?y :where [?x _ ?v] [(- ?v) ?y]]"#);
+        assert_eq!(cc.known_type_set(&Variable::from_valid_name("?v")), ValueTypeSet::of_numeric_types());
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Double));
+
+        let cc = alg(&schema, r#"[:find ?y :where [?x :foo/long ?v] [(/ ?v 2) ?y]]"#);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Double));
+        assert!(cc.wheres.0.iter().any(|c| match c {
+            &ColumnConstraintOrAlternation::Constraint(ColumnConstraint::NotNull(_)) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_computed_binding() {
+        let schema = prepopulated_schema();
+        let cc = alg(&schema, r#"[:find ?n ?u :where [?x :foo/name ?n] [(upper-case ?n) ?u]]"#);
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?u")), Some(ValueType::String));
+
+        let n = QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value);
+        let expected = QualifiedAlias("datoms00".to_string(), Column::Computed(Box::new(ComputedValue {
+            function: ComputedFunction::UpperCase,
+            args: vec![QueryValue::Column(n)],
+            value_type: ValueType::String,
+        })));
+        assert_eq!(cc.column_bindings.get(&Variable::from_valid_name("?u")), Some(&vec![expected]));
+    }
+
+    #[test]
+    fn test_mismatched_argument_types() {
+        let schema = prepopulated_schema();
+
+        // A string can't be added…
+        let cc = alg(&schema, r#"[:find ?y :where [?x :foo/name ?v] [(+ ?v 1) ?y]]"#);
+        assert!(cc.is_known_empty());
+
+        // … nor can a constant of the wrong type be given.
+        let known = Known::for_schema(&schema);
+        let query = r#"[:find ?y :where [?x :foo/name ?v] [(subs ?v "a") ?y]]"#;
+        match algebrize(known, parse_find_string(query).expect("parsed")).expect_err("expected an error") {
+            AlgebrizerError::InvalidArgumentType(op, types, position) => {
+                assert_eq!(op, PlainSymbol::plain("subs"));
+                assert_eq!(types, ValueTypeSet::of_longs());
+                assert_eq!(position, 1);
+            },
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // The output has a single type: it can't also be a string.
+        let cc = alg(&schema, r#"[:find ?x :where [?x :foo/long ?v] [(+ ?v 1) ?y] [?x :foo/name ?y]]"#);
+        assert!(cc.is_known_empty());
+    }
+
+    #[test]
+    fn test_arity() {
+        let schema = prepopulated_schema();
+        let known = Known::for_schema(&schema);
+        let query = r#"[:find ?y :where [?x :foo/long ?v] [(mod ?v) ?y]]"#;
+        match algebrize(known, parse_find_string(query).expect("parsed")).expect_err("expected an error") {
+            AlgebrizerError::InvalidNumberOfArguments(_, count, expected) => {
+                assert_eq!(count, 1);
+                assert_eq!(expected, 2);
+            },
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_custom_functions() {
+        let schema = prepopulated_schema();
+        let mut functions = FunctionRegistry::default();
+        functions.register("my/valid?", CustomFunction::predicate(vec![ValueType::String], |_| true));
+        functions.register("my/twice", CustomFunction::new(vec![ValueType::Long], ValueType::Long, |args| {
+            args[0].clone().into_long().map(|v| TypedValue::Long(v * 2))
+        }));
+        let known = Known::for_schema(&schema).with_functions(&functions);
+
+        let query = r#"[:find ?n :where [?x :foo/name ?n] [(my/valid? ?n)]]"#;
+        let cc = algebrize(known, parse_find_string(query).expect("parsed")).expect("algebrized").cc;
+        let n = QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value);
+        let valid = QualifiedAlias("datoms00".to_string(), Column::Computed(Box::new(ComputedValue {
+            function: ComputedFunction::custom("my/valid?", functions.get("my/valid?").unwrap()),
+            args: vec![QueryValue::Column(n)],
+            value_type: ValueType::Boolean,
+        })));
+        assert_eq!(cc.wheres.0, vec![
+            ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Equals(
+                QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Attribute),
+                QueryValue::Entid(67))),
+            ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Equals(
+                valid,
+                QueryValue::TypedValue(TypedValue::Boolean(true)))),
+        ]);
+
+        let query = r#"[:find ?y :where [?x :foo/long ?v] [(my/twice ?v) ?y]]"#;
+        let cc = algebrize(known, parse_find_string(query).expect("parsed")).expect("algebrized").cc;
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Long));
+
+        // Arguments of other types don't match.
+        let query = r#"[:find ?n :where [?x :foo/long ?n] [(my/valid? ?n)]]"#;
+        let cc = algebrize(known, parse_find_string(query).expect("parsed")).expect("algebrized").cc;
+        assert!(cc.is_known_empty());
+
+        // A function that doesn't return a boolean isn't a predicate, and without a registry,
+        // neither function is known.
+        for (known, query) in vec![
+            (known, r#"[:find ?x :where [?x :foo/long ?v] [(my/twice ?v)]]"#),
+            (Known::for_schema(&schema), r#"[:find ?n :where [?x :foo/name ?n] [(my/valid? ?n)]]"#),
+        ] {
+            match algebrize(known, parse_find_string(query).expect("parsed")).expect_err("expected an error") {
+                AlgebrizerError::UnknownFunction(_) => {},
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+}
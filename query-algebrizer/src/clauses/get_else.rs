@@ -0,0 +1,151 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use core_traits::{
+    ValueTypeSet,
+};
+
+use mentat_core::{
+    HasSchema,
+};
+
+use edn::query::{
+    Binding,
+    FnArg,
+    SrcVar,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    PushComputed,
+};
+
+use clauses::convert::ValueConversion;
+
+use clauses::sources::known_for_source;
+
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
+    BindingError,
+    Result,
+};
+
+use types::{
+    Column,
+    ColumnConstraint,
+    ColumnIntersection,
+    ComputedTable,
+    DatomsColumn,
+    DatomsTable,
+    EmptyBecause,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+};
+
+use Known;
+
+impl ConjoiningClauses {
+    /// `[(get-else $ ?e :foo/bar "default") ?v]` binds `?v` to the value of `:foo/bar` on `?e`,
+    /// or to the default if `?e` has no such value. Unlike a pattern, it never eliminates `?e`.
+    /// A named source, like `$a`, is looked up in that source's datoms, with its schema.
+    ///
+    /// This is a `LEFT JOIN` against the datoms table, with `?v` bound to
+    /// `COALESCE(v, "default")`. The attribute must be cardinality-one, and the default must
+    /// agree with its value type.
+    pub(crate) fn apply_get_else(&mut self, known: Known, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 4 {
+            bail!(AlgebrizerError::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 4));
+        }
+
+        if where_fn.binding.is_empty() {
+            // The binding must introduce at least one bound variable.
+            bail!(AlgebrizerError::InvalidBinding(where_fn.operator.clone(), BindingError::NoBoundVariable));
+        }
+
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => {
+                bail!(AlgebrizerError::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindScalar))
+            },
+        };
+
+        let mut args = where_fn.args.into_iter();
+
+        let source = match args.next().unwrap() {
+            FnArg::SrcVar(source) => source,
+            _ => bail!(AlgebrizerError::InvalidArgument(where_fn.operator.clone(), "source variable", 0)),
+        };
+
+        // The attribute is resolved against the schema of the source whose datoms we join.
+        let sources = self.sources.clone();
+        let known = known_for_source(&sources, known, &source)?;
+        let schema = known.schema;
+
+        let entity = self.resolve_ref_argument(schema, &where_fn.operator, 1, args.next().unwrap())?;
+
+        let a = match args.next().unwrap() {
+            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
+            FnArg::EntidOrInteger(e) => Some(e),
+            _ => None,
+        };
+        let attribute = a.and_then(|a| schema.attribute_for_entid(a).cloned())
+                         .ok_or(AlgebrizerError::InvalidArgument(where_fn.operator.clone(), "attribute", 2))?;
+        let a = a.unwrap();
+
+        // A cardinality-many attribute could produce several rows for a single entity.
+        if attribute.multival {
+            bail!(AlgebrizerError::InvalidArgument(where_fn.operator.clone(), "cardinality-one attribute", 2));
+        }
+
+        let value_type = attribute.value_type;
+        let default = match self.typed_value_from_arg(schema, &var, args.next().unwrap(), ValueTypeSet::of_one(value_type))? {
+            ValueConversion::Val(value) => value,
+            ValueConversion::Impossible(EmptyBecause::TypeMismatch { .. }) => {
+                bail!(AlgebrizerError::InvalidArgumentType(where_fn.operator.clone(), ValueTypeSet::of_one(value_type), 3));
+            },
+            ValueConversion::Impossible(because) => {
+                self.mark_known_empty(because);
+                return Ok(());
+            },
+        };
+
+        self.constrain_var_to_type(var.clone(), value_type);
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        // Fulltext values live in their own table; the view gives us the strings themselves.
+        let table = if attribute.fulltext { DatomsTable::FulltextDatoms } else { DatomsTable::Datoms };
+
+        // The join constraints need to know the alias of the table they join, so pick it first.
+        let alias = self.next_alias_for_table(DatomsTable::Computed(self.computed_tables.len()));
+        let constraints = vec![
+            ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Entity), entity),
+            ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Attribute), QueryValue::Entid(a)),
+        ];
+        let source = match source {
+            SrcVar::DefaultSrc => None,
+            SrcVar::NamedSrc(name) => Some(name),
+        };
+        let computed = self.computed_tables.push_computed(ComputedTable::LeftJoin {
+            source: source,
+            table: table,
+            constraints: ColumnIntersection::from(constraints),
+        });
+
+        self.from.push(SourceAlias(computed, alias.clone()));
+        self.bind_column_to_var(schema, alias, Column::ValueOrDefault(default), var);
+        Ok(())
+    }
+}
@@ -8,6 +8,8 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::collections::BTreeMap;
+
 use core_traits::{
     ValueType,
     ValueTypeSet,
@@ -118,6 +120,72 @@ impl ConjoiningClauses {
         Ok(())
     }
 
+    /// Bind the collections and relations supplied as query inputs to the `[?x ...]` and
+    /// `[[?x ?y]]` bindings in `:in`. Like the values given to `ground`, they become a computed
+    /// table of values.
+    pub(crate) fn apply_input_bindings(&mut self,
+                                       known: Known,
+                                       bindings: Vec<Binding>,
+                                       mut collections: BTreeMap<Variable, Vec<TypedValue>>,
+                                       mut relations: BTreeMap<Vec<Variable>, Vec<Vec<TypedValue>>>) -> Result<()> {
+        for binding in bindings.into_iter() {
+            let (places, rows) = match binding {
+                Binding::BindColl(var) => {
+                    let values = collections.remove(&var)
+                                            .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()))?;
+                    (vec![VariableOrPlaceholder::Variable(var)],
+                     values.into_iter().map(|v| vec![v]).collect())
+                },
+                Binding::BindRel(places) => {
+                    let names: Vec<Variable> = places.iter().filter_map(|p| p.var().cloned()).collect();
+                    let rows = relations.remove(&names)
+                                        .ok_or_else(|| AlgebrizerError::UnboundVariable(names[0].name()))?;
+                    (places, rows)
+                },
+                // The parser only produces collection and relation bindings in `:in`.
+                Binding::BindScalar(_) | Binding::BindTuple(_) => bail!(AlgebrizerError::UnsupportedArgument),
+            };
+            self.apply_input_relation(known.schema, places, rows)?;
+        }
+        Ok(())
+    }
+
+    fn apply_input_relation<'s>(&mut self, schema: &'s Schema, places: Vec<VariableOrPlaceholder>, rows: Vec<Vec<TypedValue>>) -> Result<()> {
+        let full_width = places.len();
+        let names: Vec<Variable> = places.iter().filter_map(|p| p.var().cloned()).collect();
+
+        if rows.is_empty() {
+            self.mark_known_empty(EmptyBecause::NoInputValues(names[0].clone()));
+            return Ok(());
+        }
+
+        // As with `ground`, each column must be of a single type.
+        let mut types: Vec<Option<ValueType>> = vec![None; names.len()];
+        let mut matrix = Vec::with_capacity(names.len() * rows.len());
+        for row in rows.into_iter() {
+            if row.len() != full_width {
+                bail!(AlgebrizerError::InputBindingMismatch(names[0].name(), full_width, row.len()));
+            }
+            let named = row.into_iter()
+                           .zip(places.iter())
+                           .filter_map(|(value, place)| place.var().map(|var| (var, value)));
+            for (column, (var, value)) in named.enumerate() {
+                let value_type = value.value_type();
+                match types[column] {
+                    Some(existing) if existing != value_type => {
+                        bail!(AlgebrizerError::InputTypeDisagreement(var.name(), existing, value_type));
+                    },
+                    _ => types[column] = Some(value_type),
+                }
+                matrix.push(value);
+            }
+        }
+
+        let types = types.into_iter().map(|t| t.expect("a type for every column")).collect();
+        self.collect_named_bindings(schema, names, types, matrix);
+        Ok(())
+    }
+
     pub(crate) fn apply_ground(&mut self, known: Known, where_fn: WhereFn) -> Result<()> {
         if where_fn.args.len() != 1 {
             bail!(AlgebrizerError::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 1));
@@ -16,9 +16,13 @@ use core_traits::{
 };
 
 use edn::query::{
+    Rule,
+    SrcVar,
     Variable,
 };
 
+use types::Source;
+
 use query_algebrizer_traits::errors::{
     AlgebrizerError,
     Result,
@@ -30,9 +34,24 @@ use query_algebrizer_traits::errors::{
 /// the bindings that will be used at execution time.
 /// When built correctly, `types` is guaranteed to contain the types of `values` -- use
 /// `QueryInputs::new` or `QueryInputs::with_values` to construct an instance.
+/// Rules, bound to `%` in the query's `:in`, are supplied with `QueryInputs::with_rules`, and
+/// named sources, like `$a`, with `QueryInputs::with_source`.
+/// Collection bindings like `[?x ...]` and relation bindings like `[[?x ?y]]` are supplied with
+/// `QueryInputs::with_coll` and `QueryInputs::with_rel`. Unlike scalar values, these must be known
+/// when the query is algebrized.
+/// A page of the results of an ordered query is selected with `QueryInputs::with_limit`,
+/// `QueryInputs::paged`, and `QueryInputs::with_after`.
+#[derive(Clone)]
 pub struct QueryInputs {
     pub(crate) types: BTreeMap<Variable, ValueType>,
     pub(crate) values: BTreeMap<Variable, TypedValue>,
+    pub(crate) rules: Vec<Rule>,
+    pub(crate) sources: BTreeMap<SrcVar, Source>,
+    pub(crate) collections: BTreeMap<Variable, Vec<TypedValue>>,
+    pub(crate) relations: BTreeMap<Vec<Variable>, Vec<Vec<TypedValue>>>,
+    pub(crate) limit: Option<u64>,
+    pub(crate) paged: bool,
+    pub(crate) after: Option<Vec<TypedValue>>,
 }
 
 impl Default for QueryInputs {
@@ -40,6 +59,13 @@ impl Default for QueryInputs {
         QueryInputs {
             types: BTreeMap::default(),
             values: BTreeMap::default(),
+            rules: vec![],
+            sources: BTreeMap::default(),
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            limit: None,
+            paged: false,
+            after: None,
         }
     }
 }
@@ -54,6 +80,13 @@ impl QueryInputs {
         QueryInputs {
             types: types.into_iter().collect(),
             values: BTreeMap::default(),
+            rules: vec![],
+            sources: BTreeMap::default(),
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            limit: None,
+            paged: false,
+            after: None,
         }
     }
 
@@ -61,6 +94,13 @@ impl QueryInputs {
         QueryInputs {
             types: values.iter().map(|(var, val)| (var.clone(), val.value_type())).collect(),
             values: values,
+            rules: vec![],
+            sources: BTreeMap::default(),
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            limit: None,
+            paged: false,
+            after: None,
         }
     }
 
@@ -76,6 +116,118 @@ impl QueryInputs {
                 }
             }
         }
-        Ok(QueryInputs { types: types, values: values })
+        Ok(QueryInputs {
+            types: types,
+            values: values,
+            rules: vec![],
+            sources: BTreeMap::default(),
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            limit: None,
+            paged: false,
+            after: None,
+        })
+    }
+
+    /// Supply the type of `var`, whose value won't be known until the query is run.
+    pub fn with_type(mut self, var: Variable, value_type: ValueType) -> QueryInputs {
+        self.types.insert(var, value_type);
+        self
+    }
+
+    /// Bind `var` to `value`, replacing any type or value already supplied for it.
+    pub fn with_value(mut self, var: Variable, value: TypedValue) -> QueryInputs {
+        self.types.insert(var.clone(), value.value_type());
+        self.values.insert(var, value);
+        self
+    }
+
+    pub fn value(&self, var: &Variable) -> Option<&TypedValue> {
+        self.values.get(var)
     }
+
+    /// Supply the rules that the query refers to as `%`.
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> QueryInputs {
+        self.rules = rules;
+        self
+    }
+
+    /// Supply the store or view of a store that the query refers to by the source variable `var`.
+    pub fn with_source(mut self, var: SrcVar, source: Source) -> QueryInputs {
+        self.sources.insert(var, source);
+        self
+    }
+
+    /// Supply the values for a collection binding, `[?x ...]`. Entities should be supplied as
+    /// `TypedValue::Ref`. Every value must have the same type.
+    pub fn with_coll(mut self, var: Variable, values: Vec<TypedValue>) -> QueryInputs {
+        self.collections.insert(var, values);
+        self
+    }
+
+    /// Supply the rows for a relation binding, like `[[?x _ ?y]]`, which is identified by its
+    /// variables: here, `[?x, ?y]`. Each row has a value for every place in the binding, including
+    /// placeholders, and the values in each column must have the same type.
+    pub fn with_rel(mut self, vars: Vec<Variable>, rows: Vec<Vec<TypedValue>>) -> QueryInputs {
+        self.relations.insert(vars, rows);
+        self
+    }
+
+    /// Return at most `limit` results, in place of any `:limit` in the query.
+    pub fn with_limit(mut self, limit: u64) -> QueryInputs {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Order the results for paging: by the query's `:order`, then by each other variable in
+    /// `:find`, so that no two results tie. See `FindSpec::keyset_order`.
+    pub fn paged(mut self) -> QueryInputs {
+        self.paged = true;
+        self
+    }
+
+    /// Return only the results of a paged query that follow a row whose values for the
+    /// variables it's ordered by are `after`, in the same order, and ignore any `:offset`. This
+    /// is keyset pagination: unlike `:offset`, the rows before `after` don't need to be found to
+    /// be skipped.
+    pub fn with_after(mut self, after: Vec<TypedValue>) -> QueryInputs {
+        self.paged = true;
+        self.after = Some(after);
+        self
+    }
+
+    /// Identify these inputs by the types of their values, so that a query algebrized with only
+    /// those types -- see `without_values` -- can be reused with any values of the same types.
+    /// Rules, sources, collection and relation bindings, and `after` rows aren't compared, so
+    /// inputs that supply any of them have no key.
+    pub fn key(&self) -> Option<QueryInputsKey> {
+        if !self.rules.is_empty() ||
+           !self.sources.is_empty() ||
+           !self.collections.is_empty() ||
+           !self.relations.is_empty() ||
+           self.after.is_some() {
+            return None;
+        }
+        Some(QueryInputsKey {
+            types: self.types.clone(),
+            limit: self.limit,
+            paged: self.paged,
+        })
+    }
+
+    /// Keep the types of these inputs' values, but not the values themselves, which become
+    /// parameters of the query: see `QueryValue::Parameter`.
+    pub fn without_values(mut self) -> QueryInputs {
+        self.values.clear();
+        self
+    }
+}
+
+/// The parts of some `QueryInputs` that determine how a query is algebrized. See
+/// `QueryInputs::key`.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct QueryInputsKey {
+    types: BTreeMap<Variable, ValueType>,
+    limit: Option<u64>,
+    paged: bool,
 }
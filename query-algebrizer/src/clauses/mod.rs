@@ -25,6 +25,10 @@ use std::fmt::{
     Formatter,
 };
 
+use std::cell::RefCell;
+
+use std::rc::Rc;
+
 use core_traits::{
     Attribute,
     Entid,
@@ -46,7 +50,9 @@ use edn::query::{
     Element,
     FindSpec,
     Keyword,
+    PlainSymbol,
     Pull,
+    SrcVar,
     Variable,
     WhereClause,
     PatternNonValuePlace,
@@ -58,6 +64,7 @@ use query_algebrizer_traits::errors::{
 };
 
 use types::{
+    ClauseDecision,
     ColumnConstraint,
     ColumnIntersection,
     ComputedTable,
@@ -72,6 +79,7 @@ use types::{
     PlaceOrEmpty,
     QualifiedAlias,
     QueryValue,
+    Source,
     SourceAlias,
     TableAlias,
 };
@@ -83,9 +91,13 @@ mod not;
 mod pattern;
 mod predicate;
 mod resolve;
+mod rules;
+mod sources;
 
 mod ground;
 mod fulltext;
+mod functions;
+mod get_else;
 mod tx_log_api;
 mod where_fn;
 
@@ -94,7 +106,17 @@ use validate::{
     validate_or_join,
 };
 
-pub use self::inputs::QueryInputs;
+pub use self::inputs::{
+    QueryInputs,
+    QueryInputsKey,
+};
+
+use self::rules::{
+    RuleRecursion,
+    RuleSet,
+};
+
+use self::sources::known_for_source;
 
 use Known;
 
@@ -223,6 +245,27 @@ pub struct ConjoiningClauses {
 
     /// Map of variables to the set of type requirements we have for them.
     required_types: BTreeMap<Variable, ValueTypeSet>,
+
+    /// The rules supplied to the query through `%`, shared with every nested CC.
+    rules: Rc<RuleSet>,
+
+    /// The named sources supplied to the query, like `$a`, shared with every nested CC.
+    sources: Rc<BTreeMap<SrcVar, Source>>,
+
+    /// The recursive rules whose definitions we are currently expanding, innermost last.
+    expanding_rules: Vec<PlainSymbol>,
+
+    /// `Some` if this CC is one definition of a recursive rule, in which case invoking that rule
+    /// refers back to the enclosing CTE.
+    rule_recursion: Option<RuleRecursion>,
+
+    /// The decisions made while algebrizing, in order, for `EXPLAIN`. Shared with nested CCs,
+    /// except those for the arms of an `or` or a rule and the body of a `not`, whose decisions are
+    /// nested within a single decision of this CC.
+    decisions: Rc<RefCell<Vec<ClauseDecision>>>,
+
+    /// Whether to record `decisions`. Only `EXPLAIN` needs them, so they're usually not.
+    recording_decisions: bool,
 }
 
 impl PartialEq for ConjoiningClauses {
@@ -274,6 +317,12 @@ impl Default for ConjoiningClauses {
             value_bindings: BTreeMap::new(),
             known_types: BTreeMap::new(),
             extracted_types: BTreeMap::new(),
+            rules: Rc::new(RuleSet::default()),
+            sources: Rc::new(BTreeMap::new()),
+            expanding_rules: vec![],
+            rule_recursion: None,
+            decisions: Rc::new(RefCell::new(vec![])),
+            recording_decisions: false,
         }
     }
 }
@@ -313,7 +362,7 @@ impl ConjoiningClauses {
     where T: Into<Option<QueryInputs>> {
         match inputs.into() {
             None => ConjoiningClauses::with_alias_counter(alias_counter),
-            Some(QueryInputs { mut types, mut values }) => {
+            Some(QueryInputs { mut types, mut values, .. }) => {
                 // Discard any bindings not mentioned in our :in clause.
                 types.keep_intersected_keys(&in_variables);
                 values.keep_intersected_keys(&in_variables);
@@ -361,6 +410,11 @@ impl ConjoiningClauses {
             known_types: self.known_types.clone(),
             extracted_types: self.extracted_types.clone(),
             required_types: self.required_types.clone(),
+            rules: self.rules.clone(),
+            sources: self.sources.clone(),
+            expanding_rules: self.expanding_rules.clone(),
+            decisions: self.decisions.clone(),
+            recording_decisions: self.recording_decisions,
             ..Default::default()
         }
     }
@@ -376,6 +430,11 @@ impl ConjoiningClauses {
             known_types: self.known_types.with_intersected_keys(&vars),
             extracted_types: self.extracted_types.with_intersected_keys(&vars),
             required_types: self.required_types.with_intersected_keys(&vars),
+            rules: self.rules.clone(),
+            sources: self.sources.clone(),
+            expanding_rules: self.expanding_rules.clone(),
+            decisions: self.decisions.clone(),
+            recording_decisions: self.recording_decisions,
             ..Default::default()
         }
     }
@@ -461,7 +520,8 @@ impl ConjoiningClauses {
                 },
 
                 Column::Fulltext(FulltextColumn::Rowid) |
-                Column::Fulltext(FulltextColumn::Text) => {
+                Column::Fulltext(FulltextColumn::Text) |
+                Column::Fulltext(FulltextColumn::Table) => {
                     // We never expose `rowid` via queries.  We do expose `text`, but only
                     // indirectly, by joining against `datoms`.  Therefore, these are meaningless.
                     unimplemented!()
@@ -480,7 +540,9 @@ impl ConjoiningClauses {
                 },
 
                 // TODO: recognize when the valueType might be a ref and also translate entids there.
-                Column::Fixed(DatomsColumn::Value) => {
+                Column::Fixed(DatomsColumn::Value) |
+                Column::ValueOrDefault(_) |
+                Column::Computed(_) => {
                     self.constrain_column_to_constant(table, column, bound_val);
                 },
 
@@ -754,10 +816,33 @@ impl ConjoiningClauses {
         if self.empty_because.is_some() {
             return;
         }
-        println!("CC known empty: {:?}.", &why);                   // TODO: proper logging.
+        self.decide(ClauseDecision::Empty(why.clone()));
         self.empty_because = Some(why);
     }
 
+    /// Record the decisions made from now on while algebrizing this CC and those nested within it.
+    pub(crate) fn record_decisions(&mut self) {
+        self.recording_decisions = true;
+    }
+
+    fn decide(&self, decision: ClauseDecision) {
+        if self.recording_decisions {
+            self.decisions.borrow_mut().push(decision);
+        }
+    }
+
+    /// Record this CC's decisions apart from those of the CC it was made from, so that they can be
+    /// nested within one of that CC's decisions, like `ClauseDecision::Or`.
+    fn record_own_decisions(&mut self) {
+        self.decisions = Rc::new(RefCell::new(vec![]));
+    }
+
+    /// The decisions made while algebrizing this CC and those nested within it, in order, if
+    /// they were recorded: see `algebrize_explained`.
+    pub fn decisions(&self) -> Vec<ClauseDecision> {
+        self.decisions.borrow().clone()
+    }
+
     fn entid_for_ident<'s, 'a>(&self, schema: &'s Schema, ident: &'a Keyword) -> Option<KnownEntid> {
         schema.get_entid(&ident)
     }
@@ -937,6 +1022,29 @@ impl ConjoiningClauses {
                 }
             }
         }
+
+        // Inputs without values are compared to parameters instead.
+        let mut parameters = vec![];
+        for var in self.input_variables.iter() {
+            if self.value_bindings.contains_key(var) {
+                continue;
+            }
+            if let Some(primary) = self.column_bindings.get(var).and_then(|cols| cols.first()) {
+                parameters.push(ColumnConstraint::Equals(primary.clone(), QueryValue::Parameter(var.clone())));
+            }
+        }
+        for constraint in parameters {
+            self.wheres.add_intersection(constraint);
+        }
+    }
+
+    /// Return a parameter for `var` if it's an input without a value. See `QueryValue::Parameter`.
+    pub(crate) fn input_parameter(&self, var: &Variable) -> Option<QueryValue> {
+        if self.input_variables.contains(var) && !self.value_bindings.contains_key(var) {
+            Some(QueryValue::Parameter(var.clone()))
+        } else {
+            None
+        }
     }
 
     /// Eliminate any type extractions for variables whose types are definitely known.
@@ -1054,7 +1162,9 @@ impl ConjoiningClauses {
 
 impl ConjoiningClauses {
     fn apply_evolved_patterns(&mut self, known: Known, mut patterns: VecDeque<EvolvedPattern>) -> Result<()> {
+        let sources = self.sources.clone();
         while let Some(pattern) = patterns.pop_front() {
+            let known = known_for_source(&sources, known, &pattern.source)?;
             match self.evolve_pattern(known, pattern) {
                 PlaceOrEmpty::Place(re_evolved) => self.apply_pattern(known, re_evolved),
                 PlaceOrEmpty::Empty(because) => {
@@ -1099,6 +1209,7 @@ impl ConjoiningClauses {
         // together to take advantage of mutual partial evaluation.
         let mut remaining = where_clauses.len();
         let mut patterns: VecDeque<EvolvedPattern> = VecDeque::with_capacity(remaining);
+        let sources = self.sources.clone();
         for clause in where_clauses {
             remaining -= 1;
             if let &WhereClause::TypeAnnotation(_) = &clause {
@@ -1106,6 +1217,8 @@ impl ConjoiningClauses {
             }
             match clause {
                 WhereClause::Pattern(p) => {
+                    let source = p.source.clone().unwrap_or(SrcVar::DefaultSrc);
+                    let known = known_for_source(&sources, known, &source)?;
                     match self.make_evolved_pattern(known, p) {
                         PlaceOrEmpty::Place(evolved) => patterns.push_back(evolved),
                         PlaceOrEmpty::Empty(because) => {
@@ -1131,6 +1244,9 @@ impl ConjoiningClauses {
     pub(crate) fn apply_clause(&mut self, known: Known, where_clause: WhereClause) -> Result<()> {
         match where_clause {
             WhereClause::Pattern(p) => {
+                let sources = self.sources.clone();
+                let source = p.source.clone().unwrap_or(SrcVar::DefaultSrc);
+                let known = known_for_source(&sources, known, &source)?;
                 match self.make_evolved_pattern(known, p) {
                     PlaceOrEmpty::Place(evolved) => self.apply_pattern(known, evolved),
                     PlaceOrEmpty::Empty(because) => self.mark_known_empty(because),
@@ -1154,7 +1270,9 @@ impl ConjoiningClauses {
             WhereClause::TypeAnnotation(anno) => {
                 self.apply_type_anno(&anno)
             },
-            _ => unimplemented!(),
+            WhereClause::RuleExpr(r) => {
+                self.apply_rule_expr(known, r)
+            },
         }
     }
 }
@@ -22,6 +22,7 @@ use query_algebrizer_traits::errors::{
 };
 
 use types::{
+    ClauseDecision,
     ColumnConstraint,
     ComputedTable,
 };
@@ -36,6 +37,7 @@ impl ConjoiningClauses {
         };
 
         let mut template = self.use_as_template(&unified);
+        template.record_own_decisions();
 
         for v in unified.iter() {
             if self.value_bindings.contains_key(&v) {
@@ -50,6 +52,7 @@ impl ConjoiningClauses {
         }
 
         template.apply_clauses(known, not_join.clauses)?;
+        self.decide(ClauseDecision::Not(template.decisions()));
 
         if template.is_known_empty() {
             return Ok(());
@@ -39,6 +39,7 @@ use query_algebrizer_traits::errors::{
 };
 
 use types::{
+    ClauseDecision,
     ColumnConstraintOrAlternation,
     ColumnAlternation,
     ColumnIntersection,
@@ -432,14 +433,17 @@ impl ConjoiningClauses {
             //  :where [?a :some/int ?x]
             //         [_ :some/otherint ?x]]
             // ```
-            let mut receptacles =
+            let receptacles: Vec<ConjoiningClauses> =
                 patterns.into_iter()
                         .map(|pattern| {
                             let mut receptacle = template.make_receptacle();
+                            receptacle.record_own_decisions();
                             receptacle.apply_pattern_clause_for_alias(known, &pattern, &source_alias);
                             receptacle
                         })
-                        .peekable();
+                        .collect();
+            self.decide(ClauseDecision::Or(receptacles.iter().map(|r| r.decisions()).collect()));
+            let mut receptacles = receptacles.into_iter().peekable();
 
             // Let's see if we can grab a reason if every pattern failed.
             // If every pattern failed, we can just take the first!
@@ -586,10 +590,12 @@ impl ConjoiningClauses {
         let template = self.use_as_template(&projected);
 
         let mut acc = Vec::with_capacity(join_clauses.len());
+        let mut arms = Vec::with_capacity(join_clauses.len());
         let mut empty_because: Option<EmptyBecause> = None;
 
         for clause in join_clauses.into_iter() {
             let mut receptacle = template.make_receptacle();
+            receptacle.record_own_decisions();
             match clause {
                 OrWhereClause::And(clauses) => {
                     receptacle.apply_clauses(known, clauses)?;
@@ -598,6 +604,7 @@ impl ConjoiningClauses {
                     receptacle.apply_clause(known, clause)?;
                 },
             }
+            arms.push(receptacle.decisions());
             if receptacle.is_known_empty() {
                 empty_because = receptacle.empty_because;
             } else {
@@ -608,6 +615,8 @@ impl ConjoiningClauses {
             }
         }
 
+        self.decide(ClauseDecision::Or(arms));
+
         if acc.is_empty() {
             self.mark_known_empty(empty_because.expect("empty for a reason"));
             return Ok(());
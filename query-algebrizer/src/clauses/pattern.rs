@@ -34,6 +34,7 @@ use clauses::{
 };
 
 use types::{
+    ClauseDecision,
     ColumnConstraint,
     DatomsColumn,
     EmptyBecause,
@@ -100,6 +101,19 @@ impl ConjoiningClauses {
             return;
         }
 
+        self.constrain_alias_to_pattern(known, pattern, alias);
+
+        // If the pattern can't match, that's already been recorded.
+        if !self.is_known_empty() && self.recording_decisions {
+            self.decide(ClauseDecision::Table {
+                pattern: pattern.describe(known.schema),
+                alias: alias.clone(),
+            });
+        }
+    }
+
+    fn constrain_alias_to_pattern(&mut self, known: Known, pattern: &EvolvedPattern, alias: &SourceAlias) {
+
         // Process each place in turn, applying constraints.
         // Both `e` and `a` must be entities, which is equivalent here
         // to being typed as Ref.
@@ -160,6 +174,10 @@ impl ConjoiningClauses {
                     if self.is_known_empty() {
                         return;
                     }
+                } else if let (Some(_), Some(input_type)) = (self.input_parameter(v), self.known_type(v)) {
+                    // The value column can hold any type, but the parameter's value will have a
+                    // known type.
+                    self.wheres.add_intersection(ColumnConstraint::has_unit_type(col.clone(), input_type));
                 }
 
                 self.bind_column_to_var(schema, col.clone(), DatomsColumn::Value, v.clone());
@@ -629,19 +647,20 @@ impl ConjoiningClauses {
         };
     }
 
+    /// Apply a pattern, given the `Known` for the pattern's source.
     pub(crate) fn apply_pattern(&mut self, known: Known, pattern: EvolvedPattern) {
-        // For now we only support the default source.
-        if pattern.source != SrcVar::DefaultSrc {
-            unimplemented!();
-        }
-
-        if self.attempt_cache_lookup(known, &pattern) {
+        // Only the default source is cached.
+        if pattern.source == SrcVar::DefaultSrc && self.attempt_cache_lookup(known, &pattern) {
+            // If the cache showed that the pattern can't match, that's already been recorded.
+            if !self.is_known_empty() && self.recording_decisions {
+                self.decide(ClauseDecision::Cached { pattern: pattern.describe(known.schema) });
+            }
             return;
         }
 
         if let Some(alias) = self.alias_table(known.schema, &pattern) {
             self.apply_pattern_clause_for_alias(known, &pattern, &alias);
-            self.from.push(alias);
+            self.push_source_table(&pattern.source, alias);
         } else {
             // We didn't determine a table, likely because there was a mismatch
             // between an attribute and a value.
@@ -693,6 +712,7 @@ mod testing {
 
     use {
         algebrize,
+        algebrize_explained,
         parse_find_string,
     };
 
@@ -1272,4 +1292,42 @@ mod testing {
         assert!(!cc.extracted_types.contains_key(&e));
         assert!(!cc.extracted_types.contains_key(&v));
     }
+
+    #[test]
+    fn test_decisions() {
+        let query = r#"[:find ?e :where [?e :foo/bar ?v] (or [?e :foo/bar "yes"] [?e :foo/bar false])]"#;
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, Keyword::namespaced("foo", "bar"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Boolean,
+            ..Default::default()
+        });
+        // Decisions are only recorded for `EXPLAIN`.
+        assert_eq!(alg(&schema, query).decisions(), vec![]);
+
+        let parsed = parse_find_string(query).expect("parse failed");
+        let cc = algebrize_explained(Known::for_schema(&schema), parsed, QueryInputs::default())
+            .expect("algebrize failed")
+            .cc;
+
+        // The `or` arm that can't match is pruned, and the other shares the `or`'s alias. Each
+        // arm's decisions are nested within the `or`.
+        assert_eq!(cc.decisions(), vec![
+            ClauseDecision::Table {
+                pattern: "[?e :foo/bar ?v]".to_string(),
+                alias: SourceAlias(DatomsTable::Datoms, "datoms00".to_string()),
+            },
+            ClauseDecision::Or(vec![
+                vec![
+                    ClauseDecision::Empty(EmptyBecause::ValueTypeMismatch(ValueType::Boolean, TypedValue::typed_string("yes"))),
+                ],
+                vec![
+                    ClauseDecision::Table {
+                        pattern: "[?e :foo/bar false]".to_string(),
+                        alias: SourceAlias(DatomsTable::Datoms, "datoms01".to_string()),
+                    },
+                ],
+            ]),
+        ]);
+    }
 }
@@ -8,12 +8,16 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::collections::BTreeSet;
+
 use core_traits::{
+    TypedValue,
     ValueType,
     ValueTypeSet,
 };
 
 use mentat_core::{
+    HasSchema,
     Schema,
 };
 
@@ -28,6 +32,8 @@ use clauses::ConjoiningClauses;
 
 use clauses::convert::ValueTypes;
 
+use clauses::sources::known_for_source;
+
 use query_algebrizer_traits::errors::{
     AlgebrizerError,
     Result,
@@ -35,9 +41,15 @@ use query_algebrizer_traits::errors::{
 
 use types::{
     ColumnConstraint,
+    ComputedFunction,
+    ComputedTable,
+    DatomsColumn,
+    DatomsTable,
     EmptyBecause,
     Inequality,
+    QualifiedAlias,
     QueryValue,
+    SourceAlias,
 };
 
 use Known;
@@ -47,16 +59,24 @@ impl ConjoiningClauses {
     /// There are several kinds of predicates in our Datalog:
     /// - A limited set of binary comparison operators: < > <= >= !=.
     ///   These are converted into SQLite binary comparisons and some type constraints.
-    /// - In the future, some predicates that are implemented via function calls in SQLite.
-    ///
-    /// At present we have implemented only the five built-in comparison binary operators.
+    /// - `missing?`, which is converted into a `NOT EXISTS` subquery.
+    /// - Custom predicates registered with the store, which are called from SQL.
     pub(crate) fn apply_predicate(&mut self, known: Known, predicate: Predicate) -> Result<()> {
         // Because we'll be growing the set of built-in predicates, handling each differently,
-        // and ultimately allowing user-specified predicates, we match on the predicate name first.
-        if let Some(op) = Inequality::from_datalog_operator(predicate.operator.0.as_str()) {
+        // and allowing user-specified predicates, we match on the predicate name first.
+        let name = predicate.operator.0.as_str();
+        if let Some(op) = Inequality::from_datalog_operator(name) {
             self.apply_inequality(known, op, predicate)
+        } else if name == "missing?" {
+            self.apply_missing(known, predicate)
         } else {
-            bail!(AlgebrizerError::UnknownFunction(predicate.operator.clone()))
+            match known.functions.and_then(|functions| functions.get(name)) {
+                Some(custom) if custom.is_predicate() => {
+                    let function = ComputedFunction::custom(name, custom);
+                    self.apply_custom_predicate(function, predicate)
+                },
+                _ => bail!(AlgebrizerError::UnknownFunction(predicate.operator.clone())),
+            }
         }
     }
 
@@ -162,6 +182,73 @@ impl ConjoiningClauses {
         self.wheres.add_intersection(constraint);
         Ok(())
     }
+
+    /// `[(missing? $ ?e :foo/bar)]` is satisfied when `?e` has no value for `:foo/bar`. It's
+    /// equivalent to `(not [?e :foo/bar _])`, and like that `not` it becomes a `NOT EXISTS`
+    /// subquery. If the entity is known and the attribute is cached, we can answer without one.
+    /// A named source, like `$a`, is checked against that source's datoms, with its schema.
+    pub(crate) fn apply_missing(&mut self, known: Known, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 3 {
+            bail!(AlgebrizerError::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 3));
+        }
+
+        let mut args = predicate.args.into_iter();
+
+        let source = match args.next().unwrap() {
+            FnArg::SrcVar(source) => source,
+            _ => bail!(AlgebrizerError::InvalidArgument(predicate.operator.clone(), "source variable", 0)),
+        };
+
+        // The attribute is resolved against the schema of the source whose datoms we check.
+        let sources = self.sources.clone();
+        let known = known_for_source(&sources, known, &source)?;
+        let schema = known.schema;
+        let entity = self.resolve_ref_argument(schema, &predicate.operator, 1, args.next().unwrap())?;
+
+        let a = match args.next().unwrap() {
+            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
+            FnArg::EntidOrInteger(e) => Some(e),
+            _ => None,
+        };
+        let attribute = a.and_then(|a| schema.attribute_for_entid(a))
+                         .ok_or(AlgebrizerError::InvalidArgument(predicate.operator.clone(), "attribute", 2))?;
+        let a = a.unwrap();
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let known_entity = match entity {
+            QueryValue::Entid(e) |
+            QueryValue::TypedValue(TypedValue::Ref(e)) => Some(e),
+            _ => None,
+        };
+        if let Some(e) = known_entity {
+            if known.is_attribute_cached_forward(a) {
+                let present = if attribute.multival {
+                    known.get_values_for_entid(schema, a, e).map(|vs| !vs.is_empty()).unwrap_or(false)
+                } else {
+                    known.get_value_for_entid(schema, a, e).is_some()
+                };
+                if present {
+                    self.mark_known_empty(EmptyBecause::CachedAttributeHasValues {
+                        entity: e,
+                        attr: a,
+                    });
+                }
+                return Ok(());
+            }
+        }
+
+        let mut subquery = self.use_as_template(&BTreeSet::new());
+        let datoms = subquery.next_alias_for_table(DatomsTable::Datoms);
+        subquery.push_source_table(&source, SourceAlias(DatomsTable::Datoms, datoms.clone()));
+        subquery.wheres.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(datoms.clone(), DatomsColumn::Entity), entity));
+        subquery.constrain_attribute(datoms, a);
+
+        self.wheres.add_intersection(ColumnConstraint::NotExists(ComputedTable::Subquery(subquery)));
+        Ok(())
+    }
 }
 
 impl Inequality {
@@ -10,6 +10,7 @@
 
 use core_traits::{
     ValueType,
+    ValueTypeSet,
     TypedValue,
 };
 
@@ -59,6 +60,7 @@ impl ConjoiningClauses {
                     self.column_bindings
                         .get(&var)
                         .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                        .or_else(|| self.input_parameter(&var))
                         .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()).into())
                 }
             },
@@ -71,7 +73,9 @@ impl ConjoiningClauses {
             Constant(NonIntegerConstant::Uuid(_)) |
             Constant(NonIntegerConstant::Instant(_)) |        // Instants are covered below.
             Constant(NonIntegerConstant::BigInteger(_)) |
-            Vector(_) => {
+            Vector(_) |
+            Set(_) |
+            Placeholder => {
                 self.mark_known_empty(EmptyBecause::NonNumericArgument);
                 bail!(AlgebrizerError::InvalidArgument(function.clone(), "numeric", position))
             },
@@ -92,6 +96,7 @@ impl ConjoiningClauses {
                         self.column_bindings
                             .get(&var)
                             .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                            .or_else(|| self.input_parameter(&var))
                             .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()).into())
                     },
                 }
@@ -109,13 +114,56 @@ impl ConjoiningClauses {
             Constant(NonIntegerConstant::Text(_)) |
             Constant(NonIntegerConstant::Uuid(_)) |
             Constant(NonIntegerConstant::BigInteger(_)) |
-            Vector(_) => {
+            Vector(_) |
+            Set(_) |
+            Placeholder => {
                 self.mark_known_empty(EmptyBecause::NonInstantArgument);
                 bail!(AlgebrizerError::InvalidArgumentType(function.clone(), ValueType::Instant.into(), position))
             },
         }
     }
 
+    /// Take a function argument and turn it into a `QueryValue` that must have one of `types`.
+    /// Variables are narrowed to `types`; constants and inputs of any other type are rejected.
+    /// Also returns the types the value might have, which callers use to infer a result type.
+    pub(crate) fn resolve_typed_argument(&mut self, function: &PlainSymbol, position: usize, arg: FnArg, types: ValueTypeSet) -> Result<(QueryValue, ValueTypeSet)> {
+        use self::FnArg::*;
+        let value = match arg {
+            FnArg::Variable(var) => {
+                match self.bound_value(&var) {
+                    Some(v) => v,
+                    None => {
+                        self.narrow_types_for_var(var.clone(), types);
+                        let value = self.column_bindings
+                                        .get(&var)
+                                        .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                                        .or_else(|| self.input_parameter(&var))
+                                        .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()))?;
+                        return Ok((value, self.known_type_set(&var)));
+                    },
+                }
+            },
+            EntidOrInteger(i) => TypedValue::Long(i),
+            Constant(NonIntegerConstant::Boolean(val)) => TypedValue::Boolean(val),
+            Constant(NonIntegerConstant::Float(f)) => TypedValue::Double(f),
+            Constant(NonIntegerConstant::Text(s)) => TypedValue::typed_string(s.as_str()),
+            Constant(NonIntegerConstant::Uuid(u)) => TypedValue::Uuid(u),
+            Constant(NonIntegerConstant::Instant(u)) => TypedValue::Instant(u),
+            IdentOrKeyword(_) |
+            Constant(NonIntegerConstant::BigInteger(_)) |
+            SrcVar(_) |
+            Vector(_) |
+            Set(_) |
+            Placeholder => bail!(AlgebrizerError::InvalidArgumentType(function.clone(), types, position)),
+        };
+
+        let value_type = value.value_type();
+        if !types.contains(value_type) {
+            bail!(AlgebrizerError::InvalidArgumentType(function.clone(), types, position));
+        }
+        Ok((QueryValue::TypedValue(value), ValueTypeSet::of_one(value_type)))
+    }
+
     /// Take a function argument and turn it into a `QueryValue` suitable for use in a concrete
     /// constraint.
     pub(crate) fn resolve_ref_argument(&mut self, schema: &Schema, function: &PlainSymbol, position: usize, arg: FnArg) -> Result<QueryValue> {
@@ -130,6 +178,7 @@ impl ConjoiningClauses {
                     self.column_bindings
                         .get(&var)
                         .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                        .or_else(|| self.input_parameter(&var))
                         .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()).into())
                 }
             },
@@ -146,7 +195,9 @@ impl ConjoiningClauses {
             Constant(NonIntegerConstant::Instant(_)) |
             Constant(NonIntegerConstant::BigInteger(_)) |
             SrcVar(_) |
-            Vector(_) => {
+            Vector(_) |
+            Set(_) |
+            Placeholder => {
                 self.mark_known_empty(EmptyBecause::NonEntityArgument);
                 bail!(AlgebrizerError::InvalidArgumentType(function.clone(), ValueType::Ref.into(), position))
             },
@@ -175,6 +226,7 @@ impl ConjoiningClauses {
                         self.column_bindings
                             .get(&var)
                             .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                            .or_else(|| self.input_parameter(&var))
                             .ok_or_else(|| AlgebrizerError::UnboundVariable(var.name()).into())
                     },
                 }
@@ -187,8 +239,13 @@ impl ConjoiningClauses {
             Constant(NonIntegerConstant::Uuid(u)) => Ok(QueryValue::TypedValue(TypedValue::Uuid(u))),
             Constant(NonIntegerConstant::Instant(u)) => Ok(QueryValue::TypedValue(TypedValue::Instant(u))),
             Constant(NonIntegerConstant::BigInteger(_)) => unimplemented!(),
-            SrcVar(_) => unimplemented!(),
-            Vector(_) => unimplemented!(),    // TODO
+            // Neither a source nor a vector is a single value. Functions that take them, like
+            // `fulltext` and `ground`, handle them themselves; collections of inputs are bound in
+            // `:in`.
+            SrcVar(_) |
+            Vector(_) |
+            Set(_) |
+            Placeholder => bail!(AlgebrizerError::UnsupportedArgument),
         }
     }
 }
@@ -0,0 +1,853 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::btree_map::Entry;
+use std::rc::Rc;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use core_traits::{
+    TypedValue,
+    ValueTypeSet,
+};
+
+use mentat_core::{
+    HasSchema,
+    ValueRc,
+};
+
+use edn::query::{
+    Binding,
+    ContainsVariables,
+    FnArg,
+    NotJoin,
+    OrJoin,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    PlainSymbol,
+    Predicate,
+    Rule,
+    RuleExpr,
+    TypeAnnotation,
+    UnifyVars,
+    Variable,
+    VariableOrPlaceholder,
+    WhereClause,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    PushComputed,
+};
+
+use clauses::pattern::into_typed_value;
+
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
+    Result,
+};
+
+use types::{
+    ClauseDecision,
+    ColumnConstraint,
+    ComputedTable,
+    DatomsTable,
+    EmptyBecause,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+    TableAlias,
+    VariableColumn,
+};
+
+use Known;
+
+/// The rules supplied to a query through `%`, grouped by name.
+#[derive(Debug, Default)]
+pub(crate) struct RuleSet {
+    definitions: BTreeMap<PlainSymbol, Vec<Rule>>,
+
+    /// The names of those rules that can invoke themselves, directly or indirectly.
+    recursive: BTreeSet<PlainSymbol>,
+}
+
+impl RuleSet {
+    pub(crate) fn new(rules: Vec<Rule>) -> Result<RuleSet> {
+        let mut definitions: BTreeMap<PlainSymbol, Vec<Rule>> = BTreeMap::new();
+        for rule in rules.into_iter() {
+            // Every rule variable must appear in the body, or an invocation couldn't bind it.
+            let mut mentioned = BTreeSet::new();
+            for clause in rule.clauses.iter() {
+                clause.accumulate_mentioned_variables(&mut mentioned);
+            }
+            if let Some(var) = rule.vars.iter().find(|v| !mentioned.contains(v)) {
+                bail!(AlgebrizerError::UnusedRuleVariable(rule.name.clone(), var.name()));
+            }
+
+            match definitions.entry(rule.name.clone()) {
+                Entry::Vacant(e) => {
+                    e.insert(vec![rule]);
+                },
+                Entry::Occupied(mut e) => {
+                    if e.get()[0].vars.len() != rule.vars.len() {
+                        bail!(AlgebrizerError::InconsistentRuleArity(rule.name.clone()));
+                    }
+                    e.get_mut().push(rule);
+                },
+            }
+        }
+
+        let invocations: BTreeMap<PlainSymbol, BTreeSet<PlainSymbol>> =
+            definitions.iter()
+                       .map(|(name, rules)| {
+                           let mut acc = BTreeSet::new();
+                           for clause in rules.iter().flat_map(|r| r.clauses.iter()) {
+                               accumulate_invoked_rules(clause, &mut acc);
+                           }
+                           (name.clone(), acc)
+                       })
+                       .collect();
+
+        let recursive = definitions.keys()
+                                   .filter(|name| can_reach(&invocations, name, name))
+                                   .cloned()
+                                   .collect();
+
+        Ok(RuleSet {
+            definitions,
+            recursive,
+        })
+    }
+
+    fn definitions(&self, name: &PlainSymbol) -> Option<&Vec<Rule>> {
+        self.definitions.get(name)
+    }
+
+    fn is_recursive(&self, name: &PlainSymbol) -> bool {
+        self.recursive.contains(name)
+    }
+}
+
+fn accumulate_invoked_rules(clause: &WhereClause, acc: &mut BTreeSet<PlainSymbol>) {
+    match clause {
+        &WhereClause::RuleExpr(ref r) => {
+            acc.insert(r.name.clone());
+        },
+        &WhereClause::OrJoin(ref o) => {
+            for arm in o.clauses.iter() {
+                match arm {
+                    &OrWhereClause::Clause(ref c) => accumulate_invoked_rules(c, acc),
+                    &OrWhereClause::And(ref cs) => for c in cs { accumulate_invoked_rules(c, acc) },
+                }
+            }
+        },
+        &WhereClause::NotJoin(ref n) => {
+            for c in n.clauses.iter() {
+                accumulate_invoked_rules(c, acc);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// Return true if `to` is invoked by `from`, or by any rule that `from` invokes, and so on.
+fn can_reach(invocations: &BTreeMap<PlainSymbol, BTreeSet<PlainSymbol>>, from: &PlainSymbol, to: &PlainSymbol) -> bool {
+    let mut seen: BTreeSet<&PlainSymbol> = BTreeSet::new();
+    let mut pending: Vec<&PlainSymbol> = vec![from];
+    while let Some(next) = pending.pop() {
+        if let Some(invoked) = invocations.get(next) {
+            for name in invoked.iter() {
+                if name == to {
+                    return true;
+                }
+                if seen.insert(name) {
+                    pending.push(name);
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Describes the recursive rule whose definitions a CC is algebrizing. An invocation of that rule
+/// becomes a reference to the CTE's own table, whose columns are named by `head`.
+#[derive(Clone, Debug)]
+pub(crate) struct RuleRecursion {
+    name: PlainSymbol,
+    head: Vec<Variable>,
+}
+
+/// Rewrites the body of one rule definition for a particular invocation: each rule variable is
+/// replaced by the corresponding argument, and every other variable by a fresh one, so that
+/// nothing in the body can collide with the enclosing query.
+struct Substitution<'a> {
+    rule: &'a PlainSymbol,
+    args: BTreeMap<Variable, FnArg>,
+    positions: BTreeMap<Variable, usize>,
+}
+
+impl<'a> Substitution<'a> {
+    fn lookup(&self, var: &Variable) -> FnArg {
+        self.args.get(var).cloned().unwrap_or_else(|| FnArg::Variable(var.clone()))
+    }
+
+    fn invalid<T>(&self, var: &Variable, expected: &'static str) -> Result<T> {
+        let position = self.positions.get(var).cloned().unwrap_or(0);
+        bail!(AlgebrizerError::InvalidArgument(self.rule.clone(), expected, position))
+    }
+
+    fn variable(&self, var: Variable) -> Result<Variable> {
+        match self.lookup(&var) {
+            FnArg::Variable(v) => Ok(v),
+            _ => self.invalid(&var, "variable"),
+        }
+    }
+
+    fn non_value_place(&self, place: PatternNonValuePlace) -> Result<PatternNonValuePlace> {
+        match place {
+            PatternNonValuePlace::Variable(var) => {
+                match self.lookup(&var) {
+                    FnArg::Variable(v) => Ok(PatternNonValuePlace::Variable(v)),
+                    FnArg::EntidOrInteger(e) => Ok(PatternNonValuePlace::Entid(e)),
+                    FnArg::IdentOrKeyword(kw) => Ok(kw.into()),
+                    _ => self.invalid(&var, "entity, ident, or variable"),
+                }
+            },
+            place => Ok(place),
+        }
+    }
+
+    fn value_place(&self, place: PatternValuePlace) -> Result<PatternValuePlace> {
+        match place {
+            PatternValuePlace::Variable(var) => {
+                match self.lookup(&var) {
+                    FnArg::Variable(v) => Ok(PatternValuePlace::Variable(v)),
+                    FnArg::EntidOrInteger(i) => Ok(PatternValuePlace::EntidOrInteger(i)),
+                    FnArg::IdentOrKeyword(kw) => Ok(PatternValuePlace::IdentOrKeyword(ValueRc::new(kw))),
+                    FnArg::Constant(c) => Ok(PatternValuePlace::Constant(c)),
+                    _ => self.invalid(&var, "constant or variable"),
+                }
+            },
+            place => Ok(place),
+        }
+    }
+
+    fn fn_arg(&self, arg: FnArg) -> Result<FnArg> {
+        match arg {
+            FnArg::Variable(var) => Ok(self.lookup(&var)),
+            FnArg::Vector(args) => args.into_iter().map(|a| self.fn_arg(a)).collect::<Result<Vec<_>>>().map(FnArg::Vector),
+            FnArg::Set(args) => args.into_iter().map(|a| self.fn_arg(a)).collect::<Result<Vec<_>>>().map(FnArg::Set),
+            arg => Ok(arg),
+        }
+    }
+
+    fn variable_or_placeholder(&self, v: VariableOrPlaceholder) -> Result<VariableOrPlaceholder> {
+        match v {
+            VariableOrPlaceholder::Variable(var) => self.variable(var).map(VariableOrPlaceholder::Variable),
+            VariableOrPlaceholder::Placeholder => Ok(VariableOrPlaceholder::Placeholder),
+        }
+    }
+
+    fn binding(&self, binding: Binding) -> Result<Binding> {
+        Ok(match binding {
+            Binding::BindScalar(var) => Binding::BindScalar(self.variable(var)?),
+            Binding::BindColl(var) => Binding::BindColl(self.variable(var)?),
+            Binding::BindRel(vs) => Binding::BindRel(vs.into_iter().map(|v| self.variable_or_placeholder(v)).collect::<Result<_>>()?),
+            Binding::BindTuple(vs) => Binding::BindTuple(vs.into_iter().map(|v| self.variable_or_placeholder(v)).collect::<Result<_>>()?),
+        })
+    }
+
+    fn unify_vars(&self, unify_vars: UnifyVars) -> Result<UnifyVars> {
+        match unify_vars {
+            UnifyVars::Implicit => Ok(UnifyVars::Implicit),
+            UnifyVars::Explicit(vars) => vars.into_iter().map(|v| self.variable(v)).collect::<Result<_>>().map(UnifyVars::Explicit),
+        }
+    }
+
+    fn clauses(&self, clauses: Vec<WhereClause>) -> Result<Vec<WhereClause>> {
+        clauses.into_iter().map(|c| self.clause(c)).collect()
+    }
+
+    fn clause(&self, clause: WhereClause) -> Result<WhereClause> {
+        Ok(match clause {
+            WhereClause::Pattern(p) => {
+                WhereClause::Pattern(Pattern {
+                    source: p.source,
+                    entity: self.non_value_place(p.entity)?,
+                    attribute: self.non_value_place(p.attribute)?,
+                    value: self.value_place(p.value)?,
+                    tx: self.non_value_place(p.tx)?,
+                })
+            },
+            WhereClause::Pred(p) => {
+                WhereClause::Pred(Predicate {
+                    operator: p.operator,
+                    args: p.args.into_iter().map(|a| self.fn_arg(a)).collect::<Result<_>>()?,
+                })
+            },
+            WhereClause::WhereFn(f) => {
+                WhereClause::WhereFn(WhereFn {
+                    operator: f.operator,
+                    args: f.args.into_iter().map(|a| self.fn_arg(a)).collect::<Result<_>>()?,
+                    binding: self.binding(f.binding)?,
+                })
+            },
+            WhereClause::OrJoin(o) => {
+                let (arms, unify_vars, _) = o.dismember();
+                let arms = arms.into_iter()
+                               .map(|arm| match arm {
+                                   OrWhereClause::Clause(c) => self.clause(c).map(OrWhereClause::Clause),
+                                   OrWhereClause::And(cs) => self.clauses(cs).map(OrWhereClause::And),
+                               })
+                               .collect::<Result<_>>()?;
+                WhereClause::OrJoin(OrJoin::new(self.unify_vars(unify_vars)?, arms))
+            },
+            WhereClause::NotJoin(n) => {
+                WhereClause::NotJoin(NotJoin::new(self.unify_vars(n.unify_vars)?, self.clauses(n.clauses)?))
+            },
+            WhereClause::RuleExpr(r) => {
+                WhereClause::RuleExpr(RuleExpr {
+                    name: r.name,
+                    args: r.args.into_iter().map(|a| self.fn_arg(a)).collect::<Result<_>>()?,
+                })
+            },
+            WhereClause::TypeAnnotation(anno) => {
+                WhereClause::TypeAnnotation(TypeAnnotation {
+                    value_type: anno.value_type,
+                    variable: self.variable(anno.variable)?,
+                })
+            },
+        })
+    }
+}
+
+/// Application of rule invocations.
+impl ConjoiningClauses {
+    pub(crate) fn use_rules(&mut self, rules: Vec<Rule>) -> Result<()> {
+        self.rules = Rc::new(RuleSet::new(rules)?);
+        Ok(())
+    }
+
+    /// A rule invocation is expanded in one of three ways:
+    ///
+    /// - A non-recursive rule is inlined: its definitions, rewritten in terms of the arguments,
+    ///   become the arms of an `or-join` on the argument variables.
+    /// - A recursive rule becomes a computed table, a `WITH RECURSIVE` CTE whose arms are the
+    ///   rule's definitions. Definitions that invoke the rule itself join against the CTE.
+    /// - Within one of those definitions, an invocation of the rule being defined is exactly that
+    ///   join.
+    ///
+    /// For example, given
+    ///
+    /// ```edn
+    /// [[(ancestor ?c ?p) [?c :person/parent ?p]]
+    ///  [(ancestor ?c ?p) [?c :person/parent ?x] (ancestor ?x ?p)]]
+    /// ```
+    ///
+    /// the clause `(ancestor ?me ?who)` turns into something like
+    ///
+    /// ```sql
+    /// (WITH RECURSIVE rule AS
+    ///   (SELECT datoms01.e AS `?c__0`, datoms01.v AS `?p__1`, … FROM datoms AS datoms01 …
+    ///    UNION
+    ///    SELECT datoms03.e AS `?c__0`, rule04.`?p__1` AS `?p__1`, …
+    ///    FROM datoms AS datoms03, rule AS rule04
+    ///    WHERE … datoms03.v = rule04.`?c__0`)
+    ///  SELECT * FROM rule) AS c00
+    /// ```
+    ///
+    /// SQLite only supports linear recursion, so a definition may invoke the rule it defines at
+    /// most once, and not from within `or` or `not`. Mutual recursion isn't supported.
+    pub(crate) fn apply_rule_expr(&mut self, known: Known, rule_expr: RuleExpr) -> Result<()> {
+        let RuleExpr { name, args } = rule_expr;
+
+        let arity = match self.rules.definitions(&name) {
+            Some(definitions) => definitions[0].vars.len(),
+            None => bail!(AlgebrizerError::UnknownRule(name)),
+        };
+        if args.len() != arity {
+            bail!(AlgebrizerError::InvalidNumberOfArguments(name, args.len(), arity));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            match arg {
+                &FnArg::SrcVar(_) | &FnArg::Vector(_) | &FnArg::Set(_) | &FnArg::Placeholder => {
+                    bail!(AlgebrizerError::InvalidArgument(name, "constant or variable", i));
+                },
+                _ => {},
+            }
+        }
+
+        let recursion = self.rule_recursion.clone();
+        match recursion {
+            Some(ref recursion) if recursion.name == name => {
+                let table = DatomsTable::RecursiveRule;
+                let alias = self.next_alias_for_table(table);
+                self.bind_rule_columns(known, &alias, &recursion.head, args);
+                self.from.push(SourceAlias(table, alias));
+                Ok(())
+            },
+            _ => {
+                if self.expanding_rules.contains(&name) {
+                    bail!(AlgebrizerError::UnsupportedRecursion(name));
+                }
+                if self.rules.is_recursive(&name) {
+                    self.apply_recursive_rule(known, name, args)
+                } else {
+                    self.apply_non_recursive_rule(known, name, args)
+                }
+            },
+        }
+    }
+
+    fn fresh_variable(&self, var: &Variable) -> Variable {
+        Variable::from_valid_name(format!("{}__{}", var.as_str(), self.alias_counter.next()).as_str())
+    }
+
+    fn substitution_for<'a>(&self, rule: &'a Rule, head: Vec<FnArg>) -> Substitution<'a> {
+        let mut args = BTreeMap::new();
+        let mut positions = BTreeMap::new();
+        for (i, (var, arg)) in rule.vars.iter().zip(head.into_iter()).enumerate() {
+            args.insert(var.clone(), arg);
+            positions.insert(var.clone(), i);
+        }
+
+        let mut mentioned = BTreeSet::new();
+        for clause in rule.clauses.iter() {
+            clause.accumulate_mentioned_variables(&mut mentioned);
+        }
+        for var in mentioned.into_iter() {
+            if !args.contains_key(&var) {
+                let fresh = self.fresh_variable(&var);
+                args.insert(var, FnArg::Variable(fresh));
+            }
+        }
+
+        Substitution {
+            rule: &rule.name,
+            args,
+            positions,
+        }
+    }
+
+    fn apply_non_recursive_rule(&mut self, known: Known, name: PlainSymbol, args: Vec<FnArg>) -> Result<()> {
+        let definitions = self.rules.definitions(&name).cloned().unwrap_or_default();
+
+        let mut arms: Vec<Vec<WhereClause>> = Vec::with_capacity(definitions.len());
+        for rule in definitions.iter() {
+            let substitution = self.substitution_for(rule, args.clone());
+            arms.push(substitution.clauses(rule.clauses.clone())?);
+        }
+
+        if arms.len() == 1 {
+            // Every variable not in the rule head is fresh, so we can simply apply the body.
+            let clauses = arms.pop().expect("one arm");
+            return self.apply_clauses(known, clauses);
+        }
+
+        // With only constant arguments nothing is unified, and the arms only check that one of
+        // them matches.
+        let unified: BTreeSet<Variable> = args.iter().filter_map(|a| a.as_variable().cloned()).collect();
+        let or_join = OrJoin::new(UnifyVars::Explicit(unified),
+                                  arms.into_iter().map(OrWhereClause::And).collect());
+        self.apply_or_join(known, or_join)
+    }
+
+    fn apply_recursive_rule(&mut self, known: Known, name: PlainSymbol, args: Vec<FnArg>) -> Result<()> {
+        let definitions = self.rules.definitions(&name).cloned().unwrap_or_default();
+
+        // Each definition names its variables independently; the CTE's columns use one shared set.
+        let head: Vec<Variable> = definitions[0].vars.iter().map(|v| self.fresh_variable(v)).collect();
+        let projection: BTreeSet<Variable> = head.iter().cloned().collect();
+
+        let mut template = self.use_as_template(&BTreeSet::new());
+        template.expanding_rules.push(name.clone());
+
+        let mut bodies = Vec::with_capacity(definitions.len());
+        for rule in definitions.iter() {
+            let substitution = self.substitution_for(rule, head.iter().cloned().map(FnArg::Variable).collect());
+            bodies.push(substitution.clauses(rule.clauses.clone())?);
+        }
+        let seeds = self.rule_seeds(known, &name, &head, &args, &bodies);
+
+        let mut base = vec![];
+        let mut recursive = vec![];
+        let mut arms = vec![];
+        let mut empty_because: Option<EmptyBecause> = None;
+
+        for (rule, clauses) in definitions.iter().zip(bodies.into_iter()) {
+            let mut receptacle = template.make_receptacle();
+            receptacle.rule_recursion = Some(RuleRecursion {
+                name: name.clone(),
+                head: head.clone(),
+            });
+            receptacle.record_own_decisions();
+            receptacle.apply_clauses(known, clauses)?;
+            arms.push(receptacle.decisions());
+
+            let self_references = receptacle.from
+                                            .iter()
+                                            .filter(|s| s.0 == DatomsTable::RecursiveRule)
+                                            .count();
+            if self_references > 1 {
+                bail!(AlgebrizerError::UnsupportedRecursion(name));
+            }
+
+            if receptacle.is_known_empty() {
+                empty_because = receptacle.empty_because;
+                continue;
+            }
+
+            for (var, column) in rule.vars.iter().zip(head.iter()) {
+                if !receptacle.column_bindings.contains_key(column) && !receptacle.is_value_bound(column) {
+                    bail!(AlgebrizerError::UnboundVariable(var.name()));
+                }
+            }
+
+            if self_references == 0 {
+                for (column, value) in seeds.iter() {
+                    if let Some(qa) = receptacle.column_bindings.get(column).and_then(|cols| cols.first()).cloned() {
+                        receptacle.wheres.add_intersection(ColumnConstraint::Equals(qa, value.clone()));
+                    }
+                }
+            }
+
+            receptacle.expand_column_bindings();
+            receptacle.prune_extracted_types();
+            receptacle.process_required_types()?;
+            if self_references == 0 {
+                base.push(receptacle);
+            } else {
+                recursive.push(receptacle);
+            }
+        }
+
+        self.decide(ClauseDecision::Or(arms));
+
+        if base.is_empty() {
+            // Without a base case the recursion never gets started.
+            self.mark_known_empty(empty_because.unwrap_or(EmptyBecause::RuleWithoutBaseCase(name)));
+            return Ok(());
+        }
+
+        // Any type that the CTE can produce for a column is one that some arm can produce.
+        for (column, arg) in head.iter().zip(args.iter()) {
+            if let &FnArg::Variable(ref var) = arg {
+                let types = base.iter()
+                                .chain(recursive.iter())
+                                .fold(ValueTypeSet::none(), |acc, cc| acc.union(&cc.known_type_set(column)));
+                if types != ValueTypeSet::any() {
+                    self.narrow_types_for_var(var.clone(), types);
+                }
+            }
+        }
+
+        let table = self.computed_tables.push_computed(ComputedTable::RecursiveRule {
+            projection,
+            base,
+            recursive,
+        });
+        let alias = self.next_alias_for_table(table);
+        self.bind_rule_columns(known, &alias, &head, args);
+        self.from.push(SourceAlias(table, alias));
+        Ok(())
+    }
+
+    /// The values that seed the base case of a recursive rule, by the column of its CTE that they
+    /// restrict: those of the arguments that are constants or bound to values, in positions that
+    /// every recursive arm passes along unchanged. Each row that the recursion derives from a
+    /// base row has the same value in such a position, so the CTE need only start from the base
+    /// rows with the argument's value, rather than computing the rule's whole relation to be
+    /// joined against the argument afterwards.
+    ///
+    /// For `[(ancestor ?c ?p) [?c :person/parent ?x] (ancestor ?x ?p)]`, that's the second
+    /// argument: `(ancestor ?c :x)` seeds the base case, but `(ancestor :x ?p)` can't.
+    fn rule_seeds(&self, known: Known, name: &PlainSymbol, head: &[Variable], args: &[FnArg], bodies: &[Vec<WhereClause>]) -> BTreeMap<Variable, QueryValue> {
+        let mut seeds = BTreeMap::new();
+        for (i, (column, arg)) in head.iter().zip(args.iter()).enumerate() {
+            let invariant = bodies.iter().flat_map(|clauses| clauses.iter()).all(|clause| match clause {
+                &WhereClause::RuleExpr(ref r) if r.name == *name => r.args.get(i) == Some(&FnArg::Variable(column.clone())),
+                _ => true,
+            });
+            if !invariant {
+                continue;
+            }
+            let value = match arg {
+                &FnArg::Variable(ref var) => match self.bound_value(var) {
+                    Some(TypedValue::Ref(entid)) => QueryValue::Entid(entid),
+                    Some(value) => QueryValue::TypedValue(value),
+                    None => continue,
+                },
+                arg => rule_arg_value(known, arg.clone()),
+            };
+            seeds.insert(column.clone(), value);
+        }
+        seeds
+    }
+
+    /// Join the columns of a recursive rule's CTE, which are named by `head`, against `args`.
+    fn bind_rule_columns(&mut self, known: Known, alias: &TableAlias, head: &[Variable], args: Vec<FnArg>) {
+        for (column, arg) in head.iter().zip(args.into_iter()) {
+            let value_column = VariableColumn::Variable(column.clone());
+            match arg {
+                FnArg::Variable(var) => {
+                    self.bind_column_to_var(known.schema, alias.clone(), value_column, var.clone());
+
+                    // The CTE always projects type tags, so we can extract any type we don't know.
+                    if self.known_type(&var).is_none() && !self.extracted_types.contains_key(&var) {
+                        let type_column = VariableColumn::VariableTypeTag(column.clone());
+                        self.extracted_types.insert(var, QualifiedAlias::new(alias.clone(), type_column));
+                    }
+                },
+                arg => {
+                    let value = rule_arg_value(known, arg);
+                    self.wheres.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), value_column), value));
+                },
+            }
+        }
+    }
+}
+
+/// The value of a constant argument to a recursive rule, to match against a column of its CTE.
+fn rule_arg_value(known: Known, arg: FnArg) -> QueryValue {
+    match arg {
+        // Entities and longs share a representation, so this matches either.
+        FnArg::EntidOrInteger(i) => QueryValue::Entid(i),
+        FnArg::IdentOrKeyword(kw) => {
+            match known.schema.get_entid(&kw) {
+                Some(entid) => QueryValue::Entid(entid.into()),
+                None => QueryValue::TypedValue(TypedValue::Keyword(ValueRc::new(kw))),
+            }
+        },
+        FnArg::Constant(c) => {
+            match into_typed_value(c) {
+                TypedValue::Ref(entid) => QueryValue::Entid(entid),
+                value => QueryValue::TypedValue(value),
+            }
+        },
+        FnArg::Variable(_) | FnArg::SrcVar(_) | FnArg::Vector(_) | FnArg::Set(_) | FnArg::Placeholder => {
+            // Variables are bound, and the rest are rejected by `apply_rule_expr`.
+            unreachable!();
+        },
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use core_traits::{
+        Attribute,
+        ValueType,
+    };
+
+    use mentat_core::{
+        Schema,
+    };
+
+    use edn::query::{
+        Keyword,
+    };
+
+    use edn::parse::{
+        parse_rules,
+    };
+
+    use clauses::{
+        QueryInputs,
+        add_attribute,
+        associate_ident,
+    };
+
+    use types::{
+        ColumnConstraintOrAlternation,
+    };
+
+    use {
+        algebrize_with_inputs,
+        parse_find_string,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, Keyword::namespaced("person", "parent"), 65);
+        associate_ident(&mut schema, Keyword::namespaced("person", "name"), 66);
+        add_attribute(&mut schema, 65, Attribute {
+            value_type: ValueType::Ref,
+            multival: true,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 66, Attribute {
+            value_type: ValueType::String,
+            multival: false,
+            ..Default::default()
+        });
+        schema
+    }
+
+    fn alg_with_rules(schema: &Schema, query: &str, rules: &str) -> Result<ConjoiningClauses> {
+        let known = Known::for_schema(schema);
+        let parsed = parse_find_string(query).expect("parse failed");
+        let rules = parse_rules(rules).expect("rules to parse");
+        let inputs = QueryInputs::default().with_rules(rules);
+        algebrize_with_inputs(known, parsed, 0, inputs).map(|q| q.cc)
+    }
+
+    const ANCESTOR: &'static str = r#"[[(ancestor ?c ?p) [?c :person/parent ?p]]
+                                        [(ancestor ?c ?p) [?c :person/parent ?x] (ancestor ?x ?p)]]"#;
+
+    #[test]
+    fn test_rule_set_recursion() {
+        let rules = parse_rules(r#"[[(a ?x) [?x :person/name _]]
+                                    [(b ?x) (a ?x)]
+                                    [(c ?x) (d ?x)]
+                                    [(d ?x) (not (c ?x)) [?x :person/name _]]]"#).expect("rules to parse");
+        let rule_set = RuleSet::new(rules).expect("valid rules");
+        assert!(!rule_set.is_recursive(&PlainSymbol::plain("a")));
+        assert!(!rule_set.is_recursive(&PlainSymbol::plain("b")));
+        assert!(rule_set.is_recursive(&PlainSymbol::plain("c")));
+        assert!(rule_set.is_recursive(&PlainSymbol::plain("d")));
+    }
+
+    #[test]
+    fn test_rule_set_validation() {
+        let unused = parse_rules("[[(a ?x ?y) [?x :person/name _]]]").expect("rules to parse");
+        assert_eq!(RuleSet::new(unused).unwrap_err(),
+                   AlgebrizerError::UnusedRuleVariable(PlainSymbol::plain("a"), PlainSymbol::plain("?y")));
+
+        let arity = parse_rules("[[(a ?x) [?x :person/name _]] [(a ?x ?y) [?x :person/name ?y]]]").expect("rules to parse");
+        assert_eq!(RuleSet::new(arity).unwrap_err(),
+                   AlgebrizerError::InconsistentRuleArity(PlainSymbol::plain("a")));
+    }
+
+    #[test]
+    fn test_unknown_rule() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?x :in $ % :where (named ?x ?y)]"#;
+        assert_eq!(alg_with_rules(&schema, query, ANCESTOR).unwrap_err(),
+                   AlgebrizerError::UnknownRule(PlainSymbol::plain("named")));
+
+        // Without `%` the query can't see any rules.
+        let query = r#"[:find ?x ?y :where (ancestor ?x ?y)]"#;
+        assert_eq!(alg_with_rules(&schema, query, ANCESTOR).unwrap_err(),
+                   AlgebrizerError::UnknownRule(PlainSymbol::plain("ancestor")));
+    }
+
+    #[test]
+    fn test_rule_arity() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?x :in $ % :where (ancestor ?x)]"#;
+        assert_eq!(alg_with_rules(&schema, query, ANCESTOR).unwrap_err(),
+                   AlgebrizerError::InvalidNumberOfArguments(PlainSymbol::plain("ancestor"), 1, 2));
+    }
+
+    #[test]
+    fn test_simple_rule_is_inlined() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?name :in $ % :where (parent-name ?x ?name)]"#;
+        let rules = r#"[[(parent-name ?c ?n) [?c :person/parent ?p] [?p :person/name ?n]]]"#;
+        let cc = alg_with_rules(&schema, query, rules).expect("algebrized");
+        assert!(!cc.is_known_empty());
+        assert!(cc.computed_tables.is_empty());
+        assert_eq!(cc.from.len(), 2);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?name")), Some(ValueType::String));
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+    }
+
+    #[test]
+    fn test_alternative_rules_become_union() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?x :in $ % :where (named-or-parent ?x)]"#;
+        let rules = r#"[[(named-or-parent ?e) [?e :person/name _]]
+                        [(named-or-parent ?e) [?e :person/parent _]]]"#;
+        let cc = alg_with_rules(&schema, query, rules).expect("algebrized");
+        assert!(!cc.is_known_empty());
+        match cc.computed_tables.first() {
+            Some(&ComputedTable::Union { ref arms, .. }) => assert_eq!(arms.len(), 2),
+            _ => panic!("expected a union"),
+        }
+    }
+
+    #[test]
+    fn test_recursive_rule_becomes_cte() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?a :in $ % :where [?x :person/name "Alice"] (ancestor ?x ?a)]"#;
+        let cc = alg_with_rules(&schema, query, ANCESTOR).expect("algebrized");
+        assert!(!cc.is_known_empty());
+        match cc.computed_tables.first() {
+            Some(&ComputedTable::RecursiveRule { ref projection, ref base, ref recursive }) => {
+                assert_eq!(projection.len(), 2);
+                assert_eq!(base.len(), 1);
+                assert_eq!(recursive.len(), 1);
+                assert_eq!(recursive[0].from.iter().filter(|s| s.0 == DatomsTable::RecursiveRule).count(), 1);
+            },
+            _ => panic!("expected a recursive rule"),
+        }
+
+        // Every arm makes `?c` a ref, but `?p` comes through the recursion untyped.
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+        assert!(cc.extracted_types.contains_key(&Variable::from_valid_name("?a")));
+    }
+
+    #[test]
+    fn test_recursive_rule_seeds_base_case() {
+        let schema = prepopulated_schema();
+        let seeded = |cc: &ConjoiningClauses| match cc.computed_tables.first() {
+            Some(&ComputedTable::RecursiveRule { ref base, .. }) => {
+                base[0].wheres.0.iter().any(|c| match c {
+                    &ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Equals(_, QueryValue::Entid(99))) => true,
+                    _ => false,
+                })
+            },
+            _ => panic!("expected a recursive rule"),
+        };
+
+        // The recursive arm passes `?p` along unchanged, so the base case can start from 99.
+        let query = r#"[:find ?c :in $ % :where (ancestor ?c 99)]"#;
+        let cc = alg_with_rules(&schema, query, ANCESTOR).expect("algebrized");
+        assert!(seeded(&cc));
+
+        // But it doesn't pass along `?c`, so that's only joined against the whole relation.
+        let query = r#"[:find ?p :in $ % :where (ancestor 99 ?p)]"#;
+        let cc = alg_with_rules(&schema, query, ANCESTOR).expect("algebrized");
+        assert!(!seeded(&cc));
+    }
+
+    #[test]
+    fn test_recursive_rule_without_base_case() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?a :in $ % :where (loop ?a)]"#;
+        let rules = r#"[[(loop ?x) [?x :person/parent ?y] (loop ?y)]]"#;
+        let cc = alg_with_rules(&schema, query, rules).expect("algebrized");
+        assert_eq!(cc.empty_because, Some(EmptyBecause::RuleWithoutBaseCase(PlainSymbol::plain("loop"))));
+    }
+
+    #[test]
+    fn test_unsupported_recursion() {
+        let schema = prepopulated_schema();
+        let query = r#"[:find ?a :in $ % :where (twice ?a ?b)]"#;
+
+        // Non-linear recursion.
+        let rules = r#"[[(twice ?x ?y) [?x :person/parent ?y]]
+                        [(twice ?x ?y) (twice ?x ?z) (twice ?z ?y)]]"#;
+        assert_eq!(alg_with_rules(&schema, query, rules).unwrap_err(),
+                   AlgebrizerError::UnsupportedRecursion(PlainSymbol::plain("twice")));
+
+        // Recursion through `or`.
+        let rules = r#"[[(twice ?x ?y) [?x :person/parent ?y]]
+                        [(twice ?x ?y) [?x :person/parent ?z] (or (twice ?z ?y) [?z :person/name ?y])]]"#;
+        assert_eq!(alg_with_rules(&schema, query, rules).unwrap_err(),
+                   AlgebrizerError::UnsupportedRecursion(PlainSymbol::plain("twice")));
+    }
+}
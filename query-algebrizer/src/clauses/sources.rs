@@ -0,0 +1,159 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use edn::query::{
+    SrcVar,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    PushComputed,
+};
+
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
+    Result,
+};
+
+use types::{
+    ComputedTable,
+    Source,
+    SourceAlias,
+};
+
+use Known;
+
+/// Return the `Known` to use when algebrizing a clause against `source`: the query's own for the
+/// default source, and the named source's schema and view otherwise. Named sources are never
+/// cached.
+pub(crate) fn known_for_source<'s, 'c>(sources: &'s BTreeMap<SrcVar, Source>,
+                                       known: Known<'s, 'c>,
+                                       source: &SrcVar) -> Result<Known<'s, 'c>> {
+    match source {
+        &SrcVar::DefaultSrc => Ok(known),
+        &SrcVar::NamedSrc(ref name) => {
+            match sources.get(source) {
+                Some(s) => Ok(Known::for_view(&s.schema, s.view)),
+                None => bail!(AlgebrizerError::UnknownSource(name.clone())),
+            }
+        },
+    }
+}
+
+impl ConjoiningClauses {
+    pub(crate) fn use_sources(&mut self, sources: BTreeMap<SrcVar, Source>) {
+        self.sources = Rc::new(sources);
+    }
+
+    /// Add a datoms table of `source` to the query. Tables of the default source are used
+    /// directly; those of a named source are computed tables that refer to that source's datoms.
+    pub(crate) fn push_source_table(&mut self, source: &SrcVar, alias: SourceAlias) {
+        match source {
+            &SrcVar::DefaultSrc => self.from.push(alias),
+            &SrcVar::NamedSrc(ref name) => {
+                let SourceAlias(table, alias) = alias;
+                let database = self.sources
+                                   .get(source)
+                                   .map(|s| s.database.clone())
+                                   .expect("named source to have been resolved");
+                let computed = self.computed_tables.push_computed(ComputedTable::Source {
+                    source: name.clone(),
+                    database: database,
+                    table: table,
+                });
+                self.from.push(SourceAlias(computed, alias));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use core_traits::{
+        Attribute,
+        ValueType,
+    };
+
+    use mentat_core::Schema;
+
+    use edn::query::{
+        Keyword,
+    };
+
+    use clauses::{
+        QueryInputs,
+        add_attribute,
+        associate_ident,
+    };
+
+    use types::{
+        DatabaseView,
+        DatomsTable,
+    };
+
+    use {
+        algebrize_with_inputs,
+        parse_find_string,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, Keyword::namespaced("foo", "name"), 65);
+        add_attribute(&mut schema, 65, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        schema
+    }
+
+    #[test]
+    fn test_named_source_tables() {
+        let schema = prepopulated_schema();
+        let known = Known::for_schema(&schema);
+        let query = r#"[:find ?x ?y :in $ $b :where [?x :foo/name ?n] [$b ?y :foo/name ?n]]"#;
+        let source = Source::new("shared", Rc::new(prepopulated_schema())).with_view(DatabaseView::History);
+        let inputs = QueryInputs::default().with_source(SrcVar::NamedSrc("b".to_string()), source);
+        let algebrized = algebrize_with_inputs(known, parse_find_string(query).expect("parsed"), 0, inputs)
+            .expect("algebrized");
+        let cc = algebrized.cc;
+
+        assert_eq!(cc.from, vec![
+            SourceAlias(DatomsTable::Datoms, "datoms00".to_string()),
+            SourceAlias(DatomsTable::Computed(0), "datoms01".to_string()),
+        ]);
+        assert_eq!(cc.computed_tables, vec![
+            ComputedTable::Source {
+                source: "b".to_string(),
+                database: "shared".to_string(),
+                table: DatomsTable::Datoms,
+            },
+        ]);
+        assert!(algebrized.sources.contains_key(&SrcVar::NamedSrc("b".to_string())));
+    }
+
+    #[test]
+    fn test_unknown_source() {
+        let schema = prepopulated_schema();
+        let known = Known::for_schema(&schema);
+
+        // `$b` is named in `:in`, but isn't supplied.
+        let query = r#"[:find ?y :in $b :where [$b ?y :foo/name "x"]]"#;
+        let result = algebrize_with_inputs(known, parse_find_string(query).expect("parsed"), 0, QueryInputs::default());
+        match result.expect_err("expected an unknown source") {
+            AlgebrizerError::UnknownSource(name) => assert_eq!(name, "b"),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -21,6 +21,10 @@ use query_algebrizer_traits::errors::{
     Result,
 };
 
+use types::{
+    ComputedFunction,
+};
+
 use Known;
 
 /// Application of `where` functions.
@@ -28,18 +32,30 @@ impl ConjoiningClauses {
     /// There are several kinds of functions binding variables in our Datalog:
     /// - A set of functions like `ground`, fulltext` and `get-else` that are translated into SQL
     ///   `VALUES`, `MATCH`, or `JOIN`, yielding bindings.
-    /// - In the future, some functions that are implemented via function calls in SQLite.
-    ///
-    /// At present we have implemented only a limited selection of functions.
+    /// - Arithmetic, string, and instant functions like `+`, `upper-case`, and `day-start`, which
+    ///   are translated into SQL expressions over their arguments.
+    /// - Custom functions registered with the store, which are called from SQL in just the same
+    ///   way.
     pub(crate) fn apply_where_fn(&mut self, known: Known, where_fn: WhereFn) -> Result<()> {
         // Because we'll be growing the set of built-in functions, handling each differently, and
         // ultimately allowing user-specified functions, we match on the function name first.
         match where_fn.operator.0.as_str() {
             "fulltext" => self.apply_fulltext(known, where_fn),
+            "get-else" => self.apply_get_else(known, where_fn),
             "ground" => self.apply_ground(known, where_fn),
             "tx-data" => self.apply_tx_data(known, where_fn),
             "tx-ids" => self.apply_tx_ids(known, where_fn),
-            _ => bail!(AlgebrizerError::UnknownFunction(where_fn.operator.clone())),
+            name => {
+                let function = ComputedFunction::from_name(name).or_else(|| {
+                    known.functions
+                         .and_then(|functions| functions.get(name))
+                         .map(|custom| ComputedFunction::custom(name, custom))
+                });
+                match function {
+                    Some(function) => self.apply_computed_fn(known, function, where_fn),
+                    None => bail!(AlgebrizerError::UnknownFunction(where_fn.operator.clone())),
+                }
+            },
         }
     }
 }
@@ -14,9 +14,12 @@ extern crate mentat_core;
 extern crate core_traits;
 extern crate query_algebrizer_traits;
 
-use std::collections::BTreeSet;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 use std::ops::Sub;
-use std::rc::Rc;
+use std::sync::Arc;
 
 mod types;
 mod validate;
@@ -29,7 +32,9 @@ use core_traits::{
 };
 
 use mentat_core::{
+    AggregateRegistry,
     CachedAttributes,
+    FunctionRegistry,
     Schema,
     parse_query,
 };
@@ -37,11 +42,17 @@ use mentat_core::{
 use mentat_core::counter::RcCounter;
 
 use edn::query::{
+    Aggregate,
+    Direction,
     Element,
     FindSpec,
+    FnArg,
     Limit,
+    NullOrdering,
+    Offset,
     Order,
     ParsedQuery,
+    ReturnMap,
     SrcVar,
     Variable,
     WhereClause,
@@ -54,15 +65,22 @@ use query_algebrizer_traits::errors::{
 
 pub use clauses::{
     QueryInputs,
+    QueryInputsKey,
     VariableBindings,
 };
 
 pub use types::{
+    ClauseDecision,
+    DatabaseView,
     EmptyBecause,
     FindQuery,
+    Source,
 };
 
-/// A convenience wrapper around things known in memory: the schema and caches.
+use types::Inequality;
+
+/// A convenience wrapper around things known in memory: the schema, caches, and custom aggregates
+/// and functions.
 /// We use a trait object here to avoid making dozens of functions generic over the type
 /// of the cache. If performance becomes a concern, we should hard-code specific kinds of
 /// cache right here, and/or eliminate the Option.
@@ -70,6 +88,9 @@ pub use types::{
 pub struct Known<'s, 'c> {
     pub schema: &'s Schema,
     pub cache: Option<&'c CachedAttributes>,
+    pub view: DatabaseView,
+    pub aggregates: Option<&'c AggregateRegistry>,
+    pub functions: Option<&'c FunctionRegistry>,
 }
 
 impl<'s, 'c> Known<'s, 'c> {
@@ -77,6 +98,9 @@ impl<'s, 'c> Known<'s, 'c> {
         Known {
             schema: s,
             cache: None,
+            view: DatabaseView::Current,
+            aggregates: None,
+            functions: None,
         }
     }
 
@@ -84,6 +108,36 @@ impl<'s, 'c> Known<'s, 'c> {
         Known {
             schema: s,
             cache: c,
+            view: DatabaseView::Current,
+            aggregates: None,
+            functions: None,
+        }
+    }
+
+    /// Caches only describe the current state of the store, so a historical view never uses one.
+    pub fn for_view(s: &'s Schema, view: DatabaseView) -> Known<'s, 'static> {
+        Known {
+            schema: s,
+            cache: None,
+            view: view,
+            aggregates: None,
+            functions: None,
+        }
+    }
+
+    /// Make the aggregates registered in `aggregates` available to queries.
+    pub fn with_aggregates(self, aggregates: &'c AggregateRegistry) -> Known<'s, 'c> {
+        Known {
+            aggregates: Some(aggregates),
+            ..self
+        }
+    }
+
+    /// Make the predicates and functions registered in `functions` available to queries.
+    pub fn with_functions(self, functions: &'c FunctionRegistry) -> Known<'s, 'c> {
+        Known {
+            functions: Some(functions),
+            ..self
         }
     }
 }
@@ -127,7 +181,7 @@ impl<'s, 'c> Known<'s, 'c> {
 #[derive(Debug)]
 pub struct AlgebraicQuery {
     default_source: SrcVar,
-    pub find_spec: Rc<FindSpec>,
+    pub find_spec: Arc<FindSpec>,
     has_aggregates: bool,
 
     /// The set of variables that the caller wishes to be used for grouping when aggregating.
@@ -144,7 +198,20 @@ pub struct AlgebraicQuery {
     pub named_projection: BTreeSet<Variable>,
     pub order: Option<Vec<OrderBy>>,
     pub limit: Limit,
+    pub offset: Offset,
     pub cc: clauses::ConjoiningClauses,
+
+    /// The database value against which the query runs.
+    pub view: DatabaseView,
+
+    /// The named sources, like `$a`, that the query's clauses refer to.
+    pub sources: BTreeMap<SrcVar, Source>,
+
+    /// The custom aggregates that the query's find spec can use.
+    pub aggregates: AggregateRegistry,
+
+    /// The names given with `:keys`, `:strs`, or `:syms`, if the results' rows are maps.
+    pub return_map: Option<ReturnMap>,
 }
 
 impl AlgebraicQuery {
@@ -198,15 +265,43 @@ pub fn algebrize(known: Known, parsed: FindQuery) -> Result<AlgebraicQuery> {
 /// a vector of `OrderBy` instances, including type comparisons if necessary. This function also
 /// returns a set of variables that should be added to the `with` clause to make the ordering
 /// clauses possible.
-fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>)
+///
+/// Aggregates can be ordered by if they're in the find spec. Missing values only arise from
+/// `get-else`, so a variable bound by it can put them first or last; for others, `:nulls-first`
+/// and `:nulls-last` make no difference. An aggregate query groups its rows in an outer query
+/// that can't see whether they were missing, so putting them first or last there is an
+/// `InvalidOrder` error.
+fn validate_and_simplify_order(cc: &ConjoiningClauses, find_spec: &FindSpec, order: Option<Vec<Order>>)
     -> Result<(Option<Vec<OrderBy>>, BTreeSet<Variable>)> {
     match order {
         None => Ok((None, BTreeSet::default())),
         Some(order) => {
+            let aggregates = find_spec.columns().any(|e| match e {
+                &Element::Aggregate(_) => true,
+                _ => false,
+            });
             let mut order_bys: Vec<OrderBy> = Vec::with_capacity(order.len() * 2);   // Space for tags.
             let mut vars: BTreeSet<Variable> = BTreeSet::default();
 
-            for Order(direction, var) in order.into_iter() {
+            for Order(direction, element, nulls) in order.into_iter() {
+                let var = match element {
+                    Element::Variable(var) => var,
+                    Element::Aggregate(aggregate) => {
+                        let name = order_aggregate_column_name(&aggregate)?;
+                        if !find_spec.columns().any(|e| match e {
+                            &Element::Aggregate(ref a) => *a == aggregate,
+                            _ => false,
+                        }) {
+                            bail!(AlgebrizerError::InvalidOrder(format!("{} is not in :find", name)));
+                        }
+                        order_bys.push(OrderBy(direction, OrderColumn::Aggregate(name)));
+                        continue;
+                    },
+                    element => {
+                        bail!(AlgebrizerError::InvalidOrder(format!("can't order by {}", element)));
+                    },
+                };
+
                 // Eliminate any ordering clauses that are bound to fixed values.
                 if cc.bound_value(&var).is_some() {
                     continue;
@@ -217,11 +312,25 @@ fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>
                     bail!(AlgebrizerError::UnboundVariable(var.name()))
                 }
 
+                // Missing values come first when ordering by `table.v IS NULL` descending.
+                if let Some(nulls) = nulls {
+                    if let Some(table) = get_else_table(cc, &var) {
+                        if aggregates {
+                            bail!(AlgebrizerError::InvalidOrder(format!("can't put missing values of {} first or last in an aggregate query", var)));
+                        }
+                        let missing = match nulls {
+                            NullOrdering::First => Direction::Descending,
+                            NullOrdering::Last => Direction::Ascending,
+                        };
+                        order_bys.push(OrderBy(missing, OrderColumn::Missing(table)));
+                    }
+                }
+
                 // Otherwise, determine if we also need to order by type…
                 if cc.known_type(&var).is_none() {
-                    order_bys.push(OrderBy(direction.clone(), VariableColumn::VariableTypeTag(var.clone())));
+                    order_bys.push(OrderBy(direction.clone(), VariableColumn::VariableTypeTag(var.clone()).into()));
                 }
-                order_bys.push(OrderBy(direction, VariableColumn::Variable(var.clone())));
+                order_bys.push(OrderBy(direction, VariableColumn::Variable(var.clone()).into()));
                 vars.insert(var.clone());
             }
 
@@ -230,6 +339,124 @@ fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>
     }
 }
 
+/// The name of the column that projects `aggregate`, which must, like the simple aggregates
+/// that the projector knows how to project, apply to a single variable.
+fn order_aggregate_column_name(aggregate: &Aggregate) -> Result<String> {
+    match aggregate.args.as_slice() {
+        &[FnArg::Variable(ref var)] => {
+            Ok(aggregate_column_name(aggregate.func.0.name(), None, var))
+        },
+        &[FnArg::EntidOrInteger(n), FnArg::Variable(ref var)] if n >= 0 => {
+            Ok(aggregate_column_name(aggregate.func.0.name(), Some(n as usize), var))
+        },
+        _ => bail!(AlgebrizerError::InvalidOrder(format!("can't order by {}", Element::Aggregate(aggregate.clone())))),
+    }
+}
+
+/// The `LEFT JOIN`ed table whose value `var` is bound to by `get-else`, if it is.
+fn get_else_table(cc: &ConjoiningClauses, var: &Variable) -> Option<TableAlias> {
+    cc.column_bindings
+      .get(var)
+      .and_then(|columns| columns.iter().find(|qa| match qa.1 {
+          Column::ValueOrDefault(_) => true,
+          _ => false,
+      }))
+      .map(|qa| qa.0.clone())
+}
+
+/// Check that a paged query, ordered by `order`, can resume after any of its rows: it must be
+/// ordered only by variables, each with a single known type.
+fn check_keyset_order(cc: &ConjoiningClauses, order: &[Order]) -> Result<()> {
+    if cc.is_known_empty() {
+        return Ok(());
+    }
+    for &Order(_, ref element, ref nulls) in order.iter() {
+        match element {
+            &Element::Variable(ref var) if nulls.is_none() => {
+                if cc.known_type(var).is_none() {
+                    bail!(AlgebrizerError::InvalidKeysetCursor(format!("{} doesn't have a single type", var)));
+                }
+            },
+            _ => bail!(AlgebrizerError::InvalidKeysetCursor(format!("can't resume after ordering by {}", element))),
+        }
+    }
+    Ok(())
+}
+
+/// Restrict the query to the rows that come after `after` in `order`, its keyset order. `after`
+/// holds the values of the ordering variables in the last row of the previous page, in the same
+/// order. For `:order (asc ?a) (desc ?b)` and `after` `[x y]`, those are the rows in which
+/// `?a > x`, or `?a = x` and `?b < y`.
+fn apply_keyset_cursor(cc: &mut ConjoiningClauses, order: &[Order], after: Vec<TypedValue>) -> Result<()> {
+    if order.len() != after.len() {
+        bail!(AlgebrizerError::InvalidKeysetCursor(format!("expected {} values, got {}", order.len(), after.len())));
+    }
+
+    if cc.is_known_empty() {
+        return Ok(());
+    }
+
+    let mut preceding: Vec<(QualifiedAlias, TypedValue)> = Vec::with_capacity(order.len());
+    let mut alternation = ColumnAlternation::default();
+    for (&Order(ref direction, ref element, ref nulls), value) in order.iter().zip(after.into_iter()) {
+        let var = match element {
+            &Element::Variable(ref var) if nulls.is_none() => var,
+            _ => bail!(AlgebrizerError::InvalidKeysetCursor(format!("can't resume after ordering by {}", element))),
+        };
+
+        if cc.known_type(var) != Some(value.value_type()) {
+            bail!(AlgebrizerError::InvalidKeysetCursor(format!("{:?} can't follow {}", value, var)));
+        }
+
+        // A variable with a single value doesn't distinguish between rows.
+        if cc.bound_value(var).is_some() {
+            continue;
+        }
+
+        let column = match cc.column_bindings.get(var).and_then(|columns| columns.first()) {
+            Some(column) => column.clone(),
+            None => bail!(AlgebrizerError::UnboundVariable(var.name())),
+        };
+
+        let operator = match direction {
+            &Direction::Ascending => Inequality::GreaterThan,
+            &Direction::Descending => Inequality::LessThan,
+        };
+
+        let mut intersection: Vec<ColumnConstraint> =
+            preceding.iter()
+                     .map(|&(ref column, ref value)| ColumnConstraint::Equals(column.clone(), QueryValue::TypedValue(value.clone())))
+                     .collect();
+        intersection.push(ColumnConstraint::Inequality {
+            operator: operator,
+            left: QueryValue::Column(column.clone()),
+            right: QueryValue::TypedValue(value.clone()),
+        });
+        alternation.add_alternate(intersection.into());
+        preceding.push((column, value));
+    }
+
+    cc.wheres.add(ColumnConstraintOrAlternation::Alternation(alternation));
+    Ok(())
+}
+
+/// User-specified limits should always be natural numbers (> 0).
+fn limit_from_value(value: &TypedValue) -> Result<u64> {
+    match value {
+        &TypedValue::Long(n) if n > 0 => Ok(n as u64),
+        &TypedValue::Long(n) => bail!(AlgebrizerError::InvalidLimit(n.to_string(), ValueType::Long)),
+        val => bail!(AlgebrizerError::InvalidLimit(format!("{:?}", val), val.value_type())),
+    }
+}
+
+/// Offsets can be zero, but not negative.
+fn offset_from_value(value: &TypedValue) -> Result<u64> {
+    match value {
+        &TypedValue::Long(n) if n >= 0 => Ok(n as u64),
+        &TypedValue::Long(n) => bail!(AlgebrizerError::InvalidOffset(n.to_string(), ValueType::Long)),
+        val => bail!(AlgebrizerError::InvalidOffset(format!("{:?}", val), val.value_type())),
+    }
+}
 
 fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     // Unpack any limit variables in place.
@@ -237,18 +464,7 @@ fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
         match query.limit {
             Limit::Variable(ref v) => {
                 match query.cc.bound_value(v) {
-                    Some(TypedValue::Long(n)) => {
-                        if n <= 0 {
-                            // User-specified limits should always be natural numbers (> 0).
-                            bail!(AlgebrizerError::InvalidLimit(n.to_string(), ValueType::Long))
-                        } else {
-                            Some(Limit::Fixed(n as u64))
-                        }
-                    },
-                    Some(val) => {
-                        // Same.
-                        bail!(AlgebrizerError::InvalidLimit(format!("{:?}", val), val.value_type()))
-                    },
+                    Some(val) => Some(Limit::Fixed(limit_from_value(&val)?)),
                     None => {
                         // We know that the limit variable is mentioned in `:in`.
                         // That it's not bound here implies that we haven't got all the variables
@@ -269,12 +485,73 @@ fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     Ok(query)
 }
 
+fn simplify_offset(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
+    // As with limits, unpack any offset variable that's bound now.
+    let refined_offset =
+        match query.offset {
+            Offset::Variable(ref v) => {
+                match query.cc.bound_value(v) {
+                    Some(val) => Some(Offset::Fixed(offset_from_value(&val)?)),
+                    None => None,
+                }
+            },
+            Offset::None => None,
+            Offset::Fixed(_) => None,
+        };
+
+    if let Some(offset) = refined_offset {
+        query.offset = offset;
+    }
+    Ok(query)
+}
+
 pub fn algebrize_with_inputs(known: Known,
                              parsed: FindQuery,
                              counter: usize,
                              inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    algebrize_recording(known, parsed, counter, inputs, false)
+}
+
+/// Like `algebrize_with_inputs`, but record the decisions made about the query's clauses, for
+/// `EXPLAIN`. See `ConjoiningClauses::decisions`.
+pub fn algebrize_explained(known: Known,
+                           parsed: FindQuery,
+                           inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    algebrize_recording(known, parsed, 0, inputs, true)
+}
+
+fn algebrize_recording(known: Known,
+                       parsed: FindQuery,
+                       counter: usize,
+                       mut inputs: QueryInputs,
+                       record_decisions: bool) -> Result<AlgebraicQuery> {
     let alias_counter = RcCounter::with_initial(counter);
+    let rules = ::std::mem::replace(&mut inputs.rules, vec![]);
+    let mut sources = ::std::mem::replace(&mut inputs.sources, BTreeMap::new());
+    let collections = ::std::mem::replace(&mut inputs.collections, BTreeMap::new());
+    let relations = ::std::mem::replace(&mut inputs.relations, BTreeMap::new());
+    let paged = inputs.paged;
+    let after = inputs.after.take();
+    let resumed = after.is_some();
+    let limit = inputs.limit.take();
     let mut cc = ConjoiningClauses::with_inputs_and_alias_counter(parsed.in_vars, inputs, alias_counter);
+    if record_decisions {
+        cc.record_decisions();
+    }
+
+    // Rules are only visible to a query that asks for them with `%`.
+    if parsed.in_rules {
+        cc.use_rules(rules)?;
+    }
+
+    // Likewise, named sources must be named in `:in`. The default source is always the store
+    // we're querying.
+    let in_sources = &parsed.in_sources;
+    sources.retain(|var, _| *var != SrcVar::DefaultSrc && in_sources.contains(var));
+    cc.use_sources(sources.clone());
+
+    // Collections and relations are bound before the query's clauses, just like `ground`.
+    cc.apply_input_bindings(known, parsed.in_bindings, collections, relations)?;
 
     // This is so the rest of the query knows that `?x` is a ref if `(pull ?x …)` appears in `:find`.
     cc.derive_types_from_find_spec(&parsed.find_spec);
@@ -284,6 +561,11 @@ pub fn algebrize_with_inputs(known: Known,
         cc.constrain_var_to_long(var.clone());
     }
 
+    // Same for a variable offset.
+    if let &Offset::Variable(ref var) = &parsed.offset {
+        cc.constrain_var_to_long(var.clone());
+    }
+
     // TODO: integrate default source into pattern processing.
     // TODO: flesh out the rest of find-into-context.
     cc.apply_clauses(known, parsed.where_clauses)?;
@@ -292,23 +574,45 @@ pub fn algebrize_with_inputs(known: Known,
     cc.prune_extracted_types();
     cc.process_required_types()?;
 
-    let (order, extra_vars) = validate_and_simplify_order(&cc, parsed.order)?;
+    // A page is ordered so that no two rows tie, and the next page starts after its last row.
+    let order = if paged {
+        let keyset = parsed.find_spec.keyset_order(parsed.order.as_ref());
+        check_keyset_order(&cc, &keyset)?;
+        if let Some(after) = after {
+            apply_keyset_cursor(&mut cc, &keyset, after)?;
+        }
+        Some(keyset)
+    } else {
+        parsed.order
+    };
+
+    let (order, extra_vars) = validate_and_simplify_order(&cc, &parsed.find_spec, order)?;
 
     // This might leave us with an unused `:in` variable.
-    let limit = if parsed.find_spec.is_unit_limited() { Limit::Fixed(1) } else { parsed.limit };
+    let limit = if parsed.find_spec.is_unit_limited() {
+        Limit::Fixed(1)
+    } else {
+        limit.map_or(parsed.limit, Limit::Fixed)
+    };
     let q = AlgebraicQuery {
         default_source: parsed.default_source,
-        find_spec: Rc::new(parsed.find_spec),
+        find_spec: Arc::new(parsed.find_spec),
         has_aggregates: false,           // TODO: we don't parse them yet.
         with: parsed.with,
         named_projection: extra_vars,
         order: order,
         limit: limit,
+        // Only the first page is offset: the others start after the last row of the one before.
+        offset: if resumed { Offset::None } else { parsed.offset },
         cc: cc,
+        view: known.view,
+        sources: sources,
+        aggregates: known.aggregates.cloned().unwrap_or_default(),
+        return_map: parsed.return_map,
     };
 
     // Substitute in any fixed values and fail if they're out of range.
-    simplify_limit(q)
+    simplify_limit(q).and_then(simplify_offset)
 }
 
 pub use clauses::{
@@ -322,16 +626,20 @@ pub use types::{
     ColumnConstraintOrAlternation,
     ColumnIntersection,
     ColumnName,
+    ComputedFunction,
     ComputedTable,
+    ComputedValue,
     DatomsColumn,
     DatomsTable,
     FulltextColumn,
     OrderBy,
+    OrderColumn,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
     TableAlias,
     VariableColumn,
+    aggregate_column_name,
 };
 
 
@@ -342,10 +650,14 @@ impl FindQuery {
             default_source: SrcVar::DefaultSrc,
             with: BTreeSet::default(),
             in_vars: BTreeSet::default(),
+            in_bindings: vec![],
             in_sources: BTreeSet::default(),
+            in_rules: false,
             limit: Limit::None,
+            offset: Offset::None,
             where_clauses: where_clauses,
             order: None,
+            return_map: None,
         }
     }
 
@@ -362,6 +674,16 @@ impl FindQuery {
             set
         };
 
+        // Collection and relation bindings can't reuse a variable from elsewhere in `:in`.
+        {
+            let mut seen = in_vars.clone();
+            for var in parsed.in_bindings.iter().flat_map(|b| b.variables()).filter_map(|v| v) {
+                if !seen.insert(var.clone()) {
+                    bail!(AlgebrizerError::DuplicateVariableError(var.name(), ":in"));
+                }
+            }
+        }
+
         let with = {
             let mut set: BTreeSet<Variable> = BTreeSet::default();
 
@@ -381,17 +703,45 @@ impl FindQuery {
             }
         }
 
+        // And the same for `:offset ?x`.
+        if let Offset::Variable(ref v) = parsed.offset {
+            if !in_vars.contains(v) {
+                bail!(AlgebrizerError::UnknownOffsetVar(v.name()));
+            }
+        }
+
         Ok(FindQuery {
             find_spec: parsed.find_spec,
             default_source: parsed.default_source,
             with,
             in_vars,
+            in_bindings: parsed.in_bindings,
             in_sources: parsed.in_sources,
+            in_rules: parsed.in_rules,
             limit: parsed.limit,
+            offset: parsed.offset,
             where_clauses: parsed.where_clauses,
             order: parsed.order,
+            return_map: parsed.return_map,
         })
     }
+
+    /// Check the values of this query's limit and offset variables, if it has them. A plan made
+    /// without their values binds them as SQL parameters, so they must be checked when it's run,
+    /// just as `algebrize_with_inputs` checks them when they're known.
+    pub fn check_limit_and_offset(&self, values: &BTreeMap<Variable, TypedValue>) -> Result<()> {
+        if let Limit::Variable(ref var) = self.limit {
+            if let Some(value) = values.get(var) {
+                limit_from_value(value)?;
+            }
+        }
+        if let Offset::Variable(ref var) = self.offset {
+            if let Some(value) = values.get(var) {
+                offset_from_value(value)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn parse_find_string(string: &str) -> Result<FindQuery> {
@@ -9,6 +9,7 @@
 // specific language governing permissions and limitations under the License.
 
 use std::collections::BTreeSet;
+use std::rc::Rc;
 use std::fmt::{
     Debug,
     Formatter,
@@ -22,16 +23,25 @@ use core_traits::{
 };
 
 use mentat_core::{
+    CustomFunction,
+    FunctionRegistry,
+    HasSchema,
+    Schema,
     ValueRc,
 };
 
 use edn::query::{
+    Binding,
     Direction,
     FindSpec,
     Keyword,
     Limit,
+    Offset,
     Order,
+    PlainSymbol,
+    ReturnMap,
     SrcVar,
+    SrcVarName,
     Variable,
     WhereClause,
 };
@@ -46,6 +56,7 @@ pub enum DatomsTable {
     AllDatoms,          // Fulltext and non-fulltext datoms.
     Computed(usize),    // A computed table, tracked elsewhere in the query.
     Transactions,       // The transactions table, which makes the tx-data log API efficient.
+    RecursiveRule,      // The recursive CTE of the rule currently being defined.
 }
 
 /// A source of rows that isn't a named table -- typically a subquery or union.
@@ -61,6 +72,29 @@ pub enum ComputedTable {
         names: Vec<Variable>,
         values: Vec<TypedValue>,
     },
+    /// A recursive rule, evaluated as a `WITH RECURSIVE` CTE. The `base` arms don't refer to the
+    /// rule itself; each of the `recursive` arms joins against it exactly once, via
+    /// `DatomsTable::RecursiveRule`. Every arm projects each variable and its type tag.
+    RecursiveRule {
+        projection: BTreeSet<Variable>,
+        base: Vec<::clauses::ConjoiningClauses>,
+        recursive: Vec<::clauses::ConjoiningClauses>,
+    },
+    /// A table that is `LEFT JOIN`ed on `constraints`, so that rows of the tables to its left
+    /// survive even when nothing matches. Used by `get-else`. The table belongs to the named
+    /// `source`, if there is one.
+    LeftJoin {
+        source: Option<SrcVarName>,
+        table: DatomsTable,
+        constraints: ColumnIntersection,
+    },
+    /// One of the datoms tables of the source bound to the named source variable, which lives in
+    /// the SQLite database called `database`.
+    Source {
+        source: SrcVarName,
+        database: String,
+        table: DatomsTable,
+    },
 }
 
 impl DatomsTable {
@@ -72,6 +106,59 @@ impl DatomsTable {
             DatomsTable::AllDatoms => "all_datoms",
             DatomsTable::Computed(_) => "c",
             DatomsTable::Transactions => "transactions",
+            DatomsTable::RecursiveRule => "rule",
+        }
+    }
+}
+
+/// The database value a query runs against. Anything other than `Current` is derived from the
+/// transaction log, and is exposed to the query under the usual datoms table names.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DatabaseView {
+    /// The datoms currently in the store.
+    Current,
+
+    /// The datoms that were in the store immediately after the given transaction.
+    AsOf(Entid),
+
+    /// The datoms currently in the store that were asserted after the given transaction.
+    Since(Entid),
+
+    /// Every datom ever asserted or retracted.
+    History,
+}
+
+impl Default for DatabaseView {
+    fn default() -> DatabaseView {
+        DatabaseView::Current
+    }
+}
+
+/// A store that a query can name with a source variable like `$a`: the default store, or another
+/// Mentat store attached to the same SQLite connection, seen through a view.
+/// Its own schema is used to interpret the parts of the query that refer to it.
+#[derive(Clone, Debug)]
+pub struct Source {
+    /// The name of the SQLite database holding the store: `main`, or the name it was attached as.
+    pub database: String,
+    pub schema: Rc<Schema>,
+    pub view: DatabaseView,
+}
+
+impl Source {
+    pub fn new<S>(database: S, schema: Rc<Schema>) -> Source where S: Into<String> {
+        Source {
+            database: database.into(),
+            schema: schema,
+            view: DatabaseView::Current,
+        }
+    }
+
+    /// Query `view` of this store instead of its current state.
+    pub fn with_view(self, view: DatabaseView) -> Source {
+        Source {
+            view: view,
+            ..self
         }
     }
 }
@@ -95,6 +182,9 @@ pub enum DatomsColumn {
 pub enum FulltextColumn {
     Rowid,
     Text,
+
+    /// The hidden column named after the table, which FTS functions like `matchinfo` take.
+    Table,
 }
 
 /// One of the named columns of our transactions table.
@@ -120,6 +210,12 @@ pub enum Column {
     Fulltext(FulltextColumn),
     Variable(VariableColumn),
     Transactions(TransactionsColumn),
+
+    /// The `v` column of a `LEFT JOIN`ed table, or the given value if the join found no row.
+    ValueOrDefault(TypedValue),
+
+    /// A value computed from other columns and constants by a where-fn like `+` or `upper-case`.
+    Computed(Box<ComputedValue>),
 }
 
 impl From<DatomsColumn> for Column {
@@ -201,6 +297,8 @@ impl Debug for Column {
             &Column::Fulltext(ref c) => c.fmt(f),
             &Column::Variable(ref v) => v.fmt(f),
             &Column::Transactions(ref t) => t.fmt(f),
+            &Column::ValueOrDefault(ref v) => write!(f, "v or {:?}", v),
+            &Column::Computed(ref c) => c.fmt(f),
         }
     }
 }
@@ -211,6 +309,7 @@ impl FulltextColumn {
         match *self {
             Rowid => "rowid",
             Text => "text",
+            Table => "fulltext_values",
         }
     }
 }
@@ -295,6 +394,8 @@ impl QualifiedAlias {
             Column::Fulltext(_) => None,
             Column::Variable(_) => None,
             Column::Transactions(ref c) => c.associated_type_tag_column().map(Column::Transactions),
+            Column::ValueOrDefault(_) => None,
+            Column::Computed(_) => None,
         }.map(|d| QualifiedAlias(self.0.clone(), d))
     }
 }
@@ -310,6 +411,10 @@ pub enum QueryValue {
     // cannot be a boolean, so `datoms00.value_type_tag` must be in the set `#{0, 4, 5}`.
     // Note that `5 = 5.0` in SQLite, and we preserve that here.
     PrimitiveLong(i64),
+
+    // An input whose value isn't known until the query is run, like `?name` in a prepared query
+    // that was given only the type of `?name`. It's bound as a SQL parameter.
+    Parameter(Variable),
 }
 
 impl Debug for QueryValue {
@@ -328,20 +433,135 @@ impl Debug for QueryValue {
             &PrimitiveLong(value) => {
                 write!(f, "primitive({:?})", value)
             },
+            &Parameter(ref var) => {
+                write!(f, "parameter({:?})", var)
+            },
+
+        }
+    }
+}
+
+/// The functions that can compute a value in a where-fn binding, like `[(+ ?a ?b) ?c]`.
+/// Each is translated into a SQL expression over its arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ComputedFunction {
+    // Arithmetic.
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Quot,
+    Mod,
+
+    // Strings.
+    Str,
+    Subs,
+    UpperCase,
+    LowerCase,
+    StartsWith,
+    Includes,
+    ReFind,
+
+    // Instants.
+    DayStart,
+    MinusMillis,
+
+    // Fulltext search results. These aren't where-fns: `fulltext` binds them. Their first
+    // argument is the table column of the searched fulltext values table.
+    FulltextScore,
+    FulltextSnippet,
+    FulltextHighlight,
+
+    /// A `CustomFunction`, which is called by its SQL name.
+    Custom {
+        sql_name: String,
+        argument_types: Vec<ValueType>,
+        return_type: ValueType,
+    },
+}
+
+impl ComputedFunction {
+    pub fn from_name(name: &str) -> Option<ComputedFunction> {
+        use self::ComputedFunction::*;
+        match name {
+            "+" => Some(Add),
+            "-" => Some(Subtract),
+            "*" => Some(Multiply),
+            "/" => Some(Divide),
+            "quot" => Some(Quot),
+            "mod" => Some(Mod),
+            "str" => Some(Str),
+            "subs" => Some(Subs),
+            "upper-case" => Some(UpperCase),
+            "lower-case" => Some(LowerCase),
+            "str/starts-with?" => Some(StartsWith),
+            "str/includes?" => Some(Includes),
+            "re-find" => Some(ReFind),
+            "day-start" => Some(DayStart),
+            "-millis" => Some(MinusMillis),
+            _ => None,
+        }
+    }
+
+    pub fn custom(name: &str, function: &CustomFunction) -> ComputedFunction {
+        ComputedFunction::Custom {
+            sql_name: FunctionRegistry::sql_name(name),
+            argument_types: function.argument_types().to_vec(),
+            return_type: function.return_type(),
+        }
+    }
 
+    /// Whether the SQL expression can be `NULL` for some inputs: division by zero, a regular
+    /// expression that doesn't match, or a custom function without a result. Such a binding fails
+    /// rather than binding `NULL`.
+    pub fn is_nullable(&self) -> bool {
+        use self::ComputedFunction::*;
+        match *self {
+            Divide | Quot | Mod | ReFind | Custom { .. } => true,
+            _ => false,
         }
     }
 }
 
-/// Represents an entry in the ORDER BY list: a variable or a variable's type tag.
-/// (We require order vars to be projected, so we can simply use a variable here.)
+/// A function applied to its arguments, together with the type of the value it produces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ComputedValue {
+    pub function: ComputedFunction,
+    pub args: Vec<QueryValue>,
+    pub value_type: ValueType,
+}
+
+/// Represents an entry in the ORDER BY list.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct OrderBy(pub Direction, pub VariableColumn);
+pub struct OrderBy(pub Direction, pub OrderColumn);
+
+/// What a query can be ordered by.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OrderColumn {
+    /// A variable or a variable's type tag.
+    /// (We require order vars to be projected, so we can simply use a variable here.)
+    Variable(VariableColumn),
 
-impl From<Order> for OrderBy {
-    fn from(item: Order) -> OrderBy {
-        let Order(direction, variable) = item;
-        OrderBy(direction, VariableColumn::Variable(variable))
+    /// An aggregate in the find spec, by the name of the column that projects it.
+    Aggregate(String),
+
+    /// Whether the `LEFT JOIN` behind a `get-else` binding found no value: `0` if it did, and `1`
+    /// if the binding has its default.
+    Missing(TableAlias),
+}
+
+impl From<VariableColumn> for OrderColumn {
+    fn from(column: VariableColumn) -> OrderColumn {
+        OrderColumn::Variable(column)
+    }
+}
+
+/// The name of the column that projects an aggregate of `var`, which might take a count first,
+/// like `(max 3 ?x)`.
+pub fn aggregate_column_name(function: &str, n: Option<usize>, var: &Variable) -> String {
+    match n {
+        Some(n) => format!("({} {} {})", function, n, var.name()),
+        None => format!("({} {})", function, var.name()),
     }
 }
 
@@ -458,6 +678,7 @@ pub enum ColumnConstraint {
     },
     NotExists(ComputedTable),
     Matches(QualifiedAlias, QueryValue),
+    NotNull(QualifiedAlias),
 }
 
 impl ColumnConstraint {
@@ -600,6 +821,9 @@ impl Debug for ColumnConstraint {
             &NotExists(ref ct) => {
                 write!(f, "NOT EXISTS {:?}", ct)
             },
+            &NotNull(ref qa) => {
+                write!(f, "{:?} IS NOT NULL", qa)
+            },
         }
     }
 }
@@ -607,6 +831,7 @@ impl Debug for ColumnConstraint {
 #[derive(PartialEq, Clone)]
 pub enum EmptyBecause {
     CachedAttributeHasNoValues { entity: Entid, attr: Entid },
+    CachedAttributeHasValues { entity: Entid, attr: Entid },
     CachedAttributeHasNoEntity { value: TypedValue, attr: Entid },
     ConflictingBindings { var: Variable, existing: TypedValue, desired: TypedValue },
 
@@ -622,11 +847,14 @@ pub enum EmptyBecause {
     NonEntityArgument,
     NonStringFulltextValue,
     NonFulltextAttribute(Entid),
+    NoFulltextAttributes,
     UnresolvedIdent(Keyword),
     InvalidAttributeIdent(Keyword),
     InvalidAttributeEntid(Entid),
     InvalidBinding(Column, TypedValue),
     ValueTypeMismatch(ValueType, TypedValue),
+    RuleWithoutBaseCase(PlainSymbol),
+    NoInputValues(Variable),
     AttributeLookupFailed,         // Catch-all, because the table lookup code is lazy. TODO
 }
 
@@ -640,6 +868,9 @@ impl Debug for EmptyBecause {
             &CachedAttributeHasNoValues { ref entity, ref attr } => {
                 write!(f, "({}, {}, ?v, _) not present in store", entity, attr)
             },
+            &CachedAttributeHasValues { ref entity, ref attr } => {
+                write!(f, "({}, {}, _, _) present in store", entity, attr)
+            },
             &ConflictingBindings { ref var, ref existing, ref desired } => {
                 write!(f, "Var {:?} can't be {:?} because it's already bound to {:?}",
                        var, desired, existing)
@@ -682,6 +913,9 @@ impl Debug for EmptyBecause {
             &NonFulltextAttribute(entid) => {
                 write!(f, "{} is not a fulltext attribute", entid)
             },
+            &NoFulltextAttributes => {
+                write!(f, "No fulltext attributes to search")
+            },
             &InvalidBinding(ref column, ref tv) => {
                 write!(f, "{:?} cannot name column {:?}", tv, column)
             },
@@ -689,6 +923,12 @@ impl Debug for EmptyBecause {
                 write!(f, "Type mismatch: {:?} doesn't match attribute type {:?}",
                        typed_value, value_type)
             },
+            &RuleWithoutBaseCase(ref name) => {
+                write!(f, "Recursive rule {} has no non-recursive definition that can match", name)
+            },
+            &NoInputValues(ref var) => {
+                write!(f, "No values supplied for input {:?}", var)
+            },
             &AttributeLookupFailed => {
                 write!(f, "Attribute lookup failed")
             },
@@ -702,16 +942,20 @@ impl Debug for EmptyBecause {
 /// We split `FindQuery` from `ParsedQuery` because it's not easy to generalize over containers
 /// (here, `Vec` and `BTreeSet`) in Rust.
 #[allow(dead_code)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FindQuery {
     pub find_spec: FindSpec,
     pub default_source: SrcVar,
     pub with: BTreeSet<Variable>,
     pub in_vars: BTreeSet<Variable>,
+    pub in_bindings: Vec<Binding>,
     pub in_sources: BTreeSet<SrcVar>,
+    pub in_rules: bool,
     pub limit: Limit,
+    pub offset: Offset,
     pub where_clauses: Vec<WhereClause>,
     pub order: Option<Vec<Order>>,
+    pub return_map: Option<ReturnMap>,
 }
 
 // Intermediate data structures for resolving patterns.
@@ -755,3 +999,72 @@ pub struct EvolvedPattern {
     pub value: EvolvedValuePlace,
     pub tx: EvolvedNonValuePlace,
 }
+
+impl EvolvedNonValuePlace {
+    fn describe(&self, schema: &Schema) -> String {
+        match self {
+            &EvolvedNonValuePlace::Placeholder => "_".to_string(),
+            &EvolvedNonValuePlace::Variable(ref var) => var.to_string(),
+            &EvolvedNonValuePlace::Entid(e) => describe_entid(schema, e),
+        }
+    }
+}
+
+impl EvolvedValuePlace {
+    fn describe(&self, schema: &Schema) -> String {
+        match self {
+            &EvolvedValuePlace::Placeholder => "_".to_string(),
+            &EvolvedValuePlace::Variable(ref var) => var.to_string(),
+            &EvolvedValuePlace::Entid(e) => describe_entid(schema, e),
+            &EvolvedValuePlace::Value(TypedValue::String(ref s)) => format!("{:?}", s),
+            &EvolvedValuePlace::Value(ref v) => v.to_string(),
+            &EvolvedValuePlace::EntidOrInteger(i) => i.to_string(),
+            &EvolvedValuePlace::IdentOrKeyword(ref kw) => kw.to_string(),
+        }
+    }
+}
+
+fn describe_entid(schema: &Schema, entid: Entid) -> String {
+    schema.get_ident(entid).map(|ident| ident.to_string()).unwrap_or_else(|| entid.to_string())
+}
+
+impl EvolvedPattern {
+    /// Render this pattern as Datalog, naming entities by their idents where they have them.
+    pub fn describe(&self, schema: &Schema) -> String {
+        let source = match self.source {
+            SrcVar::DefaultSrc => "".to_string(),
+            SrcVar::NamedSrc(ref name) => format!("${} ", name),
+        };
+        let tx = match self.tx {
+            EvolvedNonValuePlace::Placeholder => "".to_string(),
+            ref tx => format!(" {}", tx.describe(schema)),
+        };
+        format!("[{}{} {} {}{}]",
+                source,
+                self.entity.describe(schema),
+                self.attribute.describe(schema),
+                self.value.describe(schema),
+                tx)
+    }
+}
+
+/// A decision the algebrizer made while algebrizing a query, kept so that `EXPLAIN` can report
+/// why the query is translated as it is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClauseDecision {
+    /// A pattern is matched against this table, under this alias.
+    Table { pattern: String, alias: SourceAlias },
+
+    /// A pattern was answered from the attribute cache, without consulting the store.
+    Cached { pattern: String },
+
+    /// Some clauses -- the whole query, or one arm of an `or` -- can't match anything.
+    Empty(EmptyBecause),
+
+    /// An `or`, or the definitions of a rule: the decisions made within each of its arms, in
+    /// order, including those of arms that can't match.
+    Or(Vec<Vec<ClauseDecision>>),
+
+    /// A `not`: the decisions made within it.
+    Not(Vec<ClauseDecision>),
+}
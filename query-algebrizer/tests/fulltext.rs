@@ -29,10 +29,15 @@ use edn::query::{
     Keyword,
 };
 
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
+};
+
 use utils::{
     add_attribute,
     alg,
     associate_ident,
+    bails,
 };
 
 use mentat_query_algebrizer::Known;
@@ -90,3 +95,38 @@ fn test_apply_fulltext() {
                     [?score :foo/bar _]]"#;
     assert!(alg(known, query).is_known_empty());
 }
+
+#[test]
+fn test_fulltext_attributes() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    // A set of attributes can't match if none of them is a fulltext attribute…
+    let query = r#"[:find ?val
+                    :where [(fulltext $ #{:foo/name :foo/parent} "hello") [[?entity ?val]]]]"#;
+    assert!(alg(known, query).is_known_empty());
+
+    // … but it can if one is.
+    let query = r#"[:find ?val
+                    :where [(fulltext $ #{:foo/name :foo/description} "hello") [[?entity ?val]]]]"#;
+    assert!(!alg(known, query).is_known_empty());
+
+    // The matched attribute is a ref.
+    let query = r#"[:find ?val
+                    :where [(fulltext $ _ "hello") [[?entity ?val _ _ ?attr]]]
+                           [?entity :foo/age ?attr]]"#;
+    assert!(alg(known, query).is_known_empty());
+
+    // There's nothing to search without fulltext attributes.
+    let empty = Schema::default();
+    let query = r#"[:find ?val :where [(fulltext $ _ "hello") [[?entity ?val]]]]"#;
+    assert!(alg(Known::for_schema(&empty), query).is_known_empty());
+
+    // Unknown attributes are an error.
+    let query = r#"[:find ?val
+                    :where [(fulltext $ #{:foo/description :foo/nope} "hello") [[?entity ?val]]]]"#;
+    match bails(known, query) {
+        AlgebrizerError::InvalidArgument(_, "attribute", 1) => {},
+        e => panic!("unexpected error {:?}", e),
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate edn;
+extern crate mentat_core;
+extern crate core_traits;
+extern crate mentat_query_algebrizer;
+extern crate query_algebrizer_traits;
+
+mod utils;
+
+use core_traits::{
+    ValueType,
+};
+
+use mentat_core::{
+    Schema,
+};
+
+use edn::parse::{
+    parse_rules,
+};
+
+use mentat_query_algebrizer::{
+    ComputedTable,
+    Known,
+    QueryInputs,
+};
+
+use utils::{
+    SchemaBuilder,
+    alg_with_inputs,
+};
+
+fn prepopulated_schema() -> Schema {
+    SchemaBuilder::new()
+        .define_simple_attr("foo", "name", ValueType::String, false)
+        .define_simple_attr("foo", "parent", ValueType::Ref, false)
+        .schema
+}
+
+const SPECIAL: &'static str = r#"[[(special ?e) [?e :foo/name "Alice"]]
+                                  [(special ?e) [?e :foo/parent ?p] [?p :foo/name "Alice"]]]"#;
+
+fn inputs() -> QueryInputs {
+    QueryInputs::default().with_rules(parse_rules(SPECIAL).expect("rules to parse"))
+}
+
+#[test]
+fn test_rule_with_constant_arguments() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    // Nothing is unified with the rest of the query: the rule only has to match.
+    let query = r#"[:find ?x :in $ % :where [?x :foo/name _] (special 65540)]"#;
+    let cc = alg_with_inputs(known, query, inputs());
+    assert!(!cc.is_known_empty());
+    match cc.computed_tables.first() {
+        Some(&ComputedTable::Union { ref projection, ref arms, .. }) => {
+            assert!(projection.is_empty());
+            assert_eq!(arms.len(), 2);
+        },
+        _ => panic!("expected a union"),
+    }
+
+    // Just as it does when it's called with a variable.
+    let query = r#"[:find ?x :in $ % :where [?x :foo/name _] (special ?x)]"#;
+    let cc = alg_with_inputs(known, query, inputs());
+    match cc.computed_tables.first() {
+        Some(&ComputedTable::Union { ref projection, .. }) => assert_eq!(projection.len(), 1),
+        _ => panic!("expected a union"),
+    }
+}
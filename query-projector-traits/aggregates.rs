@@ -8,23 +8,42 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use core_traits::{ValueType, ValueTypeSet};
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
 
-use edn::query::{Aggregate, QueryFunction, Variable};
+use core_traits::{TypedValue, ValueType, ValueTypeSet};
 
-use mentat_query_algebrizer::{ColumnName, ConjoiningClauses, VariableColumn};
+use mentat_core::{AggregateRegistry, SQLValueType, SQLValueTypeSet};
+
+use edn::query::{Aggregate, FnArg, PlainSymbol, QueryFunction, Variable};
+
+use mentat_query_algebrizer::{ColumnName, ConjoiningClauses, VariableColumn, aggregate_column_name};
 
 use mentat_query_sql::{ColumnOrExpression, Expression, Name, ProjectedColumn};
 
 use crate::errors::{ProjectorError, Result};
 
+/// An aggregation over the values of a single variable.
+///
+/// Most of these are computed by SQL aggregate functions, some of which Mentat registers on each
+/// connection. Those that produce collections -- `Distinct`, `MaxN`, `MinN`, and `Sample` -- are
+/// gathered in SQL and finished in Rust by `finish_collection`.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SimpleAggregationOp {
     Avg,
     Count,
+    CountDistinct,
+    Distinct,
     Max,
+    MaxN(usize),
+    Median,
     Min,
+    MinN(usize),
+    Sample(usize),
+    Stddev,
     Sum,
+    Variance,
 }
 
 impl SimpleAggregationOp {
@@ -32,24 +51,106 @@ impl SimpleAggregationOp {
         use self::SimpleAggregationOp::*;
         match self {
             &Avg => "avg",
-            &Count => "count",
+            &Count | &CountDistinct => "count",
             &Max => "max",
+            &Median => "mentat_median",
             &Min => "min",
+            &Stddev => "mentat_stddev",
+            &Sum => "sum",
+            &Variance => "mentat_variance",
+            &Distinct | &MaxN(_) | &MinN(_) | &Sample(_) => "mentat_collect",
+        }
+    }
+
+    /// The name of this aggregate in a query.
+    pub fn name(&self) -> &'static str {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Avg => "avg",
+            &Count => "count",
+            &CountDistinct => "count-distinct",
+            &Distinct => "distinct",
+            &Max | &MaxN(_) => "max",
+            &Median => "median",
+            &Min | &MinN(_) => "min",
+            &Sample(_) => "sample",
+            &Stddev => "stddev",
             &Sum => "sum",
+            &Variance => "variance",
         }
     }
 
-    fn for_function(function: &QueryFunction) -> Option<SimpleAggregationOp> {
-        match function.0.name() {
-            "avg" => Some(SimpleAggregationOp::Avg),
-            "count" => Some(SimpleAggregationOp::Count),
-            "max" => Some(SimpleAggregationOp::Max),
-            "min" => Some(SimpleAggregationOp::Min),
-            "sum" => Some(SimpleAggregationOp::Sum),
+    fn for_function(function: &QueryFunction, n: Option<usize>) -> Option<SimpleAggregationOp> {
+        use self::SimpleAggregationOp::*;
+        match (function.0.name(), n) {
+            ("avg", None) => Some(Avg),
+            ("count", None) => Some(Count),
+            ("count-distinct", None) => Some(CountDistinct),
+            ("distinct", None) => Some(Distinct),
+            ("max", None) => Some(Max),
+            ("max", Some(n)) => Some(MaxN(n)),
+            ("median", None) => Some(Median),
+            ("min", None) => Some(Min),
+            ("min", Some(n)) => Some(MinN(n)),
+            ("sample", Some(n)) => Some(Sample(n)),
+            ("stddev", None) => Some(Stddev),
+            ("sum", None) => Some(Sum),
+            ("variance", None) => Some(Variance),
             _ => None,
         }
     }
 
+    /// Return `true` if this aggregate produces a collection of values rather than a single value.
+    pub fn is_collection(&self) -> bool {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Distinct | &MaxN(_) | &MinN(_) | &Sample(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Turn all of the values collected for a group into the result of a collection aggregate:
+    /// the distinct values, in order; the `n` least or greatest values; or up to `n` distinct
+    /// values chosen at random.
+    pub fn finish_collection(&self, mut values: Vec<TypedValue>) -> Vec<TypedValue> {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Distinct => {
+                values.sort_by(compare_values);
+                values.dedup();
+                values
+            }
+            &MaxN(n) => {
+                values.sort_by(|a, b| compare_values(b, a));
+                values.truncate(n);
+                values
+            }
+            &MinN(n) => {
+                values.sort_by(compare_values);
+                values.truncate(n);
+                values
+            }
+            &Sample(n) => {
+                values.sort_by(compare_values);
+                values.dedup();
+
+                // `RandomState` is randomly seeded, so ordering by hash shuffles the values.
+                let state = RandomState::new();
+                let mut keyed: Vec<(u64, TypedValue)> = values
+                    .into_iter()
+                    .map(|v| {
+                        let mut hasher = state.build_hasher();
+                        v.hash(&mut hasher);
+                        (hasher.finish(), v)
+                    })
+                    .collect();
+                keyed.sort_by_key(|&(key, _)| key);
+                keyed.into_iter().take(n).map(|(_, v)| v).collect()
+            }
+            _ => values,
+        }
+    }
+
     /// With knowledge of the types to which a variable might be bound,
     /// return a `Result` to determine whether this aggregation is suitable.
     /// For example, it's valid to take the `Avg` of `{Double, Long}`, invalid
@@ -67,10 +168,13 @@ impl SimpleAggregationOp {
             // One can always count results.
             &Count => Ok(ValueType::Long),
 
+            &CountDistinct => Ok(ValueType::Long),
+
             // Only numeric types can be averaged or summed.
-            &Avg => {
+            &Avg | &Median | &Variance | &Stddev => {
                 if possibilities.is_only_numeric() {
-                    // The mean of a set of numeric values will always, for our purposes, be a double.
+                    // The mean (or median, or spread) of a set of numeric values will always, for
+                    // our purposes, be a double.
                     Ok(ValueType::Double)
                 } else {
                     bail!(ProjectorError::CannotApplyAggregateOperationToTypes(
@@ -95,7 +199,20 @@ impl SimpleAggregationOp {
                 }
             }
 
-            &Max | &Min => {
+            // Collections are decoded by type tag, so every value must share one. We return the
+            // type of the collection's values.
+            &Distinct | &Sample(_) => {
+                if possibilities.has_unique_type_tag() {
+                    Ok(possibilities.exemplar().expect("a type"))
+                } else {
+                    bail!(ProjectorError::CannotApplyAggregateOperationToTypes(
+                        *self,
+                        possibilities
+                    ))
+                }
+            }
+
+            &Max | &Min | &MaxN(_) | &MinN(_) => {
                 if possibilities.is_unit() {
                     use self::ValueType::*;
                     let the_type = possibilities.exemplar().expect("a type");
@@ -147,14 +264,19 @@ pub struct SimpleAggregate {
 
 impl SimpleAggregate {
     pub fn column_name(&self) -> Name {
-        format!("({} {})", self.op.to_sql(), self.var.name())
+        use self::SimpleAggregationOp::*;
+        match self.op {
+            MaxN(n) | MinN(n) | Sample(n) => aggregate_column_name(self.op.name(), Some(n), &self.var),
+            _ => aggregate_column_name(self.op.name(), None, &self.var),
+        }
     }
 
     pub fn use_static_value(&self) -> bool {
         use self::SimpleAggregationOp::*;
         match self.op {
-            Avg | Max | Min => true,
-            Count | Sum => false,
+            Avg | Max | Median | Min => true,
+            Count | CountDistinct | Distinct | MaxN(_) | MinN(_) | Sample(_) | Stddev | Sum
+            | Variance => false,
         }
     }
 
@@ -162,28 +284,96 @@ impl SimpleAggregate {
     pub fn is_nullable(&self) -> bool {
         use self::SimpleAggregationOp::*;
         match self.op {
-            Avg | Max | Min => true,
-            Count | Sum => false,
+            Avg | Max | Median | Min | Stddev | Variance => true,
+            Count | CountDistinct | Distinct | MaxN(_) | MinN(_) | Sample(_) | Sum => false,
+        }
+    }
+
+    /// The SQL aggregate expression applied to `arg`, the projected value of the variable.
+    fn expression(&self, arg: ColumnOrExpression, return_type: ValueType) -> Expression {
+        use self::SimpleAggregationOp::*;
+        match self.op {
+            CountDistinct => Expression::Aggregate {
+                sql_op: self.op.to_sql().to_string(),
+                distinct: true,
+                args: vec![arg],
+            },
+            // Each collected value is accompanied by its type tag, which is unique.
+            Distinct | MaxN(_) | MinN(_) | Sample(_) => Expression::Aggregate {
+                sql_op: self.op.to_sql().to_string(),
+                distinct: false,
+                args: vec![
+                    arg,
+                    ColumnOrExpression::Integer(return_type.value_type_tag()),
+                ],
+            },
+            _ => Expression::Unary {
+                sql_op: self.op.to_sql(),
+                arg: arg,
+            },
         }
     }
 }
 
 pub trait SimpleAggregation {
     fn to_simple(&self) -> Option<SimpleAggregate>;
+
+    /// Aggregates that aren't built in might have been registered with the store. Those are
+    /// simple, too: they're applied to a single variable.
+    fn to_custom(&self, aggregates: &AggregateRegistry) -> Option<CustomAggregateCall>;
+}
+
+/// A call to a `CustomAggregate` in a find spec, like `(histogram ?age)`.
+pub struct CustomAggregateCall {
+    pub name: PlainSymbol,
+    pub var: Variable,
+}
+
+impl CustomAggregateCall {
+    pub fn column_name(&self) -> Name {
+        aggregate_column_name(self.name.name(), None, &self.var)
+    }
 }
 
 impl SimpleAggregation for Aggregate {
     fn to_simple(&self) -> Option<SimpleAggregate> {
-        if self.args.len() != 1 {
+        // Aggregates like `(max 3 ?x)` take a count before the variable.
+        let (n, var) = match self.args.as_slice() {
+            [var] => (None, var),
+            [FnArg::EntidOrInteger(n), var] if *n >= 0 => (Some(*n as usize), var),
+            _ => return None,
+        };
+        var.as_variable().and_then(|v| {
+            SimpleAggregationOp::for_function(&self.func, n)
+                .map(|op| SimpleAggregate { op, var: v.clone() })
+        })
+    }
+
+    fn to_custom(&self, aggregates: &AggregateRegistry) -> Option<CustomAggregateCall> {
+        if self.args.len() != 1 || !aggregates.is_registered(self.func.0.name()) {
             return None;
         }
-        self.args[0].as_variable().and_then(|v| {
-            SimpleAggregationOp::for_function(&self.func)
-                .map(|op| SimpleAggregate { op, var: v.clone() })
+        self.args[0].as_variable().map(|v| CustomAggregateCall {
+            name: self.func.0.clone(),
+            var: v.clone(),
         })
     }
 }
 
+/// Order values for `min n` and `max n`. Longs and doubles share a type tag, and are compared
+/// numerically, just as SQLite does.
+fn compare_values(a: &TypedValue, b: &TypedValue) -> Ordering {
+    match (a, b) {
+        (&TypedValue::Long(x), &TypedValue::Double(y)) => {
+            (x as f64).partial_cmp(&y.into_inner()).unwrap_or(Ordering::Equal)
+        }
+        (&TypedValue::Double(x), &TypedValue::Long(y)) => {
+            x.into_inner().partial_cmp(&(y as f64)).unwrap_or(Ordering::Equal)
+        }
+        _ => a.cmp(b),
+    }
+}
+
 /// Returns two values:
 /// - The `ColumnOrExpression` to use in the query. This will always refer to other
 ///   variables by name; never to a datoms column.
@@ -198,13 +388,10 @@ pub fn projected_column_for_simple_aggregate(
         // Oh, we already know the value!
         if simple.use_static_value() {
             // We can statically compute the aggregate result for some operators -- not count or
-            // sum, but avg/max/min are OK.
+            // sum, but avg/max/min/median are OK.
             ColumnOrExpression::Value(value)
         } else {
-            let expression = Expression::Unary {
-                sql_op: simple.op.to_sql(),
-                arg: ColumnOrExpression::Value(value),
-            };
+            let expression = simple.expression(ColumnOrExpression::Value(value), return_type);
             if simple.is_nullable() {
                 ColumnOrExpression::NullableAggregate(Box::new(expression), return_type)
             } else {
@@ -214,10 +401,7 @@ pub fn projected_column_for_simple_aggregate(
     } else {
         // The common case: the values are bound during execution.
         let name = VariableColumn::Variable(simple.var.clone()).column_name();
-        let expression = Expression::Unary {
-            sql_op: simple.op.to_sql(),
-            arg: ColumnOrExpression::ExistingColumn(name),
-        };
+        let expression = simple.expression(ColumnOrExpression::ExistingColumn(name), return_type);
         if simple.is_nullable() {
             ColumnOrExpression::NullableAggregate(Box::new(expression), return_type)
         } else {
@@ -229,3 +413,57 @@ pub fn projected_column_for_simple_aggregate(
         return_type,
     ))
 }
+
+/// Just like `projected_column_for_simple_aggregate`, but for a custom aggregate. Its SQL
+/// function is given each value together with its type tag, so that it can be turned back into a
+/// `TypedValue`.
+pub fn projected_column_for_custom_aggregate(
+    custom: &CustomAggregateCall,
+    cc: &ConjoiningClauses,
+    aggregates: &AggregateRegistry,
+) -> Result<(ProjectedColumn, ValueType)> {
+    let known_types = cc.known_type_set(&custom.var);
+    let return_type = if known_types.is_empty() {
+        None
+    } else {
+        aggregates
+            .result_type(custom.name.name(), known_types)
+            .and_then(|t| t)
+    };
+    let return_type = match return_type {
+        Some(t) => t,
+        None => bail!(ProjectorError::CannotApplyCustomAggregateToTypes(
+            custom.name.clone(),
+            known_types
+        )),
+    };
+
+    let (value, type_tag) = if let Some(value) = cc.bound_value(&custom.var) {
+        let tag = value.value_type().value_type_tag();
+        (ColumnOrExpression::Value(value), ColumnOrExpression::Integer(tag))
+    } else {
+        let name = VariableColumn::Variable(custom.var.clone()).column_name();
+        let tag = match known_types.unique_type_tag() {
+            Some(tag) => ColumnOrExpression::Integer(tag),
+            // The type column is projected alongside the value.
+            None => ColumnOrExpression::ExistingColumn(
+                VariableColumn::VariableTypeTag(custom.var.clone()).column_name(),
+            ),
+        };
+        (ColumnOrExpression::ExistingColumn(name), tag)
+    };
+
+    // A custom aggregate can always decline to produce a result.
+    let expression = Expression::Aggregate {
+        sql_op: AggregateRegistry::sql_name(custom.name.name()),
+        distinct: false,
+        args: vec![value, type_tag],
+    };
+    Ok((
+        ProjectedColumn(
+            ColumnOrExpression::NullableAggregate(Box::new(expression), return_type),
+            custom.column_name(),
+        ),
+        return_type,
+    ))
+}
@@ -36,6 +36,9 @@ pub enum ProjectorError {
     #[error("cannot apply projection operation {0:?} to types {1:?}")]
     CannotApplyAggregateOperationToTypes(SimpleAggregationOp, ValueTypeSet),
 
+    #[error("cannot apply custom aggregate {0} to types {1:?}")]
+    CannotApplyCustomAggregateToTypes(PlainSymbol, ValueTypeSet),
+
     #[error("invalid projection: {0}")]
     InvalidProjection(String),
 
@@ -59,6 +62,9 @@ pub enum ProjectorError {
     #[error("SQL error: {0}")]
     RusqliteError(String),
 
+    #[error("query interrupted")]
+    Interrupted,
+
     #[error(transparent)]
     DbError(#[from] DbError),
 
@@ -68,6 +74,9 @@ pub enum ProjectorError {
 
 impl From<rusqlite::Error> for ProjectorError {
     fn from(error: rusqlite::Error) -> ProjectorError {
+        if error.sqlite_error_code() == Some(rusqlite::ErrorCode::OperationInterrupted) {
+            return ProjectorError::Interrupted;
+        }
         ProjectorError::RusqliteError(error.to_string())
     }
 }
@@ -27,19 +27,20 @@ use std::collections::BTreeSet;
 
 use std::iter;
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use rusqlite::{Row, Rows};
 
-use core_traits::{Binding, TypedValue};
+use core_traits::{Binding, KeyedRow, TypedValue};
 
-use mentat_core::{Schema, ValueTypeTag};
+use mentat_core::{CachedAttributes, Schema, ValueTypeTag};
 
 use mentat_core::util::Either;
 
-use mentat_db::TypedSQLValue;
+use mentat_db::{decode_collected_values, TypedSQLValue};
 
-use edn::query::{Element, FindSpec, Limit, Variable};
+use edn::query::{Element, FindSpec, Limit, Offset, Variable};
+use edn::{Value, ValueRc};
 
 use mentat_query_algebrizer::{AlgebraicQuery, VariableBindings};
 
@@ -58,20 +59,22 @@ use project::{project_elements, ProjectedElements};
 
 pub use project::projected_column_for_var;
 
-pub use projectors::{ConstantProjector, Projector};
+pub use projectors::{ConstantProjector, Projector, RowProjector};
 
 use projectors::{
     CollProjector, CollTwoStagePullProjector, RelProjector, RelTwoStagePullProjector,
-    ScalarProjector, ScalarTwoStagePullProjector, TupleProjector, TupleTwoStagePullProjector,
+    ReturnMapProjector, ScalarProjector, ScalarTwoStagePullProjector, TupleProjector,
+    TupleTwoStagePullProjector,
 };
 
 pub use relresult::{RelResult, StructuredRelResult};
 
+use query_projector_traits::aggregates::SimpleAggregationOp;
 use query_projector_traits::errors::{ProjectorError, Result};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct QueryOutput {
-    pub spec: Rc<FindSpec>,
+    pub spec: Arc<FindSpec>,
     pub results: QueryResults,
 }
 
@@ -81,6 +84,9 @@ pub enum QueryResults {
     Tuple(Option<Vec<Binding>>),
     Coll(Vec<Binding>),
     Rel(RelResult<Binding>),
+
+    /// The rows of a relation whose query has a return map: `:keys`, `:strs`, or `:syms`.
+    Maps(Vec<KeyedRow>),
 }
 
 impl From<QueryOutput> for QueryResults {
@@ -90,7 +96,7 @@ impl From<QueryOutput> for QueryResults {
 }
 
 impl QueryOutput {
-    pub fn empty_factory(spec: &FindSpec) -> Box<Fn() -> QueryResults> {
+    pub fn empty_factory(spec: &FindSpec) -> Box<Fn() -> QueryResults + Send + Sync> {
         use self::FindSpec::*;
         match spec {
             &FindScalar(_) => Box::new(|| QueryResults::Scalar(None)),
@@ -111,7 +117,7 @@ impl QueryOutput {
         self.results.is_empty()
     }
 
-    pub fn empty(spec: &Rc<FindSpec>) -> QueryOutput {
+    pub fn empty(spec: &Arc<FindSpec>) -> QueryOutput {
         use self::FindSpec::*;
         let results = match &**spec {
             &FindScalar(_) => QueryResults::Scalar(None),
@@ -125,7 +131,7 @@ impl QueryOutput {
         }
     }
 
-    pub fn from_constants(spec: &Rc<FindSpec>, bindings: VariableBindings) -> QueryResults {
+    pub fn from_constants(spec: &Arc<FindSpec>, bindings: VariableBindings) -> QueryResults {
         use self::FindSpec::*;
         match &**spec {
             &FindScalar(Element::Variable(ref var))
@@ -235,6 +241,10 @@ impl QueryOutput {
     pub fn into_rel(self) -> Result<RelResult<Binding>> {
         self.results.into_rel()
     }
+
+    pub fn into_maps(self) -> Result<Vec<KeyedRow>> {
+        self.results.into_maps()
+    }
 }
 
 impl QueryResults {
@@ -257,6 +267,7 @@ impl QueryResults {
             }
             &Coll(ref v) => v.len(),
             &Rel(ref r) => r.row_count(),
+            &Maps(ref m) => m.len(),
         }
     }
 
@@ -267,6 +278,7 @@ impl QueryResults {
             &Tuple(ref o) => o.is_none(),
             &Coll(ref v) => v.is_empty(),
             &Rel(ref r) => r.is_empty(),
+            &Maps(ref m) => m.is_empty(),
         }
     }
 
@@ -278,6 +290,7 @@ impl QueryResults {
                 bail!(ProjectorError::UnexpectedResultsType("tuple", "scalar"))
             }
             QueryResults::Rel(_) => bail!(ProjectorError::UnexpectedResultsType("rel", "scalar")),
+            QueryResults::Maps(_) => bail!(ProjectorError::UnexpectedResultsType("maps", "scalar")),
         }
     }
 
@@ -289,6 +302,7 @@ impl QueryResults {
             QueryResults::Coll(c) => Ok(c),
             QueryResults::Tuple(_) => bail!(ProjectorError::UnexpectedResultsType("tuple", "coll")),
             QueryResults::Rel(_) => bail!(ProjectorError::UnexpectedResultsType("rel", "coll")),
+            QueryResults::Maps(_) => bail!(ProjectorError::UnexpectedResultsType("maps", "coll")),
         }
     }
 
@@ -300,6 +314,7 @@ impl QueryResults {
             QueryResults::Coll(_) => bail!(ProjectorError::UnexpectedResultsType("coll", "tuple")),
             QueryResults::Tuple(t) => Ok(t),
             QueryResults::Rel(_) => bail!(ProjectorError::UnexpectedResultsType("rel", "tuple")),
+            QueryResults::Maps(_) => bail!(ProjectorError::UnexpectedResultsType("maps", "tuple")),
         }
     }
 
@@ -311,6 +326,43 @@ impl QueryResults {
             QueryResults::Coll(_) => bail!(ProjectorError::UnexpectedResultsType("coll", "rel")),
             QueryResults::Tuple(_) => bail!(ProjectorError::UnexpectedResultsType("tuple", "rel")),
             QueryResults::Rel(r) => Ok(r),
+            QueryResults::Maps(_) => bail!(ProjectorError::UnexpectedResultsType("maps", "rel")),
+        }
+    }
+
+    pub fn into_maps(self) -> Result<Vec<KeyedRow>> {
+        match self {
+            QueryResults::Scalar(_) => {
+                bail!(ProjectorError::UnexpectedResultsType("scalar", "maps"))
+            }
+            QueryResults::Coll(_) => bail!(ProjectorError::UnexpectedResultsType("coll", "maps")),
+            QueryResults::Tuple(_) => bail!(ProjectorError::UnexpectedResultsType("tuple", "maps")),
+            QueryResults::Rel(_) => bail!(ProjectorError::UnexpectedResultsType("rel", "maps")),
+            QueryResults::Maps(m) => Ok(m),
+        }
+    }
+
+    /// Key the rows of a relation with `keys`, one for each column.
+    fn into_keyed(self, keys: &[Value]) -> QueryResults {
+        match self {
+            QueryResults::Rel(rel) => QueryResults::Maps(
+                rel.into_iter()
+                   .map(|row| KeyedRow(keys.iter().cloned().zip(row).collect()))
+                   .collect(),
+            ),
+            results => results,
+        }
+    }
+
+    /// The results as rows, whatever their shape: scalar and coll results have one binding per
+    /// row, and maps' values are in the order of their keys.
+    pub fn into_rows(self) -> Vec<Vec<Binding>> {
+        match self {
+            QueryResults::Scalar(v) => v.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Coll(vs) => vs.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Tuple(t) => t.into_iter().collect(),
+            QueryResults::Rel(rel) => rel.into_iter().collect(),
+            QueryResults::Maps(m) => m.into_iter().map(|row| row.0.into_iter().map(|(_, v)| v).collect()).collect(),
         }
     }
 }
@@ -319,6 +371,7 @@ type Index = usize; // See rusqlite::RowIndex.
 enum TypedIndex {
     Known(Index, ValueTypeTag),
     Unknown(Index, Index),
+    Collected(Index, SimpleAggregationOp),
 }
 
 impl TypedIndex {
@@ -353,6 +406,16 @@ impl TypedIndex {
                     .map(|v| v.into())
                     .map_err(|e| e.into())
             }
+            &Collected(value_index, op) => {
+                let v: Vec<u8> = row.get(value_index as usize)?;
+                let values = decode_collected_values(&v)?;
+                let bindings = op
+                    .finish_collection(values)
+                    .into_iter()
+                    .map(Binding::Scalar)
+                    .collect();
+                Ok(Binding::Vec(ValueRc::new(bindings)))
+            }
         }
     }
 }
@@ -387,8 +450,16 @@ pub struct CombinedProjection {
 }
 
 impl CombinedProjection {
-    fn flip_distinct_for_limit(mut self, limit: &Limit) -> Self {
-        if *limit == Limit::Fixed(1) {
+    fn with_return_map(mut self, keys: Option<Arc<Vec<Value>>>) -> Self {
+        if let Some(keys) = keys {
+            self.datalog_projector = Box::new(ReturnMapProjector::new(keys, self.datalog_projector));
+        }
+        self
+    }
+
+    fn flip_distinct_for_limit(mut self, limit: &Limit, offset: &Offset) -> Self {
+        // Duplicates don't matter in a single row, unless they're skipped by an offset.
+        if *limit == Limit::Fixed(1) && *offset == Offset::None {
             self.distinct = false;
         }
         self
@@ -422,6 +493,9 @@ pub fn query_projection(
 ) -> Result<Either<ConstantProjector, CombinedProjection>> {
     use self::FindSpec::*;
 
+    // With a return map, each row of a relation is keyed by the given names.
+    let keys = query.return_map.as_ref().map(|m| Arc::new(m.keys()));
+
     let spec = query.find_spec.clone();
     if query.is_fully_unit_bound() {
         // Do a few gyrations to produce empty results of the right kind for the query.
@@ -447,13 +521,20 @@ pub fn query_projection(
 
         // TODO: error handling
         let results = QueryOutput::from_constants(&spec, query.cc.value_bindings(&variables));
+        let results = match keys {
+            Some(ref keys) => results.into_keyed(keys),
+            None => results,
+        };
         let f = Box::new(move || results.clone());
 
-        Ok(Either::Left(ConstantProjector::new(spec, f)))
+        Ok(Either::Left(ConstantProjector::new(spec, f).with_keys(keys)))
     } else if query.is_known_empty() {
         // Do a few gyrations to produce empty results of the right kind for the query.
-        let empty = QueryOutput::empty_factory(&spec);
-        Ok(Either::Left(ConstantProjector::new(spec, empty)))
+        let empty = match keys {
+            Some(_) => Box::new(|| QueryResults::Maps(vec![])) as Box<Fn() -> QueryResults + Send + Sync>,
+            None => QueryOutput::empty_factory(&spec),
+        };
+        Ok(Either::Left(ConstantProjector::new(spec, empty).with_keys(keys)))
     } else {
         match *query.find_spec {
             FindColl(ref element) => {
@@ -463,7 +544,7 @@ pub fn query_projection(
                 } else {
                     CollProjector::combine(spec, elements)
                 }
-                .map(|p| p.flip_distinct_for_limit(&query.limit))
+                .map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             }
 
             FindScalar(ref element) => {
@@ -484,7 +565,7 @@ pub fn query_projection(
                 } else {
                     RelProjector::combine(spec, column_count, elements)
                 }
-                .map(|p| p.flip_distinct_for_limit(&query.limit))
+                .map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             }
 
             FindTuple(ref elements) => {
@@ -498,14 +579,14 @@ pub fn query_projection(
                 }
             }
         }
-        .map(Either::Right)
+        .map(|p| Either::Right(p.with_return_map(keys)))
     }
 }
 
 #[test]
 fn test_into_tuple() {
     let query_output = QueryOutput {
-        spec: Rc::new(FindSpec::FindTuple(vec![
+        spec: Arc::new(FindSpec::FindTuple(vec![
             Element::Variable(Variable::from_valid_name("?x")),
             Element::Variable(Variable::from_valid_name("?y")),
         ])),
@@ -532,7 +613,7 @@ fn test_into_tuple() {
     }
 
     let query_output = QueryOutput {
-        spec: Rc::new(FindSpec::FindTuple(vec![
+        spec: Arc::new(FindSpec::FindTuple(vec![
             Element::Variable(Variable::from_valid_name("?x")),
             Element::Variable(Variable::from_valid_name("?y")),
         ])),
@@ -27,7 +27,8 @@ use mentat_query_algebrizer::{
 use mentat_query_sql::{ColumnOrExpression, GroupBy, Name, ProjectedColumn, Projection};
 
 use query_projector_traits::aggregates::{
-    projected_column_for_simple_aggregate, SimpleAggregation,
+    projected_column_for_custom_aggregate, projected_column_for_simple_aggregate,
+    SimpleAggregation,
 };
 
 use query_projector_traits::errors::{ProjectorError, Result};
@@ -297,15 +298,14 @@ pub(crate) fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
                 }
             }
             &Element::Aggregate(ref a) => {
-                if let Some(simple) = a.to_simple() {
-                    aggregates = true;
-
+                let (var, projected_column, template) = if let Some(simple) = a.to_simple() {
                     use query_projector_traits::aggregates::SimpleAggregationOp::*;
                     match simple.op {
                         Max | Min => {
                             min_max_count += 1;
                         }
-                        Avg | Count | Sum => (),
+                        Avg | Count | CountDistinct | Distinct | MaxN(_) | Median | MinN(_)
+                        | Sample(_) | Stddev | Sum | Variance => (),
                     }
 
                     // When we encounter a simple aggregate -- one in which the aggregation can be
@@ -319,35 +319,45 @@ pub(crate) fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
 
                     let (projected_column, return_type) =
                         projected_column_for_simple_aggregate(&simple, &query.cc)?;
-                    outer_projection.push(Either::Right(projected_column));
-
-                    if !inner_variables.contains(&simple.var) {
-                        inner_variables.insert(simple.var.clone());
-                        let (projected_column, _type_set) =
-                            projected_column_for_var(&simple.var, &query.cc)?;
-                        inner_projection.push(projected_column);
-                        if query
-                            .cc
-                            .known_type_set(&simple.var)
-                            .unique_type_tag()
-                            .is_none()
-                        {
-                            // Also project the type from the SQL query.
-                            let (type_column, type_name) =
-                                candidate_type_column(&query.cc, &simple.var)?;
-                            inner_projection.push(ProjectedColumn(type_column, type_name.clone()));
-                        }
-                    }
 
-                    // We might regret using the type tag here instead of the `ValueType`.
-                    templates.push(TypedIndex::Known(i, return_type.value_type_tag()));
-                    i += 1;
+                    let template = if simple.op.is_collection() {
+                        // The values are collected into a blob, which we decode and finish.
+                        TypedIndex::Collected(i, simple.op)
+                    } else {
+                        // We might regret using the type tag here instead of the `ValueType`.
+                        TypedIndex::Known(i, return_type.value_type_tag())
+                    };
+                    (simple.var, projected_column, template)
+                } else if let Some(custom) = a.to_custom(&query.aggregates) {
+                    // Custom aggregates are registered with the store, and are simple in the same
+                    // way: the registered SQL function aggregates a single variable.
+                    let (projected_column, return_type) =
+                        projected_column_for_custom_aggregate(&custom, &query.cc, &query.aggregates)?;
+                    let template = TypedIndex::Known(i, return_type.value_type_tag());
+                    (custom.var, projected_column, template)
                 } else {
                     // TODO: complex aggregates.
                     bail!(ProjectorError::NotYetImplemented(
                         "complex aggregates".into()
                     ));
+                };
+
+                aggregates = true;
+                outer_projection.push(Either::Right(projected_column));
+
+                if !inner_variables.contains(&var) {
+                    inner_variables.insert(var.clone());
+                    let (projected_column, _type_set) = projected_column_for_var(&var, &query.cc)?;
+                    inner_projection.push(projected_column);
+                    if query.cc.known_type_set(&var).unique_type_tag().is_none() {
+                        // Also project the type from the SQL query.
+                        let (type_column, type_name) = candidate_type_column(&query.cc, &var)?;
+                        inner_projection.push(ProjectedColumn(type_column, type_name.clone()));
+                    }
                 }
+
+                templates.push(template);
+                i += 1;
             }
         }
     }
@@ -8,15 +8,17 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use ::{
+    CachedAttributes,
     Element,
     FindSpec,
     QueryOutput,
     QueryResults,
     Rows,
     Schema,
+    Value,
     rusqlite,
 };
 
@@ -31,18 +33,26 @@ use super::{
 /// A projector that produces a `QueryResult` containing fixed data.
 /// Takes a boxed function that should return an empty result set of the desired type.
 pub struct ConstantProjector {
-    spec: Rc<FindSpec>,
-    results_factory: Box<Fn() -> QueryResults>,
+    spec: Arc<FindSpec>,
+    keys: Option<Arc<Vec<Value>>>,
+    results_factory: Box<Fn() -> QueryResults + Send + Sync>,
 }
 
 impl ConstantProjector {
-    pub fn new(spec: Rc<FindSpec>, results_factory: Box<Fn() -> QueryResults>) -> ConstantProjector {
+    pub fn new(spec: Arc<FindSpec>, results_factory: Box<Fn() -> QueryResults + Send + Sync>) -> ConstantProjector {
         ConstantProjector {
             spec: spec,
+            keys: None,
             results_factory: results_factory,
         }
     }
 
+    /// Name the columns of the results, which are keyed rows, for `Projector::keys`.
+    pub fn with_keys(mut self, keys: Option<Arc<Vec<Value>>>) -> ConstantProjector {
+        self.keys = keys;
+        self
+    }
+
     pub fn project_without_rows<'stmt>(&self) -> Result<QueryOutput> {
         let results = (self.results_factory)();
         let spec = self.spec.clone();
@@ -56,11 +66,15 @@ impl ConstantProjector {
 // TODO: a ConstantProjector with non-constant pull expressions.
 
 impl Projector for ConstantProjector {
-    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _cache: Option<&CachedAttributes>, _rows: Rows<'stmt>) -> Result<QueryOutput> {
         self.project_without_rows()
     }
 
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
         self.spec.columns()
     }
+
+    fn keys(&self) -> Option<&Arc<Vec<Value>>> {
+        self.keys.as_ref()
+    }
 }
@@ -8,11 +8,17 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::sync::Arc;
+
 use super::{
+    Binding,
+    CachedAttributes,
     Element,
     Schema,
     QueryOutput,
+    Row,
     Rows,
+    Value,
     rusqlite,
 };
 
@@ -20,14 +26,36 @@ use query_projector_traits::errors::{
     Result,
 };
 
-pub trait Projector {
-    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, rows: Rows<'stmt>) -> Result<QueryOutput>;
+/// Projectors are kept in query plans, which can be shared between threads.
+pub trait Projector: Send + Sync {
+    /// Project `rows`. Pulled reversed attributes, like `:foo/_bar`, are looked up in `cache` if
+    /// it's given and caches them in reverse; all other pulled attributes are fetched.
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, rows: Rows<'stmt>) -> Result<QueryOutput>;
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's>;
+
+    /// This projector as a `RowProjector`, if it can project each row by itself, so that rows
+    /// can be streamed rather than collected by `project`. Pull projectors can't: they pull
+    /// attributes for all of the rows' entities at once.
+    fn row_projector(&self) -> Option<&RowProjector> {
+        None
+    }
+
+    /// The names that `:keys`, `:strs`, or `:syms` give the columns, if the query has them.
+    fn keys(&self) -> Option<&Arc<Vec<Value>>> {
+        None
+    }
+}
+
+/// A projector that turns each row into the bindings for one result row, independently of the
+/// other rows. Scalar and coll results have one binding per row.
+pub trait RowProjector {
+    fn project_row<'stmt>(&self, row: &Row<'stmt>) -> Result<Vec<Binding>>;
 }
 
 mod constant;
 mod simple;
 mod pull_two_stage;
+mod return_map;
 
 pub use self::constant::ConstantProjector;
 
@@ -38,6 +66,8 @@ pub(crate) use self::simple::{
     TupleProjector,
 };
 
+pub(crate) use self::return_map::ReturnMapProjector;
+
 pub(crate) use self::pull_two_stage::{
     CollTwoStagePullProjector,
     RelTwoStagePullProjector,
@@ -8,7 +8,7 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use std::iter::{
     once,
@@ -24,6 +24,7 @@ use core_traits::{
 
 use ::{
     Binding,
+    CachedAttributes,
     CombinedProjection,
     Element,
     FindSpec,
@@ -53,7 +54,7 @@ use super::{
 };
 
 pub(crate) struct ScalarTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     puller: Puller,
 }
 
@@ -61,14 +62,14 @@ pub(crate) struct ScalarTwoStagePullProjector {
 // The only output is the pull expression, and so we can directly supply the projected entity
 // to the pull SQL.
 impl ScalarTwoStagePullProjector {
-    fn with_template(schema: &Schema, spec: Rc<FindSpec>, pull: PullOperation) -> Result<ScalarTwoStagePullProjector> {
+    fn with_template(schema: &Schema, spec: Arc<FindSpec>, pull: PullOperation) -> Result<ScalarTwoStagePullProjector> {
         Ok(ScalarTwoStagePullProjector {
             spec: spec,
             puller: Puller::prepare(schema, pull.0.clone())?,
         })
     }
 
-    pub(crate) fn combine(schema: &Schema, spec: Rc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(schema: &Schema, spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let pull = elements.pulls.pop().expect("Expected a single pull");
         let projector = Box::new(ScalarTwoStagePullProjector::with_template(schema, spec, pull.op)?);
         let distinct = false;
@@ -77,12 +78,12 @@ impl ScalarTwoStagePullProjector {
 }
 
 impl Projector for ScalarTwoStagePullProjector {
-    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         // Scalar is pretty straightforward -- zero or one entity, do the pull directly.
         let results =
             if let Some(row) = rows.next()? {
                 let entity: Entid = row.get(0)?;          // This will always be 0 and a ref.
-                let bindings = self.puller.pull(schema, sqlite, once(entity))?;
+                let bindings = self.puller.pull_with_cache(schema, sqlite, cache, once(entity))?;
                 let m = Binding::Map(bindings.get(&entity).cloned().unwrap_or_else(Default::default));
                 QueryResults::Scalar(Some(m))
             } else {
@@ -102,14 +103,14 @@ impl Projector for ScalarTwoStagePullProjector {
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
 pub(crate) struct TupleTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
     pulls: Vec<PullTemplate>,
 }
 
 impl TupleTwoStagePullProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> TupleTwoStagePullProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> TupleTwoStagePullProjector {
         TupleTwoStagePullProjector {
             spec: spec,
             len: len,
@@ -130,7 +131,7 @@ impl TupleTwoStagePullProjector {
             .collect::<Result<Vec<Binding>>>()
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let projector = Box::new(TupleTwoStagePullProjector::with_templates(spec, column_count, elements.take_templates(), elements.take_pulls()));
         let distinct = false;
         elements.combine(projector, distinct)
@@ -138,7 +139,7 @@ impl TupleTwoStagePullProjector {
 }
 
 impl Projector for TupleTwoStagePullProjector {
-    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let results =
             if let Some(row) = rows.next()? {
 
@@ -158,7 +159,7 @@ impl Projector for TupleTwoStagePullProjector {
 
                 // Run the pull expressions for the collected IDs.
                 for mut p in pull_consumers.iter_mut() {
-                    p.pull(sqlite)?;
+                    p.pull(sqlite, cache)?;
                 }
 
                 // Expand the pull expressions back into the results vector.
@@ -186,14 +187,14 @@ impl Projector for TupleTwoStagePullProjector {
 /// Each column in each stride is the result of taking one or two columns from
 /// the `Row`: one for the value and optionally one for the type tag.
 pub(crate) struct RelTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
     pulls: Vec<PullTemplate>,
 }
 
 impl RelTwoStagePullProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> RelTwoStagePullProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> RelTwoStagePullProjector {
         RelTwoStagePullProjector {
             spec: spec,
             len: len,
@@ -218,7 +219,7 @@ impl RelTwoStagePullProjector {
         Ok(())
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let projector = Box::new(RelTwoStagePullProjector::with_templates(spec, column_count, elements.take_templates(), elements.take_pulls()));
 
         // If every column yields only one value, or if this is an aggregate query
@@ -233,7 +234,7 @@ impl RelTwoStagePullProjector {
 }
 
 impl Projector for RelTwoStagePullProjector {
-    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         // Allocate space for five rows to start.
         // This is better than starting off by doubling the buffer a couple of times, and will
         // rapidly grow to support larger query results.
@@ -256,7 +257,7 @@ impl Projector for RelTwoStagePullProjector {
 
         // Run the pull expressions for the collected IDs.
         for mut p in pull_consumers.iter_mut() {
-            p.pull(sqlite)?;
+            p.pull(sqlite, cache)?;
         }
 
         // Expand the pull expressions back into the results vector.
@@ -280,19 +281,19 @@ impl Projector for RelTwoStagePullProjector {
 /// A coll projector produces a vector of values.
 /// Each value is sourced from the same column.
 pub(crate) struct CollTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     pull: PullOperation,
 }
 
 impl CollTwoStagePullProjector {
-    fn with_pull(spec: Rc<FindSpec>, pull: PullOperation) -> CollTwoStagePullProjector {
+    fn with_pull(spec: Arc<FindSpec>, pull: PullOperation) -> CollTwoStagePullProjector {
         CollTwoStagePullProjector {
             spec: spec,
             pull: pull,
         }
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let pull = elements.pulls.pop().expect("Expected a single pull");
         let projector = Box::new(CollTwoStagePullProjector::with_pull(spec, pull.op));
 
@@ -305,7 +306,7 @@ impl CollTwoStagePullProjector {
 }
 
 impl Projector for CollTwoStagePullProjector {
-    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let mut pull_consumer = PullConsumer::for_operation(schema, &self.pull)?;
 
         while let Some(row) = rows.next()? {
@@ -313,7 +314,7 @@ impl Projector for CollTwoStagePullProjector {
         }
 
         // Run the pull expressions for the collected IDs.
-        pull_consumer.pull(sqlite)?;
+        pull_consumer.pull(sqlite, cache)?;
 
         // Expand the pull expressions into a results vector.
         let out = pull_consumer.into_coll_results();
@@ -0,0 +1,69 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::sync::Arc;
+
+use ::{
+    CachedAttributes,
+    Element,
+    QueryOutput,
+    Rows,
+    Schema,
+    Value,
+    rusqlite,
+};
+
+use query_projector_traits::errors::{
+    Result,
+};
+
+use super::{
+    Projector,
+    RowProjector,
+};
+
+/// A return map projector turns the rows of a relation into `KeyedRow`s, keyed by the names
+/// given with `:keys`, `:strs`, or `:syms`.
+pub(crate) struct ReturnMapProjector {
+    keys: Arc<Vec<Value>>,
+    projector: Box<Projector>,
+}
+
+impl ReturnMapProjector {
+    pub(crate) fn new(keys: Arc<Vec<Value>>, projector: Box<Projector>) -> ReturnMapProjector {
+        ReturnMapProjector {
+            keys: keys,
+            projector: projector,
+        }
+    }
+}
+
+impl Projector for ReturnMapProjector {
+    fn project<'stmt, 's>(&self, schema: &Schema, sqlite: &'s rusqlite::Connection, cache: Option<&CachedAttributes>, rows: Rows<'stmt>) -> Result<QueryOutput> {
+        let output = self.projector.project(schema, sqlite, cache, rows)?;
+        Ok(QueryOutput {
+            spec: output.spec,
+            results: output.results.into_keyed(&self.keys),
+        })
+    }
+
+    fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
+        self.projector.columns()
+    }
+
+    /// Streamed rows are tuples, whose values are in the order of the keys.
+    fn row_projector(&self) -> Option<&RowProjector> {
+        self.projector.row_projector()
+    }
+
+    fn keys(&self) -> Option<&Arc<Vec<Value>>> {
+        Some(&self.keys)
+    }
+}
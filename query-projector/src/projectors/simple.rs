@@ -8,10 +8,11 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use ::{
     Binding,
+    CachedAttributes,
     CombinedProjection,
     Element,
     FindSpec,
@@ -32,22 +33,23 @@ use query_projector_traits::errors::{
 
 use super::{
     Projector,
+    RowProjector,
 };
 
 pub(crate) struct ScalarProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
 }
 
 impl ScalarProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> ScalarProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex) -> ScalarProjector {
         ScalarProjector {
             spec: spec,
             template: template,
         }
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let template = elements.templates.pop().expect("Expected a single template");
         let projector = Box::new(ScalarProjector::with_template(spec, template));
         let distinct = false;
@@ -56,7 +58,7 @@ impl ScalarProjector {
 }
 
 impl Projector for ScalarProjector {
-    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let results =
             if let Some(row) = rows.next()? {
                 let binding = self.template.lookup(row)?;
@@ -73,17 +75,27 @@ impl Projector for ScalarProjector {
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
         self.spec.columns()
     }
+
+    fn row_projector(&self) -> Option<&RowProjector> {
+        Some(self)
+    }
+}
+
+impl RowProjector for ScalarProjector {
+    fn project_row<'stmt>(&self, row: &Row<'stmt>) -> Result<Vec<Binding>> {
+        Ok(vec![self.template.lookup(row)?])
+    }
 }
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
 pub(crate) struct TupleProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
 }
 
 impl TupleProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> TupleProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> TupleProjector {
         TupleProjector {
             spec: spec,
             len: len,
@@ -103,7 +115,7 @@ impl TupleProjector {
             .collect::<Result<Vec<Binding>>>()
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let projector = Box::new(TupleProjector::with_templates(spec, column_count, elements.take_templates()));
         let distinct = false;
         elements.combine(projector, distinct)
@@ -111,7 +123,7 @@ impl TupleProjector {
 }
 
 impl Projector for TupleProjector {
-    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let results =
             if let Some(row) = rows.next()? {
                 let bindings = self.collect_bindings(row)?;
@@ -128,6 +140,16 @@ impl Projector for TupleProjector {
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
         self.spec.columns()
     }
+
+    fn row_projector(&self) -> Option<&RowProjector> {
+        Some(self)
+    }
+}
+
+impl RowProjector for TupleProjector {
+    fn project_row<'stmt>(&self, row: &Row<'stmt>) -> Result<Vec<Binding>> {
+        self.collect_bindings(row)
+    }
 }
 
 /// A rel projector produces a RelResult, which is a striding abstraction over a vector.
@@ -135,13 +157,13 @@ impl Projector for TupleProjector {
 /// Each column in each stride is the result of taking one or two columns from
 /// the `Row`: one for the value and optionally one for the type tag.
 pub(crate) struct RelProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
 }
 
 impl RelProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> RelProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> RelProjector {
         RelProjector {
             spec: spec,
             len: len,
@@ -165,7 +187,7 @@ impl RelProjector {
         Ok(())
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let projector = Box::new(RelProjector::with_templates(spec, column_count, elements.take_templates()));
 
         // If every column yields only one value, or if this is an aggregate query
@@ -179,7 +201,7 @@ impl RelProjector {
 }
 
 impl Projector for RelProjector {
-    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         // Allocate space for five rows to start.
         // This is better than starting off by doubling the buffer a couple of times, and will
         // rapidly grow to support larger query results.
@@ -199,24 +221,36 @@ impl Projector for RelProjector {
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
         self.spec.columns()
     }
+
+    fn row_projector(&self) -> Option<&RowProjector> {
+        Some(self)
+    }
+}
+
+impl RowProjector for RelProjector {
+    fn project_row<'stmt>(&self, row: &Row<'stmt>) -> Result<Vec<Binding>> {
+        let mut out = Vec::with_capacity(self.len);
+        self.collect_bindings_into(row, &mut out)?;
+        Ok(out)
+    }
 }
 
 /// A coll projector produces a vector of values.
 /// Each value is sourced from the same column.
 pub(crate) struct CollProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
 }
 
 impl CollProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> CollProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex) -> CollProjector {
         CollProjector {
             spec: spec,
             template: template,
         }
     }
 
-    pub(crate) fn combine(spec: Rc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+    pub(crate) fn combine(spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
         let template = elements.templates.pop().expect("Expected a single template");
         let projector = Box::new(CollProjector::with_template(spec, template));
 
@@ -231,7 +265,7 @@ impl CollProjector {
 }
 
 impl Projector for CollProjector {
-    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt, 's>(&self, _schema: &Schema, _sqlite: &'s rusqlite::Connection, _cache: Option<&CachedAttributes>, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let mut out: Vec<_> = vec![];
         while let Some(row) = rows.next()? {
             let binding = self.template.lookup(row)?;
@@ -246,4 +280,14 @@ impl Projector for CollProjector {
     fn columns<'s>(&'s self) -> Box<Iterator<Item=&Element> + 's> {
         self.spec.columns()
     }
+
+    fn row_projector(&self) -> Option<&RowProjector> {
+        Some(self)
+    }
+}
+
+impl RowProjector for CollProjector {
+    fn project_row<'stmt>(&self, row: &Row<'stmt>) -> Result<Vec<Binding>> {
+        Ok(vec![self.template.lookup(row)?])
+    }
 }
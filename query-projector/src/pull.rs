@@ -21,6 +21,7 @@ use core_traits::{
 };
 
 use mentat_core::{
+    CachedAttributes,
     Schema,
     ValueRc,
 };
@@ -99,9 +100,9 @@ impl<'schema> PullConsumer<'schema> {
         entity
     }
 
-    pub(crate) fn pull(&mut self, sqlite: &rusqlite::Connection) -> Result<()> {
+    pub(crate) fn pull(&mut self, sqlite: &rusqlite::Connection, cache: Option<&CachedAttributes>) -> Result<()> {
         let entities: Vec<Entid> = self.entities.iter().cloned().collect();
-        self.results = self.puller.pull(self.schema, sqlite, entities)?;
+        self.results = self.puller.pull_with_cache(self.schema, sqlite, cache, entities)?;
         Ok(())
     }
 
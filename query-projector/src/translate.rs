@@ -9,6 +9,7 @@
 // specific language governing permissions and limitations under the License.
 
 use core_traits::{
+    Entid,
     TypedValue,
     ValueType,
     ValueTypeSet,
@@ -27,7 +28,11 @@ use mentat_core::util::{
 };
 
 use edn::query::{
+    Element,
     Limit,
+    Offset,
+    SrcVar,
+    Variable,
 };
 
 use mentat_query_algebrizer::{
@@ -39,6 +44,7 @@ use mentat_query_algebrizer::{
     ColumnName,
     ComputedTable,
     ConjoiningClauses,
+    DatabaseView,
     DatomsColumn,
     DatomsTable,
     OrderBy,
@@ -60,6 +66,7 @@ use ::{
 use mentat_query_sql::{
     ColumnOrExpression,
     Constraint,
+    DatomsView,
     FromClause,
     GroupBy,
     Op,
@@ -71,12 +78,18 @@ use mentat_query_sql::{
     Values,
 };
 
-use std::collections::HashMap;
+use std::collections::{
+    BTreeSet,
+    HashMap,
+};
 
-use super::Result;
+use query_projector_traits::errors::{
+    ProjectorError,
+    Result,
+};
 
 trait ToConstraint {
-    fn to_constraint(self) -> Constraint;
+    fn to_constraint(self) -> Result<Constraint>;
 }
 
 trait ToColumn {
@@ -90,23 +103,23 @@ impl ToColumn for QualifiedAlias {
 }
 
 impl ToConstraint for ColumnIntersection {
-    fn to_constraint(self) -> Constraint {
-        Constraint::And {
-            constraints: self.into_iter().map(|x| x.to_constraint()).collect()
-        }
+    fn to_constraint(self) -> Result<Constraint> {
+        Ok(Constraint::And {
+            constraints: self.into_iter().map(|x| x.to_constraint()).collect::<Result<_>>()?
+        })
     }
 }
 
 impl ToConstraint for ColumnAlternation {
-    fn to_constraint(self) -> Constraint {
-        Constraint::Or {
-            constraints: self.into_iter().map(|x| x.to_constraint()).collect()
-        }
+    fn to_constraint(self) -> Result<Constraint> {
+        Ok(Constraint::Or {
+            constraints: self.into_iter().map(|x| x.to_constraint()).collect::<Result<_>>()?
+        })
     }
 }
 
 impl ToConstraint for ColumnConstraintOrAlternation {
-    fn to_constraint(self) -> Constraint {
+    fn to_constraint(self) -> Result<Constraint> {
         use self::ColumnConstraintOrAlternation::*;
         match self {
             Alternation(alt) => alt.to_constraint(),
@@ -161,9 +174,9 @@ fn possible_affinities(value_types: ValueTypeSet) -> HashMap<ValueTypeTag, Vec<S
 }
 
 impl ToConstraint for ColumnConstraint {
-    fn to_constraint(self) -> Constraint {
+    fn to_constraint(self) -> Result<Constraint> {
         use self::ColumnConstraint::*;
-        match self {
+        Ok(match self {
             Equals(qa, QueryValue::Entid(entid)) =>
                 Constraint::equal(qa.to_column(), ColumnOrExpression::Entid(entid)),
 
@@ -173,6 +186,9 @@ impl ToConstraint for ColumnConstraint {
             Equals(left, QueryValue::Column(right)) =>
                 Constraint::equal(left.to_column(), right.to_column()),
 
+            Equals(qa, QueryValue::Parameter(var)) =>
+                Constraint::equal(qa.to_column(), ColumnOrExpression::Parameter(var)),
+
             Equals(qa, QueryValue::PrimitiveLong(value)) => {
                 let tag_column = qa.for_associated_type_tag().expect("an associated type tag alias").to_column();
                 let value_column = qa.to_column();
@@ -220,6 +236,12 @@ impl ToConstraint for ColumnConstraint {
                     right: right.into(),
                 }
             },
+
+            NotNull(qa) => {
+                Constraint::IsNotNull {
+                    value: qa.to_column(),
+                }
+            },
             HasTypes { value: table, value_types, check_value } => {
                 let constraints = if check_value {
                     possible_affinities(value_types)
@@ -241,12 +263,12 @@ impl ToConstraint for ColumnConstraint {
             },
 
             NotExists(computed_table) => {
-                let subquery = table_for_computed(computed_table, TableAlias::new());
+                let subquery = table_for_computed(computed_table, TableAlias::new())?;
                 Constraint::NotExists {
                     subquery: subquery,
                 }
             },
-        }
+        })
     }
 }
 
@@ -275,63 +297,98 @@ impl<T> ConsumableVec<T> {
     }
 }
 
-fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubquery {
-    match computed {
+/// Turn one arm of a computed table into a subquery. Every arm must have the same shape and the
+/// same names, so each projects every variable in `projection`, and the type tag of every
+/// variable in `type_extraction`.
+/// The values we project might be fixed or they might be columns. The algebrizer rejects arms
+/// that don't bind every variable in `projection`, so failing to find one here is an error rather
+/// than an empty arm.
+fn select_for_arm(projection: &BTreeSet<Variable>, type_extraction: &BTreeSet<Variable>, cc: ConjoiningClauses) -> Result<SelectQuery> {
+    // We're going to end up with the variables being projected and also some
+    // type tag columns.
+    let mut columns: Vec<ProjectedColumn> = Vec::with_capacity(projection.len() + type_extraction.len());
+
+    // For each variable, find out which column it maps to within this arm, and
+    // project it as the variable name.
+    // E.g., SELECT datoms03.v AS `?x`.
+    for var in projection.iter() {
+        let (projected_column, type_set) = projected_column_for_var(var, &cc)?;
+        columns.push(projected_column);
+
+        // Similarly, project type tags if they're not known conclusively in the
+        // outer query.
+        // Assumption: we'll never need to project a tag without projecting the value of a variable.
+        if type_extraction.contains(var) {
+            let expression =
+                if let Some(tag) = type_set.unique_type_tag() {
+                    // If we know the type for sure, just project the constant.
+                    // SELECT datoms03.v AS `?x`, 10 AS `?x_value_type_tag`
+                    ColumnOrExpression::Integer(tag)
+                } else {
+                    // Otherwise, we'll have an established type binding! This'll be
+                    // either a datoms table or, recursively, a subquery. Project
+                    // this:
+                    // SELECT datoms03.v AS `?x`,
+                    //        datoms03.value_type_tag AS `?x_value_type_tag`
+                    let extract = cc.extracted_types
+                                    .get(var)
+                                    .expect("Expected variable to have a known type or an extracted type");
+                    ColumnOrExpression::Column(extract.clone())
+                };
+            let type_column = VariableColumn::VariableTypeTag(var.clone());
+            let proj = ProjectedColumn(expression, type_column.column_name());
+            columns.push(proj);
+        }
+    }
+
+    // Each arm simply turns into a subquery. An arm that projects nothing -- as when a rule is
+    // called with only constants -- only says whether it matched.
+    let projection = if columns.is_empty() {
+        Projection::One
+    } else {
+        Projection::Columns(columns)
+    };
+    cc_to_select_query(projection, cc, false, vec![], None, Limit::None, Offset::None)
+}
+
+fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> Result<TableOrSubquery> {
+    Ok(match computed {
         ComputedTable::Union {
             projection, type_extraction, arms,
         } => {
-            // The projection list for each CC must have the same shape and the same names.
-            // The values we project might be fixed or they might be columns.
+            // The SQL translation will stuff "UNION" between each arm.
             TableOrSubquery::Union(
                 arms.into_iter()
-                    .map(|cc| {
-                        // We're going to end up with the variables being projected and also some
-                        // type tag columns.
-                        let mut columns: Vec<ProjectedColumn> = Vec::with_capacity(projection.len() + type_extraction.len());
-
-                        // For each variable, find out which column it maps to within this arm, and
-                        // project it as the variable name.
-                        // E.g., SELECT datoms03.v AS `?x`.
-                        for var in projection.iter() {
-                            // TODO: chain results out.
-                            let (projected_column, type_set) = projected_column_for_var(var, &cc).expect("every var to be bound");
-                            columns.push(projected_column);
-
-                            // Similarly, project type tags if they're not known conclusively in the
-                            // outer query.
-                            // Assumption: we'll never need to project a tag without projecting the value of a variable.
-                            if type_extraction.contains(var) {
-                                let expression =
-                                    if let Some(tag) = type_set.unique_type_tag() {
-                                        // If we know the type for sure, just project the constant.
-                                        // SELECT datoms03.v AS `?x`, 10 AS `?x_value_type_tag`
-                                        ColumnOrExpression::Integer(tag)
-                                    } else {
-                                        // Otherwise, we'll have an established type binding! This'll be
-                                        // either a datoms table or, recursively, a subquery. Project
-                                        // this:
-                                        // SELECT datoms03.v AS `?x`,
-                                        //        datoms03.value_type_tag AS `?x_value_type_tag`
-                                        let extract = cc.extracted_types
-                                                        .get(var)
-                                                        .expect("Expected variable to have a known type or an extracted type");
-                                        ColumnOrExpression::Column(extract.clone())
-                                    };
-                                let type_column = VariableColumn::VariableTypeTag(var.clone());
-                                let proj = ProjectedColumn(expression, type_column.column_name());
-                                columns.push(proj);
-                            }
-                        }
-
-                        // Each arm simply turns into a subquery.
-                        // The SQL translation will stuff "UNION" between each arm.
-                        let projection = Projection::Columns(columns);
-                        cc_to_select_query(projection, cc, false, vec![], None, Limit::None)
-                  }).collect(),
+                    .map(|cc| select_for_arm(&projection, &type_extraction, cc))
+                    .collect::<Result<_>>()?,
+                alias)
+        },
+        ComputedTable::RecursiveRule {
+            projection, base, recursive,
+        } => {
+            // The recursive arms read type tags back out of the CTE itself, so every arm must
+            // project them, even those that know their types.
+            TableOrSubquery::Recursive(
+                base.into_iter()
+                    .map(|cc| select_for_arm(&projection, &projection, cc))
+                    .collect::<Result<_>>()?,
+                recursive.into_iter()
+                         .map(|cc| select_for_arm(&projection, &projection, cc))
+                         .collect::<Result<_>>()?,
                 alias)
         },
+        ComputedTable::LeftJoin {
+            source, table, constraints,
+        } => {
+            TableOrSubquery::LeftJoin(
+                source,
+                SourceAlias(table, alias),
+                constraints.into_iter()
+                           .map(|c| c.to_constraint())
+                           .collect::<Result<_>>()?)
+        },
         ComputedTable::Subquery(subquery) => {
-            TableOrSubquery::Subquery(Box::new(cc_to_exists(subquery)))
+            TableOrSubquery::Subquery(Box::new(cc_to_exists(subquery)?))
         },
         ComputedTable::NamedValues {
             names, values,
@@ -339,7 +396,12 @@ fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubq
             // We assume column homogeneity, so we won't have any type tag columns.
             TableOrSubquery::Values(Values::Named(names, values), alias)
         },
-    }
+        ComputedTable::Source {
+            source, database, table,
+        } => {
+            TableOrSubquery::SourceTable(source, database, SourceAlias(table, alias))
+        },
+    })
 }
 
 fn empty_query() -> SelectQuery {
@@ -351,6 +413,8 @@ fn empty_query() -> SelectQuery {
         constraints: vec![],
         order: vec![],
         limit: Limit::None,
+        offset: Offset::None,
+        views: vec![],
     }
 }
 
@@ -362,7 +426,8 @@ fn cc_to_select_query(projection: Projection,
                       distinct: bool,
                       group_by: Vec<GroupBy>,
                       order: Option<Vec<OrderBy>>,
-                      limit: Limit) -> SelectQuery {
+                      limit: Limit,
+                      offset: Offset) -> Result<SelectQuery> {
     let from = if cc.from.is_empty() {
         FromClause::Nothing
     } else {
@@ -382,17 +447,17 @@ fn cc_to_select_query(projection: Projection,
                         table_for_computed(comp, alias)
                     },
                     _ => {
-                        TableOrSubquery::Table(source_alias)
+                        Ok(TableOrSubquery::Table(source_alias))
                     }
                 }
             });
 
-        FromClause::TableList(TableList(tables.collect()))
+        FromClause::TableList(TableList(tables.collect::<Result<_>>()?))
     };
 
     let order = order.map_or(vec![], |vec| { vec.into_iter().map(|o| o.into()).collect() });
     let limit = if cc.empty_because.is_some() { Limit::Fixed(0) } else { limit };
-    SelectQuery {
+    Ok(SelectQuery {
         distinct: distinct,
         projection: projection,
         from: from,
@@ -400,20 +465,22 @@ fn cc_to_select_query(projection: Projection,
         constraints: cc.wheres
                        .into_iter()
                        .map(|c| c.to_constraint())
-                       .collect(),
+                       .collect::<Result<_>>()?,
         order: order,
         limit: limit,
-    }
+        offset: offset,
+        views: vec![],
+    })
 }
 
 /// Return a query that projects `1` if the `cc` matches the store, and returns no results
 /// if it doesn't.
-pub fn cc_to_exists(cc: ConjoiningClauses) -> SelectQuery {
+pub fn cc_to_exists(cc: ConjoiningClauses) -> Result<SelectQuery> {
     if cc.is_known_empty() {
         // In this case we can produce a very simple query that returns no results.
-        empty_query()
+        Ok(empty_query())
     } else {
-        cc_to_select_query(Projection::One, cc, false, vec![], None, Limit::None)
+        cc_to_select_query(Projection::One, cc, false, vec![], None, Limit::None, Offset::None)
     }
 }
 
@@ -429,6 +496,8 @@ fn re_project(mut inner: SelectQuery, projection: Projection) -> SelectQuery {
     inner.order = vec![];
     let limit = inner.limit;
     inner.limit = Limit::None;
+    let offset = inner.offset;
+    inner.offset = Offset::None;
 
     use self::Projection::*;
 
@@ -458,25 +527,29 @@ fn re_project(mut inner: SelectQuery, projection: Projection) -> SelectQuery {
             group_by: group_by,
             order: order_by,
             limit: limit,
+            offset: offset,
+            views: vec![],
         };
     }
 
     // Our pattern is `SELECT * FROM (SELECT ...) WHERE (nullable aggregate) IS NOT NULL`.  If
     // there's an `ORDER BY` in the subselect, SQL does not guarantee that the outer select will
     // respect that order.  But `ORDER BY` is relevant to the subselect when we have a `LIMIT`.
-    // Thus we lift the `ORDER BY` if there’s no `LIMIT` in the subselect, and repeat the `ORDER BY`
-    // if there is.
+    // Thus we lift the `ORDER BY` if there’s no `LIMIT` or `OFFSET` in the subselect, and repeat
+    // the `ORDER BY` if there is.
     let subselect = SelectQuery {
         distinct: outer_distinct,
         projection: projection,
         from: FromClause::TableList(TableList(vec![TableOrSubquery::Subquery(Box::new(inner))])),
         constraints: vec![],
         group_by: group_by,
-        order: match &limit {
-            &Limit::None => vec![],
-            &Limit::Fixed(_) | &Limit::Variable(_) => order_by.clone(),
+        order: match (&limit, &offset) {
+            (&Limit::None, &Offset::None) => vec![],
+            _ => order_by.clone(),
         },
         limit,
+        offset,
+        views: vec![],
     };
 
     SelectQuery {
@@ -487,15 +560,60 @@ fn re_project(mut inner: SelectQuery, projection: Projection) -> SelectQuery {
         group_by: vec![],
         order: order_by,
         limit: Limit::None, // Any limiting comes from the internal query.
+        offset: Offset::None,
+        views: vec![],
     }
 }
 
+fn fulltext_attributes(schema: &Schema) -> Vec<Entid> {
+    schema.attribute_map
+          .iter()
+          .filter(|&(_, attribute)| attribute.fulltext)
+          .map(|(&entid, _)| entid)
+          .collect()
+}
+
+/// The datoms tables the query reads in place of the real ones: those of the default source when
+/// the query doesn't run against the current state of the store, and those of each named source.
+fn datoms_views(schema: &Schema, query: &AlgebraicQuery) -> Vec<DatomsView> {
+    let default = match query.view {
+        DatabaseView::Current => None,
+        view => Some(DatomsView {
+            source: None,
+            database: "main".to_string(),
+            view: view,
+            fulltext_attributes: fulltext_attributes(schema),
+        }),
+    };
+    default.into_iter()
+           .chain(query.sources.iter().filter_map(|(var, source)| match var {
+               &SrcVar::DefaultSrc => None,
+               &SrcVar::NamedSrc(ref name) => Some(DatomsView {
+                   source: Some(name.clone()),
+                   database: source.database.clone(),
+                   view: source.view,
+                   fulltext_attributes: fulltext_attributes(&source.schema),
+               }),
+           }))
+           .collect()
+}
+
 /// Consume a provided `AlgebraicQuery` to yield a new
 /// `ProjectedSelect`.
 pub fn query_to_select(schema: &Schema, query: AlgebraicQuery) -> Result<ProjectedSelect> {
+    // Pulled attributes are fetched from the current datoms after the query runs.
+    if query.view != DatabaseView::Current && query.find_spec.columns().any(|e| match e {
+        &Element::Pull(_) => true,
+        _ => false,
+    }) {
+        bail!(ProjectorError::NotYetImplemented(format!("pull in a query against {:?}", query.view)));
+    }
+
+    let views = datoms_views(schema, &query);
+
     // TODO: we can't pass `query.limit` here if we aggregate during projection.
     // SQL-based aggregation -- `SELECT SUM(datoms00.e)` -- is fine.
-    query_projection(schema, &query).map(|e| match e {
+    query_projection(schema, &query).and_then(|e| Ok(match e {
         Either::Left(constant) => ProjectedSelect::Constant(constant),
         Either::Right(CombinedProjection {
             sql_projection,
@@ -504,25 +622,28 @@ pub fn query_to_select(schema: &Schema, query: AlgebraicQuery) -> Result<Project
             distinct,
             group_by_cols,
         }) => {
-            ProjectedSelect::Query {
-                query: match pre_aggregate_projection {
-                    // If we know we need a nested query for aggregation, build that first.
-                    Some(pre_aggregate) => {
-                        let inner = cc_to_select_query(pre_aggregate,
-                                                       query.cc,
-                                                       distinct,
-                                                       group_by_cols,
-                                                       query.order,
-                                                       query.limit);
-                        let outer = re_project(inner, sql_projection);
-                        outer
-                    },
-                    None => {
-                        cc_to_select_query(sql_projection, query.cc, distinct, group_by_cols, query.order, query.limit)
-                    },
+            let mut select = match pre_aggregate_projection {
+                // If we know we need a nested query for aggregation, build that first.
+                Some(pre_aggregate) => {
+                    let inner = cc_to_select_query(pre_aggregate,
+                                                   query.cc,
+                                                   distinct,
+                                                   group_by_cols,
+                                                   query.order,
+                                                   query.limit,
+                                                   query.offset)?;
+                    let outer = re_project(inner, sql_projection);
+                    outer
+                },
+                None => {
+                    cc_to_select_query(sql_projection, query.cc, distinct, group_by_cols, query.order, query.limit, query.offset)?
                 },
+            };
+            select.views = views;
+            ProjectedSelect::Query {
+                query: select,
                 projector: datalog_projector,
             }
         },
-    })
+    }))
 }
@@ -18,10 +18,12 @@ extern crate mentat_sql;
 use std::collections::BTreeMap;
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use edn::query::{
     FindSpec,
     Keyword,
+    SrcVar,
     Variable,
 };
 
@@ -30,15 +32,20 @@ use core_traits::{
     Entid,
     TypedValue,
     ValueType,
+    ValueTypeSet,
 };
 
 use mentat_core::{
+    AggregateRegistry,
+    CustomAggregate,
     Schema,
 };
 
 use mentat_query_algebrizer::{
+    DatabaseView,
     Known,
     QueryInputs,
+    Source,
     algebrize,
     algebrize_with_inputs,
     parse_find_string,
@@ -254,13 +261,64 @@ fn test_bound_variable_limit_affects_types() {
     let select = query_to_select(&schema, algebrized).expect("query to translate");
     let SQLQuery { sql, args } = query_to_sql(select);
 
-    // TODO: this query isn't actually correct -- we don't yet algebrize for variables that are
-    // specified in `:in` but not provided at algebrizing time. But it shows what we care about
-    // at the moment: we don't project a type column, because we know it's a Long.
-    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?limit` FROM `datoms` AS `datoms00` LIMIT $ilimit");
+    // `?limit` is specified in `:in` but not provided, so it's bound as a parameter when the
+    // query is run. We don't project a type column, because we know it's a Long.
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?limit` FROM `datoms` AS `datoms00` \
+                     WHERE (`datoms00`.value_type_tag = 5) AND `datoms00`.v = $ilimit LIMIT $ilimit");
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_offset() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"] :limit 5 :offset 10]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT 5 OFFSET 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    // SQLite needs a limit to accept an offset.
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"] :offset 10]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET 10");
+
+    // Skipping rows means we still need `DISTINCT` for a single result.
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"] :limit 1 :offset 2]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT 1 OFFSET 2");
+}
+
+#[test]
+fn test_variable_offset() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :in ?offset :where [?x :foo/bar "yyy"] :offset ?offset]"#;
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, QueryInputs::default());
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET $ioffset");
+
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?offset"), TypedValue::Long(20))]);
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET 20");
+}
+
+#[test]
+fn test_keyset_cursor() {
+    let schema = prepopulated_schema();
+
+    // Rows after the cursor sort after it on `?y`, or tie on `?y` and sort after it on `?x`.
+    let query = r#"[:find ?x ?y :where [?x :foo/bar ?y] :order ?y (desc ?x)]"#;
+    let inputs = QueryInputs::default().with_limit(10)
+                                       .with_after(vec![TypedValue::typed_string("yyy"), TypedValue::Ref(65536)]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND ((`datoms00`.v > $v0) OR (`datoms00`.v = $v0 AND `datoms00`.e < 65536)) \
+                     ORDER BY `?y` ASC, `?x` DESC \
+                     LIMIT 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
 #[test]
 fn test_unknown_attribute_keyword_value() {
     let schema = Schema::default();
@@ -628,6 +686,127 @@ fn test_not() {
     assert_eq!(args, vec![make_arg("$v0", "http://foo.com/")]);
 }
 
+#[test]
+fn test_missing() {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, Keyword::namespaced("page", "url"), 97);
+    associate_ident(&mut schema, Keyword::namespaced("page", "title"), 98);
+    for x in 97..99 {
+        add_attribute(&mut schema, x, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+    }
+
+    let query = r#"[:find ?title
+                    :where [?page :page/title ?title]
+                           [(missing? $ ?page :page/url)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?title` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 98 AND NOT EXISTS (SELECT 1 FROM `datoms` AS `datoms01` WHERE `datoms01`.e = `datoms00`.e AND `datoms01`.a = 97)");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_database_views() {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, Keyword::namespaced("page", "title"), 98);
+    associate_ident(&mut schema, Keyword::namespaced("page", "body"), 99);
+    add_attribute(&mut schema, 98, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+    add_attribute(&mut schema, 99, Attribute {
+        value_type: ValueType::String,
+        fulltext: true,
+        index: true,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?title :where [?page :page/title ?title]]"#;
+    let translate_in = |view| {
+        let known = Known::for_view(&schema, view);
+        let algebrized = algebrize(known, parse_find_string(query).expect("parse to succeed")).expect("algebrize to succeed");
+        query_to_sql(query_to_select(&schema, algebrized).expect("translate to succeed")).sql
+    };
+
+    let select = "SELECT DISTINCT `datoms00`.v AS `?title` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 98";
+    let derived = "), `fulltext_datoms` AS (\
+                   SELECT d.e AS e, d.a AS a, f.text AS v, d.tx AS tx, \
+                   d.value_type_tag AS value_type_tag, d.index_fulltext AS index_fulltext \
+                   FROM `datoms` AS d, `main`.`fulltext_values` AS f \
+                   WHERE d.index_fulltext IS NOT 0 AND d.v = f.rowid\
+                   ), `all_datoms` AS (\
+                   SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `datoms` WHERE index_fulltext IS 0 \
+                   UNION ALL \
+                   SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `fulltext_datoms`\
+                   ) ";
+
+    assert_eq!(translate_in(DatabaseView::Current), select);
+    assert_eq!(translate_in(DatabaseView::AsOf(1000)),
+               format!("WITH `datoms` AS (\
+                        SELECT e, a, v, tx, value_type_tag, index_fulltext FROM \
+                        (SELECT e, a, v, max(tx) AS tx, value_type_tag, added, a IN (99) AS index_fulltext \
+                        FROM `main`.`transactions` WHERE tx <= 1000 GROUP BY e, a, value_type_tag, v) WHERE added = 1\
+                        {}{}", derived, select));
+    assert_eq!(translate_in(DatabaseView::Since(1000)),
+               format!("WITH `datoms` AS (\
+                        SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `main`.`datoms` WHERE tx > 1000\
+                        {}{}", derived, select));
+    assert_eq!(translate_in(DatabaseView::History),
+               format!("WITH `datoms` AS (\
+                        SELECT e, a, v, tx, value_type_tag, a IN (99) AS index_fulltext, added FROM `main`.`transactions`\
+                        {}{}", derived, select));
+}
+
+#[test]
+fn test_named_sources() {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, Keyword::namespaced("page", "title"), 98);
+    add_attribute(&mut schema, 98, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+
+    // The shared store knows the same attribute by a different entid.
+    let mut shared = Schema::default();
+    associate_ident(&mut shared, Keyword::namespaced("page", "title"), 65);
+    add_attribute(&mut shared, 65, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?mine ?theirs :in $ $shared :where [?mine :page/title ?title] [$shared ?theirs :page/title ?title]]"#;
+    let inputs = QueryInputs::default()
+        .with_source(SrcVar::NamedSrc("shared".to_string()), Source::new("reference", Rc::new(shared)));
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs.clone());
+    assert_eq!(sql, "WITH `datoms$shared` AS (\
+                     SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `reference`.`datoms`\
+                     ), `fulltext_datoms$shared` AS (\
+                     SELECT d.e AS e, d.a AS a, f.text AS v, d.tx AS tx, \
+                     d.value_type_tag AS value_type_tag, d.index_fulltext AS index_fulltext \
+                     FROM `datoms$shared` AS d, `reference`.`fulltext_values` AS f \
+                     WHERE d.index_fulltext IS NOT 0 AND d.v = f.rowid\
+                     ), `all_datoms$shared` AS (\
+                     SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `datoms$shared` WHERE index_fulltext IS 0 \
+                     UNION ALL \
+                     SELECT e, a, v, tx, value_type_tag, index_fulltext FROM `fulltext_datoms$shared`\
+                     ) \
+                     SELECT DISTINCT `datoms00`.e AS `?mine`, `datoms01`.e AS `?theirs` \
+                     FROM `datoms` AS `datoms00`, `datoms$shared` AS `datoms01` \
+                     WHERE `datoms00`.a = 98 AND `datoms01`.a = 65 AND `datoms00`.v = `datoms01`.v");
+    assert_eq!(args, vec![]);
+
+    // `get-else` joins the source's own table, and uses its entid for the attribute.
+    let query = r#"[:find ?mine ?theirs :in $ $shared :where [?mine :page/title _] [(get-else $shared ?mine :page/title "none") ?theirs]]"#;
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert!(sql.ends_with("SELECT DISTINCT `datoms00`.e AS `?mine`, COALESCE(`c00`.v, $v0) AS `?theirs` \
+                           FROM `datoms` AS `datoms00` \
+                           LEFT JOIN `datoms$shared` AS `c00` \
+                           ON `c00`.e = `datoms00`.e \
+                           AND `c00`.a = 65 \
+                           WHERE `datoms00`.a = 98"), "{}", sql);
+}
+
 #[test]
 fn test_not_join() {
     let mut schema = Schema::default();
@@ -697,6 +876,74 @@ fn test_order_by() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_order_by_aggregate() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x (count ?y) :where [?x :foo/bar ?y] :order (desc (count ?y)) ?x]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT `?x` AS `?x`, count(`?y`) AS `(count ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) \
+                     GROUP BY `?x` \
+                     ORDER BY `(count ?y)` DESC, `?x` ASC");
+
+    // Aggregates that take a count are named for it.
+    let query = r#"[:find ?x (max 2 ?y) :where [?x :foo/bar ?y] :order (asc (max 2 ?y))]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert!(sql.ends_with("ORDER BY `(max 2 ?y)` ASC"), "{}", sql);
+
+    // The aggregate must be projected.
+    let known = Known::for_schema(&schema);
+    let parsed = parse_find_string(r#"[:find ?x (count ?y) :where [?x :foo/bar ?y] :order (desc (sum ?y))]"#).expect("parse to succeed");
+    assert!(algebrize(known, parsed).is_err());
+}
+
+#[test]
+fn test_order_by_computed_value() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // Variables bound by where functions can be ordered by without being in `:find`.
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(* ?v 2) ?y] :order (desc ?y)]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, (`datoms00`.v * 2) AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     ORDER BY `?y` DESC");
+}
+
+#[test]
+fn test_order_by_missing() {
+    let schema = prepopulated_typed_schema(ValueType::String);
+
+    // Entities without a value, which are bound to the default, sort last.
+    let query = r#"[:find ?x ?v :where [?x :foo/fts _] [(get-else $ ?x :foo/bar "none") ?v] :order (asc ?v :nulls-last)]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, \
+                     COALESCE(`c00`.v, $v0) AS `?v` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `c00` \
+                     ON `c00`.e = `datoms00`.e \
+                     AND `c00`.a = 99 \
+                     WHERE `datoms00`.a = 100 \
+                     ORDER BY `c00`.v IS NULL ASC, `?v` ASC");
+
+    let query = r#"[:find ?x ?v :where [?x :foo/fts _] [(get-else $ ?x :foo/bar "none") ?v] :order (desc ?v :nulls-first)]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert!(sql.ends_with("ORDER BY `c00`.v IS NULL DESC, `?v` DESC"), "{}", sql);
+
+    // Other values are never missing.
+    let query = r#"[:find ?x :where [?x :foo/bar ?y] :order (desc ?y :nulls-first)]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert!(sql.ends_with("ORDER BY `?y` DESC"), "{}", sql);
+
+    // Aggregation happens outside the query that finds the values, so it's rejected up front.
+    let known = Known::for_schema(&schema);
+    let parsed = parse_find_string(r#"[:find ?v (count ?x) :where [?x :foo/fts _] [(get-else $ ?x :foo/bar "none") ?v] :order (asc ?v :nulls-last)]"#).expect("parse to succeed");
+    assert!(algebrize(known, parsed).is_err());
+}
+
 #[test]
 fn test_complex_nested_or_join_type_projection() {
     let mut schema = Schema::default();
@@ -799,6 +1046,65 @@ fn test_ground_coll() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_input_bindings() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :in [?v ...] :where [?x :foo/bar ?v]]"#;
+    let inputs = QueryInputs::default()
+        .with_coll(Variable::from_valid_name("?v"), vec![TypedValue::typed_string("xxx"), TypedValue::typed_string("yyy")]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM \
+                     (SELECT 0 AS `?v` WHERE 0 UNION ALL VALUES ($v0), ($v1)) AS `c00`, \
+                     `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `c00`.`?v` = `datoms00`.v");
+    assert_eq!(args, vec![make_arg("$v0", "xxx"),
+                          make_arg("$v1", "yyy")]);
+
+    // An empty collection can't match anything.
+    let query = r#"[:find ?x :in [?v ...] :where [?x :foo/bar ?v]]"#;
+    let inputs = QueryInputs::default().with_coll(Variable::from_valid_name("?v"), vec![]);
+    assert_query_is_empty(inner_translate_with_inputs(&schema, query, inputs),
+                          FindSpec::FindRel(vec![var!(?x).into()]));
+
+    // Columns must be homogeneous.
+    let query = r#"[:find ?x :in [[?v _]] :where [?x :foo/bar ?v]]"#;
+    let inputs = QueryInputs::default()
+        .with_rel(vec![Variable::from_valid_name("?v")],
+                  vec![vec![TypedValue::typed_string("xxx"), TypedValue::Long(1)],
+                       vec![TypedValue::Long(2), TypedValue::Long(1)]]);
+    let known = Known::for_schema(&schema);
+    assert!(algebrize_with_inputs(known, parse_find_string(query).expect("parsed"), 0, inputs).is_err());
+}
+
+#[test]
+fn test_input_parameters() {
+    let schema = prepopulated_schema();
+
+    // An input with a type but no value is bound when the query runs.
+    let query = r#"[:find ?x :in ?v :where [?x :foo/bar ?v]]"#;
+    let inputs = QueryInputs::with_type_sequence(vec![(Variable::from_valid_name("?v"), ValueType::String)]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $iv");
+    assert_eq!(args, vec![]);
+
+    // If the attribute is unknown, so is the type of the value column.
+    let query = r#"[:find ?x :in ?v :where [?x _ ?v]]"#;
+    let inputs = QueryInputs::with_type_sequence(vec![(Variable::from_valid_name("?v"), ValueType::Long)]);
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE (`datoms00`.value_type_tag = 5) AND `datoms00`.v = $iv");
+
+    // Including in predicates.
+    let query = r#"[:find ?x :in ?min :where [?x :foo/bar ?v] [(> ?v ?min)]]"#;
+    let schema = prepopulated_typed_schema(ValueType::Long);
+    let inputs = QueryInputs::with_type_sequence(vec![(Variable::from_valid_name("?min"), ValueType::Long)]);
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v > $imin");
+}
+
 #[test]
 fn test_ground_rel() {
     let schema = prepopulated_schema();
@@ -924,7 +1230,7 @@ fn test_fulltext() {
     assert_eq!(sql, "SELECT DISTINCT `datoms01`.e AS `?entity`, \
                                      `fulltext_values00`.text AS `?value`, \
                                      `datoms01`.tx AS `?tx`, \
-                                     0e0 AS `?score` \
+                                     mentat_fulltext_score(matchinfo(`fulltext_values00`.fulltext_values, 'pcnalx')) AS `?score` \
                      FROM `fulltext_values` AS `fulltext_values00`, \
                           `datoms` AS `datoms01` \
                      WHERE `datoms01`.a = 100 \
@@ -970,8 +1276,8 @@ fn test_fulltext() {
                        AND `datoms01`.v = `fulltext_values00`.rowid \
                        AND `fulltext_values00`.text MATCH $v0 \
                        AND `datoms02`.a = 99 \
-                       AND `datoms02`.v = 0e0 \
-                       AND `datoms01`.e = `datoms02`.e");
+                       AND `datoms01`.e = `datoms02`.e \
+                       AND mentat_fulltext_score(matchinfo(`fulltext_values00`.fulltext_values, 'pcnalx')) = `datoms02`.v");
     assert_eq!(args, vec![make_arg("$v0", "needle"),]);
 
     let query = r#"[:find ?entity ?value ?tx :where [?entity :foo/bar ?score] [(fulltext $ :foo/fts "needle") [[?entity ?value ?tx ?score]]]]"#;
@@ -986,9 +1292,25 @@ fn test_fulltext() {
                        AND `datoms02`.a = 100 \
                        AND `datoms02`.v = `fulltext_values01`.rowid \
                        AND `fulltext_values01`.text MATCH $v0 \
-                       AND `datoms00`.v = 0e0 \
-                       AND `datoms00`.e = `datoms02`.e");
+                       AND `datoms00`.e = `datoms02`.e \
+                       AND `datoms00`.v = mentat_fulltext_score(matchinfo(`fulltext_values01`.fulltext_values, 'pcnalx'))");
     assert_eq!(args, vec![make_arg("$v0", "needle"),]);
+
+    // Snippets and highlights mark up the matched terms.
+    let query = r#"[:find ?snippet ?highlight
+                    :where [(fulltext $ :foo/fts "needle" ["<em>" "</em>"]) [[_ _ _ _ ?snippet ?highlight]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT snippet(`fulltext_values00`.fulltext_values, $v0, $v1, $v2, 0, -15) AS `?snippet`, \
+                                     mentat_fulltext_highlight(`fulltext_values00`.text, offsets(`fulltext_values00`.fulltext_values), $v0, $v1) AS `?highlight` \
+                     FROM `fulltext_values` AS `fulltext_values00`, \
+                          `datoms` AS `datoms01` \
+                     WHERE `datoms01`.a = 100 \
+                       AND `datoms01`.v = `fulltext_values00`.rowid \
+                       AND `fulltext_values00`.text MATCH $v3");
+    assert_eq!(args, vec![make_arg("$v0", "<em>"),
+                          make_arg("$v1", "</em>"),
+                          make_arg("$v2", "…"),
+                          make_arg("$v3", "needle"),]);
 }
 
 #[test]
@@ -1003,7 +1325,7 @@ fn test_fulltext_inputs() {
     types.insert(Variable::from_valid_name("?entity"), ValueType::Ref);
     let inputs = QueryInputs::new(types, BTreeMap::default()).expect("valid inputs");
 
-    // Without binding the value. q_once will err if you try this!
+    // Without binding the value: it's bound as a parameter when the query is run.
     let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
     assert_eq!(sql, "SELECT DISTINCT `fulltext_values00`.text AS `?val` \
                      FROM \
@@ -1011,7 +1333,8 @@ fn test_fulltext_inputs() {
                      `datoms` AS `datoms01` \
                      WHERE `datoms01`.a = 100 \
                        AND `datoms01`.v = `fulltext_values00`.rowid \
-                       AND `fulltext_values00`.text MATCH $v0");
+                       AND `fulltext_values00`.text MATCH $v0 \
+                       AND `datoms01`.e = $ientity");
     assert_eq!(args, vec![make_arg("$v0", "hello"),]);
 
     // With the value bound.
@@ -1189,6 +1512,117 @@ fn test_project_aggregates() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_project_extended_aggregates() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // `count-distinct` counts distinct values even when `:with` keeps duplicates.
+    let query = r#"[:find (count-distinct ?t)
+                    :with ?e
+                    :where
+                    [?e :foo/bar ?t]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT count(DISTINCT `?t`) AS `(count-distinct ?t)` \
+                     FROM \
+                     (SELECT DISTINCT \
+                      `datoms00`.v AS `?t`, \
+                      `datoms00`.e AS `?e` \
+                      FROM `datoms` AS `datoms00` \
+                      WHERE `datoms00`.a = 99)");
+    assert_eq!(args, vec![]);
+
+    // Statistical aggregates are nullable, just like `avg`.
+    let query = r#"[:find ?e (median ?t)
+                    :where
+                    [?e :foo/bar ?t]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT * \
+                     FROM \
+                     (SELECT `?e` AS `?e`, mentat_median(`?t`) AS `(median ?t)` \
+                      FROM \
+                      (SELECT DISTINCT \
+                       `datoms00`.e AS `?e`, \
+                       `datoms00`.v AS `?t` \
+                       FROM `datoms` AS `datoms00` \
+                       WHERE `datoms00`.a = 99) \
+                      GROUP BY `?e`) \
+                     WHERE `(median ?t)` IS NOT NULL");
+    assert_eq!(args, vec![]);
+
+    // Collections are gathered along with their type tag.
+    let query = r#"[:find ?e (max 3 ?t)
+                    :where
+                    [?e :foo/bar ?t]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT `?e` AS `?e`, mentat_collect(`?t`, 5) AS `(max 3 ?t)` \
+                     FROM \
+                     (SELECT DISTINCT \
+                      `datoms00`.e AS `?e`, \
+                      `datoms00`.v AS `?t` \
+                      FROM `datoms` AS `datoms00` \
+                      WHERE `datoms00`.a = 99) \
+                     GROUP BY `?e`");
+    assert_eq!(args, vec![]);
+}
+
+/// The difference between the greatest and least of some longs.
+struct Spread;
+
+impl CustomAggregate for Spread {
+    type State = Option<(i64, i64)>;
+
+    fn result_type(&self, input_types: ValueTypeSet) -> Option<ValueType> {
+        if input_types == ValueTypeSet::of_one(ValueType::Long) { Some(ValueType::Long) } else { None }
+    }
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(&self, state: &mut Self::State, value: TypedValue) {
+        if let TypedValue::Long(v) = value {
+            *state = Some(state.map_or((v, v), |(min, max)| (min.min(v), max.max(v))));
+        }
+    }
+
+    fn finish(&self, state: Self::State) -> Option<TypedValue> {
+        state.map(|(min, max)| TypedValue::Long(max - min))
+    }
+}
+
+#[test]
+fn test_project_custom_aggregates() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+    let mut aggregates = AggregateRegistry::default();
+    aggregates.register("spread", Arc::new(Spread));
+
+    let query = r#"[:find ?e (spread ?t)
+                    :where
+                    [?e :foo/bar ?t]]"#;
+    let known = Known::for_schema(&schema).with_aggregates(&aggregates);
+    let algebrized = algebrize(known, parse_find_string(query).expect("parse to succeed")).expect("algebrize to succeed");
+    let SQLQuery { sql, args } = query_to_sql(query_to_select(&schema, algebrized).expect("translate to succeed"));
+
+    // The value's type tag is known, and is passed to the aggregate with the value.
+    assert_eq!(sql, "SELECT * \
+                     FROM \
+                     (SELECT `?e` AS `?e`, mentat_custom_spread(`?t`, 5) AS `(spread ?t)` \
+                      FROM \
+                      (SELECT DISTINCT \
+                       `datoms00`.e AS `?e`, \
+                       `datoms00`.v AS `?t` \
+                       FROM `datoms` AS `datoms00` \
+                       WHERE `datoms00`.a = 99) \
+                      GROUP BY `?e`) \
+                     WHERE `(spread ?t)` IS NOT NULL");
+    assert_eq!(args, vec![]);
+
+    // Without the registry, the aggregate is unknown.
+    let known = Known::for_schema(&schema);
+    let algebrized = algebrize(known, parse_find_string(query).expect("parse to succeed")).expect("algebrize to succeed");
+    assert!(query_to_select(&schema, algebrized).is_err());
+}
+
 #[test]
 fn test_project_the() {
     let schema = prepopulated_typed_schema(ValueType::Long);
@@ -1329,3 +1763,153 @@ fn test_tx_data() {
                      AND `transactions01`.tx = `transactions00`.tx");
     assert_eq!(args, vec![]);
 }
+
+#[test]
+fn test_get_else() {
+    let mut schema = prepopulated_typed_schema(ValueType::String);
+    associate_ident(&mut schema, Keyword::namespaced("foo", "many"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::Long,
+        multival: true,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?x ?v :where [?x :foo/fts _] [(get-else $ ?x :foo/bar "none") ?v]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, \
+                     COALESCE(`c00`.v, $v0) AS `?v` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `c00` \
+                     ON `c00`.e = `datoms00`.e \
+                     AND `c00`.a = 99 \
+                     WHERE `datoms00`.a = 100");
+    assert_eq!(args, vec![make_arg("$v0", "none")]);
+
+    // A known entity doesn't need anything on the left.
+    let query = r#"[:find ?v . :where [(get-else $ 65536 :foo/bar "none") ?v]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT COALESCE(`c00`.v, $v0) AS `?v` \
+                     FROM (SELECT 1) \
+                     LEFT JOIN `datoms` AS `c00` \
+                     ON `c00`.e = 65536 \
+                     AND `c00`.a = 99 \
+                     LIMIT 1");
+    assert_eq!(args, vec![make_arg("$v0", "none")]);
+
+    // The default must agree with the attribute.
+    let known = Known::for_schema(&schema);
+    let parsed = parse_find_string(r#"[:find ?x ?v :where [?x :foo/fts _] [(get-else $ ?x :foo/bar 5) ?v]]"#).expect("parse to succeed");
+    assert!(algebrize(known, parsed).is_err());
+
+    // Cardinality-many attributes could yield several values.
+    let parsed = parse_find_string(r#"[:find ?x ?v :where [?x :foo/fts _] [(get-else $ ?x :foo/many 5) ?v]]"#).expect("parse to succeed");
+    assert!(algebrize(known, parsed).is_err());
+}
+
+#[test]
+fn test_computed_functions() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x ?y :where [?x :foo/bar ?v] [(+ ?v 1) ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, (`datoms00`.v + 1) AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+
+    // Mixing longs and doubles yields a double.
+    let query = r#"[:find ?y :where [?x :foo/bar ?v] [?z :foo/bar ?w] [(- ?v ?w 2.5) ?y]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (CAST(`datoms00`.v AS REAL) - `datoms01`.v - 2.5e0) AS `?y` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 AND `datoms01`.a = 99");
+
+    // Dividing by zero yields `NULL`, which we don't bind.
+    let query = r#"[:find ?y :where [?x :foo/bar ?v] [(mod ?v 3) ?y]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (((`datoms00`.v % 3) + 3) % 3) AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND (((`datoms00`.v % 3) + 3) % 3) IS NOT NULL");
+
+    // Outputs can be used in later clauses.
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(quot ?v 2) ?y] [(> ?y 10)]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND (`datoms00`.v / 2) IS NOT NULL \
+                     AND (`datoms00`.v / 2) > 10");
+
+    let schema = prepopulated_schema();
+    let query = r#"[:find ?y :where [?x :foo/bar ?v] [(upper-case ?v) ?u] [(str ?u "!") ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (upper(`datoms00`.v) || $v0) AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![make_arg("$v0", "!")]);
+
+    let query = r#"[:find ?b ?s :where [?x :foo/bar ?v] [(str/starts-with? ?v "a") ?b] [(re-find "[0-9]+" ?v) ?n] [(subs ?n 1 3) ?s]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (substr(`datoms00`.v, 1, length($v0)) = $v0) AS `?b`, \
+                     substr(re_find($v1, `datoms00`.v), 1 + 1, 3 - 1) AS `?s` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND re_find($v1, `datoms00`.v) IS NOT NULL");
+    assert_eq!(args, vec![make_arg("$v0", "a"),
+                          make_arg("$v1", "[0-9]+")]);
+
+    let schema = prepopulated_typed_schema(ValueType::Instant);
+    let query = r#"[:find ?d :where [?x :foo/bar ?t] [(day-start ?t) ?s] [(-millis ?s 1000) ?d]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT ((`datoms00`.v - (((`datoms00`.v % 86400000000) + 86400000000) % 86400000000)) - (1000 * 1000)) AS `?d` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+}
+
+#[test]
+fn test_fulltext_attributes() {
+    let mut schema = prepopulated_typed_schema(ValueType::Ref);
+    associate_ident(&mut schema, Keyword::namespaced("foo", "notes"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        fulltext: true,
+        ..Default::default()
+    });
+
+    // `_` searches every fulltext attribute, and the matched attribute can be bound.
+    let query = r#"[:find ?entity ?attr :where [(fulltext $ _ "needle") [[?entity _ _ _ _ _ ?attr]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms01`.e AS `?entity`, `datoms01`.a AS `?attr` \
+                     FROM `fulltext_values` AS `fulltext_values00`, \
+                          `datoms` AS `datoms01` \
+                     WHERE ((`datoms01`.a = 100) OR (`datoms01`.a = 101)) \
+                       AND `datoms01`.v = `fulltext_values00`.rowid \
+                       AND `fulltext_values00`.text MATCH $v0");
+    assert_eq!(args, vec![make_arg("$v0", "needle"),]);
+
+    // Only the fulltext attributes of a set can match.
+    let query = r#"[:find ?entity :where [(fulltext $ #{:foo/fts :foo/bar} "needle") [[?entity]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms01`.e AS `?entity` \
+                     FROM `fulltext_values` AS `fulltext_values00`, \
+                          `datoms` AS `datoms01` \
+                     WHERE `datoms01`.a = 100 \
+                       AND `datoms01`.v = `fulltext_values00`.rowid \
+                       AND `fulltext_values00`.text MATCH $v0");
+    assert_eq!(args, vec![make_arg("$v0", "needle"),]);
+
+    // An attribute bound by an earlier clause must be a fulltext attribute.
+    let query = r#"[:find ?entity :where [_ :foo/bar ?a] [(fulltext $ ?a "needle") [[?entity]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms02`.e AS `?entity` \
+                     FROM `datoms` AS `datoms00`, \
+                          `fulltext_values` AS `fulltext_values01`, \
+                          `datoms` AS `datoms02` \
+                     WHERE `datoms00`.a = 99 \
+                       AND `datoms02`.a = `datoms00`.v \
+                       AND ((`datoms02`.a = 100) OR (`datoms02`.a = 101)) \
+                       AND `datoms02`.v = `fulltext_values01`.rowid \
+                       AND `fulltext_values01`.text MATCH $v0");
+    assert_eq!(args, vec![make_arg("$v0", "needle"),]);
+}
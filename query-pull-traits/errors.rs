@@ -18,6 +18,7 @@ use db_traits::errors::{
 
 use core_traits::{
     Entid,
+    ValueType,
 };
 
 pub type Result<T> = std::result::Result<T, PullError>;
@@ -30,6 +31,18 @@ pub enum PullError {
     #[error(":db/id repeated")]
     RepeatedDbId,
 
+    #[error("attribute {0} is not a ref, so it can't be pulled with a nested pattern")]
+    NonRefNestedAttribute(String),
+
+    #[error("attribute {0} is not a ref, so it can't be pulled in reverse")]
+    NonRefReverseAttribute(String),
+
+    #[error("{0} can't be used as a default value")]
+    UnsupportedDefaultValue(String),
+
+    #[error("default value {0} for attribute {1} is not a {2}")]
+    DefaultValueTypeMismatch(String, String, ValueType),
+
     #[error(transparent)]
     DbError(#[from] DbError),
 }
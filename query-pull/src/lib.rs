@@ -79,9 +79,11 @@ use core_traits::{
     Entid,
     TypedValue,
     StructuredMap,
+    ValueType,
 };
 
 use mentat_core::{
+    CachedAttributes,
     Cloned,
     HasSchema,
     Keyword,
@@ -92,9 +94,13 @@ use mentat_core::{
 use mentat_db::cache;
 
 use edn::query::{
+    NonIntegerConstant,
     NamedPullAttribute,
     PullAttributeSpec,
     PullConcreteAttribute,
+    PullDefaultValue,
+    PullMapEntry,
+    PullMapValue,
 };
 
 use query_pull_traits::errors::{
@@ -104,16 +110,24 @@ use query_pull_traits::errors::{
 
 type PullResults = BTreeMap<Entid, ValueRc<StructuredMap>>;
 
+/// How deep a wildcard pull goes into components of components.
+const MAX_COMPONENT_DEPTH: u64 = 16;
+
+/// Pull `attributes` of `entity`. These can be attribute entids, or any pull pattern, including
+/// map specs like `{:person/friend [:person/name]}` and reversed attributes like `:car/_owner`.
+/// Reversed attributes are looked up in `cache` if they're cached in reverse there.
 pub fn pull_attributes_for_entity<A>(schema: &Schema,
                                      db: &rusqlite::Connection,
+                                     cache: Option<&dyn CachedAttributes>,
                                      entity: Entid,
                                      attributes: A) -> Result<StructuredMap>
-    where A: IntoIterator<Item=Entid> {
+    where A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
     let attrs = attributes.into_iter()
-                          .map(|e| PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(e).into()))
+                          .map(|a| a.into())
                           .collect();
     Puller::prepare(schema, attrs)?
-        .pull(schema, db, once(entity))
+        .pull_with_cache(schema, db, cache, once(entity))
         .map(|m| m.into_iter()
                   .next()
                   .map(|(k, vs)| {
@@ -125,22 +139,51 @@ pub fn pull_attributes_for_entity<A>(schema: &Schema,
 
 pub fn pull_attributes_for_entities<E, A>(schema: &Schema,
                                           db: &rusqlite::Connection,
+                                          cache: Option<&dyn CachedAttributes>,
                                           entities: E,
                                           attributes: A) -> Result<PullResults>
     where E: IntoIterator<Item=Entid>,
-          A: IntoIterator<Item=Entid> {
+          A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
     let attrs = attributes.into_iter()
-                          .map(|e| PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(e).into()))
+                          .map(|a| a.into())
                           .collect();
     Puller::prepare(schema, attrs)?
-        .pull(schema, db, entities)
+        .pull_with_cache(schema, db, cache, entities)
+}
+
+/// An attribute to pull, and the direction to pull it in: forward, from an entity to its values,
+/// or in reverse, like `:car/_owner`, from an entity to the entities that refer to it.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum PulledAttribute {
+    Forward(Entid),
+    Reverse(Entid),
+}
+
+impl PulledAttribute {
+    /// The type of the values pulled, if the attribute exists.
+    fn value_type(&self, schema: &Schema) -> Option<ValueType> {
+        match self {
+            &PulledAttribute::Forward(a) => schema.attribute_for_entid(a).map(|a| a.value_type),
+            &PulledAttribute::Reverse(_) => Some(ValueType::Ref),
+        }
+    }
+
+    /// Whether an entity can have more than one value. In reverse, only a unique attribute has
+    /// at most one entity for each value.
+    fn is_multival(&self, schema: &Schema) -> bool {
+        match self {
+            &PulledAttribute::Forward(a) => schema.attribute_for_entid(a).map_or(false, |a| a.multival),
+            &PulledAttribute::Reverse(a) => schema.attribute_for_entid(a).map_or(true, |a| a.unique.is_none()),
+        }
+    }
 }
 
 /// A `Puller` constructs on demand a map from a provided set of entity IDs to a set of structured maps.
 pub struct Puller {
     // The domain of this map is the set of attributes to fetch.
     // The range is the set of aliases to use in the output.
-    attributes: BTreeMap<Entid, ValueRc<Keyword>>,
+    attributes: BTreeMap<PulledAttribute, ValueRc<Keyword>>,
     attribute_spec: cache::AttributeSpec,
 
     // If this is set, each pulled entity is contributed to its own output map, labeled with this
@@ -149,6 +192,20 @@ pub struct Puller {
     //  Mentat can use `TypedValue::Ref(1234)`, but it's sometimes convenient to fetch the entity ID
     // itself as part of a pull expression: `{:person 1234, :person/name "Peter"}`.
     db_id_alias: Option<ValueRc<Keyword>>,
+
+    // Ref attributes whose values are themselves pulled, from map specs like
+    // `{:person/friend [:person/name]}`, and the pullers for the entities they refer to.
+    nested: BTreeMap<PulledAttribute, Puller>,
+
+    // Ref attributes whose values are pulled with this puller again, like `{:node/child ...}`,
+    // and how deep to go, if there's a limit.
+    recursive: BTreeMap<PulledAttribute, Option<u64>>,
+
+    // The most values to pull for cardinality-many attributes, from `(limit :attr n)`.
+    limits: BTreeMap<PulledAttribute, u64>,
+
+    // Values for entities that have none, from `(default :attr value)`.
+    defaults: BTreeMap<PulledAttribute, Binding>,
 }
 
 impl Puller {
@@ -163,57 +220,147 @@ impl Puller {
                     .ok_or_else(|| PullError::UnnamedAttribute(*i))
         };
 
-        let mut names: BTreeMap<Entid, ValueRc<Keyword>> = Default::default();
-        let mut attrs: BTreeSet<Entid> = Default::default();
+        let mut names: BTreeMap<PulledAttribute, ValueRc<Keyword>> = Default::default();
         let db_id = ::std::rc::Rc::new(Keyword::namespaced("db", "id"));
         let mut db_id_alias = None;
+        let mut nested: BTreeMap<PulledAttribute, Puller> = Default::default();
+        let mut recursive: BTreeMap<PulledAttribute, Option<u64>> = Default::default();
+        let mut limits: BTreeMap<PulledAttribute, u64> = Default::default();
+        let mut defaults: BTreeMap<PulledAttribute, Binding> = Default::default();
+        let mut wildcard = false;
+
+        // The attribute a pattern names, in the direction it's pulled, and the name to use for it
+        // in the output, or `None` if there's no such attribute.
+        let resolve = |attribute: &NamedPullAttribute| -> Result<Option<(PulledAttribute, ValueRc<Keyword>)>> {
+            let pulled = match attribute.attribute {
+                PullConcreteAttribute::Ident(ref i) if i.is_backward() => {
+                    let entid = match schema.get_entid(&i.to_reversed()) {
+                        Some(entid) => entid.into(),
+                        None => return Ok(None),
+                    };
+                    if PulledAttribute::Forward(entid).value_type(schema) != Some(ValueType::Ref) {
+                        Err(PullError::NonRefReverseAttribute(i.to_string()))?
+                    }
+                    PulledAttribute::Reverse(entid)
+                },
+                PullConcreteAttribute::Ident(ref i) => {
+                    match schema.get_entid(i) {
+                        Some(entid) => PulledAttribute::Forward(entid.into()),
+                        None => return Ok(None),
+                    }
+                },
+                PullConcreteAttribute::Entid(entid) => PulledAttribute::Forward(entid),
+            };
+            let name = match (&attribute.alias, &attribute.attribute) {
+                (&Some(ref alias), _) => alias.to_value_rc(),
+                (&None, &PullConcreteAttribute::Ident(ref i)) => i.to_value_rc(),
+                (&None, &PullConcreteAttribute::Entid(ref entid)) => lookup_name(entid)?,
+            };
+            Ok(Some((pulled, name)))
+        };
 
         for attr in attributes.iter() {
             match attr {
                 &PullAttributeSpec::Wildcard => {
+                    wildcard = true;
+
+                    // Attributes named elsewhere in the pattern keep their aliases.
                     let attribute_ids = schema.attribute_map.keys();
                     for id in attribute_ids {
-                        names.insert(*id, lookup_name(id)?);
-                        attrs.insert(*id);
+                        let pulled = PulledAttribute::Forward(*id);
+                        if !names.contains_key(&pulled) {
+                            names.insert(pulled, lookup_name(id)?);
+                        }
                     }
-                    break;
                 },
-                &PullAttributeSpec::Attribute(NamedPullAttribute {
-                    ref attribute,
-                    ref alias,
-                }) => {
-                    let alias = alias.as_ref()
-                                     .map(|ref r| r.to_value_rc());
-                    match attribute {
+                &PullAttributeSpec::Attribute(ref attribute) => {
+                    match attribute.attribute {
                         // Handle :db/id.
-                        &PullConcreteAttribute::Ident(ref i) if i.as_ref() == db_id.as_ref() => {
+                        PullConcreteAttribute::Ident(ref i) if i.as_ref() == db_id.as_ref() => {
                             // We only allow :db/id once.
                             if db_id_alias.is_some() {
                                 Err(PullError::RepeatedDbId)?
                             }
+                            let alias = attribute.alias.as_ref()
+                                                       .map(|ref r| r.to_value_rc());
                             db_id_alias = Some(alias.unwrap_or_else(|| db_id.to_value_rc()));
                         },
-                        &PullConcreteAttribute::Ident(ref i) => {
-                            if let Some(entid) = schema.get_entid(i) {
-                                let name = alias.unwrap_or_else(|| i.to_value_rc());
-                                names.insert(entid.into(), name);
-                                attrs.insert(entid.into());
+                        _ => {
+                            if let Some((pulled, name)) = resolve(attribute)? {
+                                names.insert(pulled, name);
                             }
                         },
-                        &PullConcreteAttribute::Entid(ref entid) => {
-                            let name = alias.map(Ok).unwrap_or_else(|| lookup_name(entid))?;
-                            names.insert(*entid, name);
-                            attrs.insert(*entid);
-                        },
                     }
                 },
+                &PullAttributeSpec::LimitedAttribute(ref attribute, limit) => {
+                    if let Some((pulled, name)) = resolve(attribute)? {
+                        names.insert(pulled, name);
+                        limits.insert(pulled, limit);
+                    }
+                },
+                &PullAttributeSpec::DefaultedAttribute(ref attribute, ref default) => {
+                    if let Some((pulled, name)) = resolve(attribute)? {
+                        let binding = default_binding(schema, &attribute.attribute, pulled, default)?;
+                        names.insert(pulled, name);
+                        defaults.insert(pulled, binding);
+                    }
+                },
+                &PullAttributeSpec::PullMapSpec(ref entries) => {
+                    for &PullMapEntry { ref attribute, limit, ref value } in entries.iter() {
+                        let (pulled, name) = match resolve(attribute)? {
+                            Some(resolved) => resolved,
+                            None => continue,
+                        };
+                        if pulled.value_type(schema) != Some(ValueType::Ref) {
+                            Err(PullError::NonRefNestedAttribute(name.to_string()))?
+                        }
+                        names.insert(pulled, name);
+                        if let Some(limit) = limit {
+                            limits.insert(pulled, limit);
+                        }
+                        match value {
+                            &PullMapValue::Patterns(ref patterns) => {
+                                nested.insert(pulled, Puller::prepare(schema, patterns.clone())?);
+                            },
+                            &PullMapValue::Recursion(depth) => {
+                                recursive.insert(pulled, depth);
+                            },
+                        }
+                    }
+                },
+            }
+        }
+
+        // As in Datomic, a wildcard also pulls the entities that component attributes refer to,
+        // with the same pattern, unless the pattern says how to pull them. Components shouldn't
+        // refer back to their owners, but if they do, those are left as refs.
+        if wildcard {
+            for (a, attribute) in schema.attribute_map.iter() {
+                let pulled = PulledAttribute::Forward(*a);
+                if attribute.component &&
+                   attribute.value_type == ValueType::Ref &&
+                   !nested.contains_key(&pulled) &&
+                   !recursive.contains_key(&pulled) {
+                    recursive.insert(pulled, Some(MAX_COMPONENT_DEPTH));
+                }
             }
         }
 
+        let attrs: BTreeSet<Entid> = names.keys()
+                                          .filter_map(|pulled| match pulled {
+                                              &PulledAttribute::Forward(a) => Some(a),
+                                              &PulledAttribute::Reverse(_) => None,
+                                          })
+                                          .collect();
+
         Ok(Puller {
             attributes: names,
             attribute_spec: cache::AttributeSpec::specified(&attrs, schema),
             db_id_alias,
+            nested,
+            recursive,
+            limits,
+            defaults,
         })
     }
 
@@ -222,16 +369,39 @@ impl Puller {
                    db: &rusqlite::Connection,
                    entities: E) -> Result<PullResults>
         where E: IntoIterator<Item=Entid> {
+        self.pull_with_cache(schema, db, None, entities)
+    }
+
+    /// Like `pull`, but reversed attributes are looked up in `cache` if they're cached in reverse
+    /// there, rather than fetched.
+    pub fn pull_with_cache<E>(&self,
+                              schema: &Schema,
+                              db: &rusqlite::Connection,
+                              cache: Option<&dyn CachedAttributes>,
+                              entities: E) -> Result<PullResults>
+        where E: IntoIterator<Item=Entid> {
+        self.pull_below(schema, db, cache, entities, &BTreeSet::new(), &BTreeMap::new())
+    }
+
+    /// Pull `entities`, which were referred to by entities in `seen` further up a recursive pull.
+    /// `depths` is how many more levels each limited recursive attribute may go.
+    fn pull_below<E>(&self,
+                     schema: &Schema,
+                     db: &rusqlite::Connection,
+                     cache: Option<&dyn CachedAttributes>,
+                     entities: E,
+                     seen: &BTreeSet<Entid>,
+                     depths: &BTreeMap<PulledAttribute, u64>) -> Result<PullResults>
+        where E: IntoIterator<Item=Entid> {
         // We implement pull by:
         // - Generating `AttributeCaches` for the provided attributes and entities.
         //   TODO: it would be nice to invert the cache as we build it, rather than have to invert it here.
-        // - Recursing. (TODO: we'll need AttributeCaches to not overwrite in case of recursion! And
-        //   ideally not do excess work when some entity/attribute pairs are known.)
+        // - Recursing, a layer of referred-to entities at a time. (TODO: ideally not do excess work
+        //   when some entity/attribute pairs are known.)
         // - Building a structure by walking the pull expression with the caches.
-        // TODO: limits.
 
         // Build a cache for these attributes and entities.
-        // TODO: use the store's existing cache!
+        // TODO: use the store's existing cache for forward attributes, too!
         let entities: Vec<Entid> = entities.into_iter().collect();
         let caches = cache::AttributeCaches::make_cache_for_entities_and_attributes(
             schema,
@@ -239,6 +409,21 @@ impl Puller {
             self.attribute_spec.clone(),
             &entities)?;
 
+        // Reversed attributes that the store doesn't cache in reverse are fetched through the
+        // VAET index.
+        let is_cached_reverse = |a: Entid| cache.map_or(false, |c| c.is_attribute_cached_reverse(a));
+        let uncached_reverse: BTreeSet<Entid> = self.attributes.keys()
+                                                    .filter_map(|pulled| match pulled {
+                                                        &PulledAttribute::Reverse(a) if !is_cached_reverse(a) => Some(a),
+                                                        _ => None,
+                                                    })
+                                                    .collect();
+        let reverse_caches = cache::AttributeCaches::make_reverse_cache_for_values_and_attributes(
+            schema,
+            db,
+            &uncached_reverse,
+            &entities)?;
+
         // Now construct the appropriate result format.
         // TODO: should we walk `e` then `a`, or `a` then `e`? Possibly the right answer
         // is just to collect differently!
@@ -254,25 +439,199 @@ impl Puller {
             }
         }
 
-        for (name, cache) in self.attributes.iter().filter_map(|(a, name)|
-            caches.forward_attribute_cache_for_attribute(schema, *a)
-                  .map(|cache| (name.clone(), cache))) {
+        for (&pulled, name) in self.attributes.iter() {
+            let bindings: Vec<(Entid, Binding)> = match pulled {
+                PulledAttribute::Forward(a) => {
+                    match caches.forward_attribute_cache_for_attribute(schema, a) {
+                        Some(cache) => entities.iter()
+                                               .filter_map(|e| cache.binding_for_e(*e).map(|b| (*e, b)))
+                                               .collect(),
+                        None => continue,
+                    }
+                },
+                PulledAttribute::Reverse(a) => {
+                    let reverse: &dyn CachedAttributes = match cache {
+                        Some(cache) if is_cached_reverse(a) => cache,
+                        _ => &reverse_caches,
+                    };
+                    let multival = pulled.is_multival(schema);
+                    entities.iter()
+                            .filter_map(|e| reverse_binding(reverse, a, *e, multival).map(|b| (*e, b)))
+                            .collect()
+                },
+            };
+            let limit = self.limits.get(&pulled).cloned();
+            let bindings: Vec<(Entid, Binding)> = bindings.into_iter()
+                                                          .map(|(e, b)| match limit {
+                                                              Some(l) => (e, limit_binding(b, l)),
+                                                              None => (e, b),
+                                                          })
+                                                          .collect();
 
-            for e in entities.iter() {
-                if let Some(binding) = cache.binding_for_e(*e) {
-                    let mut r = maps.entry(*e)
-                                    .or_insert(ValueRc::new(StructuredMap::default()));
+            // The entities referred to by a nested or recursive attribute are pulled together, a
+            // layer at a time, and their maps take the place of the refs.
+            let mut referred = BTreeSet::new();
+            for &(_, ref binding) in bindings.iter() {
+                collect_refs(binding, &mut referred);
+            }
+            let pulled_below = if let Some(puller) = self.nested.get(&pulled) {
+                Some((puller.pull_with_cache(schema, db, cache, referred.iter().cloned())?, referred))
+            } else if let Some(depth) = self.recursive.get(&pulled) {
+                // An entity that's already been pulled, here or further up, is left as a ref, as
+                // are those past the depth limit.
+                let remaining = depths.get(&pulled).cloned().or(*depth);
+                if remaining == Some(0) {
+                    None
+                } else {
+                    let mut seen = seen.clone();
+                    seen.extend(entities.iter().cloned());
+                    let referred: BTreeSet<Entid> = referred.difference(&seen).cloned().collect();
+                    let mut depths = depths.clone();
+                    if let Some(remaining) = remaining {
+                        depths.insert(pulled, remaining - 1);
+                    }
+                    Some((self.pull_below(schema, db, cache, referred.iter().cloned(), &seen, &depths)?, referred))
+                }
+            } else {
+                None
+            };
+            let bindings = match pulled_below {
+                None => bindings,
+                Some((mut pulled_below, referred)) => {
+                    // Entities with none of the pulled attributes are empty maps.
+                    for e in referred {
+                        pulled_below.entry(e).or_insert_with(|| ValueRc::new(StructuredMap::default()));
+                    }
+                    bindings.into_iter()
+                            .map(|(e, binding)| (e, replace_refs(binding, &pulled_below)))
+                            .collect()
+                },
+            };
+
+            for (e, binding) in bindings {
+                let mut r = maps.entry(e)
+                                .or_insert(ValueRc::new(StructuredMap::default()));
+
+                // Get into the inner map so we can accumulate a value.
+                // We can unwrap here because we created all of these maps…
+                let mut m = ValueRc::get_mut(r).unwrap();
 
-                    // Get into the inner map so we can accumulate a value.
-                    // We can unwrap here because we created all of these maps…
-                    let mut m = ValueRc::get_mut(r).unwrap();
+                m.insert(name.clone(), binding);
+            }
+        }
 
-                    m.insert(name.clone(), binding);
+        for (pulled, default) in self.defaults.iter() {
+            let name = &self.attributes[pulled];
+            for e in entities.iter() {
+                let r = maps.entry(*e)
+                            .or_insert(ValueRc::new(StructuredMap::default()));
+                let m = ValueRc::get_mut(r).unwrap();
+                if !m.0.contains_key(name) {
+                    m.insert(name.clone(), default.clone());
                 }
             }
         }
 
         Ok(maps)
     }
+}
+
+/// The entities that refer to `v` through the ref attribute `a`, or `None` if there are none.
+fn reverse_binding(cache: &dyn CachedAttributes, a: Entid, v: Entid, multival: bool) -> Option<Binding> {
+    let value = TypedValue::Ref(v);
+    if multival {
+        cache.get_entids_for_value(a, &value)
+             .and_then(|es| if es.is_empty() {
+                 None
+             } else {
+                 Some(Binding::Vec(ValueRc::new(es.iter().map(|e| TypedValue::Ref(*e).into()).collect())))
+             })
+    } else {
+        cache.get_entid_for_value(a, &value)
+             .map(|e| TypedValue::Ref(e).into())
+    }
+}
+
+fn collect_refs(binding: &Binding, into: &mut BTreeSet<Entid>) {
+    match binding {
+        &Binding::Scalar(TypedValue::Ref(e)) => {
+            into.insert(e);
+        },
+        &Binding::Vec(ref bindings) => {
+            for b in bindings.iter() {
+                collect_refs(b, into);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// Replace each ref in `binding` with the map pulled for it, if there is one.
+fn replace_refs(binding: Binding, pulled: &PullResults) -> Binding {
+    match binding {
+        Binding::Scalar(TypedValue::Ref(e)) => {
+            match pulled.get(&e) {
+                Some(m) => Binding::Map(m.clone()),
+                None => Binding::Scalar(TypedValue::Ref(e)),
+            }
+        },
+        Binding::Vec(bindings) => {
+            Binding::Vec(ValueRc::new(bindings.iter().cloned().map(|b| replace_refs(b, pulled)).collect()))
+        },
+        b => b,
+    }
+}
+
+/// Keep at most `limit` of the values of a cardinality-many attribute.
+fn limit_binding(binding: Binding, limit: u64) -> Binding {
+    match binding {
+        Binding::Vec(ref bindings) if bindings.len() as u64 > limit => {
+            Binding::Vec(ValueRc::new(bindings.iter().take(limit as usize).cloned().collect()))
+        },
+        b => b,
+    }
+}
 
+/// The value to pull for an attribute that an entity doesn't have. Integers and keywords are
+/// entids and idents for ref attributes. Cardinality-many attributes get a vector of one value.
+/// Like the default of `get-else`, the value must have the attribute's type.
+fn default_binding(schema: &Schema, name: &PullConcreteAttribute, attribute: PulledAttribute, default: &PullDefaultValue) -> Result<Binding> {
+    let value_type = attribute.value_type(schema);
+    let value = match default {
+        &PullDefaultValue::EntidOrInteger(i) => {
+            match value_type {
+                Some(ValueType::Ref) => TypedValue::Ref(i),
+                Some(ValueType::Double) => TypedValue::Double((i as f64).into()),
+                _ => TypedValue::Long(i),
+            }
+        },
+        &PullDefaultValue::IdentOrKeyword(ref k) => {
+            match (value_type, schema.get_entid(k)) {
+                (Some(ValueType::Ref), Some(entid)) => TypedValue::Ref(entid.into()),
+                _ => TypedValue::Keyword(k.to_value_rc()),
+            }
+        },
+        &PullDefaultValue::Constant(ref c) => {
+            match c {
+                &NonIntegerConstant::Boolean(v) => TypedValue::Boolean(v),
+                &NonIntegerConstant::Float(v) => TypedValue::Double(v),
+                &NonIntegerConstant::Text(ref v) => v.clone().into(),
+                &NonIntegerConstant::Instant(v) => TypedValue::Instant(v),
+                &NonIntegerConstant::Uuid(v) => TypedValue::Uuid(v),
+                &NonIntegerConstant::BigInteger(ref v) => {
+                    Err(PullError::UnsupportedDefaultValue(v.to_string()))?
+                },
+            }
+        },
+    };
+    if let Some(value_type) = value_type {
+        if value.value_type() != value_type {
+            Err(PullError::DefaultValueTypeMismatch(default.to_string(), name.to_string(), value_type))?
+        }
+    }
+    if attribute.is_multival(schema) {
+        Ok(Binding::Vec(ValueRc::new(vec![value.into()])))
+    } else {
+        Ok(value.into())
+    }
 }
@@ -30,12 +30,19 @@ use mentat_core::{
 use edn::query::{
     Direction,
     Limit,
+    Offset,
+    SrcVarName,
     Variable,
 };
 
 use mentat_query_algebrizer::{
     Column,
+    ComputedFunction,
+    ComputedValue,
+    DatabaseView,
+    DatomsTable,
     OrderBy,
+    OrderColumn,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
@@ -75,10 +82,15 @@ pub enum ColumnOrExpression {
     // needs special treatment.
     NullableAggregate(Box<Expression>, ValueType),      // Track the return type.
     Expression(Box<Expression>, ValueType),             // Track the return type.
+    Parameter(Variable),                                // Bound when the query is run.
 }
 
 pub enum Expression {
     Unary { sql_op: &'static str, arg: ColumnOrExpression },
+
+    /// An aggregate function over several arguments, or over only their distinct values:
+    /// `count(DISTINCT x)`.
+    Aggregate { sql_op: String, distinct: bool, args: Vec<ColumnOrExpression> },
 }
 
 /// `QueryValue` and `ColumnOrExpression` are almost identical… merge somehow?
@@ -89,6 +101,7 @@ impl From<QueryValue> for ColumnOrExpression {
             QueryValue::Entid(e) => ColumnOrExpression::Entid(e),
             QueryValue::PrimitiveLong(v) => ColumnOrExpression::Long(v),
             QueryValue::TypedValue(v) => ColumnOrExpression::Value(v),
+            QueryValue::Parameter(var) => ColumnOrExpression::Parameter(var),
         }
     }
 }
@@ -210,6 +223,19 @@ pub enum TableOrSubquery {
     Union(Vec<SelectQuery>, TableAlias),
     Subquery(Box<SelectQuery>),
     Values(Values, TableAlias),
+
+    /// A recursive CTE, like "(WITH RECURSIVE rule AS (base UNION recursive) SELECT * FROM rule)".
+    /// The recursive arms refer to the CTE by the name of `DatomsTable::RecursiveRule`.
+    Recursive(Vec<SelectQuery>, Vec<SelectQuery>, TableAlias),
+
+    /// Like "datoms AS c01 ON (...)", or "`datoms$a` AS c01 ON (...)" for a table of the named
+    /// source. This can only appear in a `TableList`, which is responsible for joining it with
+    /// "LEFT JOIN" rather than a comma.
+    LeftJoin(Option<SrcVarName>, SourceAlias, Vec<Constraint>),
+
+    /// A table of the named source that lives in the given database, like "`datoms$a` AS datoms01".
+    /// Its datoms tables are defined by the query's `DatomsView` for that source.
+    SourceTable(SrcVarName, String, SourceAlias),
 }
 
 pub enum Values {
@@ -229,6 +255,32 @@ pub enum FromClause {
     Nothing,
 }
 
+/// The datoms of one of a query's sources, when they aren't simply the tables of the default
+/// store. These are rendered as CTEs named for the datoms tables: for the default source they
+/// shadow `datoms`, `fulltext_datoms`, and `all_datoms`, so the rest of the query is unchanged;
+/// for a named source they're distinguished by the source's name. See `source_table_name`.
+pub struct DatomsView {
+    /// The name of the source variable, or `None` for the default source.
+    pub source: Option<SrcVarName>,
+
+    /// The SQLite database holding the store: `main`, or the name it was attached as.
+    pub database: String,
+
+    pub view: DatabaseView,
+
+    /// The transaction log doesn't record which values are fulltext indexed, so we need to be
+    /// told which attributes are.
+    pub fulltext_attributes: Vec<Entid>,
+}
+
+/// The name by which a query refers to one of the datoms tables of a source.
+pub fn source_table_name(table: DatomsTable, source: Option<&str>) -> String {
+    match source {
+        None => table.name().to_string(),
+        Some(source) => format!("{}${}", table.name(), source),
+    }
+}
+
 pub struct SelectQuery {
     pub distinct: bool,
     pub projection: Projection,
@@ -237,6 +289,8 @@ pub struct SelectQuery {
     pub group_by: Vec<GroupBy>,
     pub order: Vec<OrderBy>,
     pub limit: Limit,
+    pub offset: Offset,
+    pub views: Vec<DatomsView>,
 }
 
 fn push_variable_column(qb: &mut QueryBuilder, vc: &VariableColumn) -> BuildQueryResult {
@@ -250,6 +304,18 @@ fn push_variable_column(qb: &mut QueryBuilder, vc: &VariableColumn) -> BuildQuer
     }
 }
 
+fn push_order_column(qb: &mut QueryBuilder, oc: &OrderColumn) -> BuildQueryResult {
+    match oc {
+        &OrderColumn::Variable(ref vc) => push_variable_column(qb, vc),
+        &OrderColumn::Aggregate(ref name) => qb.push_identifier(name.as_str()),
+        &OrderColumn::Missing(ref table) => {
+            qb.push_identifier(table.as_str())?;
+            qb.push_sql(".v IS NULL");
+            Ok(())
+        },
+    }
+}
+
 fn push_column(qb: &mut QueryBuilder, col: &Column) -> BuildQueryResult {
     match col {
         &Column::Fixed(ref d) => {
@@ -265,6 +331,12 @@ fn push_column(qb: &mut QueryBuilder, col: &Column) -> BuildQueryResult {
             qb.push_sql(d.as_str());
             Ok(())
         },
+        &Column::ValueOrDefault(_) => {
+            // This is an expression, not a column; see `qualified_alias_push_sql`.
+            qb.push_sql("v");
+            Ok(())
+        },
+        &Column::Computed(ref c) => computed_value_push_sql(qb, c),
     }
 }
 
@@ -300,6 +372,9 @@ impl QueryFragment for ColumnOrExpression {
             &Expression(ref e, _) => {
                 e.push_sql(out)
             },
+            &Parameter(ref var) => {
+                out.push_bind_param(variable_parameter_name(var).as_str())
+            },
         }
     }
 }
@@ -314,6 +389,18 @@ impl QueryFragment for Expression {
                 out.push_sql(")");
                 Ok(())
             },
+            &Expression::Aggregate { ref sql_op, distinct, ref args } => {
+                out.push_sql(sql_op);              // Custom aggregate names are already escaped.
+                out.push_sql("(");
+                if distinct {
+                    out.push_sql("DISTINCT ");
+                }
+                interpose!(arg, args,
+                           { arg.push_sql(out)? },
+                           { out.push_sql(", ") });
+                out.push_sql(")");
+                Ok(())
+            },
         }
     }
 }
@@ -441,11 +528,201 @@ impl QueryFragment for JoinOp {
 
 // We don't own QualifiedAlias or QueryFragment, so we can't implement the trait.
 fn qualified_alias_push_sql(out: &mut QueryBuilder, qa: &QualifiedAlias) -> BuildQueryResult {
+    if let Column::ValueOrDefault(ref default) = qa.1 {
+        out.push_sql("COALESCE(");
+        out.push_identifier(qa.0.as_str())?;
+        out.push_sql(".v, ");
+        out.push_typed_value(default)?;
+        out.push_sql(")");
+        return Ok(());
+    }
+    if let Column::Computed(ref computed) = qa.1 {
+        // Computed values refer to their own columns; the alias is only nominal.
+        return computed_value_push_sql(out, computed);
+    }
     out.push_identifier(qa.0.as_str())?;
     out.push_sql(".");
     push_column(out, &qa.1)
 }
 
+fn query_value_push_sql(out: &mut QueryBuilder, v: &QueryValue) -> BuildQueryResult {
+    match v {
+        &QueryValue::Column(ref qa) => qualified_alias_push_sql(out, qa),
+        &QueryValue::Entid(entid) => {
+            out.push_sql(entid.to_string().as_str());
+            Ok(())
+        },
+        &QueryValue::PrimitiveLong(long) => {
+            out.push_sql(long.to_string().as_str());
+            Ok(())
+        },
+        &QueryValue::TypedValue(ref v) => out.push_typed_value(v),
+        &QueryValue::Parameter(ref var) => out.push_bind_param(variable_parameter_name(var).as_str()),
+    }
+}
+
+/// Instants are stored as microseconds since the epoch.
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+fn computed_value_push_sql(out: &mut QueryBuilder, computed: &ComputedValue) -> BuildQueryResult {
+    use self::ComputedFunction::*;
+    let args = &computed.args;
+    match computed.function {
+        Add | Subtract | Multiply | Divide => {
+            let op = match computed.function {
+                Add => " + ",
+                Subtract => " - ",
+                Multiply => " * ",
+                _ => " / ",
+            };
+
+            // SQLite only does floating-point arithmetic if an operand is a REAL, and in particular
+            // divides integers without remainder. Cast the first operand if we want a `Double`.
+            let real = computed.value_type == ValueType::Double;
+            out.push_sql("(");
+            if args.len() == 1 && computed.function == Subtract {
+                out.push_sql("- ");
+            }
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_sql(op);
+                } else if real {
+                    out.push_sql("CAST(");
+                }
+                query_value_push_sql(out, arg)?;
+                if i == 0 && real {
+                    out.push_sql(" AS REAL)");
+                }
+            }
+            out.push_sql(")");
+        },
+        Quot => {
+            // Integer division truncates towards zero, just like `quot`.
+            out.push_sql("(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(" / ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(")");
+        },
+        Mod => {
+            // `%` takes the sign of the dividend; `mod` takes the sign of the divisor.
+            out.push_sql("(((");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(" % ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(") + ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(") % ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(")");
+        },
+        Str => {
+            out.push_sql("(");
+            interpose!(arg, args,
+                       { query_value_push_sql(out, arg)? },
+                       { out.push_sql(" || ") });
+            out.push_sql(")");
+        },
+        Subs => {
+            // `substr` counts from 1, and takes a length rather than an end index.
+            out.push_sql("substr(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(", ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(" + 1");
+            if let Some(end) = args.get(2) {
+                out.push_sql(", ");
+                query_value_push_sql(out, end)?;
+                out.push_sql(" - ");
+                query_value_push_sql(out, &args[1])?;
+            }
+            out.push_sql(")");
+        },
+        UpperCase | LowerCase => {
+            out.push_sql(if computed.function == UpperCase { "upper(" } else { "lower(" });
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(")");
+        },
+        StartsWith => {
+            out.push_sql("(substr(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(", 1, length(");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(")) = ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(")");
+        },
+        Includes => {
+            out.push_sql("(instr(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(", ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(") > 0)");
+        },
+        ReFind => {
+            // `re_find` is registered on each connection by `mentat_db`.
+            out.push_sql("re_find(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(", ");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(")");
+        },
+        DayStart => {
+            // Round down, even before the epoch, to midnight UTC.
+            let day = MICROS_PER_DAY.to_string();
+            out.push_sql("(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(" - (((");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(&format!(" % {}) + {}) % {}))", day, day, day));
+        },
+        MinusMillis => {
+            out.push_sql("(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(" - (");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(" * 1000))");
+        },
+        FulltextScore => {
+            // `matchinfo` only describes the row of an FTS table in a `MATCH`; we turn it into a
+            // BM25 score with a function registered by `mentat_db`.
+            out.push_sql("mentat_fulltext_score(matchinfo(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql(", 'pcnalx'))");
+        },
+        FulltextSnippet => {
+            // The text is the table's first column. A negative token count lets SQLite pick
+            // the fragment, of up to fifteen tokens, that best covers the search terms.
+            out.push_sql("snippet(");
+            interpose!(arg, args,
+                       { query_value_push_sql(out, arg)? },
+                       { out.push_sql(", ") });
+            out.push_sql(", 0, -15)");
+        },
+        FulltextHighlight => {
+            out.push_sql("mentat_fulltext_highlight(");
+            query_value_push_sql(out, &args[1])?;
+            out.push_sql(", offsets(");
+            query_value_push_sql(out, &args[0])?;
+            out.push_sql("), ");
+            query_value_push_sql(out, &args[2])?;
+            out.push_sql(", ");
+            query_value_push_sql(out, &args[3])?;
+            out.push_sql(")");
+        },
+        Custom { ref sql_name, .. } => {
+            // Custom functions are registered on the connection under an escaped name.
+            out.push_sql(sql_name);
+            out.push_sql("(");
+            interpose!(arg, args,
+                       { query_value_push_sql(out, arg)? },
+                       { out.push_sql(", ") });
+            out.push_sql(")");
+        },
+    }
+    Ok(())
+}
+
 // We don't own SourceAlias or QueryFragment, so we can't implement the trait.
 fn source_alias_push_sql(out: &mut QueryBuilder, sa: &SourceAlias) -> BuildQueryResult {
     let &SourceAlias(ref table, ref alias) = sa;
@@ -460,9 +737,23 @@ impl QueryFragment for TableList {
             return Ok(());
         }
 
-        interpose!(t, self.0,
-                   { t.push_sql(out)? },
-                   { out.push_sql(", ") });
+        for (i, t) in self.0.iter().enumerate() {
+            match t {
+                &TableOrSubquery::LeftJoin(..) => {
+                    if i == 0 {
+                        // A LEFT JOIN needs a table on its left, even if its constraints don't
+                        // refer to it.
+                        out.push_sql("(SELECT 1)");
+                    }
+                    out.push_sql(" LEFT JOIN ");
+                },
+                _ if i > 0 => {
+                    out.push_sql(", ");
+                },
+                _ => {},
+            }
+            t.push_sql(out)?;
+        }
         Ok(())
     }
 }
@@ -501,6 +792,47 @@ impl QueryFragment for TableOrSubquery {
                 out.push_sql(") AS ");
                 out.push_identifier(table_alias.as_str())
             },
+            &Recursive(ref base, ref recursive, ref table_alias) => {
+                let name = DatomsTable::RecursiveRule.name();
+                out.push_sql("(WITH RECURSIVE ");
+                out.push_identifier(name)?;
+                out.push_sql(" AS (");
+                interpose_iter!(subquery, base.iter().chain(recursive.iter()),
+                                { subquery.push_sql(out)? },
+                                { out.push_sql(" UNION ") });
+                out.push_sql(") SELECT * FROM ");
+                out.push_identifier(name)?;
+                out.push_sql(") AS ");
+                out.push_identifier(table_alias.as_str())
+            },
+            &LeftJoin(ref source, SourceAlias(ref table, ref alias), ref constraints) => {
+                let source = source.as_ref().map(|s| s.as_str());
+                out.push_identifier(source_table_name(*table, source).as_str())?;
+                out.push_sql(" AS ");
+                out.push_identifier(alias.as_str())?;
+                if !constraints.is_empty() {
+                    out.push_sql(" ON ");
+                    interpose!(constraint, constraints,
+                               { constraint.push_sql(out)? },
+                               { out.push_sql(" AND ") });
+                }
+                Ok(())
+            },
+            &SourceTable(ref source, ref database, SourceAlias(ref table, ref alias)) => {
+                match *table {
+                    // Fulltext matching needs the real FTS table, not a view of it.
+                    DatomsTable::FulltextValues => {
+                        out.push_identifier(database.as_str())?;
+                        out.push_sql(".");
+                        out.push_identifier(table.name())?;
+                    },
+                    _ => {
+                        out.push_identifier(source_table_name(*table, Some(source.as_str())).as_str())?;
+                    },
+                }
+                out.push_sql(" AS ");
+                out.push_identifier(alias.as_str())
+            },
         }
     }
 }
@@ -579,15 +911,109 @@ fn format_select_var(var: &str) -> String {
     once('i').chain(replaced_iter).collect()
 }
 
+/// The name of the SQL parameter that binds the value of `var` when a query is run, without the
+/// leading `$`.
+pub fn variable_parameter_name(var: &Variable) -> String {
+    format_select_var(var.as_str())
+}
+
 impl SelectQuery {
     fn push_variable_param(&self, var: &Variable, out: &mut QueryBuilder) -> BuildQueryResult {
-        let bind_param = format_select_var(var.as_str());
-        out.push_bind_param(bind_param.as_str())
+        out.push_bind_param(variable_parameter_name(var).as_str())
+    }
+}
+
+impl DatomsView {
+    fn push_table_name(&self, out: &mut QueryBuilder, table: DatomsTable) -> BuildQueryResult {
+        out.push_identifier(source_table_name(table, self.source.as_ref().map(|s| s.as_str())).as_str())
+    }
+
+    /// Push the name of one of the store's real tables, which the CTEs might shadow.
+    fn push_store_table(&self, out: &mut QueryBuilder, table: &str) -> BuildQueryResult {
+        out.push_identifier(self.database.as_str())?;
+        out.push_sql(".");
+        out.push_identifier(table)
+    }
+
+    fn push_index_fulltext(&self, out: &mut QueryBuilder) {
+        if self.fulltext_attributes.is_empty() {
+            out.push_sql("0");
+        } else {
+            out.push_sql("a IN (");
+            interpose!(a, self.fulltext_attributes,
+                       { out.push_sql(a.to_string().as_str()) },
+                       { out.push_sql(", ") });
+            out.push_sql(")");
+        }
+        out.push_sql(" AS index_fulltext");
+    }
+}
+
+impl QueryFragment for DatomsView {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        self.push_table_name(out, DatomsTable::Datoms)?;
+        out.push_sql(" AS (");
+        match self.view {
+            DatabaseView::Current => {
+                out.push_sql("SELECT e, a, v, tx, value_type_tag, index_fulltext FROM ");
+                self.push_store_table(out, "datoms")?;
+            },
+            DatabaseView::AsOf(tx) => {
+                // A datom was present at `tx` if the last thing that happened to it by then was
+                // an assertion. SQLite takes the bare `added` column from the row with `max(tx)`.
+                out.push_sql("SELECT e, a, v, tx, value_type_tag, index_fulltext FROM \
+                              (SELECT e, a, v, max(tx) AS tx, value_type_tag, added, ");
+                self.push_index_fulltext(out);
+                out.push_sql(" FROM ");
+                self.push_store_table(out, "transactions")?;
+                out.push_sql(" WHERE tx <= ");
+                out.push_sql(tx.to_string().as_str());
+                out.push_sql(" GROUP BY e, a, value_type_tag, v) WHERE added = 1");
+            },
+            DatabaseView::Since(tx) => {
+                out.push_sql("SELECT e, a, v, tx, value_type_tag, index_fulltext FROM ");
+                self.push_store_table(out, "datoms")?;
+                out.push_sql(" WHERE tx > ");
+                out.push_sql(tx.to_string().as_str());
+            },
+            DatabaseView::History => {
+                out.push_sql("SELECT e, a, v, tx, value_type_tag, ");
+                self.push_index_fulltext(out);
+                out.push_sql(", added FROM ");
+                self.push_store_table(out, "transactions")?;
+            },
+        }
+
+        out.push_sql("), ");
+        self.push_table_name(out, DatomsTable::FulltextDatoms)?;
+        out.push_sql(" AS (SELECT d.e AS e, d.a AS a, f.text AS v, d.tx AS tx, \
+                      d.value_type_tag AS value_type_tag, d.index_fulltext AS index_fulltext FROM ");
+        self.push_table_name(out, DatomsTable::Datoms)?;
+        out.push_sql(" AS d, ");
+        self.push_store_table(out, "fulltext_values")?;
+        out.push_sql(" AS f WHERE d.index_fulltext IS NOT 0 AND d.v = f.rowid), ");
+
+        self.push_table_name(out, DatomsTable::AllDatoms)?;
+        out.push_sql(" AS (SELECT e, a, v, tx, value_type_tag, index_fulltext FROM ");
+        self.push_table_name(out, DatomsTable::Datoms)?;
+        out.push_sql(" WHERE index_fulltext IS 0 UNION ALL \
+                      SELECT e, a, v, tx, value_type_tag, index_fulltext FROM ");
+        self.push_table_name(out, DatomsTable::FulltextDatoms)?;
+        out.push_sql(")");
+        Ok(())
     }
 }
 
 impl QueryFragment for SelectQuery {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        if !self.views.is_empty() {
+            out.push_sql("WITH ");
+            interpose!(view, self.views,
+                       { view.push_sql(out)? },
+                       { out.push_sql(", ") });
+            out.push_sql(" ");
+        }
+
         if self.distinct {
             out.push_sql("SELECT DISTINCT ");
         } else {
@@ -615,8 +1041,8 @@ impl QueryFragment for SelectQuery {
 
         if !self.order.is_empty() {
             out.push_sql(" ORDER BY ");
-            interpose!(&OrderBy(ref dir, ref var), self.order,
-                       { push_variable_column(out, var)?;
+            interpose!(&OrderBy(ref dir, ref column), self.order,
+                       { push_order_column(out, column)?;
                          match dir {
                              &Direction::Ascending => { out.push_sql(" ASC"); },
                              &Direction::Descending => { out.push_sql(" DESC"); },
@@ -639,6 +1065,23 @@ impl QueryFragment for SelectQuery {
             },
         }
 
+        if self.offset != Offset::None && self.limit == Limit::None {
+            // SQLite only allows an offset after a limit. A negative limit is no limit.
+            out.push_sql(" LIMIT -1");
+        }
+
+        match &self.offset {
+            &Offset::None => (),
+            &Offset::Fixed(offset) => {
+                out.push_sql(" OFFSET ");
+                out.push_sql(offset.to_string().as_str());
+            },
+            &Offset::Variable(ref var) => {
+                out.push_sql(" OFFSET ");
+                self.push_variable_param(var, out)?;
+            },
+        }
+
         Ok(())
     }
 }
@@ -816,6 +1259,8 @@ mod tests {
             group_by: vec![],
             order: vec![],
             limit: Limit::None,
+            offset: Offset::None,
+            views: vec![],
         };
 
         let SQLQuery { sql, args } = query.to_sql_query().unwrap();
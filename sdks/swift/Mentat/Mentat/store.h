@@ -27,6 +27,8 @@
 struct EntityBuilder; // Note: a `mentat::EntityBuilder<mentat::InProgressBuilder<'a, 'c>>`
 struct InProgress;
 struct InProgressBuilder;
+struct KeyedRow; // Note: a `mentat::KeyedRow`
+struct KeyedRows; // Note: a `Vec<mentat::KeyedRow>`
 struct Query; // Note: a `mentat::QueryBuilder`
 struct QueryResultRow; // Note: a `Vec<mentat::Binding>`
 struct QueryResultRows; // Note: a `mentat::RelResult<Binding>`
@@ -115,6 +117,8 @@ void typed_value_list_destroy(struct QueryResultRow* _Nullable obj);
 void typed_value_list_iter_destroy(struct QueryRowIterator* _Nullable obj);
 void typed_value_result_set_destroy(struct QueryResultRows* _Nullable obj);
 void typed_value_result_set_iter_destroy(struct QueryRowsIterator* _Nullable obj);
+void keyed_row_destroy(struct KeyedRow* _Nullable obj);
+void keyed_row_list_destroy(struct KeyedRows* _Nullable obj);
 void in_progress_destroy(struct InProgress* _Nullable obj);
 void in_progress_builder_destroy(struct InProgressBuilder* _Nullable obj);
 void entity_builder_destroy(struct EntityBuilder* _Nullable obj);
@@ -212,6 +216,7 @@ struct QueryResultRows* _Nullable query_builder_execute(struct Query*_Nonnull qu
 struct TypedValue* _Nullable query_builder_execute_scalar(struct Query*_Nonnull query, struct RustError* _Nonnull error);
 struct QueryResultRow* _Nullable query_builder_execute_coll(struct Query*_Nonnull query, struct RustError* _Nonnull error);
 struct QueryResultRow* _Nullable query_builder_execute_tuple(struct Query*_Nonnull query, struct RustError* _Nonnull error);
+struct KeyedRows* _Nullable query_builder_execute_maps(struct Query*_Nonnull query, struct RustError* _Nonnull error);
 
 // Query Result Processing
 int64_t typed_value_into_long(struct TypedValue*_Nonnull  value);
@@ -225,6 +230,8 @@ uuid_t* _Nonnull typed_value_into_uuid(struct TypedValue*_Nonnull  value);
 enum ValueType typed_value_value_type(struct TypedValue*_Nonnull value);
 
 struct QueryResultRow* _Nullable row_at_index(struct QueryResultRows* _Nonnull rows, const int32_t index);
+struct KeyedRow* _Nullable keyed_row_at_index(struct KeyedRows* _Nonnull rows, const int32_t index);
+struct TypedValue* _Nullable keyed_row_value_for_name(struct KeyedRow* _Nonnull row, const char* _Nonnull name);
 struct QueryRowsIterator* _Nonnull typed_value_result_set_into_iter(struct QueryResultRows* _Nonnull rows);
 struct QueryResultRow* _Nullable typed_value_result_set_iter_next(struct QueryRowsIterator* _Nonnull iter);
 struct QueryRowIterator* _Nonnull typed_value_list_into_iter(struct QueryResultRow* _Nonnull row);
@@ -41,6 +41,7 @@ pub use rusqlite::types::Value;
 /// This struct encapsulates the generated string and the _initial_ argument list.
 /// Additional user-supplied argument bindings, with their placeholders accumulated via
 /// `push_bind_param`, will be appended to this argument list.
+#[derive(Debug)]
 pub struct SQLQuery {
     pub sql: String,
 
@@ -153,8 +154,8 @@ impl QueryBuilder for SQLiteQueryBuilder {
                 // Rust's floats print without a trailing '.' in some cases.
                 // https://github.com/rust-lang/rust/issues/30967
                 // We format with 'e' -- scientific notation -- so that SQLite treats them as
-                // floats and not integers. This is most noticeable for fulltext scores, which
-                // will currently (2017-06) always be 0, and need to round-trip as doubles.
+                // floats and not integers. This is most noticeable for integral values like 0,
+                // which need to round-trip as doubles.
                 self.push_sql(format!("{:e}", v).as_str());
             },
             &Instant(dt) => {
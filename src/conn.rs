@@ -20,16 +20,18 @@ use rusqlite;
 use rusqlite::TransactionBehavior;
 
 use edn;
+use edn::query::PullAttributeSpec;
 
 pub use core_traits::{Attribute, Entid, KnownEntid, StructuredMap, TypedValue, ValueType};
 
-use mentat_core::{HasSchema, Keyword, Schema, TxReport, ValueRc};
+use mentat_core::{CustomAggregate, CustomFunction, HasSchema, Keyword, Schema, TxReport, ValueRc};
 
 use mentat_db::cache::{InProgressSQLiteAttributeCache, SQLiteAttributeCache};
 
 use mentat_db::db;
 use mentat_db::{
     InProgressObserverTransactWatcher, PartitionMap, TxObservationService, TxObserver,
+    register_custom_aggregate, register_custom_function,
 };
 
 use mentat_query_pull::{pull_attributes_for_entities, pull_attributes_for_entity};
@@ -39,10 +41,22 @@ use mentat_transaction::{CacheAction, CacheDirection, InProgress, InProgressRead
 use public_traits::errors::{MentatError, Result};
 
 use mentat_transaction::query::{
-    Known, PreparedResult, QueryExplanation, QueryInputs, QueryOutput, lookup_value_for_attribute,
-    lookup_values_for_attribute, q_explain, q_once, q_prepare, q_uncached,
+    Known, PlainSymbol, PreparedResult, QueryExplanation, QueryInputs, QueryOutput, QueryRows, lookup_value_for_attribute,
+    lookup_values_for_attribute, q_explain, q_once, q_prepare,
 };
 
+use crate::plan_cache::{PlanCache, PlanCacheStats};
+
+/// Interrupts the queries running on a SQLite connection, from any thread. Each fails with
+/// `MentatError::Interrupted`. Queries that start after the interruption aren't affected.
+pub struct InterruptHandle(rusqlite::InterruptHandle);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.interrupt()
+    }
+}
+
 /// A mutable, safe reference to the current Mentat store.
 pub struct Conn {
     /// `Mutex` since all reads and writes need to be exclusive.  Internally, owned data for the
@@ -64,8 +78,10 @@ pub struct Conn {
 
     // TODO: maintain set of change listeners or handles to transaction report queues. #298.
 
-    // TODO: maintain cache of query plans that could be shared across threads and invalidated when
-    // the schema changes. #315.
+    /// Plans for the queries run with `q_once`, shared by every thread using this `Conn`. They're
+    /// discarded when the metadata's schema generation moves on.
+    plan_cache: PlanCache,
+
     pub(crate) tx_observer_service: Mutex<TxObservationService>,
 }
 
@@ -79,6 +95,7 @@ impl Conn {
                 Arc::new(schema),
                 Default::default(),
             )),
+            plan_cache: PlanCache::new(),
             tx_observer_service: Mutex::new(TxObservationService::new()),
         }
     }
@@ -117,6 +134,8 @@ impl Conn {
     }
 
     /// Query the Mentat store, using the given connection and the current metadata.
+    /// The query's plan is cached: running it again with inputs of the same types, whatever their
+    /// values, skips parsing, algebrizing, and translating it to SQL.
     pub fn q_once<T>(
         &self,
         sqlite: &rusqlite::Connection,
@@ -128,8 +147,43 @@ impl Conn {
     {
         // Doesn't clone, unlike `current_schema`.
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
-        q_once(sqlite, known, query, inputs)
+        let inputs = inputs.into().unwrap_or_default();
+        let (plan, values) = self.plan_cache.get_or_plan(&metadata, query, inputs)?;
+        plan.run(&metadata.schema, sqlite, Some(&metadata.attribute_cache), &values)
+    }
+
+    /// Like `q_once`, but call `f` with an iterator over the rows of the results, each read only
+    /// when it's reached, rather than collecting them. See `QueryRows`.
+    pub fn q_iter<T, F, R>(
+        &self,
+        sqlite: &rusqlite::Connection,
+        query: &str,
+        inputs: T,
+        f: F,
+    ) -> Result<R>
+    where
+        T: Into<Option<QueryInputs>>,
+        F: FnOnce(QueryRows) -> Result<R>,
+    {
+        let (schema, cache, plan, values) = {
+            let metadata = self.metadata.lock().unwrap();
+            let inputs = inputs.into().unwrap_or_default();
+            let (plan, values) = self.plan_cache.get_or_plan(&metadata, query, inputs)?;
+            (metadata.schema.clone(), metadata.attribute_cache.clone(), plan, values)
+        };
+
+        // Don't hold the lock while `f` runs: it might use this `Conn`.
+        plan.iter(&schema, sqlite, Some(&cache), &values, f)
+    }
+
+    /// A handle with which another thread can interrupt the queries running on `sqlite`.
+    pub fn interrupt_handle(&self, sqlite: &rusqlite::Connection) -> InterruptHandle {
+        InterruptHandle(sqlite.get_interrupt_handle())
+    }
+
+    /// How often `q_once` has found a query's plan in the cache.
+    pub fn plan_cache_stats(&self) -> PlanCacheStats {
+        self.plan_cache.stats()
     }
 
     /// Query the Mentat store, using the given connection and the current metadata,
@@ -144,12 +198,9 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         let metadata = self.metadata.lock().unwrap();
-        q_uncached(
-            sqlite,
-            &*metadata.schema, // Doesn't clone, unlike `current_schema`.
-            query,
-            inputs,
-        )
+        let known = Known::for_schema(&*metadata.schema).with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
+        q_once(sqlite, known, query, inputs)
     }
 
     pub fn q_prepare<'sqlite, 'query, T>(
@@ -162,7 +213,9 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache))
+            .with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
         q_prepare(sqlite, known, query, inputs)
     }
 
@@ -176,7 +229,9 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache))
+            .with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
         q_explain(sqlite, known, query, inputs)
     }
 
@@ -188,11 +243,12 @@ impl Conn {
     ) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
     where
         E: IntoIterator<Item = Entid>,
-        A: IntoIterator<Item = Entid>,
+        A: IntoIterator,
+        A::Item: Into<PullAttributeSpec>,
     {
         let metadata = self.metadata.lock().unwrap();
         let schema = &*metadata.schema;
-        pull_attributes_for_entities(schema, sqlite, entities, attributes).map_err(|e| e.into())
+        pull_attributes_for_entities(schema, sqlite, Some(&metadata.attribute_cache), entities, attributes).map_err(|e| e.into())
     }
 
     pub fn pull_attributes_for_entity<A>(
@@ -202,11 +258,12 @@ impl Conn {
         attributes: A,
     ) -> Result<StructuredMap>
     where
-        A: IntoIterator<Item = Entid>,
+        A: IntoIterator,
+        A::Item: Into<PullAttributeSpec>,
     {
         let metadata = self.metadata.lock().unwrap();
         let schema = &*metadata.schema;
-        pull_attributes_for_entity(schema, sqlite, entity, attributes).map_err(|e| e.into())
+        pull_attributes_for_entity(schema, sqlite, Some(&metadata.attribute_cache), entity, attributes).map_err(|e| e.into())
     }
 
     pub fn lookup_values_for_attribute(
@@ -216,7 +273,9 @@ impl Conn {
         attribute: &edn::Keyword,
     ) -> Result<Vec<TypedValue>> {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache))
+            .with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
         lookup_values_for_attribute(sqlite, known, entity, attribute)
     }
 
@@ -227,7 +286,9 @@ impl Conn {
         attribute: &edn::Keyword,
     ) -> Result<Option<TypedValue>> {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache))
+            .with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
         lookup_value_for_attribute(sqlite, known, entity, attribute)
     }
 
@@ -238,7 +299,7 @@ impl Conn {
         behavior: TransactionBehavior,
     ) -> Result<InProgress<'m, 'conn>> {
         let tx = sqlite.transaction_with_behavior(behavior)?;
-        let (current_generation, current_partition_map, current_schema, cache_cow) = {
+        let (current_generation, current_partition_map, current_schema, cache_cow, aggregates, functions) = {
             // The mutex is taken during this block.
             let ref current: Metadata = *self.metadata.lock().unwrap();
             (
@@ -248,6 +309,8 @@ impl Conn {
                 // Cheap.
                 current.schema.clone(),
                 current.attribute_cache.clone(),
+                current.aggregates.clone(),
+                current.functions.clone(),
             )
         };
 
@@ -258,6 +321,8 @@ impl Conn {
             partition_map: current_partition_map,
             schema: (*current_schema).clone(),
             cache: InProgressSQLiteAttributeCache::from_cache(cache_cow),
+            aggregates: aggregates,
+            functions: functions,
             use_caching: true,
             tx_observer: &self.tx_observer_service,
             tx_observer_watcher: InProgressObserverTransactWatcher::new(),
@@ -345,6 +410,8 @@ impl Conn {
                 .into();
         }
 
+        metadata.schema_generation += 1;
+
         let cache = &mut metadata.attribute_cache;
         match cache_action {
             CacheAction::Register => match cache_direction {
@@ -360,6 +427,50 @@ impl Conn {
         }
     }
 
+    /// Register a custom aggregate under `name`, so that queries can use it in their find specs.
+    /// Built-in aggregates take precedence.
+    ///
+    /// SQLite functions belong to a connection: `aggregate` is registered with `sqlite`, and must
+    /// also be registered, with `mentat_db::register_custom_aggregate`, with any other connection
+    /// that runs queries using it.
+    pub fn register_aggregate<A>(
+        &mut self,
+        sqlite: &rusqlite::Connection,
+        name: &PlainSymbol,
+        aggregate: A,
+    ) -> Result<()>
+    where
+        A: CustomAggregate,
+    {
+        let aggregate = Arc::new(aggregate);
+        register_custom_aggregate(sqlite, name.name(), aggregate.clone())?;
+
+        let mut metadata = self.metadata.lock().unwrap();
+        Arc::make_mut(&mut metadata.aggregates).register(name.name(), aggregate);
+        metadata.schema_generation += 1;
+        Ok(())
+    }
+
+    /// Register a custom function under `name`, so that queries can use it as a predicate, if it
+    /// returns a boolean, or bind its result. Built-in functions take precedence.
+    ///
+    /// As with aggregates, `function` is registered with `sqlite`, and must also be registered,
+    /// with `mentat_db::register_custom_function`, with any other connection that runs queries
+    /// using it.
+    pub fn register_function(
+        &mut self,
+        sqlite: &rusqlite::Connection,
+        name: &PlainSymbol,
+        function: CustomFunction,
+    ) -> Result<()> {
+        register_custom_function(sqlite, name.name(), function.clone())?;
+
+        let mut metadata = self.metadata.lock().unwrap();
+        Arc::make_mut(&mut metadata.functions).register(name.name(), function);
+        metadata.schema_generation += 1;
+        Ok(())
+    }
+
     pub fn register_observer(&mut self, key: String, observer: Arc<TxObserver>) {
         self.tx_observer_service
             .lock()
@@ -386,7 +497,7 @@ mod tests {
 
     use mentat_transaction::query::Variable;
 
-    use crate::{IntoResult, QueryInputs, QueryResults};
+    use crate::{AlgebrizerError, IntoResult, QueryInputs, QueryResults};
 
     use mentat_db::USER0;
 
@@ -565,6 +676,328 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parameterized_prepared_query() {
+        let mut c = db::new_connection("").expect("Couldn't open conn.");
+        let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+        conn.transact(
+            &mut c,
+            r#"[
+            [:db/add "s" :db/ident :foo/boolean]
+            [:db/add "s" :db/valueType :db.type/boolean]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "t" :db/ident :foo/long]
+            [:db/add "t" :db/valueType :db.type/long]
+            [:db/add "t" :db/cardinality :db.cardinality/one]
+        ]"#,
+        )
+        .expect("successful transaction");
+
+        let report = conn
+            .transact(
+                &mut c,
+                r#"[
+            [:db/add "u" :foo/boolean true]
+            [:db/add "p" :foo/boolean false]
+            [:db/add "p" :foo/long 5]
+        ]"#,
+            )
+            .expect("successful transaction");
+        let yes = report.tempids.get("u").expect("found it").clone();
+        let no = report.tempids.get("p").expect("found it").clone();
+
+        let vv = Variable::from_valid_name("?v");
+
+        let read = conn.begin_read(&mut c).expect("read");
+
+        // `?v` has no value yet: it's bound when the query is run.
+        let mut prepared = read
+            .q_prepare(
+                r#"[:find [?x ...]
+                    :in ?v
+                    :where [?x :foo/boolean ?v]]"#,
+                None,
+            )
+            .expect("prepare succeeded");
+
+        let yeses = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), true.into())]))
+            .expect("result");
+        assert_eq!(yeses.results, QueryResults::Coll(vec![TypedValue::Ref(yes).into()]));
+
+        let nos = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), false.into())]))
+            .expect("result");
+        assert_eq!(nos.results, QueryResults::Coll(vec![TypedValue::Ref(no).into()]));
+
+        // A value of the wrong type matches nothing.
+        let none = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), TypedValue::Long(1))]))
+            .expect("result");
+        assert_eq!(none.results, QueryResults::Coll(vec![]));
+
+        match prepared.run(None) {
+            Err(MentatError::UnboundVariables(missing)) => {
+                assert_eq!(missing, vec!["?v".to_string()].into_iter().collect());
+            }
+            x => panic!("expected unbound variables, got {:?}", x.map(|r| r.results)),
+        }
+
+        // Without an attribute, the parameter's type is decided when it's run.
+        let mut prepared = read
+            .q_prepare(
+                r#"[:find [?x ...]
+                    :in ?v
+                    :where [?x _ ?v]]"#,
+                None,
+            )
+            .expect("prepare succeeded");
+        let longs = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), TypedValue::Long(5))]))
+            .expect("result");
+        assert_eq!(longs.results, QueryResults::Coll(vec![TypedValue::Ref(no).into()]));
+        let yeses = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), true.into())]))
+            .expect("result")
+            .into_coll()
+            .expect("coll");
+        assert!(yeses.contains(&TypedValue::Ref(yes).into()));
+        assert!(!yeses.contains(&TypedValue::Ref(no).into()));
+
+        // `ground` needs the value itself, so this is algebrized each time it's run.
+        let mut prepared = read
+            .q_prepare(
+                r#"[:find ?x .
+                    :in ?v
+                    :where [(ground ?v) ?x]]"#,
+                None,
+            )
+            .expect("prepare succeeded");
+        let grounded = prepared
+            .run(QueryInputs::with_value_sequence(vec![(vv.clone(), TypedValue::Long(7))]))
+            .expect("result");
+        assert_eq!(grounded.results, QueryResults::Scalar(Some(TypedValue::Long(7).into())));
+    }
+
+    #[test]
+    fn test_parameterized_prepared_query_limit_and_offset() {
+        let mut c = db::new_connection("").expect("Couldn't open conn.");
+        let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+        conn.transact(
+            &mut c,
+            r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+        ]"#,
+        )
+        .expect("successful transaction");
+        conn.transact(&mut c, r#"[[:db/add "a" :foo/name "a"] [:db/add "b" :foo/name "b"]]"#)
+            .expect("successful transaction");
+
+        let nv = Variable::from_valid_name("?n");
+        let read = conn.begin_read(&mut c).expect("read");
+
+        let mut limited = read
+            .q_prepare(
+                r#"[:find ?name
+                    :in ?n
+                    :where [_ :foo/name ?name]
+                    :limit ?n]"#,
+                None,
+            )
+            .expect("prepare succeeded");
+        let r = limited
+            .run(QueryInputs::with_value_sequence(vec![(nv.clone(), TypedValue::Long(1))]))
+            .into_rel_result()
+            .expect("result");
+        assert_eq!(r.row_count(), 1);
+
+        // The statement binds the limit as a SQL parameter, so it's checked before it's run.
+        match limited.run(QueryInputs::with_value_sequence(vec![(nv.clone(), TypedValue::Long(-1))])) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidLimit(n, ValueType::Long))) => {
+                assert_eq!(n, "-1");
+            }
+            x => panic!("expected an invalid limit, got {:?}", x.map(|r| r.results)),
+        }
+        match limited.run(QueryInputs::with_value_sequence(vec![(nv.clone(), TypedValue::typed_string("2"))])) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidLimit(_, ValueType::String))) => {}
+            x => panic!("expected an invalid limit, got {:?}", x.map(|r| r.results)),
+        }
+
+        let mut offset = read
+            .q_prepare(
+                r#"[:find ?name
+                    :in ?n
+                    :where [_ :foo/name ?name]
+                    :order ?name
+                    :offset ?n]"#,
+                None,
+            )
+            .expect("prepare succeeded");
+        let r = offset
+            .run(QueryInputs::with_value_sequence(vec![(nv.clone(), TypedValue::Long(1))]))
+            .into_rel_result()
+            .expect("result");
+        assert_eq!(r.row_count(), 1);
+        match offset.rows(QueryInputs::with_value_sequence(vec![(nv.clone(), TypedValue::Long(-1))])) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidOffset(n, ValueType::Long))) => {
+                assert_eq!(n, "-1");
+            }
+            Err(e) => panic!("expected an invalid offset, got {:?}", e),
+            Ok(_) => panic!("expected an invalid offset"),
+        }
+    }
+
+    #[test]
+    fn test_plan_cache() {
+        let mut c = db::new_connection("").expect("Couldn't open conn.");
+        let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+        let query = r#"[:find ?x . :in ?v :where [?x :foo/boolean ?v]]"#;
+        let inputs = |v: bool| {
+            QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?v"), v.into())])
+        };
+        let stats = |hits, misses| PlanCacheStats { hits, misses };
+
+        // `:foo/boolean` doesn't exist yet, so this is planned as known to be empty.
+        let r = conn.q_once(&c, query, inputs(true)).into_scalar_result().expect("result");
+        assert_eq!(r, None);
+        assert_eq!(conn.plan_cache_stats(), stats(0, 1));
+
+        conn.q_once(&c, query, inputs(true)).expect("result");
+        assert_eq!(conn.plan_cache_stats(), stats(1, 1));
+
+        // Other values of the same type use the same plan.
+        let r = conn.q_once(&c, query, inputs(false)).into_scalar_result().expect("result");
+        assert_eq!(r, None);
+        assert_eq!(conn.plan_cache_stats(), stats(2, 1));
+
+        // Changing the schema discards every plan.
+        conn.transact(
+            &mut c,
+            r#"[
+            [:db/add "s" :db/ident :foo/boolean]
+            [:db/add "s" :db/valueType :db.type/boolean]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+        ]"#,
+        )
+        .expect("successful transaction");
+        let report = conn
+            .transact(&mut c, r#"[[:db/add "u" :foo/boolean true]]"#)
+            .expect("successful transaction");
+        let yes = report.tempids.get("u").expect("found it").clone();
+
+        let r = conn.q_once(&c, query, inputs(true)).into_scalar_result().expect("result");
+        assert_eq!(r, Some(TypedValue::Ref(yes).into()));
+        assert_eq!(conn.plan_cache_stats(), stats(2, 2));
+
+        // Other writes don't.
+        let report = conn
+            .transact(&mut c, r#"[[:db/add "p" :foo/boolean false]]"#)
+            .expect("successful transaction");
+        let no = report.tempids.get("p").expect("found it").clone();
+        let r = conn.q_once(&c, query, inputs(false)).into_scalar_result().expect("result");
+        assert_eq!(r, Some(TypedValue::Ref(no).into()));
+        assert_eq!(conn.plan_cache_stats(), stats(3, 2));
+
+        // Caching an attribute does.
+        let schema = conn.current_schema();
+        conn.cache(
+            &mut c,
+            &schema,
+            &kw!(:foo/boolean),
+            CacheDirection::Forward,
+            CacheAction::Register,
+        )
+        .expect("expected caching to work");
+        conn.q_once(&c, query, inputs(true)).expect("result");
+        assert_eq!(conn.plan_cache_stats(), stats(3, 3));
+
+        // A plan made with cached values is discarded when a write changes them…
+        let cached = format!("[:find ?v . :where [{} :foo/boolean ?v]]", yes);
+        conn.q_once(&c, &cached, None).expect("result");
+        let r = conn.q_once(&c, &cached, None).into_scalar_result().expect("result");
+        assert_eq!(r, Some(TypedValue::Boolean(true).into()));
+        assert_eq!(conn.plan_cache_stats(), stats(4, 4));
+
+        conn.transact(&mut c, format!("[[:db/add {} :foo/boolean false]]", yes).as_str())
+            .expect("successful transaction");
+        let r = conn.q_once(&c, &cached, None).into_scalar_result().expect("result");
+        assert_eq!(r, Some(TypedValue::Boolean(false).into()));
+        assert_eq!(conn.plan_cache_stats(), stats(4, 5));
+
+        // … but other plans are kept.
+        conn.q_once(&c, query, inputs(true)).expect("result");
+        assert_eq!(conn.plan_cache_stats(), stats(5, 5));
+
+        // So is a plan made with cached values when a write doesn't change any.
+        conn.transact(&mut c, r#"[[:db/add "d" :db/doc "unrelated"]]"#)
+            .expect("successful transaction");
+        conn.q_once(&c, &cached, None).expect("result");
+        assert_eq!(conn.plan_cache_stats(), stats(6, 5));
+
+        // Plans are shared by every thread using the `Conn`.
+        let conn = &conn;
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                let r = conn.q_once(&c, query, inputs(true)).into_scalar_result().expect("result");
+                assert_eq!(r, None);
+            });
+        });
+        assert_eq!(conn.plan_cache_stats(), stats(7, 5));
+    }
+
+    #[test]
+    fn test_plan_cache_checks_limit_and_offset() {
+        let mut c = db::new_connection("").expect("Couldn't open conn.");
+        let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+        conn.transact(
+            &mut c,
+            r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+        ]"#,
+        )
+        .expect("successful transaction");
+        conn.transact(&mut c, r#"[[:db/add "a" :foo/name "a"] [:db/add "b" :foo/name "b"]]"#)
+            .expect("successful transaction");
+
+        let limited = r#"[:find ?name :in ?n :where [_ :foo/name ?name] :limit ?n]"#;
+        let offset = r#"[:find ?name :in ?n :where [_ :foo/name ?name] :order ?name :offset ?n]"#;
+        let inputs = |n: i64| {
+            QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?n"), TypedValue::Long(n))])
+        };
+
+        let r = conn.q_once(&c, limited, inputs(1)).into_rel_result().expect("result");
+        assert_eq!(r.row_count(), 1);
+
+        // The plan binds the limit as a SQL parameter, but its value is still checked.
+        match conn.q_once(&c, limited, inputs(-1)) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidLimit(n, ValueType::Long))) => {
+                assert_eq!(n, "-1");
+            }
+            x => panic!("Got unexpected result {:?}", x),
+        }
+        match conn.q_once(&c, limited, inputs(0)) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidLimit(n, ValueType::Long))) => {
+                assert_eq!(n, "0");
+            }
+            x => panic!("Got unexpected result {:?}", x),
+        }
+
+        let r = conn.q_once(&c, offset, inputs(1)).into_rel_result().expect("result");
+        assert_eq!(r.row_count(), 1);
+        match conn.q_once(&c, offset, inputs(-1)) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::InvalidOffset(n, ValueType::Long))) => {
+                assert_eq!(n, "-1");
+            }
+            x => panic!("Got unexpected result {:?}", x),
+        }
+        assert_eq!(conn.plan_cache_stats().misses, 2);
+    }
+
     #[test]
     fn test_compound_rollback() {
         let mut sqlite = db::new_connection("").unwrap();
@@ -21,7 +21,7 @@ extern crate mentat_tolstoy;
 extern crate tolstoy_traits;
 
 pub use core_traits::{
-    Attribute, Binding, Entid, KnownEntid, StructuredMap, TypedValue, ValueType, now,
+    Attribute, Binding, Entid, KeyedRow, KnownEntid, StructuredMap, TypedValue, ValueType, now,
 };
 pub use edn;
 // pub use mentat_entity;
@@ -29,10 +29,14 @@ pub use edn;
 pub use mentat_entity;
 #[cfg(feature = "entity")]
 pub use mentat_entity::mentat_entity_derive::Entity as EntityDerive;
+#[cfg(feature = "entity")]
+pub use mentat_entity::{FromBinding, FromBindings};
 
-pub use mentat_core::{DateTime, HasSchema, Keyword, Schema, TxReport, Utc, Uuid};
+pub use mentat_core::{
+    CustomAggregate, CustomFunction, DateTime, HasSchema, Keyword, Schema, TxReport, Utc, Uuid,
+};
 
-pub use edn::query::FindSpec;
+pub use edn::query::{FindSpec, PullAttributeSpec};
 
 pub use mentat_db::{
     AttributeSet, CORE_SCHEMA_VERSION, DB_SCHEMA_CORE, TxObserver, new_connection,
@@ -107,11 +111,13 @@ pub use mentat_transaction::entity_builder;
 pub use mentat_transaction::query;
 
 pub use mentat_transaction::query::{
-    IntoResult, PlainSymbol, QueryExecutionResult, QueryExplanation, QueryInputs, QueryOutput,
-    QueryPlanStep, QueryResults, RelResult, Variable, q_once,
+    ClauseDecision, DatabaseView, EmptyBecause, IntoResult, KeyedRows, PlainSymbol,
+    QueryExecutionResult, QueryExplanation, QueryInputs, QueryOutput, QueryPlanExplanation,
+    QueryPlanStep, QueryResults, QueryRows, RelResult, Source, Variable, q_once,
 };
 
 pub mod conn;
+mod plan_cache;
 pub mod query_builder;
 pub mod store;
 pub mod vocabulary;
@@ -125,11 +131,15 @@ pub use sync::Syncable;
 #[cfg(feature = "syncable")]
 pub use mentat_tolstoy::SyncReport;
 
-pub use query_builder::QueryBuilder;
+pub use query_builder::{Page, PageToken, QueryBuilder};
 
-pub use conn::Conn;
+pub use conn::{Conn, InterruptHandle};
 
-pub use mentat_transaction::{CacheAction, CacheDirection, InProgress, Pullable, Queryable};
+pub use plan_cache::PlanCacheStats;
+
+pub use mentat_transaction::{
+    CacheAction, CacheDirection, HistoricalRead, InProgress, Pullable, Queryable, TxPoint,
+};
 
 pub use store::Store;
 
@@ -0,0 +1,335 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A cache of query plans, so that a query run again with inputs of the same types needn't be
+//! parsed, algebrized, or translated to SQL again.
+
+use std::cell::Cell;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use std::sync::{Arc, Mutex};
+
+use core_traits::{Entid, TypedValue};
+
+use mentat_core::{CachedAttributes, Schema};
+
+use mentat_transaction::Metadata;
+
+use mentat_transaction::query::{
+    FindQuery, Known, QueryInputs, QueryInputsKey, QueryPlan, Variable, parse_find_string, q_plan,
+    q_plan_parameterized,
+};
+
+use public_traits::errors::{MentatError, Result};
+
+/// How many plans a `Conn` keeps. When there would be more, the least recently used is discarded.
+const MAX_PLANS: usize = 512;
+
+/// How often a `Conn`'s queries found a plan in its cache.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PlanCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A query, and the types of the inputs it was planned with.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct PlanKey {
+    query: String,
+    inputs: QueryInputsKey,
+}
+
+enum Planned {
+    /// A plan that's run with the values of `parameters`. The parsed query is kept to check the
+    /// values of its limit and offset, which the plan doesn't.
+    Parameterized {
+        plan: Arc<QueryPlan>,
+        parameters: BTreeSet<Variable>,
+        query: FindQuery,
+    },
+
+    /// The query needs the values of its inputs to be planned, so it's planned each time it's run.
+    /// Only the parsed query is kept.
+    ByValue(FindQuery),
+}
+
+struct CachedPlan {
+    planned: Planned,
+
+    /// When the plan was last used. See `Plans::clock`.
+    last_used: u64,
+
+    /// The cache generation of the cached values that were read while planning, if any were.
+    cache_generation: Option<u64>,
+}
+
+/// A `Conn`'s plans, all made in the same schema generation.
+struct Plans {
+    schema_generation: u64,
+
+    /// Incremented each time a plan is used, to order the plans by how recently they were used.
+    clock: u64,
+    plans: BTreeMap<PlanKey, CachedPlan>,
+    recency: BTreeMap<u64, PlanKey>,
+}
+
+impl Plans {
+    fn clear(&mut self, schema_generation: u64) {
+        self.schema_generation = schema_generation;
+        self.plans.clear();
+        self.recency.clear();
+    }
+
+    fn touch(&mut self, key: &PlanKey) {
+        self.clock += 1;
+        if let Some(cached) = self.plans.get_mut(key) {
+            self.recency.remove(&cached.last_used);
+            cached.last_used = self.clock;
+            self.recency.insert(self.clock, key.clone());
+        }
+    }
+
+    fn insert(&mut self, key: PlanKey, planned: Planned, cache_generation: Option<u64>) {
+        self.clock += 1;
+        let cached = CachedPlan {
+            planned: planned,
+            last_used: self.clock,
+            cache_generation: cache_generation,
+        };
+        if let Some(old) = self.plans.insert(key.clone(), cached) {
+            self.recency.remove(&old.last_used);
+        }
+        self.recency.insert(self.clock, key);
+
+        while self.plans.len() > MAX_PLANS {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => {
+                    self.plans.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Plans made with cached values are only good until those values change. This notes whether any
+/// were read.
+struct CacheReads<'c> {
+    cache: &'c dyn CachedAttributes,
+    read: Cell<bool>,
+}
+
+impl<'c> CachedAttributes for CacheReads<'c> {
+    fn is_attribute_cached_reverse(&self, entid: Entid) -> bool {
+        self.cache.is_attribute_cached_reverse(entid)
+    }
+
+    fn is_attribute_cached_forward(&self, entid: Entid) -> bool {
+        self.cache.is_attribute_cached_forward(entid)
+    }
+
+    fn has_cached_attributes(&self) -> bool {
+        self.cache.has_cached_attributes()
+    }
+
+    fn get_values_for_entid(
+        &self,
+        schema: &Schema,
+        attribute: Entid,
+        entid: Entid,
+    ) -> Option<&Vec<TypedValue>> {
+        self.read.set(true);
+        self.cache.get_values_for_entid(schema, attribute, entid)
+    }
+
+    fn get_value_for_entid(
+        &self,
+        schema: &Schema,
+        attribute: Entid,
+        entid: Entid,
+    ) -> Option<&TypedValue> {
+        self.read.set(true);
+        self.cache.get_value_for_entid(schema, attribute, entid)
+    }
+
+    fn get_entid_for_value(&self, attribute: Entid, value: &TypedValue) -> Option<Entid> {
+        self.read.set(true);
+        self.cache.get_entid_for_value(attribute, value)
+    }
+
+    fn get_entids_for_value(
+        &self,
+        attribute: Entid,
+        value: &TypedValue,
+    ) -> Option<&BTreeSet<Entid>> {
+        self.read.set(true);
+        self.cache.get_entids_for_value(attribute, value)
+    }
+}
+
+/// The plans for the queries run on a `Conn`, shared by all of the threads that use it. Each query
+/// is planned with the types of its inputs, not their values, so that one plan serves any values
+/// of those types.
+pub(crate) struct PlanCache {
+    plans: Mutex<Plans>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PlanCache {
+    pub(crate) fn new() -> PlanCache {
+        PlanCache {
+            plans: Mutex::new(Plans {
+                schema_generation: 0,
+                clock: 0,
+                plans: BTreeMap::new(),
+                recency: BTreeMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> PlanCacheStats {
+        PlanCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Return a plan for `query` with `inputs`, planned against `metadata`, and the values to run
+    /// it with. Plans are kept for inputs that can be compared -- see `QueryInputs::key` -- until
+    /// the schema generation moves on, or, if they were made with cached values, until the cache
+    /// generation does.
+    pub(crate) fn get_or_plan(
+        &self,
+        metadata: &Metadata,
+        query: &str,
+        inputs: QueryInputs,
+    ) -> Result<(Arc<QueryPlan>, BTreeMap<Variable, TypedValue>)> {
+        let reads = CacheReads {
+            cache: &metadata.attribute_cache,
+            read: Cell::new(false),
+        };
+        let known = Known::new(&*metadata.schema, Some(&reads))
+            .with_aggregates(&metadata.aggregates)
+            .with_functions(&metadata.functions);
+
+        let key = match inputs.key() {
+            Some(inputs) => PlanKey {
+                query: query.to_string(),
+                inputs: inputs,
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let plan = q_plan(known, parse_find_string(query)?, inputs)?;
+                return Ok((Arc::new(plan), BTreeMap::new()));
+            }
+        };
+
+        // Don't hold the lock while planning.
+        let found = {
+            let mut plans = self.plans.lock().unwrap();
+            if plans.schema_generation != metadata.schema_generation {
+                plans.clear(metadata.schema_generation);
+            }
+            let found = plans.plans.get(&key).and_then(|cached| {
+                match cached.cache_generation {
+                    Some(generation) if generation != metadata.cache_generation => None,
+                    _ => match cached.planned {
+                        Planned::Parameterized {
+                            ref plan,
+                            ref parameters,
+                            ref query,
+                        } => Some(Ok((plan.clone(), parameters.clone(), query.clone()))),
+                        Planned::ByValue(ref parsed) => Some(Err(parsed.clone())),
+                    },
+                }
+            });
+            if found.is_some() {
+                plans.touch(&key);
+            }
+            found
+        };
+
+        let (plan, parameters, parsed) = match found {
+            Some(Ok(found)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                found
+            }
+            Some(Err(parsed)) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let plan = q_plan(known, parsed, inputs)?;
+                return Ok((Arc::new(plan), BTreeMap::new()));
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let parsed = parse_find_string(query)?;
+                let planned = match q_plan_parameterized(known, parsed.clone(), inputs.clone())? {
+                    Some((plan, parameters)) => Planned::Parameterized {
+                        plan: Arc::new(plan),
+                        parameters: parameters,
+                        query: parsed.clone(),
+                    },
+                    None => Planned::ByValue(parsed.clone()),
+                };
+                let cache_generation = if reads.read.get() {
+                    Some(metadata.cache_generation)
+                } else {
+                    None
+                };
+                let found = match planned {
+                    Planned::Parameterized {
+                        ref plan,
+                        ref parameters,
+                        ref query,
+                    } => Some((plan.clone(), parameters.clone(), query.clone())),
+                    Planned::ByValue(_) => None,
+                };
+
+                {
+                    let mut plans = self.plans.lock().unwrap();
+                    if plans.schema_generation == metadata.schema_generation {
+                        plans.insert(key, planned, cache_generation);
+                    }
+                }
+
+                match found {
+                    Some(found) => found,
+                    None => {
+                        let plan = q_plan(known, parsed, inputs)?;
+                        return Ok((Arc::new(plan), BTreeMap::new()));
+                    }
+                }
+            }
+        };
+
+        let mut values = BTreeMap::new();
+        let mut missing = BTreeSet::new();
+        for var in parameters.iter() {
+            match inputs.value(var) {
+                Some(value) => {
+                    values.insert(var.clone(), value.clone());
+                }
+                None => {
+                    missing.insert(var.to_string());
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(MentatError::UnboundVariables(missing).into());
+        }
+        parsed.check_limit_and_offset(&values)?;
+        Ok((plan, values))
+    }
+}
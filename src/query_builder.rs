@@ -11,18 +11,55 @@
 #![macro_use]
 use std::collections::BTreeMap;
 
-pub use core_traits::{Binding, Entid, TypedValue, ValueType};
+use std::time::Duration;
+
+pub use core_traits::{Binding, Entid, KeyedRow, TypedValue, ValueType};
 
 use mentat_core::{DateTime, Keyword, Utc};
 
-use crate::{HasSchema, QueryInputs, QueryOutput, Queryable, RelResult, Store, Variable};
+use edn::query::{Element, FindSpec, Limit, Rule};
+
+use crate::{HasSchema, QueryInputs, QueryOutput, QueryRows, Queryable, RelResult, Store, Variable};
+
+#[cfg(feature = "entity")]
+use mentat_entity::FromBindings;
 
 use public_traits::errors::{MentatError, Result};
 
+/// Where the next page of an ordered query's results starts: the values of the variables that
+/// pages are ordered by -- see `QueryBuilder::execute_page` -- in the last row of the previous
+/// page. Pass it to `QueryBuilder::after`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PageToken(Vec<TypedValue>);
+
+impl PageToken {
+    pub fn new(values: Vec<TypedValue>) -> PageToken {
+        PageToken(values)
+    }
+
+    pub fn values(&self) -> &[TypedValue] {
+        &self.0
+    }
+}
+
+/// A page of the results of a relation query, from `QueryBuilder::execute_page`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Page {
+    pub results: RelResult<Binding>,
+
+    /// Where the next page starts, or `None` if there are no more results.
+    pub next: Option<PageToken>,
+}
+
 pub struct QueryBuilder<'a> {
     query: String,
     values: BTreeMap<Variable, TypedValue>,
     types: BTreeMap<Variable, ValueType>,
+    rules: Vec<Rule>,
+    collections: BTreeMap<Variable, Vec<TypedValue>>,
+    relations: BTreeMap<Vec<Variable>, Vec<Vec<TypedValue>>>,
+    after: Option<PageToken>,
+    timeout: Option<Duration>,
     store: &'a mut Store,
 }
 
@@ -35,6 +72,11 @@ impl<'a> QueryBuilder<'a> {
             query: query.into(),
             values: BTreeMap::new(),
             types: BTreeMap::new(),
+            rules: vec![],
+            collections: BTreeMap::new(),
+            relations: BTreeMap::new(),
+            after: None,
+            timeout: None,
             store,
         }
     }
@@ -98,12 +140,90 @@ impl<'a> QueryBuilder<'a> {
         self
     }
 
-    pub fn execute(&mut self) -> Result<QueryOutput> {
+    /// Parse a vector of rules, like `[[(parent ?c ?p) [?c :person/parent ?p]]]`, for the query
+    /// to refer to as `%`.
+    pub fn bind_rules(&mut self, rules: &str) -> Result<&mut Self> {
+        self.rules = edn::parse::parse_rules(rules)?;
+        Ok(self)
+    }
+
+    /// Bind the values of a collection binding, like `[?x ...]` in `:in`. Bind entities as
+    /// `TypedValue::Ref` or `KnownEntid`.
+    pub fn bind_coll<T>(&mut self, var: &str, values: Vec<T>) -> &mut Self
+    where
+        T: Into<TypedValue>,
+    {
+        self.collections.insert(
+            Variable::from_valid_name(var),
+            values.into_iter().map(|v| v.into()).collect(),
+        );
+        self
+    }
+
+    /// Bind the rows of a relation binding, like `[[?x ?y]]` in `:in`, which is named by its
+    /// variables. Each row has a value for every place in the binding, including placeholders.
+    pub fn bind_rel(&mut self, vars: &[&str], rows: Vec<Vec<TypedValue>>) -> &mut Self {
+        self.relations.insert(
+            vars.iter().map(|&v| Variable::from_valid_name(v)).collect(),
+            rows,
+        );
+        self
+    }
+
+    /// Resume an ordered query after the page that returned `token`.
+    pub fn after(&mut self, token: PageToken) -> &mut Self {
+        self.after = Some(token);
+        self
+    }
+
+    /// Interrupt the query, failing with `MentatError::Interrupted`, if it runs for longer than
+    /// `timeout`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn inputs(&mut self) -> Result<QueryInputs> {
         let values = ::std::mem::replace(&mut self.values, Default::default());
         let types = ::std::mem::replace(&mut self.types, Default::default());
-        let query_inputs = QueryInputs::new(types, values)?;
-        let read = self.store.begin_read()?;
-        read.q_once(&self.query, query_inputs).map_err(|e| e.into())
+        let rules = ::std::mem::replace(&mut self.rules, Default::default());
+        let collections = ::std::mem::replace(&mut self.collections, Default::default());
+        let relations = ::std::mem::replace(&mut self.relations, Default::default());
+        let mut query_inputs = QueryInputs::new(types, values)?.with_rules(rules);
+        for (var, values) in collections {
+            query_inputs = query_inputs.with_coll(var, values);
+        }
+        for (vars, rows) in relations {
+            query_inputs = query_inputs.with_rel(vars, rows);
+        }
+        Ok(query_inputs)
+    }
+
+    fn run(&mut self, query_inputs: QueryInputs) -> Result<QueryOutput> {
+        let query = &self.query;
+        self.store.with_timeout(self.timeout, |store| {
+            let read = store.begin_read()?;
+            read.q_once(query, query_inputs)
+        })
+    }
+
+    pub fn execute(&mut self) -> Result<QueryOutput> {
+        let query_inputs = self.inputs()?;
+        self.run(query_inputs)
+    }
+
+    /// Run the query and call `f` with an iterator over the rows of its results, each read only
+    /// when it's reached. A timeout includes the time spent in `f`.
+    pub fn execute_iter<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(QueryRows) -> Result<R>,
+    {
+        let query_inputs = self.inputs()?;
+        let query = &self.query;
+        self.store.with_timeout(self.timeout, |store| {
+            let read = store.begin_read()?;
+            read.q_iter(query, query_inputs, f)
+        })
     }
 
     pub fn execute_scalar(&mut self) -> Result<Option<Binding>> {
@@ -125,11 +245,137 @@ impl<'a> QueryBuilder<'a> {
         let results = self.execute()?;
         results.into_rel().map_err(|e| e.into())
     }
+
+    /// Run a query with a return map -- `:keys`, `:strs`, or `:syms` -- and return its rows.
+    pub fn execute_maps(&mut self) -> Result<Vec<KeyedRow>> {
+        let results = self.execute()?;
+        results.into_maps().map_err(|e| e.into())
+    }
+
+    /// Run the query and map each row of its results into a `T`, usually one that derives
+    /// `FromBindings`. Scalar, collection, and tuple results are treated as rows of a relation.
+    #[cfg(feature = "entity")]
+    pub fn execute_as<T>(&mut self) -> Result<Vec<T>>
+    where
+        T: FromBindings,
+    {
+        let output = self.execute()?;
+        let columns: Vec<String> = output.spec.columns().map(|e| e.to_string()).collect();
+        output.results.into_rows().into_iter()
+            .map(|row| T::from_bindings(&columns, row))
+            .collect()
+    }
+
+    /// Return the first `page_size` results of a relation query, or those after the token given
+    /// to `after`, with a token for the next page. This is keyset pagination: each page starts
+    /// where the last left off, so it costs no more to find than the first.
+    ///
+    /// The query must have an `:order`, and each variable it orders by must be in `:find`. Rows
+    /// that tie on the `:order` are ordered by the other variables in `:find`, so the query can't
+    /// `pull`. Any `:offset` applies to the first page only. The page size takes the place of a
+    /// `:limit`, so a query with one is rejected: each page is found afresh from its token, and
+    /// no page knows how many rows came before it.
+    pub fn execute_page(&mut self, page_size: u64) -> Result<Page> {
+        if page_size == 0 {
+            return Err(MentatError::InvalidPagination(
+                "page size must be positive".to_string(),
+            ));
+        }
+
+        let parsed = edn::parse::parse_query(&self.query)?;
+        let elements = match parsed.find_spec {
+            FindSpec::FindRel(ref elements) => elements,
+            _ => {
+                return Err(MentatError::InvalidPagination(
+                    "expected a relation query".to_string(),
+                ));
+            }
+        };
+        if parsed.order.is_none() {
+            return Err(MentatError::InvalidPagination(
+                "the query has no :order".to_string(),
+            ));
+        }
+        if parsed.limit != Limit::None {
+            return Err(MentatError::InvalidPagination(
+                "the page size takes the place of :limit".to_string(),
+            ));
+        }
+        if let Some(element) = elements.iter().find(|e| matches!(e, Element::Pull(_))) {
+            return Err(MentatError::InvalidPagination(format!(
+                "can't page by {}",
+                element
+            )));
+        }
+        let order = parsed.find_spec.keyset_order(parsed.order.as_ref());
+
+        // Find the column of each ordering variable.
+        let mut columns = Vec::with_capacity(order.len());
+        for o in order.iter() {
+            let var = match &o.1 {
+                Element::Variable(var) => var,
+                element => {
+                    return Err(MentatError::InvalidPagination(format!(
+                        "can't page by {}",
+                        element
+                    )));
+                }
+            };
+            let index = elements.iter().position(|e| match e {
+                Element::Variable(v) | Element::Corresponding(v) => v == var,
+                _ => false,
+            });
+            match index {
+                Some(index) => columns.push(index),
+                None => {
+                    return Err(MentatError::InvalidPagination(format!(
+                        ":order variable {} is not in :find",
+                        var
+                    )));
+                }
+            }
+        }
+
+        // Fetch one more row than we need to tell whether there's another page.
+        let mut query_inputs = self.inputs()?.paged().with_limit(page_size + 1);
+        if let Some(PageToken(after)) = self.after.take() {
+            query_inputs = query_inputs.with_after(after);
+        }
+        let mut results = self.run(query_inputs)?.into_rel()?;
+
+        if (results.row_count() as u64) <= page_size {
+            return Ok(Page {
+                results,
+                next: None,
+            });
+        }
+
+        results.values.truncate(page_size as usize * results.width);
+        let next = match results.row(page_size as usize - 1) {
+            Some(last) => {
+                let mut after = Vec::with_capacity(columns.len());
+                for &index in columns.iter() {
+                    match last[index].as_scalar() {
+                        Some(value) => after.push(value.clone()),
+                        None => {
+                            return Err(MentatError::InvalidPagination(format!(
+                                "can't page by the non-scalar value of {}",
+                                elements[index]
+                            )));
+                        }
+                    }
+                }
+                Some(PageToken(after))
+            }
+            None => None,
+        };
+        Ok(Page { results, next })
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{QueryBuilder, Store, TypedValue};
+    use super::{Binding, Duration, MentatError, QueryBuilder, Store, TypedValue};
 
     #[test]
     fn test_scalar_query() {
@@ -538,4 +784,222 @@ mod test {
             25
         );
     }
+
+    #[test]
+    fn test_bind_coll_and_rel() {
+        let mut store = Store::open("").expect("store connection");
+        store
+            .transact(
+                r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "t" :db/ident :foo/long]
+            [:db/add "t" :db/valueType :db.type/long]
+            [:db/add "t" :db/cardinality :db.cardinality/one]
+        ]"#,
+            )
+            .expect("successful transaction");
+
+        let report = store
+            .transact(
+                r#"[
+            [:db/add "l" :foo/name "l"]
+            [:db/add "l" :foo/long 25]
+            [:db/add "m" :foo/name "m"]
+            [:db/add "m" :foo/long 26]
+            [:db/add "n" :foo/name "n"]
+            [:db/add "n" :foo/long 27]
+        ]"#,
+            )
+            .expect("successful transaction");
+
+        let l = report.tempids.get("l").expect("found it").clone();
+        let n = report.tempids.get("n").expect("found it").clone();
+
+        let results = QueryBuilder::new(
+            &mut store,
+            r#"[:find [?name ...]
+                :in [?x ...]
+                :where [?x :foo/name ?name]
+                :order ?name]"#,
+        )
+        .bind_coll("?x", vec![TypedValue::Ref(l), TypedValue::Ref(n)])
+        .execute_coll()
+        .expect("CollResult");
+        assert_eq!(
+            results,
+            vec![TypedValue::typed_string("l").into(), TypedValue::typed_string("n").into()]
+        );
+
+        let results = QueryBuilder::new(
+            &mut store,
+            r#"[:find [?x ...]
+                :in [[?name _ ?i]]
+                :where [?x :foo/name ?name] [?x :foo/long ?i]]"#,
+        )
+        .bind_rel(
+            &["?name", "?i"],
+            vec![
+                vec![TypedValue::typed_string("l"), TypedValue::Long(0), TypedValue::Long(25)],
+                vec![TypedValue::typed_string("m"), TypedValue::Long(0), TypedValue::Long(99)],
+            ],
+        )
+        .execute_coll()
+        .expect("CollResult");
+        assert_eq!(results, vec![TypedValue::Ref(l).into()]);
+    }
+
+    fn store_with_names(names: &[&str]) -> Store {
+        let mut store = Store::open("").expect("store connection");
+        store
+            .transact(
+                r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+        ]"#,
+            )
+            .expect("successful transaction");
+        let entities: Vec<String> = names
+            .iter()
+            .map(|name| format!("{{:foo/name \"{}\"}}", name))
+            .collect();
+        store
+            .transact(format!("[{}]", entities.join(" ")).as_str())
+            .expect("successful transaction");
+        store
+    }
+
+    fn names(rows: &[Binding], width: usize) -> Vec<String> {
+        rows.chunks(width)
+            .map(|row| row[0].clone().into_string().expect("string").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_offset() {
+        let mut store = store_with_names(&["a", "b", "c", "d", "e"]);
+
+        let results = QueryBuilder::new(
+            &mut store,
+            r#"[:find ?name :where [_ :foo/name ?name] :order ?name :limit 2 :offset 1]"#,
+        )
+        .execute_rel()
+        .expect("RelResult");
+        assert_eq!(names(&results.values, 1), vec!["b", "c"]);
+
+        let results = QueryBuilder::new(
+            &mut store,
+            r#"[:find ?name :in ?offset :where [_ :foo/name ?name] :order ?name :offset ?offset]"#,
+        )
+        .bind_long("?offset", 3)
+        .execute_rel()
+        .expect("RelResult");
+        assert_eq!(names(&results.values, 1), vec!["d", "e"]);
+
+        let result = QueryBuilder::new(
+            &mut store,
+            r#"[:find ?name :in ?offset :where [_ :foo/name ?name] :offset ?offset]"#,
+        )
+        .bind_long("?offset", -1)
+        .execute_rel();
+        assert!(result.is_err());
+    }
+
+    fn pages(store: &mut Store, query: &str, page_size: u64) -> Vec<Vec<String>> {
+        let mut pages = vec![];
+        let mut token = None;
+        loop {
+            let mut builder = QueryBuilder::new(&mut *store, query);
+            if let Some(token) = token.take() {
+                builder.after(token);
+            }
+            let page = builder.execute_page(page_size).expect("Page");
+            pages.push(names(&page.results.values, page.results.width));
+            match page.next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+        pages
+    }
+
+    #[test]
+    fn test_execute_page() {
+        // Two entities share a name, so the order includes the entity to tell them apart.
+        let mut store = store_with_names(&["d", "b", "a", "c", "b"]);
+        let query = r#"[:find ?name ?e :where [?e :foo/name ?name] :order ?name (desc ?e)]"#;
+        assert_eq!(pages(&mut store, query, 2), vec![vec!["a", "b"], vec!["b", "c"], vec!["d"]]);
+
+        // If it doesn't, rows that tie are ordered by the rest of `:find`, and none are lost.
+        let ties = r#"[:find ?name ?e :where [?e :foo/name ?name] :order ?name]"#;
+        assert_eq!(pages(&mut store, ties, 2), vec![vec!["a", "b"], vec!["b", "c"], vec!["d"]]);
+
+        // An offset skips rows before the first page only.
+        let offset = r#"[:find ?name ?e :where [?e :foo/name ?name] :order ?name :offset 1]"#;
+        assert_eq!(pages(&mut store, offset, 2), vec![vec!["b", "b"], vec!["c", "d"]]);
+
+        // An exactly full last page has no next page.
+        let page = QueryBuilder::new(&mut store, query)
+            .execute_page(5)
+            .expect("Page");
+        assert_eq!(page.results.row_count(), 5);
+        assert_eq!(page.next, None);
+
+        // Pages are found from the order of the results.
+        match QueryBuilder::new(&mut store, r#"[:find ?name :where [_ :foo/name ?name]]"#)
+            .execute_page(2)
+        {
+            Err(MentatError::InvalidPagination(_)) => {}
+            x => panic!("expected InvalidPagination, got {:?}", x),
+        }
+        match QueryBuilder::new(
+            &mut store,
+            r#"[:find ?name :where [?e :foo/name ?name] :order ?name ?e]"#,
+        )
+        .execute_page(2)
+        {
+            Err(MentatError::InvalidPagination(_)) => {}
+            x => panic!("expected InvalidPagination, got {:?}", x),
+        }
+
+        // A page can't tell how many rows came before it, so it can't honor a `:limit`.
+        match QueryBuilder::new(
+            &mut store,
+            r#"[:find ?name ?e :where [?e :foo/name ?name] :order ?name :limit 3]"#,
+        )
+        .execute_page(2)
+        {
+            Err(MentatError::InvalidPagination(_)) => {}
+            x => panic!("expected InvalidPagination, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut store = Store::open("").expect("store connection");
+
+        // Every combination of three datoms: far too many to count in a millisecond.
+        let query = "[:find (count ?a) . :where [?a _ _] [?b _ _] [?c _ _]]";
+        match QueryBuilder::new(&mut store, query)
+            .timeout(Duration::from_millis(1))
+            .execute_scalar()
+        {
+            Err(MentatError::Interrupted) => {}
+            x => panic!("expected Interrupted, got {:?}", x),
+        }
+
+        // The timeout doesn't outlive its query.
+        let count = QueryBuilder::new(&mut store, "[:find (count ?a) . :where [?a :db/ident _]]")
+            .execute_scalar()
+            .expect("ScalarResult");
+        assert!(count.is_some());
+
+        let count = QueryBuilder::new(&mut store, "[:find (count ?a) . :where [?a :db/ident _]]")
+            .timeout(Duration::from_secs(60))
+            .execute_scalar()
+            .expect("ScalarResult");
+        assert!(count.is_some());
+    }
 }
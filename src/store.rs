@@ -15,26 +15,34 @@ use mentat_entity::Entity;
 
 use std::collections::BTreeMap;
 
+use std::os::raw::c_int;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use rusqlite;
 
 use edn;
+use edn::query::PullAttributeSpec;
 
 use core_traits::{Entid, StructuredMap, TypedValue};
 
-use mentat_core::{Keyword, TxReport, ValueRc};
+use mentat_core::{CustomAggregate, CustomFunction, Keyword, TxReport, ValueRc};
 use mentat_db::TxObserver;
 
 use mentat_transaction::{
-    CacheAction, CacheDirection, InProgress, InProgressRead, Pullable, Queryable,
+    CacheAction, CacheDirection, HistoricalRead, InProgress, InProgressRead, Pullable, Queryable,
+    TxPoint,
 };
 
-use crate::conn::Conn;
+use crate::conn::{Conn, InterruptHandle};
+use crate::plan_cache::PlanCacheStats;
 
-use public_traits::errors::Result;
+use public_traits::errors::{MentatError, Result};
 
-use mentat_transaction::query::{PreparedResult, QueryExplanation, QueryInputs, QueryOutput};
+use mentat_transaction::query::{
+    PlainSymbol, PreparedResult, QueryExplanation, QueryInputs, QueryOutput, QueryRows, Source,
+};
 
 #[cfg(feature = "syncable")]
 use mentat_tolstoy::{SyncFollowup, SyncReport, SyncResult};
@@ -42,6 +50,20 @@ use mentat_tolstoy::{SyncFollowup, SyncReport, SyncResult};
 #[cfg(feature = "syncable")]
 use crate::sync::Syncable;
 
+/// How many SQLite virtual machine instructions run between checks of a query's deadline.
+const TIMEOUT_CHECK_INTERVAL: c_int = 1000;
+
+/// Removes a store's timeout when dropped, so that it doesn't outlive the function run with it,
+/// even if that function panics.
+struct TimeoutGuard<'a>(&'a mut Store);
+
+impl<'a> Drop for TimeoutGuard<'a> {
+    fn drop(&mut self) {
+        // There's nothing to be done if this fails.
+        let _ = self.0.sqlite.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
 /// A convenience wrapper around a single SQLite connection and a Conn. This is suitable
 /// for applications that don't require complex connection management.
 pub struct Store {
@@ -146,10 +168,89 @@ impl Store {
         self.conn.begin_read(&mut self.sqlite)
     }
 
+    /// A handle with which another thread can interrupt this store's running queries.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.conn.interrupt_handle(&self.sqlite)
+    }
+
+    /// Run `f`, interrupting any query it runs once `timeout` has passed.
+    pub(crate) fn with_timeout<T, F>(&mut self, timeout: Option<Duration>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Store) -> Result<T>,
+    {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return f(self),
+        };
+        let deadline = Instant::now() + timeout;
+        self.sqlite
+            .progress_handler(TIMEOUT_CHECK_INTERVAL, Some(move || Instant::now() >= deadline))?;
+        let guard = TimeoutGuard(self);
+        f(&mut *guard.0)
+    }
+
     pub fn begin_transaction<'m>(&'m mut self) -> Result<InProgress<'m, 'm>> {
         self.conn.begin_transaction(&mut self.sqlite)
     }
 
+    /// Begin a read of the store as it was immediately after `point`, a transaction or instant.
+    /// Queries against it can't yet use pull expressions; they fail with `NotYetImplemented`.
+    pub fn as_of<'m, P>(&'m mut self, point: P) -> Result<HistoricalRead<'m, 'm>>
+    where
+        P: Into<TxPoint>,
+    {
+        self.begin_read()?.as_of(point)
+    }
+
+    /// Begin a read of the current datoms that were asserted after `point`. As with `as_of`,
+    /// pull expressions aren't yet supported.
+    pub fn since<'m, P>(&'m mut self, point: P) -> Result<HistoricalRead<'m, 'm>>
+    where
+        P: Into<TxPoint>,
+    {
+        self.begin_read()?.since(point)
+    }
+
+    /// Begin a read of every assertion and retraction in the store's history. As with `as_of`,
+    /// pull expressions aren't yet supported.
+    pub fn history<'m>(&'m mut self) -> Result<HistoricalRead<'m, 'm>> {
+        self.begin_read().map(|read| read.history())
+    }
+
+    /// This store, as a source that a query can name in `:in`, like `$a`. Use
+    /// `Source::with_view` to query one of its historical views alongside its current state.
+    pub fn source(&self) -> Source {
+        Source::new("main", Rc::new((*self.conn.current_schema()).clone()))
+    }
+
+    /// Attach the Mentat store at `path` to this store's connection as `name`, and return it as a
+    /// source that queries against this store can join with.
+    ///
+    /// The attached store is read-only as far as Mentat is concerned: transactions only ever
+    /// apply to this store.
+    pub fn attach(&mut self, path: &str, name: &str) -> Result<Source> {
+        // SQLite can't bind the name as a parameter, so it must be a plain identifier.
+        if name.is_empty()
+            || name == "main"
+            || name == "temp"
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(MentatError::InvalidArgumentName(name.to_string()));
+        }
+        self.sqlite
+            .execute(&format!("ATTACH DATABASE ? AS {}", name), [path])?;
+        // Don't leave it attached if it isn't a Mentat store, so that attaching it can be retried.
+        match mentat_db::read_attached_schema(&self.sqlite, name) {
+            Ok(schema) => Ok(Source::new(name, Rc::new(schema))),
+            Err(e) => {
+                let _ = self
+                    .sqlite
+                    .execute(&format!("DETACH DATABASE {}", name), []);
+                Err(e.into())
+            }
+        }
+    }
+
     pub fn cache(&mut self, attr: &Keyword, direction: CacheDirection) -> Result<()> {
         let schema = &self.conn.current_schema();
         self.conn.cache(
@@ -161,6 +262,18 @@ impl Store {
         )
     }
 
+    /// Register a custom aggregate under `name`, so that queries can use it in their find specs.
+    pub fn register_aggregate<A>(&mut self, name: &PlainSymbol, aggregate: A) -> Result<()>
+    where
+        A: CustomAggregate,
+    {
+        self.conn.register_aggregate(&self.sqlite, name, aggregate)
+    }
+
+    pub fn register_function(&mut self, name: &PlainSymbol, function: CustomFunction) -> Result<()> {
+        self.conn.register_function(&self.sqlite, name, function)
+    }
+
     pub fn register_observer(&mut self, key: String, observer: Arc<TxObserver>) {
         self.conn.register_observer(key, observer);
     }
@@ -172,6 +285,10 @@ impl Store {
     pub fn last_tx_id(&self) -> Entid {
         self.conn.last_tx_id()
     }
+
+    pub fn plan_cache_stats(&self) -> PlanCacheStats {
+        self.conn.plan_cache_stats()
+    }
 }
 
 #[cfg(feature = "entity")]
@@ -227,6 +344,14 @@ impl Queryable for Store {
         self.conn.q_once(&self.sqlite, query, inputs)
     }
 
+    fn q_iter<T, F, R>(&self, query: &str, inputs: T, f: F) -> Result<R>
+    where
+        T: Into<Option<QueryInputs>>,
+        F: FnOnce(QueryRows) -> Result<R>,
+    {
+        self.conn.q_iter(&self.sqlite, query, inputs, f)
+    }
+
     fn q_prepare<T>(&'_ self, query: &str, inputs: T) -> PreparedResult<'_>
     where
         T: Into<Option<QueryInputs>>,
@@ -274,7 +399,8 @@ impl Pullable for Store {
     ) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
     where
         E: IntoIterator<Item = Entid>,
-        A: IntoIterator<Item = Entid>,
+        A: IntoIterator,
+        A::Item: Into<PullAttributeSpec>,
     {
         self.conn
             .pull_attributes_for_entities(&self.sqlite, entities, attributes)
@@ -282,7 +408,8 @@ impl Pullable for Store {
 
     fn pull_attributes_for_entity<A>(&self, entity: Entid, attributes: A) -> Result<StructuredMap>
     where
-        A: IntoIterator<Item = Entid>,
+        A: IntoIterator,
+        A::Item: Into<PullAttributeSpec>,
     {
         self.conn
             .pull_attributes_for_entity(&self.sqlite, entity, attributes)
@@ -299,6 +426,7 @@ mod tests {
     use std::collections::BTreeSet;
     use std::path::{Path, PathBuf};
     use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::mpsc;
     use std::time::Duration;
 
@@ -923,4 +1051,59 @@ mod tests {
         assert_eq!(o.txids, tx_ids);
         assert_eq!(o.changes, changesets);
     }
+
+    #[test]
+    fn test_interrupt_handle() {
+        let store = Store::open("").expect("opened");
+        let handle = store.interrupt_handle();
+
+        // Keep interrupting until the query is done: an interruption before it starts is lost.
+        let done = Arc::new(AtomicBool::new(false));
+        let interrupter = {
+            let done = done.clone();
+            ::std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    handle.interrupt();
+                    ::std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+
+        // Every combination of three datoms: far too many to count before being interrupted.
+        let result = store.q_once("[:find (count ?a) . :where [?a _ _] [?b _ _] [?c _ _]]", None);
+        done.store(true, Ordering::SeqCst);
+        interrupter.join().expect("joined");
+        match result {
+            Err(MentatError::Interrupted) => {}
+            x => panic!("expected Interrupted, got {:?}", x.map(|o| o.results)),
+        }
+
+        // Later queries aren't affected.
+        let count = store
+            .q_once("[:find (count ?a) . :where [?a :db/ident _]]", None)
+            .expect("query")
+            .into_scalar()
+            .expect("scalar");
+        assert!(count.is_some());
+    }
+
+    #[test]
+    fn test_timeout_after_panic() {
+        let mut store = Store::open("").expect("opened");
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            store.with_timeout(Some(Duration::from_millis(1)), |_| -> Result<()> {
+                panic!("expected panic")
+            })
+        }));
+        assert!(result.is_err());
+
+        // The timeout doesn't outlive the function that panicked.
+        ::std::thread::sleep(Duration::from_millis(5));
+        let count = store
+            .q_once("[:find (count ?a) . :where [?a _ _] [?b :db/ident _]]", None)
+            .expect("query")
+            .into_scalar()
+            .expect("scalar");
+        assert!(count.is_some());
+    }
 }
@@ -396,3 +396,141 @@ fn test_derived_entity() {
         TestPerson::read(&store.begin_transaction().expect("Begin transaction"), id).unwrap();
     assert_eq!(person.email, "email@gmail.com");
 }
+
+#[derive(mentat::FromBindings, Debug, PartialEq)]
+struct NameAndAge {
+    name: String,
+    age: i64,
+}
+
+#[derive(mentat::FromBindings, Debug, PartialEq)]
+#[bindings(by_name)]
+struct PersonRow {
+    person_name: String,
+    #[bindings(var = "?e")]
+    entity: mentat::KnownEntid,
+    email: String,
+}
+
+#[derive(mentat::FromBindings, Debug, PartialEq)]
+struct Count(i64);
+
+#[derive(mentat::FromBindings, Debug, PartialEq)]
+#[bindings(by_name)]
+struct MaybeAge {
+    name: String,
+    age: Option<i64>,
+}
+
+#[test]
+fn test_execute_as() {
+    use mentat::QueryBuilder;
+
+    let mut store = mentat::store::Store::open("").unwrap();
+    store.ensure_entity_schema::<TestPersonDerive>().unwrap();
+    let report = store
+        .transact(
+            r#"[
+        {:db/id "a" :person/email "alice@example.com" :person/name "Alice" :person/age 30}
+        {:db/id "b" :person/email "bob@example.com" :person/name "Bob" :person/age 25}
+    ]"#,
+        )
+        .unwrap();
+    let alice = report.tempids.get("a").cloned().unwrap();
+
+    // By position.
+    let rows: Vec<NameAndAge> = QueryBuilder::new(
+        &mut store,
+        "[:find ?name ?age :where [?e :person/name ?name] [?e :person/age ?age] :order ?name]",
+    )
+    .execute_as()
+    .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            NameAndAge { name: "Alice".to_string(), age: 30 },
+            NameAndAge { name: "Bob".to_string(), age: 25 },
+        ]
+    );
+
+    // By name, in any order.
+    let rows: Vec<PersonRow> = QueryBuilder::new(
+        &mut store,
+        r#"[:find ?email ?e ?person-name
+            :in ?name
+            :where [?e :person/name ?name] [?e :person/email ?email] [?e :person/name ?person-name]]"#,
+    )
+    .bind_value("?name", "Alice")
+    .execute_as()
+    .unwrap();
+    assert_eq!(
+        rows,
+        vec![PersonRow {
+            person_name: "Alice".to_string(),
+            entity: mentat::KnownEntid(alice),
+            email: "alice@example.com".to_string(),
+        }]
+    );
+
+    // Scalar results are a single row.
+    let counts: Vec<Count> = QueryBuilder::new(&mut store, "[:find (count ?e) . :where [?e :person/name _]]")
+        .execute_as()
+        .unwrap();
+    assert_eq!(counts, vec![Count(2)]);
+
+    // The wrong type.
+    match QueryBuilder::new(&mut store, "[:find ?age ?name :where [?e :person/name ?name] [?e :person/age ?age]]")
+        .execute_as::<NameAndAge>()
+    {
+        Err(MentatError::BindingTypeMismatch(field, expected, found)) => {
+            assert_eq!(field, "name");
+            assert_eq!(expected, ":db.type/string");
+            assert_eq!(found, ":db.type/long");
+        }
+        x => panic!("expected a type mismatch, got {:?}", x),
+    }
+
+    // A missing column.
+    match QueryBuilder::new(&mut store, "[:find ?name :where [_ :person/name ?name]]")
+        .execute_as::<NameAndAge>()
+    {
+        Err(MentatError::MissingBinding(field)) => assert_eq!(field, "age"),
+        x => panic!("expected a missing binding, got {:?}", x),
+    }
+
+    // An optional field can be missing...
+    let rows: Vec<MaybeAge> = QueryBuilder::new(&mut store, "[:find ?name :where [_ :person/name ?name] :order ?name]")
+        .execute_as()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            MaybeAge { name: "Alice".to_string(), age: None },
+            MaybeAge { name: "Bob".to_string(), age: None },
+        ]
+    );
+
+    // ... but when it's present, it must have the right type.
+    let rows: Vec<MaybeAge> = QueryBuilder::new(
+        &mut store,
+        "[:find ?name ?age :where [?e :person/name ?name] [(get-else $ ?e :person/age -1) ?age] :order ?name]",
+    )
+    .execute_as()
+    .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            MaybeAge { name: "Alice".to_string(), age: Some(30) },
+            MaybeAge { name: "Bob".to_string(), age: Some(25) },
+        ]
+    );
+    match QueryBuilder::new(&mut store, "[:find ?name ?age :where [?e :person/name ?name] [?e :person/email ?age]]")
+        .execute_as::<MaybeAge>()
+    {
+        Err(MentatError::BindingTypeMismatch(field, _, found)) => {
+            assert_eq!(field, "age");
+            assert_eq!(found, ":db.type/string");
+        }
+        x => panic!("expected a type mismatch, got {:?}", x),
+    }
+}
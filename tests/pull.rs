@@ -13,6 +13,7 @@ extern crate mentat;
 extern crate mentat_core;
 extern crate core_traits;
 extern crate mentat_query_pull;
+extern crate query_pull_traits;
 
 use std::collections::{
     BTreeMap,
@@ -34,11 +35,17 @@ use mentat_core::{
     ValueRc,
 };
 
+use mentat::edn::parse::parse_pull_pattern;
+
+use query_pull_traits::errors::PullError;
+
 use mentat::{
+    CacheDirection,
     Entid,
     HasSchema,
     IntoResult,
     Keyword,
+    MentatError,
     Pullable,
     Queryable,
     QueryInputs,
@@ -251,6 +258,284 @@ fn test_simple_pull() {
     assert_eq!(results, expected);
 }
 
+#[test]
+fn test_nested_pull() {
+    let mut store = Store::open("").expect("opened");
+    store.transact(r#"[
+        {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :person/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+        {:db/ident :person/pet :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+        {:db/ident :pet/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).expect("transacted schema");
+    let report = store.transact(r#"[
+        {:db/id "alice" :person/name "Alice" :person/friend "bob"}
+        {:db/id "bob" :person/name "Bob" :person/pet "rex"}
+        {:db/id "rex" :pet/name "Rex"}
+    ]"#).expect("transacted data");
+    let alice = *report.tempids.get("alice").expect("alice");
+    let bob = *report.tempids.get("bob").expect("bob");
+
+    let rex: StructuredMap = vec![(kw!(:pet/name), TypedValue::from("Rex"))].into();
+    let bob_pull: StructuredMap = vec![
+        (kw!(:person/name), Binding::Scalar(TypedValue::from("Bob"))),
+        (kw!(:person/pet), rex.into()),
+    ].into();
+    let alice_pull: StructuredMap = vec![
+        (kw!(:person/name), Binding::Scalar(TypedValue::from("Alice"))),
+        (kw!(:person/friend), vec![Binding::from(bob_pull.clone())].into()),
+    ].into();
+
+    // Refs are followed, whether cardinality one or many, and replaced by the pulled entities.
+    let query = r#"[:find (pull ?p [:person/name {:person/friend [:person/name {:person/pet [:pet/name]}]}]) .
+                    :where [?p :person/name "Alice"]]"#;
+    let result = store.q_once(query, None)
+                      .into_scalar_result()
+                      .expect("result")
+                      .expect("alice");
+    assert_eq!(result, alice_pull.clone().into());
+
+    // The same patterns can be pulled for an entity directly.
+    let pattern = parse_pull_pattern("[:person/name {:person/friend [:person/name {:person/pet [:pet/name]}]}]")
+        .expect("parsed pattern");
+    let reader = store.begin_read().expect("read");
+    assert_eq!(reader.pull_attributes_for_entity(alice, pattern.clone()).expect("pulled"), alice_pull);
+
+    // A referred-to entity with none of the pulled attributes is an empty map.
+    let pattern = parse_pull_pattern("[{:person/pet [:person/name]}]").expect("parsed pattern");
+    let pulled = reader.pull_attributes_for_entity(bob, pattern).expect("pulled");
+    let expected: StructuredMap = vec![(kw!(:person/pet), StructuredMap::default())].into();
+    assert_eq!(pulled, expected);
+
+    // Only refs can be pulled with a nested pattern.
+    let pattern = parse_pull_pattern("[{:person/name [:pet/name]}]").expect("parsed pattern");
+    assert!(reader.pull_attributes_for_entity(alice, pattern).is_err());
+}
+
+#[test]
+fn test_pull_options() {
+    let mut store = Store::open("").expect("opened");
+    store.transact(r#"[
+        {:db/ident :node/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :node/child :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+        {:db/ident :node/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}
+        {:db/ident :node/size :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    ]"#).expect("transacted schema");
+
+    // A cycle: root -> a -> b -> root.
+    let report = store.transact(r#"[
+        {:db/id "root" :node/name "root" :node/child "a" :node/tag ["x" "y" "z"] :node/size 3}
+        {:db/id "a" :node/name "a" :node/child "b"}
+        {:db/id "b" :node/name "b" :node/child "root"}
+    ]"#).expect("transacted data");
+    let root = *report.tempids.get("root").expect("root");
+    let b = *report.tempids.get("b").expect("b");
+
+    let reader = store.begin_read().expect("read");
+    let pull = |pattern: &str, e: Entid| {
+        reader.pull_attributes_for_entity(e, parse_pull_pattern(pattern).expect("parsed pattern"))
+              .expect("pulled")
+    };
+    let node = |name: &str, children: Vec<Binding>| -> Binding {
+        let m: StructuredMap = vec![
+            (kw!(:node/name), Binding::Scalar(TypedValue::from(name))),
+            (kw!(:node/child), children.into()),
+        ].into();
+        m.into()
+    };
+
+    // Recursion follows refs until it reaches an entity it has already pulled, which is left as a ref.
+    let expected = node("root", vec![node("a", vec![node("b", vec![TypedValue::Ref(root).into()])])]);
+    assert_eq!(Binding::from(pull("[:node/name {:node/child ...}]", root)), expected);
+
+    // A depth limit stops it sooner.
+    let expected = node("root", vec![node("a", vec![TypedValue::Ref(b).into()])]);
+    assert_eq!(Binding::from(pull("[:node/name {:node/child 1}]", root)), expected);
+
+    // The same in a query.
+    let result = reader.q_once("[:find (pull ?n [:node/name {:node/child 1}]) . :where [?n :node/name \"root\"]]", None)
+                       .into_scalar_result()
+                       .expect("result")
+                       .expect("root");
+    assert_eq!(result, expected);
+
+    // Limits and defaults.
+    let pulled = pull("[(limit :node/tag 2) (default :node/size 0)]", root);
+    match pulled.get(&kw!(:node/tag)) {
+        Some(&Binding::Vec(ref tags)) => assert_eq!(tags.len(), 2),
+        x => panic!("expected tags, got {:?}", x),
+    }
+    assert_eq!(pulled.get(&kw!(:node/size)), Some(&Binding::Scalar(TypedValue::Long(3))));
+
+    let pulled = pull("[(limit :node/tag nil) (default :node/size 0) (default :node/tag \"none\")]", b);
+    let expected: StructuredMap = vec![
+        (kw!(:node/size), Binding::Scalar(TypedValue::Long(0))),
+        (kw!(:node/tag), vec![Binding::Scalar(TypedValue::from("none"))].into()),
+    ].into();
+    assert_eq!(pulled, expected);
+
+    // A default must have the attribute's type, like the default of `get-else`.
+    let pattern = |pattern: &str| parse_pull_pattern(pattern).expect("parsed pattern");
+    for mismatched in &["[(default :node/size \"none\")]",
+                        "[(default :node/name :node/size)]",
+                        "[(default :node/name 0)]",
+                        "[(default :node/size true)]"] {
+        match reader.pull_attributes_for_entity(b, pattern(mismatched)) {
+            Err(MentatError::PullError(PullError::DefaultValueTypeMismatch(..))) => {},
+            x => panic!("expected DefaultValueTypeMismatch for {}, got {:?}", mismatched, x),
+        }
+    }
+
+    // An ident is an entid for a ref attribute.
+    let size = reader.q_once("[:find ?a . :where [?a :db/ident :node/size]]", None)
+                     .into_scalar_result()
+                     .expect("result")
+                     .expect("size");
+    let size = match size {
+        Binding::Scalar(TypedValue::Ref(size)) => size,
+        x => panic!("expected an entid, got {:?}", x),
+    };
+    let pulled = reader.pull_attributes_for_entity(size, pattern("[(default :node/child :node/size)]"))
+                       .expect("pulled");
+    assert_eq!(pulled.get(&kw!(:node/child)), Some(&vec![Binding::Scalar(TypedValue::Ref(size))].into()));
+}
+
+#[test]
+fn test_reverse_pull() {
+    let mut store = Store::open("").expect("opened");
+    store.transact(r#"[
+        {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :car/model :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :car/owner :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+        {:db/ident :account/holder :db/valueType :db.type/ref :db/cardinality :db.cardinality/one
+         :db/unique :db.unique/value :db/index true}
+    ]"#).expect("transacted schema");
+    let report = store.transact(r#"[
+        {:db/id "alice" :person/name "Alice"}
+        {:db/id "bob" :person/name "Bob"}
+        {:db/id "beetle" :car/model "Beetle" :car/owner "alice"}
+        {:db/id "mini" :car/model "Mini" :car/owner "alice"}
+        {:db/id "account" :account/holder "alice"}
+    ]"#).expect("transacted data");
+    let alice = *report.tempids.get("alice").expect("alice");
+    let bob = *report.tempids.get("bob").expect("bob");
+    let account = *report.tempids.get("account").expect("account");
+
+    let car = |model: &str| -> Binding {
+        let m: StructuredMap = vec![(kw!(:car/model), TypedValue::from(model))].into();
+        m.into()
+    };
+    let expected: StructuredMap = vec![
+        (kw!(:person/name), Binding::Scalar(TypedValue::from("Alice"))),
+        (kw!(:car/_owner), vec![car("Beetle"), car("Mini")].into()),
+    ].into();
+
+    // A non-unique ref can be asserted on many entities, so pulling it in reverse makes a collection.
+    let query = r#"[:find (pull ?p [:person/name {:car/_owner [:car/model]}]) .
+                    :where [?p :person/name "Alice"]]"#;
+    let result = store.q_once(query, None)
+                      .into_scalar_result()
+                      .expect("result")
+                      .expect("alice");
+    assert_eq!(result, expected.clone().into());
+
+    let pattern = parse_pull_pattern("[:person/name {:car/_owner [:car/model]}]").expect("parsed pattern");
+    assert_eq!(store.begin_read().expect("read").pull_attributes_for_entity(alice, pattern.clone()).expect("pulled"),
+               expected);
+
+    // The store's reverse cache is used when there is one.
+    store.cache(&kw!(:car/owner), CacheDirection::Reverse).expect("cached");
+    assert_eq!(store.pull_attributes_for_entity(alice, pattern.clone()).expect("pulled"), expected);
+    assert_eq!(store.begin_read().expect("read").pull_attributes_for_entity(alice, pattern).expect("pulled"),
+               expected);
+
+    // A unique ref makes a single entity. Entities that nothing refers to have nothing to pull.
+    let reader = store.begin_read().expect("read");
+    let pattern = parse_pull_pattern("[:account/_holder :as :account :car/_owner]").expect("parsed pattern");
+    let pulled = reader.pull_attributes_for_entity(alice, pattern.clone()).expect("pulled");
+    assert_eq!(pulled.get(&kw!(:account)), Some(&Binding::Scalar(TypedValue::Ref(account))));
+    assert_eq!(reader.pull_attributes_for_entity(bob, pattern).expect("pulled"), StructuredMap::default());
+
+    // Only refs can be reversed.
+    let pattern = parse_pull_pattern("[:car/_model]").expect("parsed pattern");
+    assert!(reader.pull_attributes_for_entity(alice, pattern).is_err());
+    drop(reader);
+
+    // Pulls in `:find` use the reverse cache too: they find the cars in it even once they're gone
+    // from the store.
+    let owner = store.conn().current_schema().get_entid(&kw!(:car/owner)).expect("owner").0;
+    store.sqlite_mut().execute("DELETE FROM datoms WHERE a = ?", [owner]).expect("deleted");
+    let result = store.q_once(query, None)
+                      .into_scalar_result()
+                      .expect("result")
+                      .expect("alice");
+    assert_eq!(result, expected.into());
+}
+
+#[test]
+fn test_component_pull() {
+    let mut store = Store::open("").expect("opened");
+    store.transact(r#"[
+        {:db/ident :order/items :db/valueType :db.type/ref :db/cardinality :db.cardinality/many
+         :db/isComponent true}
+        {:db/ident :order/customer :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+        {:db/ident :item/sku :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :item/part :db/valueType :db.type/ref :db/cardinality :db.cardinality/one
+         :db/isComponent true}
+        {:db/ident :part/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :customer/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).expect("transacted schema");
+    let report = store.transact(r#"[
+        {:db/id "customer" :customer/name "Alice"}
+        {:db/id "bolt" :part/name "Bolt"}
+        {:db/id "a" :item/sku "A" :item/part "bolt"}
+        {:db/id "b" :item/sku "B"}
+        {:db/id "order" :order/customer "customer" :order/items ["a" "b"]}
+    ]"#).expect("transacted data");
+    let customer = *report.tempids.get("customer").expect("customer");
+    let order = *report.tempids.get("order").expect("order");
+    let a = *report.tempids.get("a").expect("a");
+    let b = *report.tempids.get("b").expect("b");
+
+    // A wildcard inlines components, and their components, but not other refs.
+    let part: StructuredMap = vec![(kw!(:part/name), TypedValue::from("Bolt"))].into();
+    let item_a: StructuredMap = vec![
+        (kw!(:item/sku), Binding::Scalar(TypedValue::from("A"))),
+        (kw!(:item/part), part.into()),
+    ].into();
+    let item_b: StructuredMap = vec![(kw!(:item/sku), TypedValue::from("B"))].into();
+    let expected: StructuredMap = vec![
+        (kw!(:order/customer), Binding::Scalar(TypedValue::Ref(customer))),
+        (kw!(:order/items), vec![Binding::from(item_a), Binding::from(item_b)].into()),
+    ].into();
+
+    let query = r#"[:find (pull ?o [*]) .
+                    :where [?o :order/customer _]]"#;
+    let result = store.q_once(query, None)
+                      .into_scalar_result()
+                      .expect("result")
+                      .expect("order");
+    assert_eq!(result, expected.clone().into());
+
+    let pattern = parse_pull_pattern("[*]").expect("parsed pattern");
+    assert_eq!(store.begin_read().expect("read").pull_attributes_for_entity(order, pattern).expect("pulled"),
+               expected);
+
+    // Naming the attribute, or saying how to pull it, is left alone.
+    let reader = store.begin_read().expect("read");
+    let pattern = parse_pull_pattern("[:order/items]").expect("parsed pattern");
+    let refs: Binding = vec![Binding::Scalar(TypedValue::Ref(a)), Binding::Scalar(TypedValue::Ref(b))].into();
+    let expected: StructuredMap = vec![(kw!(:order/items), refs)].into();
+    assert_eq!(reader.pull_attributes_for_entity(order, pattern).expect("pulled"), expected);
+
+    let pattern = parse_pull_pattern("[* {:order/items [:item/sku]}]").expect("parsed pattern");
+    let skus: Vec<Binding> = vec!["A", "B"].into_iter().map(|sku| {
+        let m: StructuredMap = vec![(kw!(:item/sku), TypedValue::from(sku))].into();
+        m.into()
+    }).collect();
+    let pulled = reader.pull_attributes_for_entity(order, pattern).expect("pulled");
+    assert_eq!(pulled.get(&kw!(:order/items)), Some(&skus.into()));
+}
+
 // TEST:
 // - Constant query bodies in pull.
 // - Values that are present in the cache (=> constant pull, too).
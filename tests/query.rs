@@ -35,10 +35,13 @@ use mentat_core::{DateTime, HasSchema, Utc, Uuid};
 use query_projector_traits::aggregates::SimpleAggregationOp;
 
 use mentat::{
-    new_connection, Binding, IntoResult, Keyword, PlainSymbol, QueryInputs, QueryResults,
-    Queryable, RelResult, Store, TxReport, TypedValue, Variable,
+    new_connection, Binding, CacheDirection, ClauseDecision, CustomAggregate, CustomFunction, DatabaseView, EmptyBecause,
+    IntoResult, KeyedRow, Keyword, PlainSymbol, QueryInputs, QueryPlanExplanation, QueryResults, Queryable, RelResult, Store,
+    TxReport, TypedValue, Variable,
 };
 
+use mentat::edn::query::SrcVar;
+
 use mentat::query::q_uncached;
 
 use mentat::conn::Conn;
@@ -481,7 +484,7 @@ fn test_fulltext() {
                 ) => {
                     assert_eq!(x, v);
                     assert_eq!(text.as_str(), "hello darkness my old friend");
-                    assert_eq!(score, 0.0f64.into());
+                    assert!(score > 0.0f64.into());
                 }
                 _ => panic!("Unexpected results."),
             }
@@ -564,6 +567,105 @@ fn test_fulltext() {
         }
         _ => panic!("Expected query to work."),
     }
+
+    // Results can be ranked by relevance, and displayed with the matched terms marked up.
+    conn.transact(
+        &mut c,
+        r#"[
+        [:db/add "w" :foo/fts "the sound of silence"]
+        [:db/add "x" :foo/fts "silence is golden, silence is sound"]
+        [:db/add "y" :foo/fts "a long and winding road, far from any sound or silence at all"]
+        [:db/add "z" :foo/fts "darkness again"]
+    ]"#,
+    )
+    .unwrap();
+
+    let query = r#"[:find ?val ?score ?snippet ?highlight
+                    :where [(fulltext $ :foo/fts "silence") [[_ ?val _ ?score ?snippet ?highlight]]]
+                    :order (desc ?score)]"#;
+    let rows: Vec<Vec<Binding>> = conn
+        .q_once(&mut c, query, None)
+        .into_rel_result()
+        .expect("results")
+        .into_iter()
+        .collect();
+    let texts: Vec<Binding> = rows.iter().map(|row| row[0].clone()).collect();
+    assert_eq!(
+        texts,
+        vec![
+            "silence is golden, silence is sound".into(),
+            "the sound of silence".into(),
+            "a long and winding road, far from any sound or silence at all".into(),
+        ]
+    );
+    assert_eq!(rows[1][2], "the sound of <b>silence</b>".into());
+    assert_eq!(
+        rows[0][3],
+        "<b>silence</b> is golden, <b>silence</b> is sound".into()
+    );
+
+    // The markup can be chosen by the query.
+    let query = r#"[:find ?highlight .
+                    :where [(fulltext $ :foo/fts "golden" ["[" "]"]) [[_ _ _ _ _ ?highlight]]]]"#;
+    let r = conn
+        .q_once(&mut c, query, None)
+        .into_scalar_result()
+        .expect("results");
+    assert_eq!(r, Some("silence is [golden], silence is sound".into()));
+}
+
+#[test]
+fn test_fulltext_attributes() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :note/title :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+        {:db/ident :note/body  :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+        {:db/ident :note/tag   :db/valueType :db.type/string :db/cardinality :db.cardinality/many :db/fulltext true :db/index true}
+        {:db/ident :note/kind  :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    store.transact(r#"[
+        {:db/id "a" :note/title "Groceries" :note/body "eggs, milk and bread" :note/tag "errands"}
+        {:db/id "b" :note/title "Bread recipes" :note/body "flour, water, salt" :note/tag "baking"}
+        {:db/id "c" :note/title "Holiday" :note/body "pack sunscreen" :note/tag "bread" :note/kind :note/tag}
+    ]"#).unwrap();
+
+    // One search across every fulltext attribute, with the matched attribute.
+    let query = r#"[:find ?title ?attr
+                    :where [(fulltext $ _ "bread") [[?note _ _ _ _ _ ?attr]]]
+                           [?note :note/title ?title]
+                    :order ?title]"#;
+    let rows: Vec<Vec<Binding>> = store
+        .q_once(query, None)
+        .into_rel_result()
+        .expect("results")
+        .into_iter()
+        .collect();
+    let title = store.conn().current_schema().get_entid(&Keyword::namespaced("note", "title")).unwrap().0;
+    let body = store.conn().current_schema().get_entid(&Keyword::namespaced("note", "body")).unwrap().0;
+    let tag = store.conn().current_schema().get_entid(&Keyword::namespaced("note", "tag")).unwrap().0;
+    assert_eq!(rows, vec![
+        vec!["Bread recipes".into(), Binding::Scalar(TypedValue::Ref(title))],
+        vec!["Groceries".into(), Binding::Scalar(TypedValue::Ref(body))],
+        vec!["Holiday".into(), Binding::Scalar(TypedValue::Ref(tag))],
+    ]);
+
+    // A set of attributes.
+    let query = r#"[:find [?title ...]
+                    :where [(fulltext $ #{:note/title :note/tag} "bread") [[?note]]]
+                           [?note :note/title ?title]
+                    :order ?title]"#;
+    let r = store.q_once(query, None).into_coll_result().expect("results");
+    assert_eq!(r, vec!["Bread recipes".into(), "Holiday".into()]);
+
+    // An attribute bound by an earlier clause.
+    let query = r#"[:find [?title ...]
+                    :where [_ :note/kind ?attr]
+                           [(fulltext $ ?attr "bread") [[?note]]]
+                           [?note :note/title ?title]]"#;
+    let r = store.q_once(query, None).into_coll_result().expect("results");
+    assert_eq!(r, vec!["Holiday".into()]);
 }
 
 #[test]
@@ -1277,6 +1379,624 @@ fn test_basic_aggregates() {
     }
 }
 
+#[test]
+fn test_extended_aggregates() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/team :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    store
+        .transact(
+            r#"[
+        {:foo/age 14 :foo/team "red"}
+        {:foo/age 22 :foo/team "red"}
+        {:foo/age 22 :foo/team "blue"}
+        {:foo/age 42 :foo/team "blue"}
+        {:foo/age 28 :foo/team "blue"}
+    ]"#,
+        )
+        .unwrap();
+
+    // Two people are 22, but there are only four distinct ages.
+    let r = store
+        .q_once(r#"[:find [(count ?age) (count-distinct ?age)] :with ?p :where [?p :foo/age ?age]]"#, None)
+        .into_tuple_result()
+        .expect("results");
+    assert_eq!(r, Some(vec![Binding::Scalar(TypedValue::Long(5)), Binding::Scalar(TypedValue::Long(4))]));
+
+    // 14, 22, 22, 28, 42.
+    let r = store
+        .q_once(r#"[:find [(median ?age) (variance ?age) (stddev ?age)] :with ?p :where [?p :foo/age ?age]]"#, None)
+        .into_tuple_result()
+        .expect("results")
+        .expect("a tuple");
+    assert_eq!(r[0], Binding::Scalar(TypedValue::Double(22.0.into())));
+    match (&r[1], &r[2]) {
+        (&Binding::Scalar(TypedValue::Double(variance)), &Binding::Scalar(TypedValue::Double(stddev))) => {
+            assert!((variance.into_inner() - 87.04).abs() < 1e-9);
+            assert!((stddev.into_inner() - 87.04f64.sqrt()).abs() < 1e-9);
+        }
+        r => panic!("Unexpected results {:?}", r),
+    }
+
+    // Collections: distinct values in order, and the least and greatest few.
+    let longs = |vs: &[i64]| Binding::Vec(vs.iter().map(|v| Binding::Scalar(TypedValue::Long(*v))).collect::<Vec<_>>().into());
+    let r = store
+        .q_once(
+            r#"[:find ?team (distinct ?age) (min 1 ?age) (max 2 ?age)
+                :with ?p
+                :where [?p :foo/age ?age] [?p :foo/team ?team]
+                :order ?team]"#,
+            None,
+        )
+        .into_rel_result()
+        .expect("results");
+    assert_eq!(r.row_count(), 2);
+    let rows: Vec<Vec<Binding>> = r.into_iter().collect();
+    assert_eq!(rows[0], vec!["blue".into(), longs(&[22, 28, 42]), longs(&[22]), longs(&[42, 28])]);
+    assert_eq!(rows[1], vec!["red".into(), longs(&[14, 22]), longs(&[14]), longs(&[22, 14])]);
+
+    // A sample has at most `n` distinct values.
+    let r = store
+        .q_once(r#"[:find (sample 3 ?age) . :with ?p :where [?p :foo/age ?age]]"#, None)
+        .into_scalar_result()
+        .expect("results");
+    match r {
+        Some(Binding::Vec(sample)) => {
+            assert_eq!(sample.len(), 3);
+            for v in sample.iter() {
+                assert!([14, 22, 28, 42].iter().any(|age| *v == Binding::Scalar(TypedValue::Long(*age))));
+            }
+            assert!(sample[0] != sample[1] && sample[1] != sample[2] && sample[0] != sample[2]);
+        }
+        r => panic!("Unexpected results {:?}", r),
+    }
+
+    // Strings have no median.
+    match store.q_once(r#"[:find (median ?t) . :where [_ :foo/team ?t]]"#, None).expect_err("expected query to fail") {
+        MentatError::ProjectorError(
+            ::query_projector_traits::errors::ProjectorError::CannotApplyAggregateOperationToTypes(
+                SimpleAggregationOp::Median,
+                types,
+            ),
+        ) => {
+            assert_eq!(types, ValueTypeSet::of_one(ValueType::String));
+        }
+        e => panic!("Unexpected error type {:?}", e),
+    }
+}
+
+#[test]
+fn test_order_by_aggregates_and_missing() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/team :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    store.transact(r#"[
+        {:foo/name "Alice" :foo/team "red"}
+        {:foo/name "Bob"   :foo/team "blue"}
+        {:foo/name "Carol" :foo/team "blue"}
+        {:foo/name "Dave"}
+    ]"#).unwrap();
+
+    // Biggest team first.
+    let r = store
+        .q_once(r#"[:find ?team (count ?p)
+                    :where [?p :foo/team ?team]
+                    :order (desc (count ?p)) ?team]"#, None)
+        .into_rel_result()
+        .expect("results");
+    let rows: Vec<Vec<Binding>> = r.into_iter().collect();
+    assert_eq!(rows, vec![vec!["blue".into(), Binding::Scalar(TypedValue::Long(2))],
+                          vec!["red".into(), Binding::Scalar(TypedValue::Long(1))]]);
+
+    // The default sorts before "blue", but people without a team go last.
+    let r = store
+        .q_once(r#"[:find ?name ?team
+                    :where [?p :foo/name ?name] [(get-else $ ?p :foo/team "-") ?team]
+                    :order (asc ?team :nulls-last) ?name]"#, None)
+        .into_rel_result()
+        .expect("results");
+    let names: Vec<Binding> = r.into_iter().map(|row| row[0].clone()).collect();
+    assert_eq!(names, vec!["Bob".into(), "Carol".into(), "Alice".into(), "Dave".into()]);
+
+    let r = store
+        .q_once(r#"[:find ?name ?team
+                    :where [?p :foo/name ?name] [(get-else $ ?p :foo/team "zzz") ?team]
+                    :order (asc ?team :nulls-first) ?name]"#, None)
+        .into_rel_result()
+        .expect("results");
+    let names: Vec<Binding> = r.into_iter().map(|row| row[0].clone()).collect();
+    assert_eq!(names, vec!["Dave".into(), "Bob".into(), "Carol".into(), "Alice".into()]);
+
+    // Aggregating loses track of which values were missing.
+    match store.q_once(r#"[:find ?team (count ?p)
+                           :where [?p :foo/name _] [(get-else $ ?p :foo/team "-") ?team]
+                           :order (asc ?team :nulls-last)]"#, None).expect_err("expected query to fail") {
+        MentatError::AlgebrizerError(::query_algebrizer_traits::errors::AlgebrizerError::InvalidOrder(_)) => {}
+        e => panic!("Unexpected error type {:?}", e),
+    }
+}
+
+#[test]
+fn test_explain() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity :db/index true}
+        {:db/ident :foo/team :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let report = store.transact(r#"[
+        {:db/id "a" :foo/name "Alice" :foo/team "red"}
+    ]"#).unwrap();
+    let alice = report.tempids.get("a").cloned().expect("alice");
+
+    store.cache(&Keyword::namespaced("foo", "name"), CacheDirection::Reverse).expect("cached");
+
+    let explanation = store
+        .q_explain(r#"[:find ?team :where [?p :foo/name "Alice"] [?p :foo/team ?team]]"#, None)
+        .expect("explained");
+    assert_eq!(explanation.known_types.get(&Variable::from_valid_name("?team")),
+               Some(&ValueTypeSet::of_one(ValueType::String)));
+
+    // Alice is found in the cache, so only her team is looked up in the store.
+    let team_pattern = format!("[{} :foo/team ?team]", alice);
+    assert_eq!(explanation.decisions.len(), 2);
+    match &explanation.decisions[0] {
+        &ClauseDecision::Cached { ref pattern } => assert_eq!(pattern, r#"[?p :foo/name "Alice"]"#),
+        x => panic!("expected a cached pattern, got {:?}", x),
+    }
+    let alias = match &explanation.decisions[1] {
+        &ClauseDecision::Table { ref pattern, ref alias } => {
+            assert_eq!(pattern, &team_pattern);
+            alias.1.clone()
+        },
+        x => panic!("expected a table, got {:?}", x),
+    };
+
+    // SQLite's plan is mapped back to the pattern.
+    match explanation.plan {
+        QueryPlanExplanation::ExecutionPlan { steps, .. } => {
+            let step = steps.iter().find(|step| step.detail.contains(alias.as_str())).expect("step for table");
+            assert_eq!(step.patterns, vec![team_pattern]);
+        },
+        _ => panic!("expected an execution plan"),
+    }
+
+    // Nobody is missing from the cache.
+    let explanation = store
+        .q_explain(r#"[:find ?p :where [?p :foo/name "Nobody"]]"#, None)
+        .expect("explained");
+    match explanation.plan {
+        QueryPlanExplanation::KnownEmpty(EmptyBecause::CachedAttributeHasNoEntity { .. }) => {},
+        _ => panic!("expected the query to be known empty"),
+    }
+    match explanation.decisions.as_slice() {
+        &[ClauseDecision::Empty(EmptyBecause::CachedAttributeHasNoEntity { .. })] => {},
+        x => panic!("expected one empty decision, got {:?}", x),
+    }
+
+    // The decisions about the clauses in an `or` or a `not` are nested, arm by arm.
+    let explanation = store
+        .q_explain(r#"[:find ?p
+                       :where [?p :foo/team _]
+                              (or [?p :foo/team "red"] [?p :foo/team "green"])
+                              (not [?p :foo/team "blue"])]"#, None)
+        .expect("explained");
+    let (or_alias, not_alias) = match explanation.decisions.as_slice() {
+        &[ClauseDecision::Table { .. }, ClauseDecision::Or(ref arms), ClauseDecision::Not(ref not)] => {
+            // A simple `or` matches each arm against the same table.
+            let or_alias = match arms.as_slice() {
+                &[ref red, ref green] => {
+                    match (red.as_slice(), green.as_slice()) {
+                        (&[ClauseDecision::Table { pattern: ref red, alias: ref red_alias }],
+                         &[ClauseDecision::Table { pattern: ref green, alias: ref green_alias }]) => {
+                            assert_eq!(red, r#"[?p :foo/team "red"]"#);
+                            assert_eq!(green, r#"[?p :foo/team "green"]"#);
+                            assert_eq!(red_alias, green_alias);
+                            red_alias.1.clone()
+                        },
+                        x => panic!("unexpected arms {:?}", x),
+                    }
+                },
+                x => panic!("expected two arms, got {:?}", x),
+            };
+            let not_alias = match not.as_slice() {
+                &[ClauseDecision::Table { ref pattern, ref alias }] => {
+                    assert_eq!(pattern, r#"[?p :foo/team "blue"]"#);
+                    alias.1.clone()
+                },
+                x => panic!("unexpected not {:?}", x),
+            };
+            (or_alias, not_alias)
+        },
+        x => panic!("unexpected decisions {:?}", x),
+    };
+
+    // Nested patterns are still mapped back from SQLite's plan.
+    match explanation.plan {
+        QueryPlanExplanation::ExecutionPlan { steps, .. } => {
+            let patterns = |alias: &str| {
+                steps.iter().find(|step| step.detail.contains(alias)).expect("step for table").patterns.clone()
+            };
+            assert_eq!(patterns(&or_alias), vec![r#"[?p :foo/team "red"]"#, r#"[?p :foo/team "green"]"#]);
+            assert_eq!(patterns(&not_alias), vec![r#"[?p :foo/team "blue"]"#]);
+        },
+        x => panic!("expected an execution plan, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_q_iter() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+    store.transact(r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob" :foo/age 40}
+        {:foo/name "Carol" :foo/age 35}
+    ]"#).unwrap();
+
+    // Rows are projected as they're read, and match those that are collected.
+    let query = "[:find ?name ?age :where [?p :foo/name ?name] [?p :foo/age ?age] :order ?name]";
+    let rows = store.q_iter(query, None, |rows| {
+        assert!(rows.is_streamed());
+        rows.collect::<Result<Vec<Vec<Binding>>, MentatError>>()
+    }).expect("rows");
+    let expected: Vec<Vec<Binding>> = store.q_once(query, None).into_rel_result().expect("rel").into_iter().collect();
+    assert_eq!(rows, expected);
+    assert_eq!(rows[0], vec!["Alice".into(), TypedValue::Long(30).into()]);
+
+    // The rest needn't be read.
+    let first = store.q_iter(query, None, |mut rows| rows.next().expect("a row")).expect("first");
+    assert_eq!(first, vec!["Alice".into(), TypedValue::Long(30).into()]);
+
+    // Scalar and coll queries have a binding per row, and aggregates are computed by SQLite.
+    let oldest = store.q_iter("[:find (max ?age) . :where [_ :foo/age ?age]]", None, |rows| {
+        assert!(rows.is_streamed());
+        rows.collect::<Result<Vec<Vec<Binding>>, MentatError>>()
+    }).expect("max");
+    assert_eq!(oldest, vec![vec![TypedValue::Long(40).into()]]);
+
+    let count = store.q_iter("[:find [?name ...] :where [_ :foo/name ?name]]", None, |rows| {
+        Ok(rows.count())
+    }).expect("names");
+    assert_eq!(count, 3);
+
+    // Pull can't be projected row by row, so it's collected first.
+    let pulled = store.q_iter("[:find (pull ?p [:foo/name]) :where [?p :foo/age 40]]", None, |rows| {
+        assert!(!rows.is_streamed());
+        rows.collect::<Result<Vec<Vec<Binding>>, MentatError>>()
+    }).expect("pulled");
+    assert_eq!(pulled.len(), 1);
+    match pulled[0][0] {
+        Binding::Map(ref m) => assert_eq!(m.get(&Keyword::namespaced("foo", "name")),
+                                          Some(&Binding::Scalar("Bob".into()))),
+        ref x => panic!("expected a map, got {:?}", x),
+    }
+
+    // So are queries known to be empty.
+    let empty = store.q_iter(r#"[:find ?p :where [?p :foo/age "old"]]"#, None, |rows| {
+        assert!(!rows.is_streamed());
+        Ok(rows.count())
+    }).expect("empty");
+    assert_eq!(empty, 0);
+
+    // Prepared queries, including those with parameters, can be iterated too.
+    let in_progress = store.begin_read().expect("read");
+    let mut prepared = in_progress
+        .q_prepare("[:find ?name :in ?age :where [?p :foo/age ?age] [?p :foo/name ?name]]", None)
+        .expect("prepared");
+    for &(age, name) in &[(30, "Alice"), (35, "Carol")] {
+        let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?age"), TypedValue::Long(age))]);
+        let rows = prepared.rows(inputs).expect("rows");
+        assert!(rows.is_streamed());
+        let rows = rows.collect::<Result<Vec<Vec<Binding>>, MentatError>>().expect("rows");
+        assert_eq!(rows, vec![vec![name.into()]]);
+    }
+}
+
+#[test]
+fn test_return_maps() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+    store.transact(r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob" :foo/age 40}
+    ]"#).unwrap();
+
+    // Each row is keyed in the order of the find spec.
+    let rows = store
+        .q_once("[:find ?name ?age :keys name person/age :where [?p :foo/name ?name] [?p :foo/age ?age] :order ?name]", None)
+        .expect("keys")
+        .into_maps()
+        .expect("maps");
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].keys().cloned().collect::<Vec<_>>(),
+               vec![mentat::edn::Value::from_keyword(None, "name"), mentat::edn::Value::from_keyword("person", "age")]);
+    assert_eq!(rows[0].by_name("name"), Some(&Binding::Scalar("Alice".into())));
+    assert_eq!(rows[1].by_name("person/age"), Some(&Binding::Scalar(TypedValue::Long(40))));
+    assert_eq!(rows[0].by_name("age"), None);
+
+    let rows = store
+        .q_once("[:find ?name :strs name :where [?p :foo/age 40] [?p :foo/name ?name]]", None)
+        .expect("strs")
+        .into_maps()
+        .expect("maps");
+    assert_eq!(rows[0].get(&mentat::edn::Value::Text("name".to_string())), Some(&Binding::Scalar("Bob".into())));
+
+    let rows = store
+        .q_once("[:find ?age :syms p/age :where [_ :foo/age ?age] :order ?age]", None)
+        .expect("syms")
+        .into_maps()
+        .expect("maps");
+    assert_eq!(rows[0].get(&mentat::edn::Value::from_symbol("p", "age")), Some(&Binding::Scalar(TypedValue::Long(30))));
+    assert_eq!(rows[0].by_name("p/age"), Some(&Binding::Scalar(TypedValue::Long(30))));
+
+    // A query known to be empty still returns maps.
+    let results = store
+        .q_once(r#"[:find ?p :keys p :where [?p :foo/age "old"]]"#, None)
+        .expect("empty")
+        .results;
+    assert_eq!(results, QueryResults::Maps(vec![]));
+
+    // Pulled entities are values like any other.
+    let rows = store
+        .q_once("[:find ?age (pull ?p [:foo/name]) :keys age person :where [?p :foo/age ?age] [(> ?age 35)]]", None)
+        .expect("pull")
+        .into_maps()
+        .expect("maps");
+    assert_eq!(rows.len(), 1);
+    match rows[0].by_name("person") {
+        Some(&Binding::Map(ref m)) => assert_eq!(m.get(&Keyword::namespaced("foo", "name")),
+                                                 Some(&Binding::Scalar("Bob".into()))),
+        x => panic!("expected a map, got {:?}", x),
+    }
+
+    // Other shapes can't be had from a map query.
+    assert!(store.q_once("[:find ?age :keys age :where [_ :foo/age ?age]]", None).expect("keys").into_rel().is_err());
+
+    // Streamed rows keep their names.
+    let rows = store.q_iter("[:find ?name ?age :keys name age :where [?p :foo/name ?name] [?p :foo/age ?age] :order ?name]", None, |rows| {
+        assert!(rows.is_streamed());
+        assert_eq!(rows.keys(), Some(&[mentat::edn::Value::from_keyword(None, "name"),
+                                       mentat::edn::Value::from_keyword(None, "age")][..]));
+        rows.maps().expect("keyed").collect::<Result<Vec<KeyedRow>, MentatError>>()
+    }).expect("rows");
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].by_name("name"), Some(&Binding::Scalar("Alice".into())));
+    assert_eq!(rows[1].by_name("age"), Some(&Binding::Scalar(TypedValue::Long(40))));
+
+    // So do rows that are collected first, like those pulled or known to be empty.
+    let rows = store.q_iter("[:find ?age (pull ?p [:foo/name]) :strs age person :where [?p :foo/age ?age]]", None, |rows| {
+        assert!(!rows.is_streamed());
+        rows.maps().expect("keyed").collect::<Result<Vec<KeyedRow>, MentatError>>()
+    }).expect("rows");
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].by_name("person").is_some());
+    let empty = store.q_iter(r#"[:find ?p :keys p :where [?p :foo/age "old"]]"#, None, |rows| {
+        assert!(rows.keys().is_some());
+        Ok(rows.maps().expect("keyed").count())
+    }).expect("empty");
+    assert_eq!(empty, 0);
+
+    // Other queries' rows have no names.
+    let keyed = store.q_iter("[:find ?age :where [_ :foo/age ?age]]", None, |rows| Ok(rows.maps().is_some()))
+        .expect("rows");
+    assert!(!keyed);
+}
+
+/// The range of some numbers.
+struct Spread;
+
+impl CustomAggregate for Spread {
+    type State = Option<(f64, f64)>;
+
+    fn result_type(&self, input_types: ValueTypeSet) -> Option<ValueType> {
+        if input_types.is_only_numeric() { Some(ValueType::Double) } else { None }
+    }
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(&self, state: &mut Self::State, value: TypedValue) {
+        let v = match value {
+            TypedValue::Long(v) => v as f64,
+            TypedValue::Double(v) => v.into_inner(),
+            _ => return,
+        };
+        *state = Some(state.map_or((v, v), |(min, max)| (min.min(v), max.max(v))));
+    }
+
+    fn finish(&self, state: Self::State) -> Option<TypedValue> {
+        state.map(|(min, max)| TypedValue::Double((max - min).into()))
+    }
+}
+
+/// Describes the types of some values, which can be of any type.
+struct Tally;
+
+impl CustomAggregate for Tally {
+    type State = (usize, usize);
+
+    fn result_type(&self, _input_types: ValueTypeSet) -> Option<ValueType> {
+        Some(ValueType::String)
+    }
+
+    fn init(&self) -> Self::State {
+        (0, 0)
+    }
+
+    fn step(&self, state: &mut Self::State, value: TypedValue) {
+        match value {
+            TypedValue::String(_) => state.0 += 1,
+            _ => state.1 += 1,
+        }
+    }
+
+    fn finish(&self, state: Self::State) -> Option<TypedValue> {
+        Some(TypedValue::typed_string(format!("{} strings, {} others", state.0, state.1)))
+    }
+}
+
+#[test]
+fn test_custom_aggregates() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/team :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    store
+        .transact(
+            r#"[
+        {:foo/age 14 :foo/team "red"}
+        {:foo/age 22 :foo/team "red"}
+        {:foo/age 42 :foo/team "blue"}
+        {:foo/age 28 :foo/team "blue"}
+    ]"#,
+        )
+        .unwrap();
+
+    let query = r#"[:find ?team (spread ?age) :where [?p :foo/age ?age] [?p :foo/team ?team] :order ?team]"#;
+
+    // Not yet registered.
+    match store.q_once(query, None).expect_err("expected query to fail") {
+        MentatError::ProjectorError(::query_projector_traits::errors::ProjectorError::NotYetImplemented(_)) => {}
+        e => panic!("Unexpected error type {:?}", e),
+    }
+
+    store.register_aggregate(&PlainSymbol::plain("spread"), Spread).expect("registered");
+    store.register_aggregate(&PlainSymbol::plain("stats/tally"), Tally).expect("registered");
+
+    let r = store.q_once(query, None).into_rel_result().expect("results");
+    let rows: Vec<Vec<Binding>> = r.into_iter().collect();
+    assert_eq!(rows, vec![
+        vec!["blue".into(), Binding::Scalar(TypedValue::Double(14.0.into()))],
+        vec!["red".into(), Binding::Scalar(TypedValue::Double(8.0.into()))],
+    ]);
+
+    // Registered aggregates are available within transactions, too.
+    {
+        let in_progress = store.begin_read().expect("began read");
+        let r = in_progress
+            .q_once(r#"[:find (spread ?age) . :where [_ :foo/age ?age]]"#, None)
+            .into_scalar_result()
+            .expect("results");
+        assert_eq!(r, Some(Binding::Scalar(TypedValue::Double(28.0.into()))));
+    }
+
+    // Values of several types are passed along with their type tags.
+    let r = store
+        .q_once(
+            r#"[:find (stats/tally ?v) .
+                :with ?p
+                :where [?p :foo/age _] [?p _ ?v]]"#,
+            None,
+        )
+        .into_scalar_result()
+        .expect("results");
+    assert_eq!(r, Some("4 strings, 4 others".into()));
+
+    // The aggregate decides which types it accepts.
+    match store
+        .q_once(r#"[:find (spread ?t) . :where [_ :foo/team ?t]]"#, None)
+        .expect_err("expected query to fail")
+    {
+        MentatError::ProjectorError(
+            ::query_projector_traits::errors::ProjectorError::CannotApplyCustomAggregateToTypes(name, types),
+        ) => {
+            assert_eq!(name, PlainSymbol::plain("spread"));
+            assert_eq!(types, ValueTypeSet::of_one(ValueType::String));
+        }
+        e => panic!("Unexpected error type {:?}", e),
+    }
+}
+
+#[test]
+fn test_custom_functions() {
+    let mut store = Store::open("").expect("opened");
+
+    store.transact(r#"[
+        {:db/ident :foo/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    store
+        .transact(
+            r#"[
+        {:foo/email "Alice@Example.com"}
+        {:foo/email "not an address"}
+        {:foo/email "bob@example.org"}
+    ]"#,
+        )
+        .unwrap();
+
+    let query = r#"[:find [?n ...]
+                    :where [_ :foo/email ?e] [(my/valid-email? ?e)] [(my/normalize ?e) ?n]
+                    :order ?n]"#;
+
+    // Not yet registered.
+    match store.q_once(query, None).expect_err("expected query to fail") {
+        MentatError::AlgebrizerError(query_algebrizer_traits::errors::AlgebrizerError::UnknownFunction(name)) => {
+            assert_eq!(name, PlainSymbol::plain("my/valid-email?"));
+        }
+        e => panic!("Unexpected error type {:?}", e),
+    }
+
+    let valid = CustomFunction::predicate(vec![ValueType::String], |args| match args[0] {
+        TypedValue::String(ref s) => !s.contains(' ') && s.contains('@'),
+        _ => false,
+    });
+    let normalize = CustomFunction::new(vec![ValueType::String], ValueType::String, |args| match args[0] {
+        TypedValue::String(ref s) => Some(s.to_lowercase().into()),
+        _ => None,
+    });
+    store.register_function(&PlainSymbol::plain("my/valid-email?"), valid).expect("registered");
+    store.register_function(&PlainSymbol::plain("my/normalize"), normalize).expect("registered");
+
+    let r = store.q_once(query, None).into_coll_result().expect("results");
+    assert_eq!(r, vec!["alice@example.com".into(), "bob@example.org".into()]);
+
+    // Registered functions are available within transactions, too, and a function without a
+    // result binds nothing.
+    store
+        .register_function(
+            &PlainSymbol::plain("my/domain"),
+            CustomFunction::new(vec![ValueType::String], ValueType::String, |args| match args[0] {
+                TypedValue::String(ref s) => s.find('@').map(|i| s[i + 1..].to_string().into()),
+                _ => None,
+            }),
+        )
+        .expect("registered");
+    {
+        let in_progress = store.begin_read().expect("began read");
+        let r = in_progress
+            .q_once(
+                r#"[:find [?d ...] :where [_ :foo/email ?e] [(my/domain ?e) ?d] :order ?d]"#,
+                None,
+            )
+            .into_coll_result()
+            .expect("results");
+        assert_eq!(r, vec!["Example.com".into(), "example.org".into()]);
+    }
+}
+
 #[test]
 fn test_combinatorial() {
     let mut store = Store::open("").expect("opened");
@@ -1365,6 +2085,657 @@ fn test_combinatorial() {
     );
 }
 
+#[test]
+fn test_rules() {
+    let mut store = Store::open("").expect("opened");
+
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :person/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :person/parent]
+        [:db/add "b" :db/valueType :db.type/ref]
+        [:db/add "b" :db/cardinality :db.cardinality/many]
+    ]"#,
+        )
+        .unwrap();
+
+    // Alice's parent is Beli, whose parents are Carlos and Diana. Diana's parent is Esme.
+    let tempids = store
+        .transact(
+            r#"[
+        {:db/id "a" :person/name "Alice" :person/parent "b"}
+        {:db/id "b" :person/name "Beli" :person/parent ["c" "d"]}
+        {:db/id "c" :person/name "Carlos"}
+        {:db/id "d" :person/name "Diana" :person/parent "e"}
+        {:db/id "e" :person/name "Esme"}
+    ]"#,
+        )
+        .unwrap()
+        .tempids;
+    let alice = *tempids.get("a").expect("a was mapped");
+    let esme = *tempids.get("e").expect("e was mapped");
+
+    let rules = r#"[[(parent-name ?c ?n) [?c :person/parent ?p] [?p :person/name ?n]]
+                    [(ancestor ?c ?p) [?c :person/parent ?p]]
+                    [(ancestor ?c ?p) [?c :person/parent ?x] (ancestor ?x ?p)]
+                    [(ancestor-of ?c ?p) [?c :person/parent ?p]]
+                    [(ancestor-of ?c ?p) (ancestor-of ?c ?x) [?x :person/parent ?p]]
+                    [(esme-or-child ?c) [?c :person/name "Esme"]]
+                    [(esme-or-child ?c) [?c :person/parent ?p] [?p :person/name "Esme"]]]"#;
+    let inputs = || {
+        QueryInputs::default()
+            .with_rules(mentat::edn::parse::parse_rules(rules).expect("rules to parse"))
+    };
+    let inputs_with = |var: &str, entid: i64| {
+        QueryInputs::with_value_sequence(vec![(
+            Variable::from_valid_name(var),
+            TypedValue::Ref(entid),
+        )])
+        .with_rules(mentat::edn::parse::parse_rules(rules).expect("rules to parse"))
+    };
+
+    // A simple rule is expanded in place.
+    let results = store
+        .q_once(
+            r#"[:find [?n ...]
+                :in $ %
+                :where [?a :person/name "Beli"] (parent-name ?a ?n)
+                :order ?n]"#,
+            inputs(),
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(
+        results,
+        vec![
+            TypedValue::typed_string("Carlos").into(),
+            TypedValue::typed_string("Diana").into(),
+        ]
+    );
+
+    // A recursive rule walks the whole tree.
+    let results = store
+        .q_once(
+            r#"[:find [?n ...]
+                :in $ %
+                :where [?a :person/name "Alice"] (ancestor ?a ?x) [?x :person/name ?n]
+                :order ?n]"#,
+            inputs(),
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(
+        results,
+        vec![
+            TypedValue::typed_string("Beli").into(),
+            TypedValue::typed_string("Carlos").into(),
+            TypedValue::typed_string("Diana").into(),
+            TypedValue::typed_string("Esme").into(),
+        ]
+    );
+
+    // A recursive rule can be bound from either end.
+    let results = store
+        .q_once(
+            r#"[:find [?n ...]
+                :in $ %
+                :where [?e :person/name "Esme"] (ancestor ?x ?e) [?x :person/name ?n]
+                :order ?n]"#,
+            inputs(),
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(
+        results,
+        vec![
+            TypedValue::typed_string("Alice").into(),
+            TypedValue::typed_string("Beli").into(),
+            TypedValue::typed_string("Diana").into(),
+        ]
+    );
+
+    // An input in a position that the recursion passes along starts the recursion from it.
+    let results = store
+        .q_once(
+            r#"[:find [?n ...]
+                :in $ % ?e
+                :where (ancestor ?x ?e) [?x :person/name ?n]
+                :order ?n]"#,
+            inputs_with("?e", esme),
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(
+        results,
+        vec![
+            TypedValue::typed_string("Alice").into(),
+            TypedValue::typed_string("Beli").into(),
+            TypedValue::typed_string("Diana").into(),
+        ]
+    );
+
+    let results = store
+        .q_once(
+            r#"[:find [?n ...]
+                :in $ % ?a
+                :where (ancestor-of ?a ?x) [?x :person/name ?n]
+                :order ?n]"#,
+            inputs_with("?a", alice),
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(
+        results,
+        vec![
+            TypedValue::typed_string("Beli").into(),
+            TypedValue::typed_string("Carlos").into(),
+            TypedValue::typed_string("Diana").into(),
+            TypedValue::typed_string("Esme").into(),
+        ]
+    );
+
+    // A rule called with only constants checks that one of its definitions matches.
+    let diana = *tempids.get("d").expect("d was mapped");
+    let check = |entid: i64| {
+        format!(r#"[:find ?n . :in $ % :where [{} :person/name ?n] (esme-or-child {})]"#, esme, entid)
+    };
+    let results = store.q_once(&check(diana), inputs()).into_scalar_result().expect("scalar result");
+    assert_eq!(results, Some(TypedValue::typed_string("Esme").into()));
+    let results = store.q_once(&check(alice), inputs()).into_scalar_result().expect("scalar result");
+    assert_eq!(results, None);
+
+    // A query that doesn't mention `%` can't use rules.
+    match store.q_once(
+        r#"[:find ?x :where (ancestor ?x ?y)]"#,
+        inputs(),
+    ) {
+        Err(MentatError::AlgebrizerError(
+            query_algebrizer_traits::errors::AlgebrizerError::UnknownRule(name),
+        )) => {
+            assert_eq!(name, PlainSymbol::plain("ancestor"));
+        }
+        x => panic!("Got unexpected result {:?}", x),
+    }
+}
+
+#[test]
+fn test_get_else() {
+    let mut store = Store::open("").expect("opened");
+
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :foo/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :foo/age]
+        [:db/add "b" :db/valueType :db.type/long]
+        [:db/add "b" :db/cardinality :db.cardinality/one]
+    ]"#,
+        )
+        .unwrap();
+
+    store
+        .transact(
+            r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Beli"}
+    ]"#,
+        )
+        .unwrap();
+
+    let results = store
+        .q_once(
+            r#"[:find ?name ?age
+                :where [?x :foo/name ?name] [(get-else $ ?x :foo/age -1) ?age]
+                :order ?name]"#,
+            None,
+        )
+        .into_rel_result()
+        .expect("rel results");
+    assert_eq!(
+        results,
+        vec![
+            vec![TypedValue::typed_string("Alice"), TypedValue::Long(30)],
+            vec![TypedValue::typed_string("Beli"), TypedValue::Long(-1)],
+        ]
+        .into()
+    );
+
+    // The bound value can be used like any other.
+    let results = store
+        .q_once(
+            r#"[:find [?name ...]
+                :where [?x :foo/name ?name] [(get-else $ ?x :foo/age -1) ?age] [(< ?age 0)]]"#,
+            None,
+        )
+        .into_coll_result()
+        .expect("coll results");
+    assert_eq!(results, vec![TypedValue::typed_string("Beli").into()]);
+}
+
+#[test]
+fn test_computed_functions() {
+    let mut store = Store::open("").expect("opened");
+
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :foo/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :foo/age]
+        [:db/add "b" :db/valueType :db.type/long]
+        [:db/add "b" :db/cardinality :db.cardinality/one]
+        [:db/add "c" :db/ident :foo/born]
+        [:db/add "c" :db/valueType :db.type/instant]
+        [:db/add "c" :db/cardinality :db.cardinality/one]
+    ]"#,
+        )
+        .unwrap();
+
+    store
+        .transact(
+            r#"[
+        {:foo/name "Alice" :foo/age 30 :foo/born #inst "1990-03-04T05:06:07.000Z"}
+        {:foo/name "Beli 2" :foo/age 7 :foo/born #inst "1969-12-31T23:00:00.000Z"}
+    ]"#,
+        )
+        .unwrap();
+
+    let results = store
+        .q_once(
+            r#"[:find ?shout ?next ?half ?odd
+                :where [?x :foo/name ?name] [?x :foo/age ?age]
+                       [(upper-case ?name) ?upper] [(str ?upper "!") ?shout]
+                       [(+ ?age 1) ?next] [(/ ?age 2) ?half] [(mod ?age 2) ?odd]
+                :order ?shout]"#,
+            None,
+        )
+        .into_rel_result()
+        .expect("rel results");
+    assert_eq!(
+        results,
+        vec![
+            vec![
+                TypedValue::typed_string("ALICE!"),
+                TypedValue::Long(31),
+                TypedValue::Double(15.0.into()),
+                TypedValue::Long(0),
+            ],
+            vec![
+                TypedValue::typed_string("BELI 2!"),
+                TypedValue::Long(8),
+                TypedValue::Double(3.5.into()),
+                TypedValue::Long(1),
+            ],
+        ]
+        .into()
+    );
+
+    // Booleans and regular expressions. Names without digits don't bind `?digits`.
+    let results = store
+        .q_once(
+            r#"[:find ?name ?a ?digits
+                :where [?x :foo/name ?name]
+                       [(str/starts-with? ?name "Al") ?a]
+                       [(re-find "[0-9]+" ?name) ?digits]]"#,
+            None,
+        )
+        .into_rel_result()
+        .expect("rel results");
+    assert_eq!(
+        results,
+        vec![vec![
+            TypedValue::typed_string("Beli 2"),
+            TypedValue::Boolean(false),
+            TypedValue::typed_string("2"),
+        ]]
+        .into()
+    );
+
+    // Instants round down to midnight, even before the epoch.
+    let results = store
+        .q_once(
+            r#"[:find ?name ?day ?earlier
+                :where [?x :foo/name ?name] [?x :foo/born ?born]
+                       [(day-start ?born) ?day] [(-millis ?born 7000) ?earlier]
+                :order ?name]"#,
+            None,
+        )
+        .into_rel_result()
+        .expect("rel results");
+    let instant = |s: &str| TypedValue::Instant(DateTime::<Utc>::from_str(s).unwrap());
+    assert_eq!(
+        results,
+        vec![
+            vec![
+                TypedValue::typed_string("Alice"),
+                instant("1990-03-04T00:00:00.000Z"),
+                instant("1990-03-04T05:06:00.000Z"),
+            ],
+            vec![
+                TypedValue::typed_string("Beli 2"),
+                instant("1969-12-31T00:00:00.000Z"),
+                instant("1969-12-31T22:59:53.000Z"),
+            ],
+        ]
+        .into()
+    );
+}
+
+#[test]
+fn test_missing() {
+    let mut store = Store::open("").expect("opened");
+
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :foo/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :foo/email]
+        [:db/add "b" :db/valueType :db.type/string]
+        [:db/add "b" :db/cardinality :db.cardinality/many]
+    ]"#,
+        )
+        .unwrap();
+
+    let report = store
+        .transact(
+            r#"[
+        {:db/id "a" :foo/name "Alice" :foo/email "alice@example.com"}
+        {:db/id "b" :foo/name "Beli"}
+    ]"#,
+        )
+        .unwrap();
+    let alice = report.tempids.get("a").cloned().expect("a");
+    let beli = report.tempids.get("b").cloned().expect("b");
+
+    let query = r#"[:find [?name ...] :where [?x :foo/name ?name] [(missing? $ ?x :foo/email)]]"#;
+    let results = store.q_once(query, None).into_coll_result().expect("coll results");
+    assert_eq!(results, vec![TypedValue::typed_string("Beli").into()]);
+
+    // With the attribute cached, a known entity can be checked without touching the store.
+    store
+        .cache(&Keyword::namespaced("foo", "email"), CacheDirection::Forward)
+        .expect("cached");
+    let query = r#"[:find ?name . :in ?x :where [?x :foo/name ?name] [(missing? $ ?x :foo/email)]]"#;
+    for &(e, expected) in [(alice, None), (beli, Some("Beli"))].iter() {
+        let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?x"), TypedValue::Ref(e))]);
+        let result = store.q_once(query, inputs).into_scalar_result().expect("scalar result");
+        assert_eq!(result, expected.map(|n| TypedValue::typed_string(n).into()));
+    }
+}
+
+#[test]
+fn test_historical_views() {
+    let mut store = Store::open("").expect("opened");
+
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :foo/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :foo/note]
+        [:db/add "b" :db/valueType :db.type/string]
+        [:db/add "b" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/fulltext true]
+        [:db/add "b" :db/index true]
+        [:db/add "c" :db/ident :foo/nickname]
+        [:db/add "c" :db/valueType :db.type/string]
+        [:db/add "c" :db/cardinality :db.cardinality/one]
+    ]"#,
+        )
+        .unwrap();
+
+    let first = store
+        .transact(r#"[{:db/id "a" :foo/name "Alice" :foo/note "first draft"}]"#)
+        .unwrap();
+    let alice = first.tempids.get("a").cloned().expect("a");
+    let renamed = store
+        .transact(format!("[[:db/add {} :foo/name \"Alicia\"] [:db/add {} :foo/nickname \"Alice\"]]", alice, alice).as_str())
+        .unwrap();
+    store
+        .transact(format!("[[:db/retract {} :foo/note \"first draft\"]]", alice).as_str())
+        .unwrap();
+
+    let names = r#"[:find [?name ...] :where [_ :foo/name ?name] :order ?name]"#;
+    let notes = r#"[:find [?note ...] :where [_ :foo/note ?note]]"#;
+    let strings = |names: &[&str]| -> Vec<Binding> {
+        names.iter().map(|&n| TypedValue::typed_string(n).into()).collect()
+    };
+
+    assert_eq!(store.q_once(names, None).into_coll_result().expect("current"), strings(&["Alicia"]));
+    assert_eq!(store.q_once(notes, None).into_coll_result().expect("current"), strings(&[]));
+
+    {
+        let as_of = store.as_of(first.tx_id).expect("as of");
+        assert_eq!(as_of.q_once(names, None).into_coll_result().expect("as of"), strings(&["Alice"]));
+        assert_eq!(as_of.q_once(notes, None).into_coll_result().expect("as of"), strings(&["first draft"]));
+        assert_eq!(
+            as_of
+                .lookup_value_for_attribute(alice, &Keyword::namespaced("foo", "name"))
+                .expect("lookup"),
+            Some(TypedValue::typed_string("Alice"))
+        );
+
+        // Pull reads only the current datoms, so it isn't yet supported against a view.
+        let pull = r#"[:find (pull ?e [:foo/name]) . :where [?e :foo/name "Alice"]]"#;
+        match as_of.q_once(pull, None).expect_err("expected pull to fail") {
+            MentatError::ProjectorError(::query_projector_traits::errors::ProjectorError::NotYetImplemented(_)) => {}
+            e => panic!("Unexpected error type {:?}", e),
+        }
+    }
+
+    {
+        let since = store.since(first.tx_id).expect("since");
+        assert_eq!(since.q_once(names, None).into_coll_result().expect("since"), strings(&["Alicia"]));
+        assert_eq!(since.q_once(notes, None).into_coll_result().expect("since"), strings(&[]));
+    }
+
+    {
+        let history = store.history().expect("history");
+        assert_eq!(
+            history.q_once(names, None).into_coll_result().expect("history"),
+            strings(&["Alice", "Alicia"])
+        );
+        assert_eq!(history.q_once(notes, None).into_coll_result().expect("history"), strings(&["first draft"]));
+
+        // Alice was renamed, which retracted her old name: joining the transaction's data says
+        // which datoms were asserted and which retracted.
+        let changes = r#"[:find ?name ?added
+                          :where [?e ?a ?name ?tx]
+                                 [(tx-data $ ?tx) [[?e ?a ?name _ ?added]]]
+                                 [?a :db/ident :foo/name]
+                          :order ?name ?added]"#;
+        assert_eq!(
+            history.q_once(changes, None).into_rel_result().expect("history").into_iter().collect::<Vec<_>>(),
+            vec![
+                vec![TypedValue::typed_string("Alice").into(), TypedValue::Boolean(false).into()],
+                vec![TypedValue::typed_string("Alice").into(), TypedValue::Boolean(true).into()],
+                vec![TypedValue::typed_string("Alicia").into(), TypedValue::Boolean(true).into()],
+            ]
+        );
+
+        // The same transaction gave Alice her old name as a nickname, so the join must match
+        // the attribute too.
+        let renaming = r#"[:find [?added ...]
+                           :in ?tx ?name
+                           :where [?e ?a ?name ?tx]
+                                  [(tx-data $ ?tx) [[?e ?a ?name _ ?added]]]
+                                  [?a :db/ident :foo/name]]"#;
+        let inputs = QueryInputs::with_value_sequence(vec![
+            (Variable::from_valid_name("?tx"), TypedValue::Ref(renamed.tx_id)),
+            (Variable::from_valid_name("?name"), TypedValue::typed_string("Alice")),
+        ]);
+        assert_eq!(
+            history.q_once(renaming, inputs).into_coll_result().expect("history"),
+            vec![TypedValue::Boolean(false).into()]
+        );
+    }
+
+    // An instant stands for the last transaction at or before it.
+    let before = DateTime::<Utc>::from_str("2000-01-01T00:00:00Z").expect("parsed");
+    {
+        let as_of = store.as_of(before).expect("as of");
+        assert_eq!(as_of.q_once(names, None).into_coll_result().expect("as of"), strings(&[]));
+    }
+    {
+        let as_of = store.as_of(Utc::now()).expect("as of");
+        assert_eq!(as_of.q_once(names, None).into_coll_result().expect("as of"), strings(&["Alicia"]));
+    }
+}
+
+#[test]
+fn test_multiple_sources() {
+    // A shared reference store, whose attributes get different entids from ours.
+    let path = std::env::temp_dir().join(format!("mentat-reference-{}.db", Uuid::new_v4()));
+    let path = path.to_str().expect("path").to_string();
+    {
+        let mut reference = Store::open(path.as_str()).expect("opened");
+        reference
+            .transact(
+                r#"[
+            [:db/add "x" :db/ident :foo/unused]
+            [:db/add "x" :db/valueType :db.type/long]
+            [:db/add "x" :db/cardinality :db.cardinality/one]
+            [:db/add "a" :db/ident :foo/name]
+            [:db/add "a" :db/valueType :db.type/string]
+            [:db/add "a" :db/cardinality :db.cardinality/one]
+            [:db/add "b" :db/ident :foo/note]
+            [:db/add "b" :db/valueType :db.type/string]
+            [:db/add "b" :db/cardinality :db.cardinality/one]
+            [:db/add "b" :db/fulltext true]
+            [:db/add "b" :db/index true]
+        ]"#,
+            )
+            .unwrap();
+        reference
+            .transact(
+                r#"[{:foo/name "Alice" :foo/note "an early draft"}
+                    {:foo/name "Bob" :foo/note "a final copy"}]"#,
+            )
+            .unwrap();
+    }
+
+    let mut store = Store::open("").expect("opened");
+    store
+        .transact(
+            r#"[
+        [:db/add "a" :db/ident :foo/name]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#,
+        )
+        .unwrap();
+    let report = store
+        .transact(r#"[{:db/id "a" :foo/name "Alice"} {:db/id "b" :foo/name "Bob"}]"#)
+        .unwrap();
+    let alice = report.tempids.get("a").cloned().expect("a");
+    let bob = report.tempids.get("b").cloned().expect("b");
+    store
+        .transact(format!("[[:db/add {} :foo/name \"Robert\"]]", bob).as_str())
+        .unwrap();
+
+    // A database that isn't a Mentat store isn't left attached.
+    assert!(store.attach("", "reference").is_err());
+    let reference = store.attach(path.as_str(), "reference").expect("attached");
+    let history = store.source().with_view(DatabaseView::History);
+    let inputs = || {
+        QueryInputs::default()
+            .with_source(SrcVar::NamedSrc("ref".to_string()), reference.clone())
+            .with_source(SrcVar::NamedSrc("h".to_string()), history.clone())
+    };
+
+    // Join our people with the reference store's notes about them.
+    let notes = r#"[:find ?person ?note
+                    :in $ $ref
+                    :where [?person :foo/name ?name]
+                           [$ref ?r :foo/name ?name]
+                           [$ref ?r :foo/note ?note]]"#;
+    assert_eq!(
+        store.q_once(notes, inputs()).into_rel_result().expect("rel"),
+        vec![vec![TypedValue::Ref(alice), TypedValue::typed_string("an early draft")]].into()
+    );
+
+    // Fulltext search works against a named source, too.
+    let drafts = r#"[:find [?person ...]
+                     :in $ $ref
+                     :where [(fulltext $ref :foo/note "draft") [[?r]]]
+                            [$ref ?r :foo/name ?name]
+                            [?person :foo/name ?name]]"#;
+    assert_eq!(
+        store.q_once(drafts, inputs()).into_coll_result().expect("coll"),
+        vec![Binding::Scalar(TypedValue::Ref(alice))]
+    );
+
+    // Bob is only known as Bob in our history, where the reference store knows him.
+    let renamed = r#"[:find ?person ?note
+                      :in $h $ref
+                      :where [$h ?person :foo/name ?name]
+                             [$ref ?r :foo/name ?name]
+                             [$ref ?r :foo/note ?note]
+                      :order ?note]"#;
+    assert_eq!(
+        store.q_once(renamed, inputs()).into_rel_result().expect("rel"),
+        vec![
+            vec![TypedValue::Ref(bob), TypedValue::typed_string("a final copy")],
+            vec![TypedValue::Ref(alice), TypedValue::typed_string("an early draft")],
+        ].into()
+    );
+
+    // `get-else` reads a named source's datoms, and resolves attributes we don't have with its
+    // schema.
+    let defaults = r#"[:find ?person ?note ?unused
+                       :in $ $ref
+                       :where [?person :foo/name ?name]
+                              [$ref ?r :foo/name ?name]
+                              [(get-else $ref ?r :foo/note "none") ?note]
+                              [(get-else $ref ?r :foo/unused -1) ?unused]]"#;
+    assert_eq!(
+        store.q_once(defaults, inputs()).into_rel_result().expect("rel"),
+        vec![vec![
+            TypedValue::Ref(alice),
+            TypedValue::typed_string("an early draft"),
+            TypedValue::Long(-1),
+        ]].into()
+    );
+
+    // So does `missing?`.
+    let missing = |attribute: &str| {
+        let query = format!(
+            r#"[:find [?name ...]
+                :in $ $ref
+                :where [$ref ?r :foo/name ?name]
+                       [(missing? $ref ?r {})]
+                :order ?name]"#,
+            attribute
+        );
+        store.q_once(query.as_str(), inputs()).into_coll_result().expect("coll")
+    };
+    assert_eq!(missing(":foo/note"), vec![]);
+    assert_eq!(
+        missing(":foo/unused"),
+        vec![TypedValue::typed_string("Alice").into(), TypedValue::typed_string("Bob").into()]
+    );
+
+    // Sources must be supplied.
+    match store.q_once(notes, None).expect_err("expected an unknown source") {
+        MentatError::AlgebrizerError(
+            query_algebrizer_traits::errors::AlgebrizerError::UnknownSource(name),
+        ) => assert_eq!(name, "ref"),
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    drop(store);
+    std::fs::remove_file(path).expect("removed");
+}
+
 #[test]
 fn test_aggregate_the() {
     let mut store = Store::open("").expect("opened");
@@ -33,8 +33,9 @@ use core_traits::{
 use mentat::{
     Binding,
     CacheDirection,
+    ClauseDecision,
     Keyword,
-    QueryExplanation,
+    QueryPlanExplanation,
     QueryOutput,
     QueryResults,
     Queryable,
@@ -112,8 +113,8 @@ lazy_static! {
             (COMMAND_TRANSACT_LONG, "Execute a transact against the current open database."),
             (COMMAND_TRANSACT_SHORT, "Shortcut for `.transact`. Execute a transact against the current open database."),
 
-            (COMMAND_QUERY_EXPLAIN_LONG, "Show the SQL and query plan that would be executed for a given query."),
-            (COMMAND_QUERY_EXPLAIN_SHORT, "Shortcut for `.explain_query`. Show the SQL and query plan that would be executed for a given query."),
+            (COMMAND_QUERY_EXPLAIN_LONG, "Show how a given query would be executed: what the algebrizer decided about its clauses, and its SQL and query plan."),
+            (COMMAND_QUERY_EXPLAIN_SHORT, "Shortcut for `.explain_query`. Show how a given query would be executed."),
 
             (COMMAND_TIMER_LONG, "Enable or disable timing of query and transact operations."),
 
@@ -464,9 +465,13 @@ impl Repl {
         let stdout = ::std::io::stdout();
         let mut output = TabWriter::new(stdout.lock());
 
-        // Print the column headers.
-        for e in query_output.spec.columns() {
-            write!(output, "| {}\t", e)?;
+        // Print the column headers: the keys of a return map, if there is one.
+        let headers: Vec<String> = match query_output.results {
+            QueryResults::Maps(ref rows) if !rows.is_empty() => rows[0].keys().map(|k| k.to_string()).collect(),
+            _ => query_output.spec.columns().map(|e| e.to_string()).collect(),
+        };
+        for header in headers {
+            write!(output, "| {}\t", header)?;
         }
         writeln!(output, "|")?;
         for _ in 0..query_output.spec.expected_column_count() {
@@ -504,6 +509,15 @@ impl Repl {
                     writeln!(output, "|")?;
                 }
             },
+
+            QueryResults::Maps(rows) => {
+                for row in rows {
+                    for v in row.values() {
+                        write!(output, "| {}\t", self.binding_as_string(v))?;
+                    }
+                    writeln!(output, "|")?;
+                }
+            },
         }
         for _ in 0..query_output.spec.expected_column_count() {
             write!(output, "---\t")?;
@@ -514,14 +528,33 @@ impl Repl {
     }
 
     pub fn explain_query(&self, query: String) {
-        match self.store.q_explain(query.as_str(), None) {
-            Result::Err(err) =>
-                println!("{:?}.", err),
-            Result::Ok(QueryExplanation::KnownConstant) =>
+        let explanation = match self.store.q_explain(query.as_str(), None) {
+            Result::Err(err) => {
+                println!("{:?}.", err);
+                return;
+            },
+            Result::Ok(explanation) => explanation,
+        };
+
+        if !explanation.known_types.is_empty() {
+            println!("Known types:");
+            for (var, types) in explanation.known_types.iter() {
+                let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+                println!("  {} {}", var, types.join(" "));
+            }
+        }
+
+        if !explanation.decisions.is_empty() {
+            println!("Clauses:");
+            self.print_decisions(&explanation.decisions, 1);
+        }
+
+        match explanation.plan {
+            QueryPlanExplanation::KnownConstant =>
                 println!("Query is known constant!"),
-            Result::Ok(QueryExplanation::KnownEmpty(empty_because)) =>
+            QueryPlanExplanation::KnownEmpty(empty_because) =>
                 println!("Query is known empty: {:?}", empty_because),
-            Result::Ok(QueryExplanation::ExecutionPlan { query, steps }) => {
+            QueryPlanExplanation::ExecutionPlan { query, steps } => {
                 println!("SQL: {}", query.sql);
                 if !query.args.is_empty() {
                     println!("  Bindings:");
@@ -530,7 +563,7 @@ impl Repl {
                     }
                 }
 
-                println!("Plan: select id | order | from | detail");
+                println!("Plan: select id | order | from | detail | clauses");
                 // Compute the number of columns we need for order, select id, and from,
                 // so that longer query plans don't become misaligned.
                 let (max_select_id, max_order, max_from) = steps.iter().fold((0, 0, 0), |acc, step|
@@ -542,8 +575,8 @@ impl Repl {
                 let max_from_digits = max_from.to_string().len();
                 for step in steps {
                     // Note: > is right align.
-                    println!("  {:>sel_cols$}|{:>ord_cols$}|{:>from_cols$}|{}",
-                             step.select_id, step.order, step.from, step.detail,
+                    println!("  {:>sel_cols$}|{:>ord_cols$}|{:>from_cols$}|{}|{}",
+                             step.select_id, step.order, step.from, step.detail, step.patterns.join(" "),
                              sel_cols = max_select_digits,
                              ord_cols = max_order_digits,
                              from_cols = max_from_digits);
@@ -552,6 +585,31 @@ impl Repl {
         };
     }
 
+    fn print_decisions(&self, decisions: &[ClauseDecision], depth: usize) {
+        let indent = "  ".repeat(depth);
+        for decision in decisions.iter() {
+            match decision {
+                &ClauseDecision::Table { ref pattern, ref alias } =>
+                    println!("{}{} from {}", indent, pattern, alias.1),
+                &ClauseDecision::Cached { ref pattern } =>
+                    println!("{}{} from the attribute cache", indent, pattern),
+                &ClauseDecision::Empty(ref empty_because) =>
+                    println!("{}Known empty: {:?}", indent, empty_because),
+                &ClauseDecision::Or(ref arms) => {
+                    println!("{}Or:", indent);
+                    for (i, arm) in arms.iter().enumerate() {
+                        println!("{}  Arm {}:", indent, i);
+                        self.print_decisions(arm, depth + 2);
+                    }
+                },
+                &ClauseDecision::Not(ref decisions) => {
+                    println!("{}Not:", indent);
+                    self.print_decisions(decisions, depth + 1);
+                },
+            }
+        }
+    }
+
     pub fn execute_transact(&mut self, transaction: String) {
         match self.transact(transaction) {
             Result::Ok(report) => println!("{:?}", report),
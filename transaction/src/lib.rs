@@ -20,7 +20,9 @@ extern crate mentat_db;
 extern crate mentat_query_algebrizer;
 extern crate mentat_query_projector;
 extern crate mentat_query_pull;
+extern crate mentat_query_sql;
 extern crate mentat_sql;
+extern crate query_algebrizer_traits;
 
 use std::sync::{
     Arc,
@@ -49,6 +51,7 @@ use edn::entities::{
     TempId,
     OpType,
 };
+use edn::query::PullAttributeSpec;
 
 use core_traits::{
     Attribute,
@@ -65,9 +68,14 @@ use public_traits::errors::{
 };
 
 use mentat_core::{
+    AggregateRegistry,
+    CachedAttributes,
+    DateTime,
+    FunctionRegistry,
     HasSchema,
     Schema,
     TxReport,
+    Utc,
     ValueRc,
 };
 
@@ -107,17 +115,21 @@ pub use metadata::{
 };
 
 use query::{
+    DatabaseView,
+    IntoResult,
     Known,
     PreparedResult,
     QueryExplanation,
     QueryInputs,
     QueryOutput,
+    QueryRows,
+    Variable,
     lookup_value_for_attribute,
     lookup_values_for_attribute,
     q_explain,
+    q_iter,
     q_once,
     q_prepare,
-    q_uncached,
 };
 
 
@@ -145,6 +157,8 @@ pub struct InProgress<'a, 'c> {
     pub partition_map: PartitionMap,
     pub schema: Schema,
     pub cache: InProgressSQLiteAttributeCache,
+    pub aggregates: Arc<AggregateRegistry>,
+    pub functions: Arc<FunctionRegistry>,
     pub use_caching: bool,
     pub tx_observer: &'a Mutex<TxObservationService>,
     pub tx_observer_watcher: InProgressObserverTransactWatcher,
@@ -156,11 +170,43 @@ pub struct InProgressRead<'a, 'c> {
     pub in_progress: InProgress<'a, 'c>,
 }
 
+/// A point in the transaction log: either a transaction, or an instant, which stands for the last
+/// transaction at or before it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxPoint {
+    Tx(Entid),
+    Instant(DateTime<Utc>),
+}
+
+impl From<Entid> for TxPoint {
+    fn from(tx: Entid) -> TxPoint {
+        TxPoint::Tx(tx)
+    }
+}
+
+impl From<DateTime<Utc>> for TxPoint {
+    fn from(instant: DateTime<Utc>) -> TxPoint {
+        TxPoint::Instant(instant)
+    }
+}
+
+/// An `InProgressRead` whose queries run against a historical view of the store, derived from the
+/// transaction log, rather than against the current datoms. The current schema is used to
+/// interpret the log. Pull expressions only read the current datoms, so queries that use them
+/// fail with `ProjectorError::NotYetImplemented`, and this doesn't implement `Pullable`.
+pub struct HistoricalRead<'a, 'c> {
+    pub in_progress: InProgressRead<'a, 'c>,
+    pub view: DatabaseView,
+}
+
 pub trait Queryable {
     fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>>;
     fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
         where T: Into<Option<QueryInputs>>;
+    fn q_iter<T, F, R>(&self, query: &str, inputs: T, f: F) -> Result<R>
+        where T: Into<Option<QueryInputs>>,
+              F: FnOnce(QueryRows) -> Result<R>;
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult<'_>
         where T: Into<Option<QueryInputs>>;
     fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::Keyword) -> Result<Vec<TypedValue>>
@@ -169,12 +215,17 @@ pub trait Queryable {
         where E: Into<Entid>;
 }
 
+/// Pulls attributes of entities. Attributes can be given as entids, or as any pull pattern,
+/// including map specs like `{:person/friend [:person/name]}` that pull the entities a ref
+/// attribute refers to.
 pub trait Pullable {
     fn pull_attributes_for_entities<E, A>(&self, entities: E, attributes: A) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
     where E: IntoIterator<Item=Entid>,
-          A: IntoIterator<Item=Entid>;
+          A: IntoIterator,
+          A::Item: Into<PullAttributeSpec>;
     fn pull_attributes_for_entity<A>(&self, entity: Entid, attributes: A) -> Result<StructuredMap>
-    where A: IntoIterator<Item=Entid>;
+    where A: IntoIterator,
+          A::Item: Into<PullAttributeSpec>;
 }
 
 impl<'a, 'c> InProgress<'a, 'c> {
@@ -276,15 +327,25 @@ impl<'a, 'c> InProgress<'a, 'c> {
         metadata.generation += 1;
         metadata.partition_map = self.partition_map;
 
+        // Queries are planned knowing which attributes are cached, and some using cached values,
+        // so changing either might change their plans.
+        let changed_registrations = self.cache.has_changed_registrations();
+        if self.cache.has_changed_values() {
+            metadata.cache_generation += 1;
+        }
+
         // Update the conn's cache if we made any changes.
         self.cache.commit_to(&mut metadata.attribute_cache);
 
         if self.schema != *(metadata.schema) {
+            metadata.schema_generation += 1;
             metadata.schema = Arc::new(self.schema);
 
             // TODO: rebuild vocabularies and notify consumers that they've changed -- it's possible
             // that a change has arrived over the wire and invalidated some local module.
             // TODO: consider making vocabulary lookup lazy -- we won't need it much of the time.
+        } else if changed_registrations {
+            metadata.schema_generation += 1;
         }
 
         let txes = self.tx_observer_watcher.txes;
@@ -340,6 +401,97 @@ impl<'a, 'c> InProgressRead<'a, 'c> {
     pub fn last_tx_id(&self) -> Entid {
         self.in_progress.last_tx_id()
     }
+
+    /// Query the store as it was immediately after `point`.
+    pub fn as_of<P>(self, point: P) -> Result<HistoricalRead<'a, 'c>> where P: Into<TxPoint> {
+        let tx = self.tx_for_point(point.into())?;
+        Ok(self.with_view(DatabaseView::AsOf(tx)))
+    }
+
+    /// Query only those current datoms that were asserted after `point`.
+    pub fn since<P>(self, point: P) -> Result<HistoricalRead<'a, 'c>> where P: Into<TxPoint> {
+        let tx = self.tx_for_point(point.into())?;
+        Ok(self.with_view(DatabaseView::Since(tx)))
+    }
+
+    /// Query every assertion and retraction ever made. A pattern matches both alike and can't
+    /// bind whether its datom was added; to tell them apart, join the pattern's datom with
+    /// `tx-data` on its entity, attribute, and value, as in
+    /// `[?e ?a ?v ?tx] [(tx-data $ ?tx) [[?e ?a ?v _ ?added]]] [?a :db/ident :foo/name]`.
+    /// That join can't match on the value of a fulltext attribute, which `tx-data` binds to
+    /// its fulltext row id rather than its text.
+    pub fn history(self) -> HistoricalRead<'a, 'c> {
+        self.with_view(DatabaseView::History)
+    }
+
+    fn with_view(self, view: DatabaseView) -> HistoricalRead<'a, 'c> {
+        HistoricalRead {
+            in_progress: self,
+            view: view,
+        }
+    }
+
+    fn tx_for_point(&self, point: TxPoint) -> Result<Entid> {
+        match point {
+            TxPoint::Tx(tx) => Ok(tx),
+            TxPoint::Instant(instant) => {
+                // If nothing was transacted by `instant`, then nothing had happened yet.
+                let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?instant"), instant.into())]);
+                let tx = self.q_once(r#"[:find ?tx .
+                                         :in ?instant
+                                         :where [?tx :db/txInstant ?t]
+                                                [(<= ?t ?instant)]
+                                         :order (desc ?tx)]"#, inputs)
+                             .into_scalar_result()?;
+                Ok(tx.and_then(|b| b.into_entid()).unwrap_or(0))
+            },
+        }
+    }
+}
+
+impl<'a, 'c> HistoricalRead<'a, 'c> {
+    fn known(&self) -> Known<'_, '_> {
+        Known::for_view(&self.in_progress.in_progress.schema, self.view)
+            .with_aggregates(&self.in_progress.in_progress.aggregates)
+            .with_functions(&self.in_progress.in_progress.functions)
+    }
+
+    fn transaction(&self) -> &rusqlite::Connection {
+        &*(self.in_progress.in_progress.transaction)
+    }
+}
+
+impl<'a, 'c> Queryable for HistoricalRead<'a, 'c> {
+    fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once(self.transaction(), self.known(), query, inputs)
+    }
+
+    fn q_iter<T, F, R>(&self, query: &str, inputs: T, f: F) -> Result<R>
+        where T: Into<Option<QueryInputs>>,
+              F: FnOnce(QueryRows) -> Result<R> {
+        q_iter(self.transaction(), self.known(), query, inputs, f)
+    }
+
+    fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult<'_>
+        where T: Into<Option<QueryInputs>> {
+        q_prepare(self.transaction(), self.known(), query, inputs)
+    }
+
+    fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>> {
+        q_explain(self.transaction(), self.known(), query, inputs)
+    }
+
+    fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::Keyword) -> Result<Vec<TypedValue>>
+        where E: Into<Entid> {
+        lookup_values_for_attribute(self.transaction(), self.known(), entity, attribute)
+    }
+
+    fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::Keyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        lookup_value_for_attribute(self.transaction(), self.known(), entity, attribute)
+    }
 }
 
 
@@ -349,6 +501,12 @@ impl<'a, 'c> Queryable for InProgressRead<'a, 'c> {
         self.in_progress.q_once(query, inputs)
     }
 
+    fn q_iter<T, F, R>(&self, query: &str, inputs: T, f: F) -> Result<R>
+        where T: Into<Option<QueryInputs>>,
+              F: FnOnce(QueryRows) -> Result<R> {
+        self.in_progress.q_iter(query, inputs, f)
+    }
+
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult<'_>
         where T: Into<Option<QueryInputs>> {
         self.in_progress.q_prepare(query, inputs)
@@ -373,12 +531,14 @@ impl<'a, 'c> Queryable for InProgressRead<'a, 'c> {
 impl<'a, 'c> Pullable for InProgressRead<'a, 'c> {
     fn pull_attributes_for_entities<E, A>(&self, entities: E, attributes: A) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
     where E: IntoIterator<Item=Entid>,
-          A: IntoIterator<Item=Entid> {
+          A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
         self.in_progress.pull_attributes_for_entities(entities, attributes)
     }
 
     fn pull_attributes_for_entity<A>(&self, entity: Entid, attributes: A) -> Result<StructuredMap>
-    where A: IntoIterator<Item=Entid> {
+    where A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
         self.in_progress.pull_attributes_for_entity(entity, attributes)
     }
 }
@@ -387,24 +547,37 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
         where T: Into<Option<QueryInputs>> {
 
-        if self.use_caching {
-            let known = Known::new(&self.schema, Some(&self.cache));
-            q_once(&*(self.transaction),
-                   known,
-                   query,
-                   inputs)
+        let known = if self.use_caching {
+            Known::new(&self.schema, Some(&self.cache))
         } else {
-            q_uncached(&*(self.transaction),
-                       &self.schema,
-                       query,
-                       inputs)
-        }
+            Known::for_schema(&self.schema)
+        };
+        q_once(&*(self.transaction),
+               known.with_aggregates(&self.aggregates).with_functions(&self.functions),
+               query,
+               inputs)
+    }
+
+    fn q_iter<T, F, R>(&self, query: &str, inputs: T, f: F) -> Result<R>
+        where T: Into<Option<QueryInputs>>,
+              F: FnOnce(QueryRows) -> Result<R> {
+
+        let known = if self.use_caching {
+            Known::new(&self.schema, Some(&self.cache))
+        } else {
+            Known::for_schema(&self.schema)
+        };
+        q_iter(&*(self.transaction),
+               known.with_aggregates(&self.aggregates).with_functions(&self.functions),
+               query,
+               inputs,
+               f)
     }
 
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult<'_>
         where T: Into<Option<QueryInputs>> {
 
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = Known::new(&self.schema, Some(&self.cache)).with_aggregates(&self.aggregates).with_functions(&self.functions);
         q_prepare(&*(self.transaction),
                   known,
                   query,
@@ -414,7 +587,7 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>> {
 
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = Known::new(&self.schema, Some(&self.cache)).with_aggregates(&self.aggregates).with_functions(&self.functions);
         q_explain(&*(self.transaction),
                   known,
                   query,
@@ -423,13 +596,13 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
 
     fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::Keyword) -> Result<Vec<TypedValue>>
         where E: Into<Entid> {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = Known::new(&self.schema, Some(&self.cache)).with_aggregates(&self.aggregates).with_functions(&self.functions);
         lookup_values_for_attribute(&*(self.transaction), known, entity, attribute)
     }
 
     fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::Keyword) -> Result<Option<TypedValue>>
         where E: Into<Entid> {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = Known::new(&self.schema, Some(&self.cache)).with_aggregates(&self.aggregates).with_functions(&self.functions);
         lookup_value_for_attribute(&*(self.transaction), known, entity, attribute)
     }
 }
@@ -437,14 +610,18 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
 impl<'a, 'c> Pullable for InProgress<'a, 'c> {
     fn pull_attributes_for_entities<E, A>(&self, entities: E, attributes: A) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
     where E: IntoIterator<Item=Entid>,
-          A: IntoIterator<Item=Entid> {
-        pull_attributes_for_entities(&self.schema, &*(self.transaction), entities, attributes)
+          A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
+        let cache = if self.use_caching { Some(&self.cache as &dyn CachedAttributes) } else { None };
+        pull_attributes_for_entities(&self.schema, &*(self.transaction), cache, entities, attributes)
             .map_err(|e| e.into())
     }
 
     fn pull_attributes_for_entity<A>(&self, entity: Entid, attributes: A) -> Result<StructuredMap>
-    where A: IntoIterator<Item=Entid> {
-        pull_attributes_for_entity(&self.schema, &*(self.transaction), entity, attributes)
+    where A: IntoIterator,
+          A::Item: Into<PullAttributeSpec> {
+        let cache = if self.use_caching { Some(&self.cache as &dyn CachedAttributes) } else { None };
+        pull_attributes_for_entity(&self.schema, &*(self.transaction), cache, entity, attributes)
             .map_err(|e| e.into())
     }
 }
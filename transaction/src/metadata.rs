@@ -1,7 +1,7 @@
 /// Connection metadata required to query from, or apply transactions to, a Mentat store.
 ///
 /// Owned data for the volatile parts (generation and partition map), and `Arc` for the infrequently
-/// changing parts (schema) that we want to share across threads.
+/// changing parts (schema, custom aggregates) that we want to share across threads.
 ///
 /// See https://github.com/mozilla/mentat/wiki/Thoughts:-modeling-db-conn-in-Rust.
 
@@ -10,6 +10,8 @@ use std::sync::{
 };
 
 use mentat_core::{
+    AggregateRegistry,
+    FunctionRegistry,
     Schema,
 };
 
@@ -23,9 +25,21 @@ use mentat_db::cache::{
 
 pub struct Metadata {
     pub generation: u64,
+
+    /// Incremented whenever something that queries are planned against changes: the schema, the
+    /// set of cached attributes, or the custom aggregates and functions. Cached query plans from an
+    /// earlier generation are discarded.
+    pub schema_generation: u64,
+
+    /// Incremented whenever a transaction changes the values of cached attributes. Cached query
+    /// plans that read those values in an earlier generation are discarded.
+    pub cache_generation: u64,
+
     pub partition_map: PartitionMap,
     pub schema: Arc<Schema>,
     pub attribute_cache: SQLiteAttributeCache,
+    pub aggregates: Arc<AggregateRegistry>,
+    pub functions: Arc<FunctionRegistry>,
 }
 
 impl Metadata {
@@ -33,9 +47,13 @@ impl Metadata {
     pub fn new(generation: u64, partition_map: PartitionMap, schema: Arc<Schema>, cache: SQLiteAttributeCache) -> Metadata {
         Metadata {
             generation: generation,
+            schema_generation: 0,
+            cache_generation: 0,
             partition_map: partition_map,
             schema: schema,
             attribute_cache: cache,
+            aggregates: Default::default(),
+            functions: Default::default(),
         }
     }
 }
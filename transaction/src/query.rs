@@ -11,30 +11,57 @@
 use rusqlite;
 use rusqlite::types::ToSql;
 
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use std::borrow::Borrow;
+
 use std::rc::Rc;
 
+use std::sync::Arc;
+
+use std::vec;
+
 use core_traits::{
     Binding,
     Entid,
+    KeyedRow,
     KnownEntid,
     TypedValue,
+    ValueType,
+    ValueTypeSet,
 };
 
 use mentat_core::{
+    AggregateRegistry,
+    CachedAttributes,
+    FunctionRegistry,
     HasSchema,
     Schema,
 };
 
+use mentat_db::{
+    TypedSQLValue,
+};
+
 use mentat_query_algebrizer::{
     AlgebraicQuery,
-    EmptyBecause,
-    FindQuery,
+    SourceAlias,
+    algebrize_explained,
     algebrize_with_inputs,
-    parse_find_string,
+};
+
+use query_algebrizer_traits::errors::{
+    AlgebrizerError,
 };
 
 pub use mentat_query_algebrizer::{
+    FindQuery,
     QueryInputs,
+    QueryInputsKey,
+    parse_find_string,
 };
 
 pub use edn::query::{
@@ -43,6 +70,8 @@ pub use edn::query::{
     Variable,
 };
 
+use edn::Value;
+
 use edn::query::{
     Element,
     FindSpec,
@@ -55,6 +84,7 @@ use edn::query::{
 use mentat_query_projector::{
     ConstantProjector,
     Projector,
+    RowProjector,
 };
 
 use mentat_query_projector::translate::{
@@ -62,12 +92,20 @@ use mentat_query_projector::translate::{
     query_to_select,
 };
 
+use mentat_query_sql::{
+    variable_parameter_name,
+};
+
 use mentat_sql::{
     SQLQuery,
 };
 
 pub use mentat_query_algebrizer::{
+    ClauseDecision,
+    DatabaseView,
+    EmptyBecause,
     Known,
+    Source,
 };
 
 pub use mentat_query_projector::{
@@ -84,9 +122,12 @@ use public_traits::errors::{
 pub type QueryExecutionResult = Result<QueryOutput>;
 pub type PreparedResult<'sqlite> = Result<PreparedQuery<'sqlite>>;
 
+/// A prepared query can be run long after it was prepared, while the only cache it could use is
+/// that of the transaction or store that prepared it, whose values may since have changed. So it
+/// fetches the attributes it pulls, rather than looking them up in the cache.
 pub enum PreparedQuery<'sqlite> {
     Empty {
-        find_spec: Rc<FindSpec>,
+        find_spec: Arc<FindSpec>,
     },
     Constant {
         select: ConstantProjector,
@@ -98,11 +139,17 @@ pub enum PreparedQuery<'sqlite> {
         args: Vec<(String, Rc<rusqlite::types::Value>)>,
         projector: Box<dyn Projector>,
     },
+    Parameterized(ParameterizedQuery<'sqlite>),
 }
 
 impl<'sqlite> PreparedQuery<'sqlite> {
-    pub fn run<T>(&mut self, _inputs: T) -> QueryExecutionResult where T: Into<Option<QueryInputs>> {
+    /// Run the query. A `Parameterized` query takes the values of its parameters from `inputs`;
+    /// other queries have no use for `inputs`.
+    pub fn run<T>(&mut self, inputs: T) -> QueryExecutionResult where T: Into<Option<QueryInputs>> {
         match self {
+            &mut PreparedQuery::Parameterized(ref mut query) => {
+                query.run(inputs.into().unwrap_or_default())
+            },
             &mut PreparedQuery::Empty { ref find_spec } => {
                 Ok(QueryOutput::empty(find_spec))
             },
@@ -111,11 +158,288 @@ impl<'sqlite> PreparedQuery<'sqlite> {
             },
             &mut PreparedQuery::Bound { ref mut statement, ref schema, ref connection, ref args, ref projector } => {
                 let rows = run_statement(statement, args)?;
-                projector.project(schema, connection, rows)
+                projector.project(schema, connection, None, rows)
                          .map_err(|e| e.into())
             }
         }
     }
+
+    /// Like `run`, but return the query's rows as they're read, rather than collecting them.
+    /// See `QueryRows`.
+    pub fn rows<T>(&mut self, inputs: T) -> Result<QueryRows<'_>> where T: Into<Option<QueryInputs>> {
+        match self {
+            &mut PreparedQuery::Parameterized(ref mut query) => {
+                query.rows(inputs.into().unwrap_or_default())
+            },
+            &mut PreparedQuery::Empty { ref find_spec } => {
+                Ok(QueryRows::collected(QueryOutput::empty(find_spec), None))
+            },
+            &mut PreparedQuery::Constant { ref select } => {
+                Ok(QueryRows::collected(select.project_without_rows()?, select.keys().cloned()))
+            },
+            &mut PreparedQuery::Bound { ref mut statement, ref schema, connection, ref args, ref projector } => {
+                let rows = run_statement(statement, args)?;
+                QueryRows::project(schema, connection, None, projector, rows)
+            }
+        }
+    }
+}
+
+/// The rows of a query's results, each read from SQLite and projected only when the iterator
+/// reaches it, so that no more than one row is held in memory at once. Each row has a binding
+/// for each column; a scalar or coll query's rows have one. The rows of a query with `:keys`,
+/// `:strs`, or `:syms` have their values in the order of `keys`; `maps` names them.
+///
+/// Queries that pull can't be projected a row at a time: they run to completion before the
+/// first row is returned.
+pub struct QueryRows<'stmt> {
+    source: RowSource<'stmt>,
+    keys: Option<Arc<Vec<Value>>>,
+}
+
+enum RowSource<'stmt> {
+    Streamed {
+        rows: rusqlite::Rows<'stmt>,
+        projector: &'stmt dyn RowProjector,
+    },
+    Collected(vec::IntoIter<Vec<Binding>>),
+}
+
+impl<'stmt> QueryRows<'stmt> {
+    fn project(schema: &Schema,
+               sqlite: &rusqlite::Connection,
+               cache: Option<&dyn CachedAttributes>,
+               projector: &'stmt Box<dyn Projector>,
+               rows: rusqlite::Rows<'stmt>) -> Result<QueryRows<'stmt>> {
+        let keys = projector.keys().cloned();
+        match projector.row_projector() {
+            Some(row_projector) => Ok(QueryRows {
+                source: RowSource::Streamed {
+                    rows: rows,
+                    projector: row_projector,
+                },
+                keys,
+            }),
+            None => Ok(QueryRows::collected(projector.project(schema, sqlite, cache, rows)?, keys)),
+        }
+    }
+
+    fn collected(output: QueryOutput, keys: Option<Arc<Vec<Value>>>) -> QueryRows<'stmt> {
+        QueryRows {
+            source: RowSource::Collected(output.results.into_rows().into_iter()),
+            keys,
+        }
+    }
+
+    /// The names that `:keys`, `:strs`, or `:syms` give the columns, if the query has them.
+    pub fn keys(&self) -> Option<&[Value]> {
+        self.keys.as_ref().map(|keys| keys.as_slice())
+    }
+
+    /// These rows, each keyed by the names in `keys`, or `None` if the query doesn't name its
+    /// columns.
+    pub fn maps(self) -> Option<KeyedRows<'stmt>> {
+        self.keys.clone().map(|keys| KeyedRows { rows: self, keys })
+    }
+
+    /// Whether rows are read as they're iterated, rather than all before the first.
+    pub fn is_streamed(&self) -> bool {
+        match self.source {
+            RowSource::Streamed { .. } => true,
+            RowSource::Collected(_) => false,
+        }
+    }
+}
+
+impl<'stmt> Iterator for QueryRows<'stmt> {
+    type Item = Result<Vec<Binding>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self.source {
+            RowSource::Streamed { ref mut rows, projector } => {
+                match rows.next() {
+                    Ok(Some(row)) => projector.project_row(row).map_err(|e| e.into()),
+                    Ok(None) => return None,
+                    Err(e) => Err(e.into()),
+                }
+            },
+            RowSource::Collected(ref mut rows) => return rows.next().map(Ok),
+        };
+        if result.is_err() {
+            // There's nothing more to read after an error.
+            self.source = RowSource::Collected(vec![].into_iter());
+        }
+        Some(result)
+    }
+}
+
+/// The rows of a query with `:keys`, `:strs`, or `:syms`, read as they're reached, just like
+/// `QueryRows`. See `QueryRows::maps`.
+pub struct KeyedRows<'stmt> {
+    rows: QueryRows<'stmt>,
+    keys: Arc<Vec<Value>>,
+}
+
+impl<'stmt> Iterator for KeyedRows<'stmt> {
+    type Item = Result<KeyedRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let keys = &self.keys;
+        self.rows.next().map(|row| row.map(|row| KeyedRow(keys.iter().cloned().zip(row).collect())))
+    }
+}
+
+/// A prepared query with inputs whose values are only supplied when it's run: its parameters.
+///
+/// Until then, the query can't be fully algebrized, because that depends on the types of the
+/// parameters. The first time it's run with parameters of some types, it's algebrized and
+/// translated with a SQL parameter in place of each of their values, and the statement is kept.
+/// Later runs with parameters of the same types only bind their values.
+///
+/// Some queries need the values themselves -- for example, to decide which attribute a pattern
+/// refers to. Those are algebrized again each time they're run.
+pub struct ParameterizedQuery<'sqlite> {
+    connection: &'sqlite rusqlite::Connection,
+    schema: Schema,
+    view: DatabaseView,
+    aggregates: AggregateRegistry,
+    functions: FunctionRegistry,
+    query: FindQuery,
+    inputs: QueryInputs,
+    parameters: BTreeSet<Variable>,
+    specializations: BTreeMap<BTreeMap<Variable, ValueType>, Specialization<'sqlite>>,
+}
+
+enum Specialization<'sqlite> {
+    Planned {
+        plan: QueryPlan,
+        statement: Option<rusqlite::Statement<'sqlite>>,
+    },
+    ByValue,
+}
+
+impl<'sqlite> ParameterizedQuery<'sqlite> {
+    /// The inputs whose values must be supplied each time the query is run.
+    pub fn parameters(&self) -> &BTreeSet<Variable> {
+        &self.parameters
+    }
+
+    fn known(&self) -> Known<'_, '_> {
+        Known::for_view(&self.schema, self.view)
+            .with_aggregates(&self.aggregates)
+            .with_functions(&self.functions)
+    }
+
+    /// Algebrize and translate the query for parameters of these types.
+    fn specialize(&self, types: &BTreeMap<Variable, ValueType>) -> Result<Specialization<'sqlite>> {
+        let inputs = types.iter().fold(self.inputs.clone(), |inputs, (var, t)| inputs.with_type(var.clone(), *t));
+        match algebrize_with_inputs(self.known(), self.query.clone(), 0, inputs) {
+            Ok(algebrized) => Specialization::plan(self.known(), self.connection, algebrized),
+            Err(AlgebrizerError::UnboundVariable(_)) => Ok(Specialization::ByValue),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Check that `inputs` has a value for each parameter, and that any limit or offset is in
+    /// range, and specialize the query for their types if it hasn't been already.
+    fn bind(&mut self, inputs: QueryInputs) -> Result<(BTreeMap<Variable, TypedValue>, BTreeMap<Variable, ValueType>)> {
+        let mut values = BTreeMap::new();
+        let mut missing = BTreeSet::new();
+        for var in self.parameters.iter() {
+            match inputs.value(var) {
+                Some(value) => { values.insert(var.clone(), value.clone()); },
+                None => { missing.insert(var.to_string()); },
+            }
+        }
+        if !missing.is_empty() {
+            bail!(MentatError::UnboundVariables(missing));
+        }
+
+        // A limit or offset parameter is bound straight into the statement, so check it here.
+        self.query.check_limit_and_offset(&values)?;
+
+        let types: BTreeMap<Variable, ValueType> = values.iter().map(|(var, v)| (var.clone(), v.value_type())).collect();
+        if !self.specializations.contains_key(&types) {
+            let specialization = self.specialize(&types)?;
+            self.specializations.insert(types.clone(), specialization);
+        }
+        Ok((values, types))
+    }
+
+    fn run(&mut self, inputs: QueryInputs) -> QueryExecutionResult {
+        let (values, types) = self.bind(inputs)?;
+        self.run_bound(values, &types)
+    }
+
+    fn rows(&mut self, inputs: QueryInputs) -> Result<QueryRows<'_>> {
+        let (values, types) = self.bind(inputs)?;
+        let prepared = match self.specializations.get(&types) {
+            Some(&Specialization::Planned { statement: Some(_), .. }) => true,
+            _ => false,
+        };
+        if !prepared {
+            let keys = self.query.return_map.as_ref().map(|m| Arc::new(m.keys()));
+            return Ok(QueryRows::collected(self.run_bound(values, &types)?, keys));
+        }
+
+        let schema = &self.schema;
+        let connection = self.connection;
+        match self.specializations.get_mut(&types) {
+            Some(&mut Specialization::Planned { ref plan, statement: Some(ref mut statement) }) => {
+                let projector = match plan {
+                    &QueryPlan::Query { ref projector, .. } => projector,
+                    _ => unreachable!("only SQL queries have statements"),
+                };
+                let rows = run_statement_with_parameters(statement, plan_args(plan), &values)?;
+                QueryRows::project(schema, connection, None, projector, rows)
+            },
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    fn run_bound(&mut self, values: BTreeMap<Variable, TypedValue>, types: &BTreeMap<Variable, ValueType>) -> QueryExecutionResult {
+        let schema = &self.schema;
+        let connection = self.connection;
+        match self.specializations.get_mut(types) {
+            Some(&mut Specialization::Planned { ref plan, statement: Some(ref mut statement) }) => {
+                let projector = match plan {
+                    &QueryPlan::Query { ref projector, .. } => projector,
+                    _ => unreachable!("only SQL queries have statements"),
+                };
+                let args = plan_args(plan);
+                let rows = run_statement_with_parameters(statement, args, &values)?;
+                projector.project(schema, connection, None, rows).map_err(|e| e.into())
+            },
+            Some(&mut Specialization::Planned { ref plan, statement: None }) => {
+                plan.run(schema, connection, None, &values)
+            },
+            _ => {
+                let inputs = values.into_iter().fold(self.inputs.clone(), |inputs, (var, v)| inputs.with_value(var, v));
+                let known = Known::for_view(&self.schema, self.view)
+                    .with_aggregates(&self.aggregates)
+                    .with_functions(&self.functions);
+                q_plan(known, self.query.clone(), inputs)?.run(schema, connection, None, &BTreeMap::new())
+            },
+        }
+    }
+}
+
+impl<'sqlite> Specialization<'sqlite> {
+    fn plan(known: Known, connection: &'sqlite rusqlite::Connection, algebrized: AlgebraicQuery) -> Result<Specialization<'sqlite>> {
+        let plan = plan_algebrized_query(known, algebrized)?;
+        let statement = match &plan {
+            &QueryPlan::Query { ref sql, .. } => Some(connection.prepare(sql.as_str())?),
+            _ => None,
+        };
+        Ok(Specialization::Planned { plan, statement })
+    }
+}
+
+fn plan_args(plan: &QueryPlan) -> &[(String, rusqlite::types::Value)] {
+    match plan {
+        &QueryPlan::Query { ref args, .. } => args,
+        _ => &[],
+    }
 }
 
 pub trait IntoResult {
@@ -143,8 +467,22 @@ impl IntoResult for QueryExecutionResult {
     }
 }
 
-/// A struct describing information about how Mentat would execute a query.
-pub enum QueryExplanation {
+/// A description of how Mentat would execute a query, and why.
+#[derive(Debug)]
+pub struct QueryExplanation {
+    /// The types that each variable is known to have before the query is run.
+    pub known_types: BTreeMap<Variable, ValueTypeSet>,
+
+    /// The algebrizer's decisions about the query's clauses, in the order it made them. Those
+    /// about the clauses within an `or`, a rule, or a `not` are nested within a decision about it.
+    pub decisions: Vec<ClauseDecision>,
+
+    /// What would be run.
+    pub plan: QueryPlanExplanation,
+}
+
+#[derive(Debug)]
+pub enum QueryPlanExplanation {
     /// A query known in advance to be empty, and why we believe that.
     KnownEmpty(EmptyBecause),
 
@@ -162,11 +500,48 @@ pub enum QueryExplanation {
 
 /// A single row in the output of SQLite's `EXPLAIN QUERY PLAN`.
 /// See https://www.sqlite.org/eqp.html for an explanation of each field.
+#[derive(Debug)]
 pub struct QueryPlanStep {
     pub select_id: i32,
     pub order: i32,
     pub from: i32,
     pub detail: String,
+
+    /// The patterns matched against the table this step reads, if it reads one of theirs.
+    pub patterns: Vec<String>,
+}
+
+impl QueryPlanStep {
+    fn new(select_id: i32, order: i32, from: i32, detail: String, decisions: &[ClauseDecision]) -> QueryPlanStep {
+        let mut patterns = vec![];
+        QueryPlanStep::collect_patterns(&detail, decisions, &mut patterns);
+        QueryPlanStep { select_id, order, from, detail, patterns }
+    }
+
+    /// Collect the patterns among `decisions`, however deeply nested, that are matched against
+    /// the table `detail` reads.
+    fn collect_patterns(detail: &str, decisions: &[ClauseDecision], patterns: &mut Vec<String>) {
+        for decision in decisions {
+            match decision {
+                // The detail names the alias of the table it reads, like "SEARCH datoms01 USING INDEX …".
+                &ClauseDecision::Table { ref pattern, alias: SourceAlias(_, ref alias) } => {
+                    if detail.split(|c: char| !(c.is_alphanumeric() || c == '_')).any(|word| word == alias) {
+                        patterns.push(pattern.clone());
+                    }
+                },
+                &ClauseDecision::Or(ref arms) => {
+                    for arm in arms {
+                        QueryPlanStep::collect_patterns(detail, arm, patterns);
+                    }
+                },
+                &ClauseDecision::Not(ref decisions) => {
+                    QueryPlanStep::collect_patterns(detail, decisions, patterns);
+                },
+                &ClauseDecision::Cached { .. } |
+                &ClauseDecision::Empty(_) => {},
+            }
+        }
+    }
 }
 
 fn algebrize_query<T>
@@ -176,6 +551,10 @@ fn algebrize_query<T>
     where T: Into<Option<QueryInputs>>
 {
     let algebrized = algebrize_with_inputs(known, query, 0, inputs.into().unwrap_or(QueryInputs::default()))?;
+    check_bound(algebrized)
+}
+
+fn check_bound(algebrized: AlgebraicQuery) -> Result<AlgebraicQuery> {
     let unbound = algebrized.unbound_variables();
     // Because we are running once, we can check that all of our `:in` variables are bound at this point.
     // If they aren't, the user has made an error -- perhaps writing the wrong variable in `:in`, or
@@ -287,18 +666,37 @@ pub fn lookup_values_for_attribute<'sqlite, 'attribute, E>
     lookup_values(sqlite, known, entity.into(), attribute)
 }
 
-fn run_statement<'sqlite, 'stmt, 'bound>
+fn run_statement<'sqlite, 'stmt, 'bound, V>
 (statement: &'stmt mut rusqlite::Statement<'sqlite>,
- bindings: &'bound [(String, Rc<rusqlite::types::Value>)]) -> Result<rusqlite::Rows<'stmt>> {
+ bindings: &'bound [(String, V)]) -> Result<rusqlite::Rows<'stmt>>
+    where V: Borrow<rusqlite::types::Value> {
+    run_statement_with_parameters(statement, bindings, &BTreeMap::new())
+}
 
-    let rows = if bindings.is_empty() {
+/// Like `run_statement`, but also bind the values of any parameters -- see
+/// `QueryValue::Parameter` -- that the statement uses.
+fn run_statement_with_parameters<'sqlite, 'stmt, 'bound, V>
+(statement: &'stmt mut rusqlite::Statement<'sqlite>,
+ bindings: &'bound [(String, V)],
+ parameters: &'bound BTreeMap<Variable, TypedValue>) -> Result<rusqlite::Rows<'stmt>>
+    where V: Borrow<rusqlite::types::Value> {
+    let mut names = vec![];
+    for (var, value) in parameters.iter() {
+        let name = format!("${}", variable_parameter_name(var));
+        if statement.parameter_index(name.as_str())?.is_some() {
+            names.push((name, value.to_sql_value_pair().0));
+        }
+    }
+
+    let rows = if bindings.is_empty() && names.is_empty() {
         statement.query(())?
     } else {
         let refs: Vec<(&str, &dyn ToSql)> =
             bindings.iter()
-                    .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &dyn ToSql))
+                    .map(|&(ref k, ref v)| (k.as_str(), v.borrow() as &dyn ToSql))
+                    .chain(names.iter().map(|&(ref k, ref v)| (k.as_str(), v as &dyn ToSql)))
                     .collect();
-        statement.query(rusqlite::params_from_iter(refs.iter().map(|(_, v)| *v)))?
+        statement.query(refs.as_slice())?
     };
     Ok(rows)
 }
@@ -328,31 +726,139 @@ fn algebrize_query_str<'query, T>
     algebrize_query(known, parsed, inputs)
 }
 
-fn run_algebrized_query<'sqlite>
-(known: Known,
- sqlite: &'sqlite rusqlite::Connection,
- algebrized: AlgebraicQuery) -> QueryExecutionResult {
-    assert!(algebrized.unbound_variables().is_empty(),
-            "Unbound variables should be checked by now");
-    if algebrized.is_known_empty() {
+/// A query that has been algebrized and translated to SQL. Unlike a `PreparedQuery`, it isn't tied
+/// to a SQLite connection, and can be run any number of times against any connection with the
+/// custom aggregates and functions that it uses. Plans can be shared between threads.
+///
+/// Inputs that were given a type but no value when the query was planned are its parameters:
+/// their values are supplied each time it's run.
+pub enum QueryPlan {
+    Empty {
+        find_spec: Arc<FindSpec>,
+    },
+    Constant {
+        select: ConstantProjector,
+    },
+    Query {
+        sql: String,
+        args: Vec<(String, rusqlite::types::Value)>,
+        projector: Box<dyn Projector>,
+    },
+}
+
+impl QueryPlan {
+    pub fn run<'sqlite>(&self,
+                        schema: &Schema,
+                        sqlite: &'sqlite rusqlite::Connection,
+                        cache: Option<&dyn CachedAttributes>,
+                        parameters: &BTreeMap<Variable, TypedValue>) -> QueryExecutionResult {
+        match self {
+            &QueryPlan::Empty { ref find_spec } => {
+                Ok(QueryOutput::empty(find_spec))
+            },
+            &QueryPlan::Constant { ref select } => {
+                select.project_without_rows().map_err(|e| e.into())
+            },
+            &QueryPlan::Query { ref sql, ref args, ref projector } => {
+                let mut statement = sqlite.prepare(sql.as_str())?;
+                let rows = run_statement_with_parameters(&mut statement, args, parameters)?;
+
+                projector.project(schema, sqlite, cache, rows).map_err(|e| e.into())
+            },
+        }
+    }
+
+    /// Run the plan, and call `f` with its rows, which are read as `f` iterates over them. See
+    /// `QueryRows`.
+    pub fn iter<'sqlite, F, R>(&self,
+                               schema: &Schema,
+                               sqlite: &'sqlite rusqlite::Connection,
+                               cache: Option<&dyn CachedAttributes>,
+                               parameters: &BTreeMap<Variable, TypedValue>,
+                               f: F) -> Result<R>
+        where F: FnOnce(QueryRows) -> Result<R> {
+        match self {
+            &QueryPlan::Empty { ref find_spec } => {
+                f(QueryRows::collected(QueryOutput::empty(find_spec), None))
+            },
+            &QueryPlan::Constant { ref select } => {
+                f(QueryRows::collected(select.project_without_rows()?, select.keys().cloned()))
+            },
+            &QueryPlan::Query { ref sql, ref args, ref projector } => {
+                let mut statement = sqlite.prepare(sql.as_str())?;
+                let rows = run_statement_with_parameters(&mut statement, args, parameters)?;
+                let rows = QueryRows::project(schema, sqlite, cache, projector, rows)?;
+                f(rows)
+            },
+        }
+    }
+}
+
+/// Translate `algebrized`. Any inputs it leaves unbound are parameters: their values must be
+/// bound when the plan's SQL is run.
+fn plan_algebrized_query(known: Known, algebrized: AlgebraicQuery) -> Result<QueryPlan> {
+    // The projector knows the shape of the empty results of a query with a return map.
+    if algebrized.is_known_empty() && algebrized.return_map.is_none() {
         // We don't need to do any SQL work at all.
-        return Ok(QueryOutput::empty(&algebrized.find_spec));
+        return Ok(QueryPlan::Empty {
+            find_spec: algebrized.find_spec,
+        });
     }
 
     let select = query_to_select(known.schema, algebrized)?;
     match select {
         ProjectedSelect::Constant(constant) => {
-            constant.project_without_rows()
-                    .map_err(|e| e.into())
+            Ok(QueryPlan::Constant {
+                select: constant,
+            })
         },
         ProjectedSelect::Query { query, projector } => {
             let SQLQuery { sql, args } = query.to_sql_query()?;
+            Ok(QueryPlan::Query {
+                sql: sql,
+                args: args.into_iter().map(|(name, value)| (name, (*value).clone())).collect(),
+                projector: projector,
+            })
+        },
+    }
+}
 
-            let mut statement = sqlite.prepare(sql.as_str())?;
-            let rows = run_statement(&mut statement, &args)?;
+fn run_algebrized_query<'sqlite>
+(known: Known,
+ sqlite: &'sqlite rusqlite::Connection,
+ algebrized: AlgebraicQuery) -> QueryExecutionResult {
+    assert!(algebrized.unbound_variables().is_empty(),
+            "Unbound variables should be checked by now");
+    plan_algebrized_query(known, algebrized)?.run(known.schema, sqlite, known.cache, &BTreeMap::new())
+}
 
-            projector.project(known.schema, sqlite, rows).map_err(|e| e.into())
+/// Algebrize and translate a parsed query, without running it.
+pub fn q_plan<T>
+(known: Known,
+ query: FindQuery,
+ inputs: T) -> Result<QueryPlan>
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query(known, query, inputs)?;
+    plan_algebrized_query(known, algebrized)
+}
+
+/// Like `q_plan`, but plan the query with only the types of the values in `inputs`, so that the
+/// plan can be run with any values of those types. Return the plan and the inputs whose values
+/// must be supplied when it's run, or `None` if the query needs the values themselves to be
+/// planned.
+pub fn q_plan_parameterized
+(known: Known,
+ query: FindQuery,
+ inputs: QueryInputs) -> Result<Option<(QueryPlan, BTreeSet<Variable>)>>
+{
+    match algebrize_with_inputs(known, query, 0, inputs.without_values()) {
+        Ok(algebrized) => {
+            let parameters = algebrized.unbound_variables();
+            Ok(Some((plan_algebrized_query(known, algebrized)?, parameters)))
         },
+        Err(AlgebrizerError::UnboundVariable(_)) => Ok(None),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -374,6 +880,24 @@ pub fn q_once<'sqlite, 'query, T>
     run_algebrized_query(known, sqlite, algebrized)
 }
 
+/// Like `q_once`, but rather than collecting the results into a `QueryOutput`, call `f` with an
+/// iterator over their rows, each of which is read from SQLite only when it's reached. This suits
+/// results too large to hold in memory at once.
+pub fn q_iter<'sqlite, 'query, T, F, R>
+(sqlite: &'sqlite rusqlite::Connection,
+ known: Known,
+ query: &'query str,
+ inputs: T,
+ f: F) -> Result<R>
+        where T: Into<Option<QueryInputs>>,
+              F: FnOnce(QueryRows) -> Result<R>
+{
+    let algebrized = algebrize_query_str(known, query, inputs)?;
+    assert!(algebrized.unbound_variables().is_empty(),
+            "Unbound variables should be checked by now");
+    plan_algebrized_query(known, algebrized)?.iter(known.schema, sqlite, known.cache, &BTreeMap::new(), f)
+}
+
 /// Just like `q_once`, but doesn't use any cached values.
 pub fn q_uncached<'sqlite, 'schema, 'query, T>
 (sqlite: &'sqlite rusqlite::Connection,
@@ -388,6 +912,9 @@ pub fn q_uncached<'sqlite, 'schema, 'query, T>
     run_algebrized_query(known, sqlite, algebrized)
 }
 
+/// Prepare a query to be run any number of times. Inputs that aren't given values here are the
+/// query's parameters: their values are supplied each time it's run, and it becomes a
+/// `PreparedQuery::Parameterized`.
 pub fn q_prepare<'sqlite, 'schema, 'cache, 'query, T>
 (sqlite: &'sqlite rusqlite::Connection,
  known: Known<'schema, 'cache>,
@@ -395,16 +922,43 @@ pub fn q_prepare<'sqlite, 'schema, 'cache, 'query, T>
  inputs: T) -> PreparedResult<'sqlite>
         where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_query_str(known, query, inputs)?;
+    let parsed = parse_find_string(query)?;
+    let inputs = inputs.into().unwrap_or_default();
 
-    let unbound = algebrized.unbound_variables();
-    if !unbound.is_empty() {
-        // TODO: Allow binding variables at execution time, not just
-        // preparation time.
-        bail!(MentatError::UnboundVariables(unbound.into_iter().map(|v| v.to_string()).collect()));
-    }
+    // The types of the parameters might not be known yet, so this only checks that the query
+    // makes sense. A query that needs the values of its parameters can't be checked until it's
+    // run.
+    let algebrized = match algebrize_with_inputs(known, parsed.clone(), 0, inputs.clone()) {
+        Ok(algebrized) => Some(algebrized),
+        Err(AlgebrizerError::UnboundVariable(_)) => None,
+        Err(e) => bail!(e),
+    };
+
+    let parameters: BTreeSet<Variable> = match algebrized {
+        Some(ref algebrized) => algebrized.unbound_variables(),
+        None => parsed.in_vars.iter().filter(|var| inputs.value(var).is_none()).cloned().collect(),
+    };
+
+    let algebrized = match algebrized {
+        Some(algebrized) if parameters.is_empty() => algebrized,
+        _ => {
+            // Cached values would be out of date the next time the query runs, so parameterized
+            // queries don't use the cache.
+            return Ok(PreparedQuery::Parameterized(ParameterizedQuery {
+                connection: sqlite,
+                schema: known.schema.clone(),
+                view: known.view,
+                aggregates: known.aggregates.cloned().unwrap_or_default(),
+                functions: known.functions.cloned().unwrap_or_default(),
+                query: parsed,
+                inputs: inputs,
+                parameters: parameters,
+                specializations: BTreeMap::new(),
+            }));
+        },
+    };
 
-    if algebrized.is_known_empty() {
+    if algebrized.is_known_empty() && algebrized.return_map.is_none() {
         // We don't need to do any SQL work at all.
         return Ok(PreparedQuery::Empty {
             find_spec: algebrized.find_spec,
@@ -440,27 +994,31 @@ pub fn q_explain<'sqlite, 'query, T>
  inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_query_str(known, query, inputs)?;
-    if algebrized.is_known_empty() {
-        return Ok(QueryExplanation::KnownEmpty(algebrized.cc.empty_because.unwrap()));
-    }
-    match query_to_select(known.schema, algebrized)? {
-        ProjectedSelect::Constant(_constant) => Ok(QueryExplanation::KnownConstant),
-        ProjectedSelect::Query { query, projector: _projector } => {
-            let query = query.to_sql_query()?;
-
-            let plan_sql = format!("EXPLAIN QUERY PLAN {}", query.sql);
-
-            let steps = run_sql_query(sqlite, &plan_sql, &query.args, |row| {
-                QueryPlanStep {
-                    select_id: row.get(0).unwrap(),
-                    order: row.get(1).unwrap(),
-                    from: row.get(2).unwrap(),
-                    detail: row.get(3).unwrap()
-                }
-            })?;
+    let parsed = parse_find_string(query)?;
+    let algebrized = check_bound(algebrize_explained(known, parsed, inputs.into().unwrap_or_default())?)?;
+    let known_types = algebrized.cc.known_types.clone();
+    let decisions = algebrized.cc.decisions();
+    let plan = if algebrized.is_known_empty() {
+        QueryPlanExplanation::KnownEmpty(algebrized.cc.empty_because.unwrap())
+    } else {
+        match query_to_select(known.schema, algebrized)? {
+            ProjectedSelect::Constant(_constant) => QueryPlanExplanation::KnownConstant,
+            ProjectedSelect::Query { query, projector: _projector } => {
+                let query = query.to_sql_query()?;
 
-            Ok(QueryExplanation::ExecutionPlan { query, steps })
-        },
-    }
+                let plan_sql = format!("EXPLAIN QUERY PLAN {}", query.sql);
+
+                let steps = run_sql_query(sqlite, &plan_sql, &query.args, |row| {
+                    QueryPlanStep::new(row.get(0).unwrap(),
+                                       row.get(1).unwrap(),
+                                       row.get(2).unwrap(),
+                                       row.get(3).unwrap(),
+                                       &decisions)
+                })?;
+
+                QueryPlanExplanation::ExecutionPlan { query, steps }
+            },
+        }
+    };
+    Ok(QueryExplanation { known_types, decisions, plan })
 }